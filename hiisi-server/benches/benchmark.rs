@@ -8,8 +8,11 @@ fn bench(c: &mut Criterion) {
     group.throughput(Throughput::Elements(1));
 
     let path = std::path::Path::new("data");
-    let manager = Rc::new(manager::ResourceManager::new(path));
-    manager.create_database("test").unwrap();
+    let manager = Rc::new(manager::ResourceManager::new(path, [0; 32]));
+    match manager.create_database("test") {
+        Ok(()) | Err(hiisi::HiisiError::DatabaseExists(_)) => {}
+        Err(e) => panic!("Failed to create database: {}", e),
+    }
     group.bench_function("execute", |b| {
         b.iter(|| {
             let exec_req = hiisi::proto::StreamRequest::Execute(hiisi::proto::ExecuteStreamReq {
@@ -28,11 +31,40 @@ fn bench(c: &mut Criterion) {
             };
             let req = hiisi::executor::Request {
                 database: "test".to_string(),
+                version: hiisi::proto::Version::Hrana2,
                 req,
             };
             hiisi::executor::execute_client_req(manager.clone(), req).unwrap();
         });
     });
+
+    let blob_req = hiisi::proto::PipelineReqBody {
+        baton: None,
+        requests: vec![hiisi::proto::StreamRequest::Execute(
+            hiisi::proto::ExecuteStreamReq {
+                stmt: hiisi::proto::Stmt {
+                    sql: Some("INSERT INTO t VALUES (?)".to_string()),
+                    sql_id: None,
+                    args: vec![hiisi::proto::Value::Blob {
+                        value: bytes::Bytes::from(vec![0xa5; 4 << 20]),
+                    }],
+                    named_args: vec![],
+                    want_rows: None,
+                    replication_index: None,
+                },
+            },
+        )],
+    };
+    group.bench_function("format_msg_blob", |b| {
+        b.iter(|| hiisi::proto::format_msg(&blob_req).unwrap());
+    });
+    group.bench_function("format_msg_into_blob", |b| {
+        let mut buf = bytes::BytesMut::new();
+        b.iter(|| {
+            buf.clear();
+            hiisi::proto::format_msg_into(&blob_req, &mut buf).unwrap();
+        });
+    });
 }
 
 criterion_group! {
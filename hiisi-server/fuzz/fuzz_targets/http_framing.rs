@@ -0,0 +1,74 @@
+//! Feed arbitrary bytes to the HTTP framing of the server, both as raw bytes
+//! cut at an arbitrary point and as the body of requests whose framing is
+//! valid, split into chunks at arbitrary points.
+//!
+//! The requests are handled like the server handles the requests it
+//! receives, against an in-memory database, so the target exercises the
+//! request parser, the executor and the response formatting together.
+
+#![no_main]
+
+use hiisi::server::{handle_bytes, DEFAULT_DATABASE};
+use hiisi::{client, Context, ResourceManager, IO};
+use libfuzzer_sys::fuzz_target;
+use socket2::{Domain, Socket, Type};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+struct Server {
+    io: IO<()>,
+    sock: Socket,
+}
+
+thread_local! {
+    // Setting up the server for every input would dominate the run time.
+    static SERVER: RefCell<Server> = RefCell::new({
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database(DEFAULT_DATABASE).unwrap();
+        Server {
+            io: IO::new(Context::new(manager, ())),
+            sock: Socket::new(Domain::IPV4, Type::STREAM, None).unwrap(),
+        }
+    });
+}
+
+const HOST: &str = "default.localhost";
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&split, data)) = data.split_first() else {
+        return;
+    };
+    SERVER.with(|server| {
+        let server = &mut *server.borrow_mut();
+        let (io, sock) = (&mut server.io, &server.sock);
+
+        // A request may be cut short at any point, and its rest may arrive
+        // on its own.
+        let split = usize::from(split).min(data.len());
+        handle_bytes(io, sock, &data[..split]);
+        handle_bytes(io, sock, &data[split..]);
+        handle_bytes(io, sock, data);
+
+        handle_bytes(
+            io,
+            sock,
+            &client::format_request(HOST, "/v2/pipeline", data),
+        );
+        handle_bytes(io, sock, &client::format_request(HOST, "/v3/cursor", data));
+        // The bytes double as the chunk sizes: every chunk is as long as the
+        // value of its first byte.
+        let mut chunks = Vec::new();
+        let mut rest = data;
+        while let Some(&len) = rest.first() {
+            let len = usize::from(len).clamp(1, rest.len());
+            let (chunk, tail) = rest.split_at(len);
+            chunks.push(chunk);
+            rest = tail;
+        }
+        handle_bytes(
+            io,
+            sock,
+            &client::format_chunked_request(HOST, "/v3/pipeline", &chunks),
+        );
+    });
+});
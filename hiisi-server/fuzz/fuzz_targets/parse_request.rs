@@ -0,0 +1,13 @@
+//! Feed arbitrary bytes to the parsers of request bodies, which must fail
+//! with an error rather than panic on any input.
+
+#![no_main]
+
+use hiisi::proto::{self, Encoding};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = proto::parse_client_req(data, Encoding::Json);
+    let _ = proto::parse_client_req(data, Encoding::Protobuf);
+    let _ = proto::parse_cursor_req(data);
+});
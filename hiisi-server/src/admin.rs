@@ -1,12 +1,24 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use serde::Deserialize;
 use socket2::{SockAddr, Socket};
 
 use std::rc::Rc;
+use std::time::Duration;
 
+use crate::database::Backup;
 use crate::http;
+use crate::ratelimit::RateLimit;
 use crate::{server::IO, HiisiError, Result};
 
+/// How many pages a backup copies before it lets the server handle other
+/// work.
+const BACKUP_PAGES_PER_STEP: i32 = 64;
+
+/// How many bytes of the image of a backup the response sends at a time.
+const BACKUP_CHUNK_SIZE: usize = 64 * 1024;
+
 pub fn serve_admin<T>(io: &mut IO<T>, sock: Rc<Socket>, addr: SockAddr) {
+    io.context().listeners.borrow_mut().push(sock.clone());
     io.accept(sock, addr, on_accept);
 }
 
@@ -19,54 +31,272 @@ fn on_accept<T>(
 ) {
     log::trace!("Server accepted connection from {:?}", sock_addr);
     conn_sock.set_nodelay(true).unwrap();
+    let peer = sock_addr.as_socket().map(|addr| addr.ip());
+    io.context().add_admin_conn(conn_sock.clone(), peer);
     io.accept(server_sock, server_addr, on_accept);
-    io.recv(conn_sock, on_recv);
+    recv_request(io, conn_sock);
+}
+
+fn recv_request<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    let timeout = io.context().idle_timeout;
+    io.recv_timeout(sock, timeout, on_recv, on_recv_timeout);
+}
+
+fn on_recv_timeout<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    log::trace!("Closing idle connection");
+    io.close(sock, on_close);
+}
+
+fn on_close<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    log::trace!("Connection closed");
+    io.context().remove_admin_conn(&sock);
 }
 
 fn on_recv<T>(io: &mut IO<T>, sock: Rc<Socket>, buf: &[u8], n: usize) {
     if n == 0 {
         log::trace!("Client closed connection");
-        io.close(sock);
+        io.close(sock, on_close);
         return;
     }
-    let resp = match execute_request(io, &buf[..n]) {
-        Ok(resp) => http::format_response(resp, http::StatusCode::OK),
-        Err(x) => http::format_response(
-            format!("{}", x).into(),
-            http::StatusCode::INTERNAL_SERVER_ERROR,
-        ),
-    };
+    io.context().set_busy(&sock, true);
+    match execute_request(io, &buf[..n]) {
+        Ok(Response::Complete(resp)) => send_response(io, sock, resp),
+        Ok(Response::Backup(backup)) => step_backup(io, sock, backup),
+        Err(err) => send_response(io, sock, format_error(&err)),
+    }
+}
+
+fn format_error(err: &HiisiError) -> Bytes {
+    http::ResponseBuilder::new(err.status()).build(format!("{}", err).into())
+}
 
+fn send_response<T>(io: &mut IO<T>, sock: Rc<Socket>, resp: Bytes) {
     let n = resp.len();
     io.send(sock, resp, n, on_send);
 }
 
-fn on_send<T>(io: &mut IO<T>, sock: Rc<Socket>, _n: usize) {
-    io.recv(sock, on_recv)
+/// Copy the next pages of a backup, and start sending the copy of the
+/// database as the response once the backup is complete.
+///
+/// The server handles other work between the steps, which a zero-length
+/// sleep on the socket of the backup yields to.
+fn step_backup<T>(io: &mut IO<T>, sock: Rc<Socket>, mut backup: Backup) {
+    match backup.step(BACKUP_PAGES_PER_STEP) {
+        Ok(false) => {
+            io.context().put_backup(&sock, backup);
+            io.sleep(sock, Duration::ZERO, on_backup_step);
+        }
+        Ok(true) => match backup.finish() {
+            Ok(()) => {
+                let head = http::format_chunked_response_head(
+                    http::StatusCode::OK,
+                    "application/octet-stream",
+                    true,
+                );
+                send_backup_chunk(io, sock, backup, head);
+            }
+            Err(err) => send_response(io, sock, format_error(&err)),
+        },
+        Err(err) => send_response(io, sock, format_error(&err)),
+    }
+}
+
+fn on_backup_step<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    let Some(backup) = io.context().take_backup(&sock) else {
+        // The connection was closed under the backup.
+        return;
+    };
+    step_backup(io, sock, backup);
+}
+
+/// Send the next chunk of the image of a finished backup after `buf`, or
+/// the chunk that ends the response once all of the image has been sent.
+///
+/// Only one chunk is in flight at a time, so the response is never held in
+/// memory on top of the copy of the database.
+fn send_backup_chunk<T>(io: &mut IO<T>, sock: Rc<Socket>, mut backup: Backup, mut buf: BytesMut) {
+    let data = backup.read_image(BACKUP_CHUNK_SIZE);
+    if data.is_empty() {
+        buf.extend_from_slice(http::LAST_CHUNK);
+        send_response(io, sock, buf.freeze());
+        return;
+    }
+    http::format_chunk(&mut buf, data);
+    io.context().put_backup(&sock, backup);
+    let n = buf.len();
+    io.send(sock, buf.freeze(), n, on_backup_chunk_send);
+}
+
+fn on_backup_chunk_send<T>(io: &mut IO<T>, sock: Rc<Socket>, n: usize) {
+    let Some(backup) = io.context().take_backup(&sock) else {
+        return;
+    };
+    if n == 0 {
+        log::trace!("Client closed connection before receiving backup");
+        io.close(sock, on_close);
+        return;
+    }
+    send_backup_chunk(io, sock, backup, BytesMut::new());
+}
+
+fn on_send<T>(io: &mut IO<T>, sock: Rc<Socket>, n: usize) {
+    if n == 0 {
+        log::trace!("Client closed connection before receiving response");
+        io.close(sock, on_close);
+        return;
+    }
+    io.context().set_busy(&sock, false);
+    if io.context().is_draining() {
+        log::trace!("Closing drained connection");
+        io.close(sock, on_close);
+        return;
+    }
+    recv_request(io, sock)
+}
+
+enum Response {
+    Complete(Bytes),
+    /// A backup, which is copied in steps before its response is sent in
+    /// chunks.
+    Backup(Backup),
 }
 
-fn execute_request<T>(io: &mut IO<T>, buf: &[u8]) -> Result<Bytes> {
+fn execute_request<T>(io: &mut IO<T>, buf: &[u8]) -> Result<Response> {
     let mut headers = [httparse::EMPTY_HEADER; 64];
     let mut req = httparse::Request::new(&mut headers);
-    let _ = req.parse(buf).unwrap().unwrap();
-    match parse_route(req.path.unwrap()) {
-        Some(Route::CreateNamespace(name)) => {
+    let body_off = match req.parse(buf) {
+        Ok(httparse::Status::Complete(body_off)) => body_off,
+        _ => {
+            return Err(HiisiError::ProtocolError(
+                "Malformed HTTP request".to_owned(),
+            ))
+        }
+    };
+    let path = req.path.unwrap_or_default();
+    match (req.method, parse_route(path)) {
+        (Some("POST"), Some(Route::CreateNamespace(name))) => {
+            let options = parse_create_options(&buf[body_off..])?;
             let ctx = io.context();
             ctx.manager.create_database(&name)?;
-            Ok("".into())
+            if options.read_only {
+                ctx.manager.set_read_only(&name, true);
+            }
+            Ok(Response::Complete(
+                http::ResponseBuilder::new(http::StatusCode::CREATED).build(Bytes::new()),
+            ))
+        }
+        (Some("GET"), Some(Route::SchemaVersion(name))) => {
+            let version = io.context().manager.schema_version(&name)?;
+            Ok(Response::Complete(
+                http::ResponseBuilder::new(http::StatusCode::OK)
+                    .with_content_type("application/json")
+                    .build(serde_json::to_vec(&version)?.into()),
+            ))
         }
-        _ => Err(HiisiError::ProtocolError("Invalid path".to_owned()).into()),
+        (Some("POST"), Some(Route::Checkpoint(name))) => {
+            let checkpoint = io.context().manager.checkpoint(&name)?;
+            Ok(Response::Complete(
+                http::ResponseBuilder::new(http::StatusCode::OK)
+                    .with_content_type("application/json")
+                    .build(serde_json::to_vec(&checkpoint)?.into()),
+            ))
+        }
+        (Some("POST"), Some(Route::Vacuum(name))) => {
+            io.context().manager.vacuum(&name)?;
+            Ok(Response::Complete(
+                http::ResponseBuilder::new(http::StatusCode::OK).build(Bytes::new()),
+            ))
+        }
+        (Some("POST"), Some(Route::Rename(name))) => {
+            let options: RenameOptions = serde_json::from_slice(&buf[body_off..])?;
+            io.context().manager.rename_database(&name, &options.to)?;
+            Ok(Response::Complete(
+                http::ResponseBuilder::new(http::StatusCode::OK).build(Bytes::new()),
+            ))
+        }
+        (Some("POST"), Some(Route::RateLimit(name))) => {
+            let options: RateLimitOptions = serde_json::from_slice(&buf[body_off..])?;
+            let ctx = io.context();
+            if !ctx.manager.database_exists(&name) {
+                return Err(HiisiError::DatabaseNotFound(name));
+            }
+            ctx.manager.set_rate_limit(&name, options.to_rate_limit()?);
+            Ok(Response::Complete(
+                http::ResponseBuilder::new(http::StatusCode::OK).build(Bytes::new()),
+            ))
+        }
+        (Some("GET"), Some(Route::Backup(name))) => Ok(Response::Backup(
+            io.context().manager.backup_database(&name)?,
+        )),
+        _ => Err(HiisiError::NotFound(path.to_owned())),
+    }
+}
+
+/// Options of a new database, which the create request carries as a JSON
+/// body. A request without a body creates a database with the defaults.
+#[derive(Default, Deserialize)]
+struct CreateOptions {
+    /// Whether the database is read-only, such as a replica.
+    #[serde(default)]
+    read_only: bool,
+}
+
+fn parse_create_options(body: &[u8]) -> Result<CreateOptions> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(CreateOptions::default());
+    }
+    Ok(serde_json::from_slice(body)?)
+}
+
+/// The body of a rename request.
+#[derive(Deserialize)]
+struct RenameOptions {
+    /// The new name of the database.
+    to: String,
+}
+
+/// The body of a rate limit request, which overrides the rate limit of the
+/// server for the database.
+#[derive(Deserialize)]
+struct RateLimitOptions {
+    requests_per_sec: u32,
+    /// The burst of the limit, which is `requests_per_sec` by default.
+    #[serde(default)]
+    burst: Option<u32>,
+}
+
+impl RateLimitOptions {
+    fn to_rate_limit(&self) -> Result<RateLimit> {
+        let burst = self.burst.unwrap_or(self.requests_per_sec);
+        if self.requests_per_sec == 0 || burst == 0 {
+            return Err(HiisiError::ProtocolError(
+                "Rate limit must not be zero".to_owned(),
+            ));
+        }
+        Ok(RateLimit::new(self.requests_per_sec, burst))
     }
 }
 
 enum Route {
     // The `/v1/namespaces/:name/create` route.
     CreateNamespace(String),
+    // The `/v1/namespaces/:name/schema_version` route.
+    SchemaVersion(String),
+    // The `/v1/namespaces/:name/checkpoint` route.
+    Checkpoint(String),
+    // The `/v1/namespaces/:name/vacuum` route.
+    Vacuum(String),
+    // The `/v1/namespaces/:name/backup` route.
+    Backup(String),
+    // The `/v1/namespaces/:name/rename` route.
+    Rename(String),
+    // The `/v1/namespaces/:name/rate_limit` route.
+    RateLimit(String),
 }
 
 fn parse_route(path: &str) -> Option<Route> {
     let parts: Vec<&str> = path.split('/').collect();
-    if parts.len() < 4 {
+    if parts.len() != 5 {
         return None;
     }
     if parts[1] != "v1" {
@@ -75,8 +305,326 @@ fn parse_route(path: &str) -> Option<Route> {
     if parts[2] != "namespaces" {
         return None;
     }
-    if parts[4] != "create" {
-        return None;
+    match parts[4] {
+        "create" => Some(Route::CreateNamespace(parts[3].to_owned())),
+        "schema_version" => Some(Route::SchemaVersion(parts[3].to_owned())),
+        "checkpoint" => Some(Route::Checkpoint(parts[3].to_owned())),
+        "vacuum" => Some(Route::Vacuum(parts[3].to_owned())),
+        "backup" => Some(Route::Backup(parts[3].to_owned())),
+        "rename" => Some(Route::Rename(parts[3].to_owned())),
+        "rate_limit" => Some(Route::RateLimit(parts[3].to_owned())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{execute_request, Response};
+    use crate::database::StepResult;
+    use crate::executor::{execute_client_req, Request};
+    use crate::http::StatusCode;
+    use crate::manager::ResourceManager;
+    use crate::proto::{
+        ExecuteStreamReq, PipelineReqBody, Stmt, StreamRequest, StreamResponse, StreamResult,
+        Value, Version,
+    };
+    use crate::ratelimit::RateLimit;
+    use crate::server::{Context, IO};
+    use std::rc::Rc;
+
+    fn execute(manager: &Rc<ResourceManager>, db_name: &str, sql: &str) -> StreamResult {
+        let req = Request {
+            database: db_name.to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![StreamRequest::Execute(ExecuteStreamReq {
+                    stmt: Stmt::new(sql, true),
+                })],
+            },
+        };
+        let mut resp = execute_client_req(manager.clone(), req).unwrap();
+        resp.results.remove(0)
+    }
+
+    #[test]
+    fn schema_version_reads_user_version() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("test").unwrap();
+        let req = Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![StreamRequest::Execute(ExecuteStreamReq {
+                    stmt: Stmt::new("PRAGMA user_version = 7", false),
+                })],
+            },
+        };
+        execute_client_req(manager.clone(), req).unwrap();
+        let mut io = IO::new(Context::new(manager, ()));
+
+        let Ok(Response::Complete(resp)) = execute_request(
+            &mut io,
+            b"GET /v1/namespaces/test/schema_version HTTP/1.1\r\n\r\n",
+        ) else {
+            panic!("Unexpected response");
+        };
+        let mut headers = [httparse::EMPTY_HEADER; 8];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let body_off = parsed.parse(&resp).unwrap().unwrap();
+        assert_eq!(parsed.code, Some(200));
+        let version: serde_json::Value = serde_json::from_slice(&resp[body_off..]).unwrap();
+        assert_eq!(version["user_version"].as_i64(), Some(7));
+        assert!(version["schema_version"].as_i64().is_some());
+
+        let err = execute_request(
+            &mut io,
+            b"GET /v1/namespaces/missing/schema_version HTTP/1.1\r\n\r\n",
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, crate::HiisiError::DatabaseNotFound(_)));
+    }
+
+    #[test]
+    fn backup_restores_rows() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("test").unwrap();
+        execute(&manager, "test", "CREATE TABLE t (x INTEGER, y)");
+        execute(
+            &manager,
+            "test",
+            "INSERT INTO t VALUES (1, 'one'), (2, x'0102'), (3, NULL), (4, 4.5)",
+        );
+        let mut io = IO::new(Context::new(manager.clone(), ()));
+
+        let Ok(Response::Backup(mut backup)) =
+            execute_request(&mut io, b"GET /v1/namespaces/test/backup HTTP/1.1\r\n\r\n")
+        else {
+            panic!("Unexpected response");
+        };
+        // A page at a time, as the server would copy a large database, and
+        // read back in chunks smaller than a page.
+        while !backup.step(1).unwrap() {}
+        backup.finish().unwrap();
+        let mut image = Vec::new();
+        loop {
+            let chunk = backup.read_image(100);
+            if chunk.is_empty() {
+                break;
+            }
+            image.extend_from_slice(chunk);
+        }
+
+        manager.create_database("copy").unwrap();
+        let session = manager.create_session("copy", Version::Hrana2);
+        manager.get_conn(&session).unwrap().restore(&image).unwrap();
+        manager.drop_session(session.id);
+
+        let rows = |db_name| match execute(&manager, db_name, "SELECT x, y FROM t ORDER BY x") {
+            StreamResult::Ok {
+                response: StreamResponse::Execute(resp),
+            } => serde_json::to_value(&resp.result.rows).unwrap(),
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        assert_eq!(rows("copy"), rows("test"));
+        assert_eq!(rows("copy").as_array().unwrap().len(), 4);
+
+        let err = execute_request(
+            &mut io,
+            b"GET /v1/namespaces/missing/backup HTTP/1.1\r\n\r\n",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn backup_of_busy_database_gives_up() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("test").unwrap();
+        execute(&manager, "test", "CREATE TABLE t (x)");
+        execute(&manager, "test", "INSERT INTO t VALUES (1)");
+        let mut io = IO::new(Context::new(manager.clone(), ()));
+
+        let Ok(Response::Backup(mut backup)) =
+            execute_request(&mut io, b"GET /v1/namespaces/test/backup HTTP/1.1\r\n\r\n")
+        else {
+            panic!("Unexpected response");
+        };
+        // A write between every step makes the copy start over.
+        let err = loop {
+            match backup.step(1) {
+                Ok(done) => assert!(!done, "Backup completed despite the writes"),
+                Err(err) => break err,
+            }
+            execute(&manager, "test", "INSERT INTO t VALUES (2)");
+        };
+        assert!(matches!(err, crate::HiisiError::BackupBusy));
+        assert_eq!(err.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn rename_keeps_rows() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("staging").unwrap();
+        execute(&manager, "staging", "CREATE TABLE t (x)");
+        execute(&manager, "staging", "INSERT INTO t VALUES (1), (2)");
+        let mut io = IO::new(Context::new(manager.clone(), ()));
+
+        assert!(matches!(
+            execute_request(
+                &mut io,
+                b"POST /v1/namespaces/staging/rename HTTP/1.1\r\n\r\n{\"to\":\"prod\"}"
+            ),
+            Ok(Response::Complete(_))
+        ));
+        match execute(&manager, "prod", "SELECT count(*) FROM t") {
+            StreamResult::Ok {
+                response: StreamResponse::Execute(resp),
+            } => assert!(matches!(
+                resp.result.rows[0].values[..],
+                [Value::Integer { value: 2 }]
+            )),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        // A database created with the old name starts out empty.
+        manager.create_database("staging").unwrap();
+        assert!(matches!(
+            execute(&manager, "staging", "SELECT count(*) FROM t"),
+            StreamResult::Error { .. }
+        ));
+
+        let err = execute_request(
+            &mut io,
+            b"POST /v1/namespaces/missing/rename HTTP/1.1\r\n\r\n{\"to\":\"other\"}",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+        let err = execute_request(
+            &mut io,
+            b"POST /v1/namespaces/prod/rename HTTP/1.1\r\n\r\n{\"to\":\"staging\"}",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn rate_limit_overrides_server_limit() {
+        let manager =
+            Rc::new(ResourceManager::new_in_memory([0; 32]).with_rate_limit(RateLimit::new(1, 1)));
+        manager.create_database("test").unwrap();
+        manager.create_database("other").unwrap();
+        let mut io = IO::new(Context::new(manager.clone(), ()));
+
+        assert!(matches!(
+            execute_request(
+                &mut io,
+                b"POST /v1/namespaces/test/rate_limit HTTP/1.1\r\n\r\n{\"requests_per_sec\":1,\"burst\":3}"
+            ),
+            Ok(Response::Complete(_))
+        ));
+        for _ in 0..3 {
+            assert!(manager.check_rate_limit("test", None).is_ok());
+        }
+        assert!(manager.check_rate_limit("test", None).is_err());
+        // The other databases keep the limit of the server.
+        assert!(manager.check_rate_limit("other", None).is_ok());
+        assert!(manager.check_rate_limit("other", None).is_err());
+
+        let err = execute_request(
+            &mut io,
+            b"POST /v1/namespaces/test/rate_limit HTTP/1.1\r\n\r\n{\"requests_per_sec\":0}",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        let err = execute_request(
+            &mut io,
+            b"POST /v1/namespaces/missing/rate_limit HTTP/1.1\r\n\r\n{\"requests_per_sec\":1}",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn checkpoint_truncates_wal() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-admin-checkpoint-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        execute(&manager, "test", "CREATE TABLE t (x)");
+        execute(&manager, "test", "INSERT INTO t VALUES (1), (2), (3)");
+        let wal_frame_count = || {
+            let session = manager.create_session("test", Version::Hrana2);
+            let frame_count = manager
+                .get_conn(&session)
+                .unwrap()
+                .wal_frame_count()
+                .unwrap();
+            manager.drop_session(session.id);
+            frame_count
+        };
+        let frames_before = wal_frame_count();
+        assert!(frames_before > 0);
+        let mut io = IO::new(Context::new(manager.clone(), ()));
+        let checkpoint = |io: &mut IO<()>| {
+            let Ok(Response::Complete(resp)) =
+                execute_request(io, b"POST /v1/namespaces/test/checkpoint HTTP/1.1\r\n\r\n")
+            else {
+                panic!("Unexpected response");
+            };
+            let mut headers = [httparse::EMPTY_HEADER; 8];
+            let mut parsed = httparse::Response::new(&mut headers);
+            let body_off = parsed.parse(&resp).unwrap().unwrap();
+            assert_eq!(parsed.code, Some(200));
+            serde_json::from_slice::<serde_json::Value>(&resp[body_off..]).unwrap()
+        };
+
+        let result = checkpoint(&mut io);
+        assert_eq!(result["busy"].as_bool(), Some(false));
+        assert_eq!(
+            result["checkpointed_frames"].as_i64(),
+            Some(i64::from(frames_before))
+        );
+        assert!(wal_frame_count() < frames_before);
+
+        // A session in a read transaction keeps the frames after its snapshot
+        // from being copied back, so the checkpoint copies only the frames
+        // before them and reports that it is busy rather than failing.
+        execute(&manager, "test", "INSERT INTO t VALUES (4)");
+        let reader = manager.create_session("test", Version::Hrana2);
+        let reader_conn = manager.get_conn(&reader).unwrap();
+        reader_conn.exec("BEGIN").unwrap();
+        let stmt = reader_conn.prepare("SELECT count(*) FROM t").unwrap();
+        while let StepResult::Row = stmt.step().unwrap() {}
+        drop(stmt);
+        execute(&manager, "test", "INSERT INTO t VALUES (5)");
+        let result = checkpoint(&mut io);
+        assert_eq!(result["busy"].as_bool(), Some(true));
+        let checkpointed_frames = result["checkpointed_frames"].as_i64().unwrap();
+        assert!(checkpointed_frames > 0, "{}", result);
+        assert!(
+            checkpointed_frames < result["log_frames"].as_i64().unwrap(),
+            "{}",
+            result
+        );
+        reader_conn.exec("COMMIT").unwrap();
+        drop(reader_conn);
+        manager.drop_session(reader.id);
+
+        assert!(matches!(
+            execute_request(&mut io, b"POST /v1/namespaces/test/vacuum HTTP/1.1\r\n\r\n"),
+            Ok(Response::Complete(_))
+        ));
+        assert!(matches!(
+            execute(&manager, "test", "SELECT count(*) FROM t"),
+            StreamResult::Ok { .. }
+        ));
+        std::fs::remove_dir_all(db_path).unwrap();
     }
-    Some(Route::CreateNamespace(parts[3].to_owned()))
 }
@@ -0,0 +1,123 @@
+//! Stream batons.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::cell::Cell;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Length of the baton payload, which is the session id followed by the
+// baton counter.
+const PAYLOAD_LEN: usize = 16;
+
+/// Generate a random key for signing batons.
+///
+/// Batons don't survive a restart, which is fine because neither do the
+/// sessions they refer to.
+pub fn generate_key() -> [u8; 32] {
+    let mut key = [0; 32];
+    key[..16].copy_from_slice(uuid::Uuid::new_v4().as_bytes());
+    key[16..].copy_from_slice(uuid::Uuid::new_v4().as_bytes());
+    key
+}
+
+/// The contents of an authenticated baton.
+#[derive(Debug, PartialEq)]
+pub struct Baton {
+    pub session_id: u64,
+    pub counter: u64,
+}
+
+/// The baton manager issues the batons that clients use to continue a stream.
+///
+/// A baton is a session id and a counter signed with HMAC-SHA256, which makes
+/// batons opaque and unguessable to clients. The counter is incremented for
+/// every baton issued, so every response gets a fresh baton and sessions can
+/// reject batons that they have already rotated away from.
+pub struct BatonManager {
+    key: [u8; 32],
+    next_session_id: Cell<u64>,
+    counter: Cell<u64>,
+}
+
+impl BatonManager {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            next_session_id: Cell::new(0),
+            counter: Cell::new(0),
+        }
+    }
+
+    pub fn next_session_id(&self) -> u64 {
+        let session_id = self.next_session_id.get();
+        self.next_session_id.set(session_id + 1);
+        session_id
+    }
+
+    /// Issue a new baton for a session, returning the baton and its counter.
+    pub fn issue(&self, session_id: u64) -> (String, u64) {
+        // Counters start from 1 so that a session that has not been issued a
+        // baton yet never matches one.
+        let counter = self.counter.get() + 1;
+        self.counter.set(counter);
+        let mut buf = Vec::with_capacity(PAYLOAD_LEN + 32);
+        buf.extend_from_slice(&session_id.to_be_bytes());
+        buf.extend_from_slice(&counter.to_be_bytes());
+        let tag = self.mac(&buf).finalize().into_bytes();
+        buf.extend_from_slice(tag.as_slice());
+        (URL_SAFE_NO_PAD.encode(buf), counter)
+    }
+
+    /// Decode a baton, returning `None` if it was not issued by this manager.
+    pub fn decode(&self, baton: &str) -> Option<Baton> {
+        let buf = URL_SAFE_NO_PAD.decode(baton).ok()?;
+        if buf.len() <= PAYLOAD_LEN {
+            return None;
+        }
+        let (payload, tag) = buf.split_at(PAYLOAD_LEN);
+        self.mac(payload).verify_slice(tag).ok()?;
+        let (session_id, counter) = payload.split_at(8);
+        Some(Baton {
+            session_id: u64::from_be_bytes(session_id.try_into().unwrap()),
+            counter: u64::from_be_bytes(counter.try_into().unwrap()),
+        })
+    }
+
+    fn mac(&self, payload: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+        mac.update(payload);
+        mac
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Baton, BatonManager};
+
+    #[test]
+    fn issue_and_decode() {
+        let manager = BatonManager::new([7; 32]);
+        let session_id = manager.next_session_id();
+        let (baton, counter) = manager.issue(session_id);
+        assert_eq!(
+            manager.decode(&baton),
+            Some(Baton {
+                session_id,
+                counter
+            })
+        );
+        let (rotated, _) = manager.issue(session_id);
+        assert_ne!(baton, rotated);
+    }
+
+    #[test]
+    fn decode_forged() {
+        let manager = BatonManager::new([7; 32]);
+        let (baton, _) = manager.issue(manager.next_session_id());
+        assert_eq!(BatonManager::new([8; 32]).decode(&baton), None);
+        assert_eq!(manager.decode("not a baton"), None);
+        assert_eq!(manager.decode(""), None);
+    }
+}
@@ -0,0 +1,66 @@
+//! A pool of receive buffers.
+
+use bytes::BytesMut;
+
+use std::cell::{Cell, RefCell};
+
+/// Capacity of the buffers that the pool hands out.
+pub const BUFFER_SIZE: usize = 4096;
+
+/// A pool of buffers that connections receive their requests into.
+///
+/// A connection takes a buffer when it starts receiving a request and
+/// returns it once it has handled every request in the buffer, so that idle
+/// connections don't hold on to buffers and busy ones don't allocate a new
+/// buffer for every request.
+pub struct BufferPool {
+    buffers: RefCell<Vec<BytesMut>>,
+    /// Maximum number of buffers the pool keeps.
+    max_buffers: usize,
+    /// Number of buffers the pool has allocated.
+    allocations: Cell<usize>,
+}
+
+impl BufferPool {
+    /// Create a pool that keeps at most `max_buffers` buffers.
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            buffers: RefCell::new(Vec::new()),
+            max_buffers,
+            allocations: Cell::new(0),
+        }
+    }
+
+    /// Take an empty buffer from the pool, or allocate one if the pool is
+    /// empty.
+    pub fn take(&self) -> BytesMut {
+        match self.buffers.borrow_mut().pop() {
+            Some(buf) => buf,
+            None => {
+                self.allocations.set(self.allocations.get() + 1);
+                BytesMut::with_capacity(BUFFER_SIZE)
+            }
+        }
+    }
+
+    /// Return a buffer to the pool, dropping it if the pool is full.
+    ///
+    /// Requests that are split off from the buffer share its allocation. The
+    /// buffer reclaims the allocation, rather than allocating anew, only once
+    /// the requests have been dropped, so a buffer must not be returned
+    /// while its requests are being handled.
+    pub fn put(&self, mut buf: BytesMut) {
+        let mut buffers = self.buffers.borrow_mut();
+        if buffers.len() >= self.max_buffers {
+            return;
+        }
+        buf.clear();
+        buf.reserve(BUFFER_SIZE);
+        buffers.push(buf);
+    }
+
+    /// The number of buffers the pool has allocated.
+    pub fn allocations(&self) -> usize {
+        self.allocations.get()
+    }
+}
@@ -0,0 +1,520 @@
+//! A client of the pipeline protocol.
+//!
+//! The client sends its requests over the same callback IO that the server
+//! runs on, so a program that embeds the server, like the simulator, can
+//! talk to it without duplicating the HTTP framing. A `Client` keeps the
+//! baton of its stream, so every request continues the stream that the
+//! previous response left open.
+
+use bytes::{Bytes, BytesMut};
+use socket2::Socket;
+use thiserror::Error;
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use crate::proto;
+use crate::server::IO;
+
+/// An error that fails a client request.
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Connection closed")]
+    ConnectionClosed,
+    #[error("HTTP {0}: {1}")]
+    Http(u16, String),
+    #[error("Invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("Stream error: {}", .0.message)]
+    Stream(proto::Error),
+}
+
+/// Called with the result of an `execute()` request.
+pub type ExecuteCallback<T> = fn(&mut IO<T>, Rc<Socket>, Result<proto::StmtResult, ClientError>);
+
+/// Called with the result of a `batch()` request.
+pub type BatchCallback<T> = fn(&mut IO<T>, Rc<Socket>, Result<proto::BatchResult, ClientError>);
+
+/// Called with the number of rows that an `execute_many()` request changed.
+pub type ExecuteManyCallback<T> = fn(&mut IO<T>, Rc<Socket>, Result<u64, ClientError>);
+
+/// The user data of an IO context that has clients.
+///
+/// IO callbacks only get the socket they completed on, so the client of a
+/// socket is looked up from the user data.
+pub trait ClientData: Sized {
+    fn client(&self, sock: &Socket) -> &Client<Self>;
+}
+
+enum Pending<T> {
+    Execute(ExecuteCallback<T>),
+    Batch(BatchCallback<T>),
+    ExecuteMany(ExecuteManyCallback<T>),
+}
+
+/// A client of a database, which sends pipeline requests on a connection.
+pub struct Client<T> {
+    host: String,
+    path: &'static str,
+    /// The baton of the stream that the next request continues.
+    baton: RefCell<Option<String>>,
+    /// The response received so far.
+    resp: RefCell<BytesMut>,
+    /// The callback of the request that is waiting for its response.
+    pending: Cell<Option<Pending<T>>>,
+}
+
+impl<T> Client<T> {
+    /// Create a client of the database that `host` routes to, speaking the
+    /// given Hrana version.
+    pub fn new(host: impl Into<String>, version: proto::Version) -> Self {
+        let path = match version {
+            proto::Version::Hrana2 => "/v2/pipeline",
+            proto::Version::Hrana3 => "/v3/pipeline",
+        };
+        Self {
+            host: host.into(),
+            path,
+            baton: RefCell::new(None),
+            resp: RefCell::new(BytesMut::new()),
+            pending: Cell::new(None),
+        }
+    }
+
+    /// The baton of the stream that the next request continues, if the
+    /// client has a stream open.
+    pub fn baton(&self) -> Option<String> {
+        self.baton.borrow().clone()
+    }
+
+    /// Forget the stream, so that the next request opens a new one.
+    ///
+    /// A stream does not survive a reset connection, because the request
+    /// may have been executed and its baton rotated without the client
+    /// receiving the response.
+    pub fn reset(&self) {
+        self.baton.replace(None);
+        self.resp.borrow_mut().clear();
+        self.pending.set(None);
+    }
+
+    /// Format the HTTP request of a pipeline that continues the client's
+    /// stream.
+    pub fn format_pipeline(&self, requests: Vec<proto::StreamRequest>) -> Bytes {
+        let req = proto::PipelineReqBody {
+            baton: self.baton(),
+            requests,
+        };
+        // Request bodies are plain data, which always serialize.
+        let body = proto::format_msg(&req).unwrap();
+        format_request(&self.host, self.path, &body)
+    }
+
+    /// Parse the response to a pipeline request, returning `None` if the
+    /// response is incomplete.
+    ///
+    /// The next request continues the stream with the baton of the response.
+    pub fn parse_pipeline_resp(
+        &self,
+        buf: &[u8],
+    ) -> Result<Option<proto::PipelineRespBody>, ClientError> {
+        let (status, body) = match parse_response(buf)? {
+            Some(resp) => resp,
+            None => return Ok(None),
+        };
+        if status != 200 {
+            let body = String::from_utf8_lossy(body).into_owned();
+            return Err(ClientError::Http(status, body));
+        }
+        let resp =
+            proto::parse_resp(body).map_err(|err| ClientError::InvalidResponse(err.to_string()))?;
+        self.baton.replace(resp.baton.clone());
+        Ok(Some(resp))
+    }
+}
+
+impl<T: ClientData> Client<T> {
+    /// Execute a statement with positional arguments on the client's stream.
+    pub fn execute(
+        io: &mut IO<T>,
+        sock: Rc<Socket>,
+        sql: &str,
+        args: Vec<proto::Value>,
+        on_result: ExecuteCallback<T>,
+    ) {
+        let mut stmt = proto::Stmt::new(sql, true);
+        stmt.args = args;
+        let req = proto::StreamRequest::Execute(proto::ExecuteStreamReq { stmt });
+        send(io, sock, req, Pending::Execute(on_result));
+    }
+
+    /// Execute a batch on the client's stream.
+    pub fn batch(
+        io: &mut IO<T>,
+        sock: Rc<Socket>,
+        steps: Vec<proto::BatchStep>,
+        on_result: BatchCallback<T>,
+    ) {
+        let batch = proto::Batch {
+            steps,
+            replication_index: None,
+        };
+        let req = proto::StreamRequest::Batch(proto::BatchStreamReq { batch });
+        send(io, sock, req, Pending::Batch(on_result));
+    }
+
+    /// Execute a statement once for every row of arguments, such as an
+    /// `INSERT` of many rows, in one transactional batch on the client's
+    /// stream.
+    ///
+    /// Either every row is executed or none is: the first row that fails
+    /// rolls the transaction back and fails the request with its error. The
+    /// callback gets the number of rows that the statements changed. Without
+    /// any rows, the callback is called right away, and nothing is sent.
+    pub fn execute_many(
+        io: &mut IO<T>,
+        sock: Rc<Socket>,
+        sql: &str,
+        rows: Vec<Vec<proto::Value>>,
+        on_result: ExecuteManyCallback<T>,
+    ) {
+        if rows.is_empty() {
+            on_result(io, sock, Ok(0));
+            return;
+        }
+        let batch = proto::Batch::transactional(rows.into_iter().map(|args| {
+            let mut stmt = proto::Stmt::new(sql, false);
+            stmt.args = args;
+            stmt
+        }));
+        let req = proto::StreamRequest::Batch(proto::BatchStreamReq { batch });
+        send(io, sock, req, Pending::ExecuteMany(on_result));
+    }
+}
+
+fn send<T: ClientData>(
+    io: &mut IO<T>,
+    sock: Rc<Socket>,
+    req: proto::StreamRequest,
+    pending: Pending<T>,
+) {
+    let client = io.context().user_data.client(&sock);
+    let http_req = client.format_pipeline(vec![req]);
+    client.resp.borrow_mut().clear();
+    client.pending.set(Some(pending));
+    let n = http_req.len();
+    io.send(sock, http_req, n, on_send::<T>);
+}
+
+fn on_send<T: ClientData>(io: &mut IO<T>, sock: Rc<Socket>, n: usize) {
+    if n == 0 {
+        complete(io, sock, Err(ClientError::ConnectionClosed));
+        return;
+    }
+    io.recv(sock, on_recv::<T>);
+}
+
+fn on_recv<T: ClientData>(io: &mut IO<T>, sock: Rc<Socket>, buf: &[u8], n: usize) {
+    if n == 0 {
+        complete(io, sock, Err(ClientError::ConnectionClosed));
+        return;
+    }
+    let client = io.context().user_data.client(&sock);
+    client.resp.borrow_mut().extend_from_slice(&buf[..n]);
+    let resp = client.parse_pipeline_resp(&client.resp.borrow());
+    match resp {
+        Ok(Some(resp)) => complete(io, sock, Ok(resp)),
+        Ok(None) => io.recv(sock, on_recv::<T>),
+        Err(err) => complete(io, sock, Err(err)),
+    }
+}
+
+/// Pass the response to the callback of the pending request.
+fn complete<T: ClientData>(
+    io: &mut IO<T>,
+    sock: Rc<Socket>,
+    resp: Result<proto::PipelineRespBody, ClientError>,
+) {
+    let client = io.context().user_data.client(&sock);
+    client.resp.borrow_mut().clear();
+    let Some(pending) = client.pending.take() else {
+        return;
+    };
+    let response = resp.and_then(|mut resp| match resp.results.pop() {
+        Some(proto::StreamResult::Ok { response }) => Ok(response),
+        Some(proto::StreamResult::Error { error }) => Err(ClientError::Stream(error)),
+        _ => Err(ClientError::InvalidResponse(
+            "Missing stream result".to_owned(),
+        )),
+    });
+    match pending {
+        Pending::Execute(on_result) => {
+            let result = response.and_then(|response| match response {
+                proto::StreamResponse::Execute(resp) => Ok(resp.result),
+                response => Err(unexpected_response(&response)),
+            });
+            on_result(io, sock, result);
+        }
+        Pending::Batch(on_result) => {
+            let result = response.and_then(|response| match response {
+                proto::StreamResponse::Batch(resp) => Ok(resp.result),
+                response => Err(unexpected_response(&response)),
+            });
+            on_result(io, sock, result);
+        }
+        Pending::ExecuteMany(on_result) => {
+            let result = response.and_then(|response| match response {
+                proto::StreamResponse::Batch(resp) => affected_row_count(resp.result),
+                response => Err(unexpected_response(&response)),
+            });
+            on_result(io, sock, result);
+        }
+    }
+}
+
+/// Sum up the rows that the steps of a transactional batch changed, or
+/// return the error of the step that failed it.
+fn affected_row_count(result: proto::BatchResult) -> Result<u64, ClientError> {
+    if let Some(error) = result.step_errors.into_iter().flatten().next() {
+        return Err(ClientError::Stream(error));
+    }
+    Ok(result
+        .step_results
+        .iter()
+        .flatten()
+        .map(|result| result.affected_row_count)
+        .sum())
+}
+
+fn unexpected_response(response: &proto::StreamResponse) -> ClientError {
+    ClientError::InvalidResponse(format!(
+        "Unexpected stream response: {}",
+        response.type_name()
+    ))
+}
+
+/// Format a `POST` request with a body of known length.
+pub fn format_request(host: &str, path: &str, body: &[u8]) -> Bytes {
+    let mut http_req = BytesMut::new();
+    let http_header = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n",
+        path,
+        host,
+        body.len()
+    );
+    http_req.extend_from_slice(http_header.as_bytes());
+    http_req.extend_from_slice(body);
+    http_req.into()
+}
+
+/// Format a `POST` request whose body is sent with chunked transfer
+/// encoding, one chunk for each of `chunks`.
+pub fn format_chunked_request(host: &str, path: &str, chunks: &[&[u8]]) -> Bytes {
+    let mut http_req = BytesMut::new();
+    let http_header = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nTransfer-Encoding: chunked\r\n\r\n",
+        path, host,
+    );
+    http_req.extend_from_slice(http_header.as_bytes());
+    for chunk in chunks {
+        crate::http::format_chunk(&mut http_req, chunk);
+    }
+    http_req.extend_from_slice(crate::http::LAST_CHUNK);
+    http_req.into()
+}
+
+/// Parse a response into its status code and body, returning `None` if the
+/// response is shorter than its `Content-Length` says.
+///
+/// A response without a `Content-Length` is taken to end with the buffer.
+pub fn parse_response(buf: &[u8]) -> Result<Option<(u16, &[u8])>, ClientError> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut resp = httparse::Response::new(&mut headers);
+    let body_off = match resp.parse(buf) {
+        Ok(httparse::Status::Complete(body_off)) => body_off,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(err) => return Err(ClientError::InvalidResponse(err.to_string())),
+    };
+    let content_len = resp
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Content-Length"))
+        .map(|header| {
+            std::str::from_utf8(header.value)
+                .ok()
+                .and_then(|value| value.parse::<usize>().ok())
+                .ok_or_else(|| ClientError::InvalidResponse("Invalid Content-Length".to_owned()))
+        })
+        .transpose()?;
+    let body = &buf[body_off..];
+    match content_len {
+        Some(content_len) if body.len() < content_len => Ok(None),
+        Some(content_len) => Ok(Some((resp.code.unwrap(), &body[..content_len]))),
+        None => Ok(Some((resp.code.unwrap(), body))),
+    }
+}
+
+#[cfg(all(test, feature = "simulation"))]
+mod test {
+    use super::{Client, ClientData, ClientError};
+    use crate::clock::SimClock;
+    use crate::io::Faults;
+    use crate::proto::{BatchResult, BatchStep, Stmt, StmtResult, Value, Version};
+    use crate::server::{serve, Context, IO};
+    use crate::ResourceManager;
+    use socket2::{Domain, Socket, Type};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct TestData {
+        client: Option<Client<TestData>>,
+        execute: RefCell<Option<Result<StmtResult, ClientError>>>,
+        batch: RefCell<Option<Result<BatchResult, ClientError>>>,
+        execute_many: RefCell<Option<Result<u64, ClientError>>>,
+    }
+
+    impl ClientData for TestData {
+        fn client(&self, _sock: &Socket) -> &Client<Self> {
+            self.client.as_ref().unwrap()
+        }
+    }
+
+    type TestIO = IO<TestData>;
+
+    fn on_connect(_io: &mut TestIO, _sock: Rc<Socket>, _addr: socket2::SockAddr) {}
+
+    fn on_execute(io: &mut TestIO, sock: Rc<Socket>, result: Result<StmtResult, ClientError>) {
+        io.context().user_data.execute.replace(Some(result));
+        // Temporary tables are private to the connection of the stream, so
+        // the batch only sees the table if it continues the stream.
+        let steps = vec![
+            BatchStep {
+                condition: None,
+                stmt: Stmt::new("INSERT INTO t VALUES (42)", false),
+            },
+            BatchStep {
+                condition: None,
+                stmt: Stmt::new("SELECT x FROM t", true),
+            },
+        ];
+        Client::batch(io, sock, steps, on_batch);
+    }
+
+    fn on_batch(io: &mut TestIO, _sock: Rc<Socket>, result: Result<BatchResult, ClientError>) {
+        io.context().user_data.batch.replace(Some(result));
+    }
+
+    #[test]
+    fn client_continues_stream() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-server-client-{}", std::process::id()));
+        let clock = Rc::new(SimClock::new());
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]).with_clock(clock.clone()));
+        manager.create_database("test").unwrap();
+        let data = TestData {
+            client: Some(Client::new("test.localhost", Version::Hrana3)),
+            ..Default::default()
+        };
+        let ctx = Context::new(manager, data);
+        let mut io = TestIO::with_clock(ctx, Faults::default(), clock);
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+        let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.connect(sock.clone(), server_addr.into(), on_connect);
+        Client::execute(
+            &mut io,
+            sock,
+            "CREATE TEMP TABLE t (x INTEGER)",
+            vec![],
+            on_execute,
+        );
+        while io.context().user_data.batch.borrow().is_none() {
+            io.run_once();
+        }
+
+        let user_data = &io.context().user_data;
+        let execute = user_data.execute.take().unwrap().unwrap();
+        assert!(execute.rows.is_empty());
+        let batch = user_data.batch.take().unwrap().unwrap();
+        assert!(batch.step_errors.iter().all(|error| error.is_none()));
+        let rows = &batch.step_results[1].as_ref().unwrap().rows;
+        assert!(matches!(rows[0].values[..], [Value::Integer { value: 42 }]));
+        assert!(user_data.client.as_ref().unwrap().baton().is_some());
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    fn on_bulk_table(io: &mut TestIO, sock: Rc<Socket>, result: Result<StmtResult, ClientError>) {
+        result.unwrap();
+        let rows = (0..100)
+            .map(|i| vec![Value::Integer { value: i }])
+            .collect();
+        Client::execute_many(io, sock, "INSERT INTO t VALUES (?)", rows, on_execute_many);
+    }
+
+    fn on_execute_many(io: &mut TestIO, sock: Rc<Socket>, result: Result<u64, ClientError>) {
+        io.context().user_data.execute_many.replace(Some(result));
+        Client::execute(io, sock, "SELECT count(*) FROM t", vec![], on_count);
+    }
+
+    fn on_count(io: &mut TestIO, _sock: Rc<Socket>, result: Result<StmtResult, ClientError>) {
+        io.context().user_data.execute.replace(Some(result));
+    }
+
+    fn on_no_rows(io: &mut TestIO, _sock: Rc<Socket>, result: Result<u64, ClientError>) {
+        io.context().user_data.execute_many.replace(Some(result));
+    }
+
+    #[test]
+    fn execute_many_inserts_rows() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-server-client-bulk-{}", std::process::id()));
+        let clock = Rc::new(SimClock::new());
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]).with_clock(clock.clone()));
+        manager.create_database("test").unwrap();
+        let data = TestData {
+            client: Some(Client::new("test.localhost", Version::Hrana3)),
+            ..Default::default()
+        };
+        let ctx = Context::new(manager, data);
+        let mut io = TestIO::with_clock(ctx, Faults::default(), clock);
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+        let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.connect(sock.clone(), server_addr.into(), on_connect);
+        // Without rows, there is nothing to send.
+        Client::execute_many(
+            &mut io,
+            sock.clone(),
+            "INSERT INTO t VALUES (?)",
+            vec![],
+            on_no_rows,
+        );
+        let no_rows = io.context().user_data.execute_many.take().unwrap();
+        assert_eq!(no_rows.unwrap(), 0);
+
+        Client::execute(
+            &mut io,
+            sock,
+            "CREATE TABLE t (x INTEGER)",
+            vec![],
+            on_bulk_table,
+        );
+        while io.context().user_data.execute.borrow().is_none() {
+            io.run_once();
+        }
+
+        let user_data = &io.context().user_data;
+        assert_eq!(user_data.execute_many.take().unwrap().unwrap(), 100);
+        let count = user_data.execute.take().unwrap().unwrap();
+        assert!(matches!(
+            count.rows[0].values[..],
+            [Value::Integer { value: 100 }]
+        ));
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+}
@@ -0,0 +1,64 @@
+//! Clocks.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+/// A source of the current time.
+///
+/// Time-dependent logic reads the time through a clock rather than from the
+/// system, so that the simulator can control time.
+pub trait Clock {
+    /// Time elapsed since the clock was started.
+    fn now(&self) -> Duration;
+}
+
+/// A clock that follows the wall clock.
+pub struct WallClock {
+    start: Instant,
+}
+
+impl WallClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for WallClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A virtual clock that advances only when it is told to.
+///
+/// The simulated IO advances the clock by a fixed step on every
+/// `run_once()`, which makes time as deterministic as the rest of a
+/// simulation.
+#[derive(Default)]
+pub struct SimClock {
+    now: Cell<Duration>,
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, step: Duration) {
+        self.now.set(self.now.get() + step);
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
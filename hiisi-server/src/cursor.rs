@@ -0,0 +1,293 @@
+//! Streaming cursors.
+//!
+//! A cursor executes a batch like the `Batch` stream request does, but
+//! instead of collecting the results in memory, it produces them as a
+//! sequence of cursor entries that the server sends to the client as
+//! SQLite steps through the statements.
+
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::database::{Connection, StepResult, Stmt};
+use crate::executor::{
+    self, affected_row_count, describe_sql, eval_cond, last_insert_rowid, log_slow, make_cols,
+    prepare_stmt, roll_back_on_storage_error, to_proto_error, to_row,
+};
+use crate::manager::ResourceManager;
+use crate::proto;
+use crate::session::Session;
+use crate::Result;
+
+pub struct CursorRequest {
+    pub database: String,
+    pub version: proto::Version,
+    pub req: proto::CursorReqBody,
+}
+
+pub struct Cursor {
+    session: Rc<Session>,
+    conn: Rc<Connection>,
+    steps: Vec<proto::BatchStep>,
+    /// Index of the next step to begin.
+    next_step: usize,
+    /// The statement of the step whose rows are being read, its column
+    /// count, and whether the client wants its rows.
+    stmt: Option<(Stmt, i32, bool)>,
+    /// Outcome of the steps that have finished, for evaluating conditions.
+    step_results: Vec<Option<()>>,
+    step_errors: Vec<Option<proto::Error>>,
+    clock: Rc<dyn Clock>,
+    slow_query_threshold: Option<Duration>,
+    /// The correlation id that slow steps are logged with.
+    request_id: Option<String>,
+    /// Time spent in SQLite on the current step, and the rows it has
+    /// produced, for the slow statement log.
+    step_elapsed: Duration,
+    step_rows: usize,
+}
+
+/// Open a cursor on the stream the request refers to.
+///
+/// Returns the cursor and the response body that precedes the cursor
+/// entries. Steps that run for at least the slow query threshold are logged
+/// with `request_id`.
+pub fn open_cursor(
+    manager: Rc<ResourceManager>,
+    req: CursorRequest,
+    request_id: Option<&str>,
+) -> Result<(Cursor, proto::CursorRespBody)> {
+    let session = executor::resolve_session(&manager, &req.req.baton, &req.database, req.version)?;
+    log::trace!(
+        "Opening cursor: {:?} on {} (session = {})",
+        req.req.batch,
+        session.db_name,
+        session.id
+    );
+    let conn = manager.get_conn(&session)?;
+    manager.check_replication_index(&session.db_name, &conn, req.req.batch.replication_index)?;
+    let baton = manager.issue_baton(&session);
+    let steps = req.req.batch.steps;
+    let cursor = Cursor {
+        session,
+        conn,
+        step_results: Vec::with_capacity(steps.len()),
+        step_errors: Vec::with_capacity(steps.len()),
+        steps,
+        next_step: 0,
+        stmt: None,
+        clock: manager.clock().clone(),
+        slow_query_threshold: manager.slow_query_threshold(),
+        request_id: request_id.map(str::to_owned),
+        step_elapsed: Duration::ZERO,
+        step_rows: 0,
+    };
+    let resp = proto::CursorRespBody {
+        baton: Some(baton),
+        base_url: None,
+    };
+    Ok((cursor, resp))
+}
+
+impl Cursor {
+    /// Produce the next cursor entry, or `None` if the cursor is exhausted.
+    ///
+    /// Rows are read from SQLite one at a time, so a cursor over a large
+    /// result set never holds more than one row in memory.
+    pub fn next_entry(&mut self) -> Option<proto::CursorEntry> {
+        let Some(threshold) = self.slow_query_threshold else {
+            return self.step_entry();
+        };
+        // A step is timed over the entries it produces, leaving out the
+        // time that the server waits for the client to read them.
+        let start = self.clock.now();
+        let entry = self.step_entry();
+        self.step_elapsed += self.clock.now().saturating_sub(start);
+        let outcome = match &entry {
+            Some(proto::CursorEntry::StepBegin(_)) => return entry,
+            Some(proto::CursorEntry::Row { .. }) => {
+                self.step_rows += 1;
+                return entry;
+            }
+            Some(proto::CursorEntry::StepEnd(_)) => Some(format!("rows={}", self.step_rows)),
+            Some(proto::CursorEntry::StepError(step_error)) => {
+                Some(format!("failed: {}", step_error.error.message))
+            }
+            _ => None,
+        };
+        if let Some(outcome) = outcome {
+            if self.step_elapsed >= threshold {
+                let stmt = &self.steps[self.current_step() as usize].stmt;
+                log_slow(
+                    self.request_id.as_deref(),
+                    "cursor step",
+                    &self.session.db_name,
+                    self.step_elapsed,
+                    stmt.args.len() + stmt.named_args.len(),
+                    &outcome,
+                    &describe_sql(stmt),
+                );
+            }
+        }
+        self.step_elapsed = Duration::ZERO;
+        self.step_rows = 0;
+        entry
+    }
+
+    fn step_entry(&mut self) -> Option<proto::CursorEntry> {
+        loop {
+            if let Some((stmt, column_count, want_rows)) = &self.stmt {
+                let row = match stmt.step() {
+                    Ok(StepResult::Row) if !*want_rows => continue,
+                    Ok(StepResult::Row) => to_row(stmt, *column_count),
+                    Ok(StepResult::Done) => {
+                        let affected_row_count = affected_row_count(&self.conn, stmt);
+                        let last_insert_rowid = last_insert_rowid(&self.conn, stmt);
+                        self.stmt = None;
+                        self.step_results.push(Some(()));
+                        self.step_errors.push(None);
+                        return Some(proto::CursorEntry::StepEnd(proto::StepEndEntry {
+                            affected_row_count,
+                            last_insert_rowid,
+                        }));
+                    }
+                    Err(err) => Err(err),
+                };
+                match row {
+                    Ok(row) => return Some(proto::CursorEntry::Row { row }),
+                    Err(err) => {
+                        self.stmt = None;
+                        return Some(self.step_error(err));
+                    }
+                }
+            }
+            let step = self.steps.get(self.next_step)?;
+            if self.session.conn.borrow().is_none() {
+                // The stream was closed, or expired, under the cursor.
+                self.next_step = self.steps.len();
+                return Some(proto::CursorEntry::Error {
+                    error: to_proto_error(&crate::HiisiError::StreamExpired),
+                });
+            }
+            self.next_step += 1;
+            let enabled = match &step.condition {
+                Some(cond) => {
+                    match eval_cond(
+                        cond,
+                        &self.step_results,
+                        &self.step_errors,
+                        self.conn.is_autocommit(),
+                    ) {
+                        Ok(enabled) => enabled,
+                        Err(err) => {
+                            // An invalid condition fails the whole cursor.
+                            self.next_step = self.steps.len();
+                            return Some(proto::CursorEntry::Error {
+                                error: to_proto_error(&err),
+                            });
+                        }
+                    }
+                }
+                None => true,
+            };
+            if !enabled {
+                self.step_results.push(None);
+                self.step_errors.push(None);
+                continue;
+            }
+            let begin = prepare_stmt(&self.conn, &self.session, &step.stmt)
+                .and_then(|stmt| make_cols(&stmt).map(|cols| (stmt, cols)));
+            match begin {
+                Ok((stmt, cols)) => {
+                    let column_count = stmt.column_count();
+                    let want_rows = step.stmt.want_rows.unwrap_or(true);
+                    self.stmt = Some((stmt, column_count, want_rows));
+                    return Some(proto::CursorEntry::StepBegin(proto::StepBeginEntry {
+                        step: self.current_step(),
+                        cols,
+                    }));
+                }
+                Err(err) => return Some(self.step_error(err)),
+            }
+        }
+    }
+
+    /// Abort the cursor after the client has disconnected.
+    ///
+    /// This finalizes the statement the cursor is reading from and releases
+    /// the session, because a client that went away in the middle of a
+    /// cursor is not going to continue the stream.
+    pub fn abort(self, manager: &ResourceManager) {
+        log::trace!("Aborting cursor (session = {})", self.session.id);
+        let session_id = self.session.id;
+        drop(self);
+        manager.drop_session(session_id);
+    }
+
+    fn step_error(&mut self, err: crate::HiisiError) -> proto::CursorEntry {
+        roll_back_on_storage_error(&self.conn, &err);
+        self.step_results.push(None);
+        self.step_errors.push(Some(to_proto_error(&err)));
+        proto::CursorEntry::StepError(proto::StepErrorEntry {
+            step: self.current_step(),
+            error: to_proto_error(&err),
+        })
+    }
+
+    fn current_step(&self) -> u32 {
+        (self.next_step - 1) as u32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{open_cursor, CursorRequest};
+    use crate::manager::ResourceManager;
+    use crate::proto::{Batch, CursorEntry, CursorReqBody, Stmt, Version};
+    use std::rc::Rc;
+
+    #[test]
+    fn closing_stream_interrupts_cursor() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-cursor-close-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        let batch = Batch::from_iter([
+            Stmt::new(
+                "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT x FROM c",
+                true,
+            ),
+            Stmt::new("SELECT 1", true),
+        ]);
+        let req = CursorRequest {
+            database: "test".to_owned(),
+            version: Version::Hrana3,
+            req: CursorReqBody { baton: None, batch },
+        };
+        let (mut cursor, resp) = open_cursor(manager.clone(), req, None).unwrap();
+        assert!(matches!(
+            cursor.next_entry(),
+            Some(CursorEntry::StepBegin(_))
+        ));
+        assert!(matches!(cursor.next_entry(), Some(CursorEntry::Row { .. })));
+
+        let session = manager.get_session(&resp.baton.unwrap()).unwrap();
+        manager.drop_session(session.id);
+        match cursor.next_entry() {
+            Some(CursorEntry::StepError(entry)) => {
+                assert_eq!(entry.error.code.as_deref(), Some("SQLITE_INTERRUPT"))
+            }
+            entry => panic!("Unexpected entry: {:?}", entry),
+        }
+        // The steps after the interrupted one don't run on the connection
+        // of the closed stream.
+        assert!(matches!(
+            cursor.next_entry(),
+            Some(CursorEntry::Error { .. })
+        ));
+        assert!(cursor.next_entry().is_none());
+        drop(cursor);
+        assert_eq!(manager.session_count(), 0);
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+}
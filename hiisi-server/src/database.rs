@@ -1,8 +1,14 @@
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 use crate::error::HiisiError;
 use crate::Result;
 
+/// How many steps in a row a backup may take without getting any further,
+/// because the source database is locked or was written to since the last
+/// step, before it gives up.
+const MAX_BACKUP_SETBACKS: usize = 64;
+
 pub struct Database {
     path: PathBuf,
 }
@@ -12,9 +18,20 @@ impl Database {
         Self { path }
     }
 
+    /// The file name that SQLite opens the database with, which is a URI
+    /// for an in-memory database.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn connect(&self) -> Result<Connection> {
         Connection::open(&self.path)
     }
+
+    /// Open a connection that fails every write with `SQLITE_READONLY`.
+    pub fn connect_read_only(&self) -> Result<Connection> {
+        Connection::open_read_only(&self.path)
+    }
 }
 pub struct Connection {
     conn: *mut libsql_ffi::sqlite3,
@@ -28,21 +45,40 @@ impl Drop for Connection {
 
 impl Connection {
     pub fn open(path: &Path) -> Result<Self> {
+        let flags = libsql_ffi::SQLITE_OPEN_READWRITE | libsql_ffi::SQLITE_OPEN_CREATE;
+        Self::open_with_flags(path, flags)
+    }
+
+    /// Open a connection to an existing database that fails every write
+    /// with `SQLITE_READONLY`.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        Self::open_with_flags(path, libsql_ffi::SQLITE_OPEN_READONLY)
+    }
+
+    fn open_with_flags(path: &Path, flags: i32) -> Result<Self> {
         log::trace!("Opening database: {:?}", path);
         let mut conn = std::ptr::null_mut();
         let path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
-        let flags = libsql_ffi::SQLITE_OPEN_READWRITE
-            | libsql_ffi::SQLITE_OPEN_CREATE
-            | libsql_ffi::SQLITE_OPEN_NOMUTEX;
+        // Paths that start with `file:` are URIs, such as the URIs of the
+        // shared-cache in-memory databases.
+        let flags = flags | libsql_ffi::SQLITE_OPEN_NOMUTEX | libsql_ffi::SQLITE_OPEN_URI;
         let vfs = std::ptr::null();
         let rc =
             unsafe { libsql_ffi::sqlite3_open_v2(path.as_ptr(), &mut conn, flags.into(), vfs) };
         if rc != libsql_ffi::SQLITE_OK {
-            return Err(HiisiError::SqliteError(rc));
+            return Err(conn_error(conn, rc));
         }
+        // Errors carry the extended result code, which tells clients more
+        // precisely what failed, for example which constraint.
+        unsafe { libsql_ffi::sqlite3_extended_result_codes(conn, 1) };
         Ok(Self { conn })
     }
 
+    /// The error of a call on the connection that failed with `rc`.
+    fn error(&self, rc: i32) -> HiisiError {
+        conn_error(self.conn, rc)
+    }
+
     pub fn prepare(&self, sql: &str) -> Result<Stmt> {
         let mut stmt = std::ptr::null_mut();
         let sql = std::ffi::CString::new(sql).unwrap();
@@ -56,27 +92,421 @@ impl Connection {
             )
         };
         if rc != libsql_ffi::SQLITE_OK {
-            return Err(HiisiError::SqliteError(rc));
+            return Err(self.error(rc));
         }
         Ok(Stmt { stmt })
     }
 
+    /// Prepare the first statement in `sql`, returning it together with the
+    /// remaining SQL text that follows it.
+    ///
+    /// The statement is `None` if the SQL text contains only whitespace or
+    /// comments before the end of the text or the next semicolon.
+    pub fn prepare_with_tail<'a>(&self, sql: &'a str) -> Result<(Option<Stmt>, &'a str)> {
+        let mut stmt = std::ptr::null_mut();
+        let mut tail = std::ptr::null();
+        let rc = unsafe {
+            libsql_ffi::sqlite3_prepare_v2(
+                self.conn,
+                sql.as_ptr() as *const std::ffi::c_char,
+                sql.len() as i32,
+                &mut stmt,
+                &mut tail,
+            )
+        };
+        if rc != libsql_ffi::SQLITE_OK {
+            return Err(self.error(rc));
+        }
+        let consumed = if tail.is_null() {
+            sql.len()
+        } else {
+            tail as usize - sql.as_ptr() as usize
+        };
+        let stmt = if stmt.is_null() {
+            None
+        } else {
+            Some(Stmt { stmt })
+        };
+        Ok((stmt, &sql[consumed..]))
+    }
+
+    /// Returns the number of rows changed by the most recently completed
+    /// `INSERT`, `UPDATE`, or `DELETE` statement.
+    pub fn changes(&self) -> u64 {
+        unsafe { libsql_ffi::sqlite3_changes64(self.conn) as u64 }
+    }
+
+    /// Returns the number of rows changed by every `INSERT`, `UPDATE`, or
+    /// `DELETE` statement completed since the connection was opened.
+    pub fn total_changes(&self) -> u64 {
+        unsafe { libsql_ffi::sqlite3_total_changes64(self.conn) as u64 }
+    }
+
+    pub fn last_insert_rowid(&self) -> i64 {
+        unsafe { libsql_ffi::sqlite3_last_insert_rowid(self.conn) }
+    }
+
+    /// Interrupt the statements that are running on the connection, which
+    /// fail with `SQLITE_INTERRUPT` the next time they are stepped.
+    ///
+    /// Statements that start once every running statement has been reset or
+    /// finalized run as usual.
+    pub fn interrupt(&self) {
+        unsafe { libsql_ffi::sqlite3_interrupt(self.conn) }
+    }
+
+    /// Returns `true` if the connection is not in an explicit transaction.
+    ///
+    /// SQLite tracks the state itself, so it follows `BEGIN`, `COMMIT` and
+    /// `ROLLBACK` however they are spelled, a `COMMIT` that fails and leaves
+    /// the transaction open, and an error that rolls back the transaction.
+    pub fn is_autocommit(&self) -> bool {
+        unsafe { libsql_ffi::sqlite3_get_autocommit(self.conn) != 0 }
+    }
+
+    /// Returns the number of frames in the WAL of the database.
+    ///
+    /// The count drops back to zero when a checkpoint restarts the WAL.
+    pub fn wal_frame_count(&self) -> Result<u32> {
+        let mut frame_count = 0;
+        let rc = unsafe { libsql_ffi::libsql_wal_frame_count(self.conn, &mut frame_count) };
+        if rc != libsql_ffi::SQLITE_OK {
+            return Err(self.error(rc));
+        }
+        Ok(frame_count)
+    }
+
+    /// Interrupt the statements that run more than `steps` virtual machine
+    /// instructions, or none if `steps` is `None`.
+    ///
+    /// An interrupted statement fails with `SQLITE_INTERRUPT`. The count
+    /// starts over with every statement, so the statements after it run as
+    /// usual.
+    pub fn set_step_limit(&self, steps: Option<u32>) {
+        unsafe extern "C" fn interrupt(_: *mut std::ffi::c_void) -> std::ffi::c_int {
+            1
+        }
+        match steps {
+            Some(steps) => unsafe {
+                libsql_ffi::sqlite3_progress_handler(
+                    self.conn,
+                    steps.min(i32::MAX as u32) as i32,
+                    Some(interrupt),
+                    std::ptr::null_mut(),
+                )
+            },
+            None => unsafe {
+                libsql_ffi::sqlite3_progress_handler(self.conn, 0, None, std::ptr::null_mut())
+            },
+        }
+    }
+
     pub fn pragma(&self, name: &str, value: impl Into<String>) -> Result<()> {
-        let name = std::ffi::CString::new(name).unwrap();
+        self.exec(&format!("PRAGMA {}={}", name, value.into()))
+    }
+
+    /// Run every statement of `sql`, discarding any rows they return.
+    pub fn exec(&self, sql: &str) -> Result<()> {
+        // SQLite reads the statement up to its terminating NUL.
+        let sql = std::ffi::CString::new(sql)
+            .map_err(|_| HiisiError::InternalError("SQL text contains NUL".to_owned()))?;
         let rc = unsafe {
             libsql_ffi::sqlite3_exec(
                 self.conn,
-                format!("PRAGMA {}={}", name.to_str().unwrap(), value.into()).as_ptr() as *const i8,
+                sql.as_ptr(),
                 None,
                 std::ptr::null_mut(),
                 std::ptr::null_mut(),
             )
         };
         if rc != libsql_ffi::SQLITE_OK {
-            return Err(HiisiError::SqliteError(rc));
+            return Err(self.error(rc));
+        }
+        Ok(())
+    }
+
+    /// Undo what a session may have left on the connection: detach the
+    /// databases it attached and drop the temporary tables, views and
+    /// triggers it created.
+    pub fn clear_session_state(&self) -> Result<()> {
+        let stmt = self.prepare("PRAGMA database_list")?;
+        let mut attached = Vec::new();
+        while let StepResult::Row = stmt.step()? {
+            let name = stmt.column_text(1);
+            if name != "main" && name != "temp" {
+                attached.push(name.to_owned());
+            }
+        }
+        drop(stmt);
+        for name in attached {
+            self.exec(&format!("DETACH DATABASE {}", quote_ident(&name)))?;
+        }
+        // Dropping a table drops its indexes and triggers too, so views and
+        // triggers, which may refer to tables, go first.
+        let stmt = self.prepare(
+            "SELECT type, name FROM temp.sqlite_schema \
+             WHERE type IN ('table', 'view', 'trigger') AND name NOT LIKE 'sqlite_%' \
+             ORDER BY type = 'table'",
+        )?;
+        let mut objects = Vec::new();
+        while let StepResult::Row = stmt.step()? {
+            objects.push((
+                stmt.column_text(0).to_owned(),
+                stmt.column_text(1).to_owned(),
+            ));
+        }
+        drop(stmt);
+        for (kind, name) in objects {
+            self.exec(&format!(
+                "DROP {} IF EXISTS temp.{}",
+                kind,
+                quote_ident(&name)
+            ))?;
         }
         Ok(())
     }
+
+    /// Read the value of a pragma that is an integer, such as
+    /// `user_version`.
+    pub fn pragma_int(&self, name: &str) -> Result<i64> {
+        let stmt = self.prepare(&format!("PRAGMA {}", name))?;
+        match stmt.step()? {
+            StepResult::Row => Ok(stmt.column_int(0)),
+            StepResult::Done => Err(HiisiError::InternalError(format!(
+                "PRAGMA {} returned no value",
+                name
+            ))),
+        }
+    }
+
+    /// Replace the database of the connection with the database image in
+    /// `data`, such as the image of a backup.
+    pub fn restore(&self, data: &[u8]) -> Result<()> {
+        let image = Connection::open(Path::new(":memory:"))?;
+        image.deserialize(data)?;
+        let backup = start_backup(self, &image)?;
+        let step = step_backup(backup, -1);
+        finish_backup(backup)?;
+        match step? {
+            BackupStep::Done => Ok(()),
+            // Another connection holds a lock on the database.
+            BackupStep::Copied | BackupStep::Locked => Err(sqlite_error(libsql_ffi::SQLITE_BUSY)),
+        }
+    }
+
+    /// Load the database image in `data` as the database of the connection,
+    /// which must be an in-memory database.
+    fn deserialize(&self, data: &[u8]) -> Result<()> {
+        // SQLite takes ownership of the buffer and frees it when the
+        // connection is closed, or right away if it fails.
+        let buf = unsafe { libsql_ffi::sqlite3_malloc64(data.len().max(1) as u64) } as *mut u8;
+        if buf.is_null() {
+            return Err(sqlite_error(libsql_ffi::SQLITE_NOMEM));
+        }
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), buf, data.len()) };
+        let flags =
+            libsql_ffi::SQLITE_DESERIALIZE_FREEONCLOSE | libsql_ffi::SQLITE_DESERIALIZE_RESIZEABLE;
+        let rc = unsafe {
+            libsql_ffi::sqlite3_deserialize(
+                self.conn,
+                c"main".as_ptr(),
+                buf,
+                data.len() as i64,
+                data.len() as i64,
+                flags as u32,
+            )
+        };
+        if rc != libsql_ffi::SQLITE_OK {
+            return Err(self.error(rc));
+        }
+        Ok(())
+    }
+
+    /// Return the image of the database of the connection without copying
+    /// it, which needs the database to be one that `deserialize()` loaded.
+    ///
+    /// The image is valid until the database is changed or the connection
+    /// is closed.
+    fn serialize_nocopy(&self) -> Result<(*const u8, usize)> {
+        let mut size = 0;
+        let data = unsafe {
+            libsql_ffi::sqlite3_serialize(
+                self.conn,
+                c"main".as_ptr(),
+                &mut size,
+                libsql_ffi::SQLITE_SERIALIZE_NOCOPY as u32,
+            )
+        };
+        if data.is_null() {
+            // An empty database has no pages to read.
+            if size == 0 {
+                return Ok((std::ptr::null(), 0));
+            }
+            return Err(HiisiError::InternalError(
+                "Database image is not contiguous in memory".to_owned(),
+            ));
+        }
+        Ok((data, size as usize))
+    }
+}
+
+/// Quote a name, such as the name of a table, for use in SQL.
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// A copy of a database that SQLite's backup API makes a few pages at a
+/// time, so that a large database does not keep the server from serving
+/// other clients while it is copied.
+///
+/// The source connection reads the database only while a step runs, so
+/// writes go on between the steps. A write on another connection makes the
+/// next step start the copy over, which keeps the copy consistent. A backup
+/// that keeps starting over, or keeps finding the database locked, gives up
+/// after `MAX_BACKUP_SETBACKS` steps.
+///
+/// Once finished, the copy is read a chunk at a time with `read_image()`,
+/// straight from the memory of the in-memory database.
+pub struct Backup {
+    backup: *mut libsql_ffi::sqlite3_backup,
+    /// The in-memory database that the backup copies into.
+    dest: Connection,
+    /// The connection that the backup copies from, which SQLite refers to
+    /// until the backup is finished.
+    _source: Rc<Connection>,
+    /// How many pages were left to copy after the last step that copied
+    /// any.
+    remaining: Option<i32>,
+    /// How many steps in a row have not got the backup any further.
+    setbacks: usize,
+    /// The image of the finished copy, which `dest` owns, and how much of it
+    /// has been read.
+    image: (*const u8, usize),
+    image_off: usize,
+}
+
+impl Drop for Backup {
+    fn drop(&mut self) {
+        if !self.backup.is_null() {
+            unsafe { libsql_ffi::sqlite3_backup_finish(self.backup) };
+        }
+    }
+}
+
+impl Backup {
+    /// Start a backup of the database of `source` into memory.
+    pub fn new(source: Rc<Connection>) -> Result<Self> {
+        let dest = Connection::open(Path::new(":memory:"))?;
+        // A deserialized database keeps its pages in one buffer, which the
+        // image is read from once the copy is finished.
+        dest.deserialize(&[])?;
+        let backup = start_backup(&dest, &source)?;
+        Ok(Self {
+            backup,
+            dest,
+            _source: source,
+            remaining: None,
+            setbacks: 0,
+            image: (std::ptr::null(), 0),
+            image_off: 0,
+        })
+    }
+
+    /// Copy up to `pages` more pages, returning `true` once the whole
+    /// database has been copied.
+    ///
+    /// A step that finds the source database locked copies nothing, and the
+    /// backup goes on with the next step, unless it has not got any further
+    /// for `MAX_BACKUP_SETBACKS` steps.
+    pub fn step(&mut self, pages: i32) -> Result<bool> {
+        let progress = match step_backup(self.backup, pages)? {
+            BackupStep::Done => return Ok(true),
+            BackupStep::Copied => {
+                let remaining = unsafe { libsql_ffi::sqlite3_backup_remaining(self.backup) };
+                // A copy that starts over has as many pages left as before,
+                // or more.
+                let progress = self.remaining.is_none_or(|before| remaining < before);
+                self.remaining = Some(remaining);
+                progress
+            }
+            BackupStep::Locked => false,
+        };
+        if progress {
+            self.setbacks = 0;
+        } else {
+            self.setbacks += 1;
+            if self.setbacks > MAX_BACKUP_SETBACKS {
+                return Err(HiisiError::BackupBusy);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Finish the backup, after which `read_image()` reads the image of the
+    /// copied database.
+    pub fn finish(&mut self) -> Result<()> {
+        finish_backup(std::mem::replace(&mut self.backup, std::ptr::null_mut()))?;
+        self.image = self.dest.serialize_nocopy()?;
+        Ok(())
+    }
+
+    /// Read up to `max_len` more bytes of the image of the finished backup,
+    /// returning an empty slice once all of it has been read.
+    pub fn read_image(&mut self, max_len: usize) -> &[u8] {
+        let (data, size) = self.image;
+        let len = max_len.min(size - self.image_off);
+        if len == 0 {
+            return &[];
+        }
+        // The image belongs to `dest`, which nothing changes once the
+        // backup is finished.
+        let chunk = unsafe { std::slice::from_raw_parts(data.add(self.image_off), len) };
+        self.image_off += len;
+        chunk
+    }
+}
+
+/// What a step of a backup did.
+enum BackupStep {
+    /// The whole database has been copied.
+    Done,
+    /// Some pages have been copied, or the copy has started over.
+    Copied,
+    /// The source database is locked, so nothing has been copied.
+    Locked,
+}
+
+/// Start a backup of the main database of `source` into the main database
+/// of `dest`.
+fn start_backup(dest: &Connection, source: &Connection) -> Result<*mut libsql_ffi::sqlite3_backup> {
+    let backup = unsafe {
+        libsql_ffi::sqlite3_backup_init(dest.conn, c"main".as_ptr(), source.conn, c"main".as_ptr())
+    };
+    if backup.is_null() {
+        let rc = unsafe { libsql_ffi::sqlite3_extended_errcode(dest.conn) };
+        return Err(dest.error(rc));
+    }
+    Ok(backup)
+}
+
+/// Copy up to `pages` pages, or all of them if `pages` is negative,
+/// returning what the step did.
+fn step_backup(backup: *mut libsql_ffi::sqlite3_backup, pages: i32) -> Result<BackupStep> {
+    let rc = unsafe { libsql_ffi::sqlite3_backup_step(backup, pages) };
+    match rc & 0xff {
+        libsql_ffi::SQLITE_DONE => Ok(BackupStep::Done),
+        libsql_ffi::SQLITE_OK => Ok(BackupStep::Copied),
+        libsql_ffi::SQLITE_BUSY | libsql_ffi::SQLITE_LOCKED => Ok(BackupStep::Locked),
+        _ => Err(sqlite_error(rc)),
+    }
+}
+
+fn finish_backup(backup: *mut libsql_ffi::sqlite3_backup) -> Result<()> {
+    let rc = unsafe { libsql_ffi::sqlite3_backup_finish(backup) };
+    if rc != libsql_ffi::SQLITE_OK {
+        return Err(sqlite_error(rc));
+    }
+    Ok(())
 }
 
 pub enum Type {
@@ -108,10 +538,93 @@ impl Stmt {
         match rc {
             libsql_ffi::SQLITE_ROW => Ok(StepResult::Row),
             libsql_ffi::SQLITE_DONE => Ok(StepResult::Done),
-            _ => Err(HiisiError::SqliteError(rc)),
+            _ => Err(conn_error(
+                unsafe { libsql_ffi::sqlite3_db_handle(self.stmt) },
+                rc,
+            )),
         }
     }
 
+    pub fn bind_null(&self, index: i32) -> Result<()> {
+        let rc = unsafe { libsql_ffi::sqlite3_bind_null(self.stmt, index) };
+        check_bind(rc)
+    }
+
+    pub fn bind_int(&self, index: i32, value: i64) -> Result<()> {
+        let rc = unsafe { libsql_ffi::sqlite3_bind_int64(self.stmt, index, value) };
+        check_bind(rc)
+    }
+
+    pub fn bind_float(&self, index: i32, value: f64) -> Result<()> {
+        let rc = unsafe { libsql_ffi::sqlite3_bind_double(self.stmt, index, value) };
+        check_bind(rc)
+    }
+
+    /// Bind a text value. SQLite makes its own copy of the text, so the
+    /// value can be freed as soon as this returns.
+    pub fn bind_text(&self, index: i32, value: &str) -> Result<()> {
+        let rc = unsafe {
+            libsql_ffi::sqlite3_bind_text(
+                self.stmt,
+                index,
+                value.as_ptr() as *const std::ffi::c_char,
+                value.len() as i32,
+                libsql_ffi::SQLITE_TRANSIENT(),
+            )
+        };
+        check_bind(rc)
+    }
+
+    /// Bind a blob value. SQLite makes its own copy of the blob, so the
+    /// value can be freed as soon as this returns.
+    pub fn bind_blob(&self, index: i32, value: &[u8]) -> Result<()> {
+        let rc = unsafe {
+            libsql_ffi::sqlite3_bind_blob(
+                self.stmt,
+                index,
+                value.as_ptr() as *const std::ffi::c_void,
+                value.len() as i32,
+                libsql_ffi::SQLITE_TRANSIENT(),
+            )
+        };
+        check_bind(rc)
+    }
+
+    pub fn bind_parameter_count(&self) -> i32 {
+        unsafe { libsql_ffi::sqlite3_bind_parameter_count(self.stmt) }
+    }
+
+    /// Returns the name of a parameter, or `None` if the parameter is a
+    /// nameless `?`. Parameter indexes start from 1.
+    pub fn bind_parameter_name(&self, index: i32) -> Option<&str> {
+        let name = unsafe { libsql_ffi::sqlite3_bind_parameter_name(self.stmt, index) };
+        if name.is_null() {
+            return None;
+        }
+        let name = unsafe { std::ffi::CStr::from_ptr(name) };
+        Some(name.to_str().unwrap())
+    }
+
+    /// Returns the index of a named parameter, or `None` if the statement
+    /// has no parameter with the name. The name includes the prefix
+    /// character, for example `:name`.
+    pub fn bind_parameter_index(&self, name: &str) -> Option<i32> {
+        let name = std::ffi::CString::new(name).ok()?;
+        let index = unsafe { libsql_ffi::sqlite3_bind_parameter_index(self.stmt, name.as_ptr()) };
+        if index == 0 {
+            return None;
+        }
+        Some(index)
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        unsafe { libsql_ffi::sqlite3_stmt_readonly(self.stmt) != 0 }
+    }
+
+    pub fn is_explain(&self) -> bool {
+        unsafe { libsql_ffi::sqlite3_stmt_isexplain(self.stmt) != 0 }
+    }
+
     pub fn column_count(&self) -> i32 {
         unsafe { libsql_ffi::sqlite3_column_count(self.stmt) }
     }
@@ -160,9 +673,119 @@ impl Stmt {
         text.to_str().unwrap()
     }
 
+    /// Read a blob column of the current row.
+    ///
+    /// The blob belongs to SQLite until the statement is stepped again or
+    /// finalized, so it has to be copied before then.
     pub fn column_blob(&self, index: i32) -> &[u8] {
+        // The length is read after the blob, as SQLite documents, so that it
+        // is the length of the value that the blob pointer refers to.
         let blob = unsafe { libsql_ffi::sqlite3_column_blob(self.stmt, index) };
         let len = unsafe { libsql_ffi::sqlite3_column_bytes(self.stmt, index) };
+        // A zero-length blob comes back as a null pointer.
+        if blob.is_null() || len == 0 {
+            return &[];
+        }
         unsafe { std::slice::from_raw_parts(blob as *const u8, len as usize) }
     }
 }
+
+fn check_bind(rc: i32) -> Result<()> {
+    if rc != libsql_ffi::SQLITE_OK {
+        return Err(sqlite_error(rc));
+    }
+    Ok(())
+}
+
+/// The error of a call on `conn` that failed with `rc`, with the message
+/// that SQLite explains the failure with, such as which table does not
+/// exist or which constraint a write violated.
+fn conn_error(conn: *mut libsql_ffi::sqlite3, rc: i32) -> HiisiError {
+    if conn.is_null() {
+        return sqlite_error(rc);
+    }
+    let msg = unsafe { std::ffi::CStr::from_ptr(libsql_ffi::sqlite3_errmsg(conn)) };
+    HiisiError::SqliteError(rc, msg.to_string_lossy().into_owned())
+}
+
+/// The error of a failure with `rc` that no connection explains, with the
+/// generic message of the result code.
+pub fn sqlite_error(rc: i32) -> HiisiError {
+    let msg = unsafe { std::ffi::CStr::from_ptr(libsql_ffi::sqlite3_errstr(rc)) };
+    HiisiError::SqliteError(rc, msg.to_string_lossy().into_owned())
+}
+
+/// Check if a result code reports that the storage of a database is full or
+/// failing.
+pub fn is_storage_error(rc: i32) -> bool {
+    matches!(
+        rc & 0xff,
+        libsql_ffi::SQLITE_FULL | libsql_ffi::SQLITE_IOERR
+    )
+}
+
+/// The name of a SQLite result code, which is how errors are identified in
+/// the Hrana protocol.
+///
+/// Extended result codes without a name of their own are named after their
+/// primary result code.
+pub fn error_code(rc: i32) -> &'static str {
+    use libsql_ffi::*;
+    match rc {
+        SQLITE_BUSY_RECOVERY => "SQLITE_BUSY_RECOVERY",
+        SQLITE_BUSY_SNAPSHOT => "SQLITE_BUSY_SNAPSHOT",
+        SQLITE_BUSY_TIMEOUT => "SQLITE_BUSY_TIMEOUT",
+        SQLITE_LOCKED_SHAREDCACHE => "SQLITE_LOCKED_SHAREDCACHE",
+        SQLITE_READONLY_RECOVERY => "SQLITE_READONLY_RECOVERY",
+        SQLITE_READONLY_CANTLOCK => "SQLITE_READONLY_CANTLOCK",
+        SQLITE_READONLY_ROLLBACK => "SQLITE_READONLY_ROLLBACK",
+        SQLITE_READONLY_DBMOVED => "SQLITE_READONLY_DBMOVED",
+        SQLITE_IOERR_READ => "SQLITE_IOERR_READ",
+        SQLITE_IOERR_SHORT_READ => "SQLITE_IOERR_SHORT_READ",
+        SQLITE_IOERR_WRITE => "SQLITE_IOERR_WRITE",
+        SQLITE_IOERR_FSYNC => "SQLITE_IOERR_FSYNC",
+        SQLITE_IOERR_TRUNCATE => "SQLITE_IOERR_TRUNCATE",
+        SQLITE_IOERR_NOMEM => "SQLITE_IOERR_NOMEM",
+        SQLITE_CORRUPT_VTAB => "SQLITE_CORRUPT_VTAB",
+        SQLITE_CONSTRAINT_CHECK => "SQLITE_CONSTRAINT_CHECK",
+        SQLITE_CONSTRAINT_COMMITHOOK => "SQLITE_CONSTRAINT_COMMITHOOK",
+        SQLITE_CONSTRAINT_FOREIGNKEY => "SQLITE_CONSTRAINT_FOREIGNKEY",
+        SQLITE_CONSTRAINT_FUNCTION => "SQLITE_CONSTRAINT_FUNCTION",
+        SQLITE_CONSTRAINT_NOTNULL => "SQLITE_CONSTRAINT_NOTNULL",
+        SQLITE_CONSTRAINT_PRIMARYKEY => "SQLITE_CONSTRAINT_PRIMARYKEY",
+        SQLITE_CONSTRAINT_TRIGGER => "SQLITE_CONSTRAINT_TRIGGER",
+        SQLITE_CONSTRAINT_UNIQUE => "SQLITE_CONSTRAINT_UNIQUE",
+        SQLITE_CONSTRAINT_VTAB => "SQLITE_CONSTRAINT_VTAB",
+        SQLITE_CONSTRAINT_ROWID => "SQLITE_CONSTRAINT_ROWID",
+        SQLITE_CONSTRAINT_DATATYPE => "SQLITE_CONSTRAINT_DATATYPE",
+        _ => match rc & 0xff {
+            SQLITE_ERROR => "SQLITE_ERROR",
+            SQLITE_INTERNAL => "SQLITE_INTERNAL",
+            SQLITE_PERM => "SQLITE_PERM",
+            SQLITE_ABORT => "SQLITE_ABORT",
+            SQLITE_BUSY => "SQLITE_BUSY",
+            SQLITE_LOCKED => "SQLITE_LOCKED",
+            SQLITE_NOMEM => "SQLITE_NOMEM",
+            SQLITE_READONLY => "SQLITE_READONLY",
+            SQLITE_INTERRUPT => "SQLITE_INTERRUPT",
+            SQLITE_IOERR => "SQLITE_IOERR",
+            SQLITE_CORRUPT => "SQLITE_CORRUPT",
+            SQLITE_NOTFOUND => "SQLITE_NOTFOUND",
+            SQLITE_FULL => "SQLITE_FULL",
+            SQLITE_CANTOPEN => "SQLITE_CANTOPEN",
+            SQLITE_PROTOCOL => "SQLITE_PROTOCOL",
+            SQLITE_EMPTY => "SQLITE_EMPTY",
+            SQLITE_SCHEMA => "SQLITE_SCHEMA",
+            SQLITE_TOOBIG => "SQLITE_TOOBIG",
+            SQLITE_CONSTRAINT => "SQLITE_CONSTRAINT",
+            SQLITE_MISMATCH => "SQLITE_MISMATCH",
+            SQLITE_MISUSE => "SQLITE_MISUSE",
+            SQLITE_NOLFS => "SQLITE_NOLFS",
+            SQLITE_AUTH => "SQLITE_AUTH",
+            SQLITE_FORMAT => "SQLITE_FORMAT",
+            SQLITE_RANGE => "SQLITE_RANGE",
+            SQLITE_NOTADB => "SQLITE_NOTADB",
+            _ => "SQLITE_ERROR",
+        },
+    }
+}
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::http::StatusCode;
+
 #[derive(Debug, Error)]
 pub enum HiisiError {
     #[error("Protocol error: {0}")]
@@ -12,6 +14,99 @@ pub enum HiisiError {
     IOError(&'static str, std::io::Error),
     #[error("Out of memory")]
     OutOfMemory,
-    #[error("SQLite error: {0}")]
-    SqliteError(i32),
+    /// A failed SQLite call, with its result code and the message that
+    /// SQLite explains the failure with.
+    #[error("SQLite error: {}: {}", crate::database::error_code(*.0), .1)]
+    SqliteError(i32, String),
+    #[error("Protocol error: Unsupported Hrana version: {0}")]
+    UnsupportedVersion(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Database already exists: {0}")]
+    DatabaseExists(String),
+    #[error("Database not found: {0}")]
+    DatabaseNotFound(String),
+    #[error("Database is in use by open streams: {0}")]
+    DatabaseInUse(String),
+    #[error("Invalid namespace name: {0}")]
+    InvalidNamespace(String),
+    #[error("Protocol error: Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+    #[error("Invalid statement arguments: {0}")]
+    ArgsInvalid(String),
+    #[error("The stream has expired due to inactivity")]
+    StreamExpired,
+    #[error("TLS error: {0}")]
+    TlsError(String),
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Statement rejected: {0}")]
+    StmtRejected(String),
+    #[error("SQL text is larger than {0} bytes")]
+    SqlTooLarge(usize),
+    #[error("Statement returned more than {0} rows, use a cursor to read larger results")]
+    ResultTooLarge(usize),
+    #[error("Replica is behind: replication index {0} is ahead of the database at {1}")]
+    ReplicaBehind(u64, u64),
+    #[error("Rate limit exceeded, retry in {} ms", .0.as_millis())]
+    RateLimited(std::time::Duration),
+    #[error("Backup gave up, as the database is too busy")]
+    BackupBusy,
+}
+
+impl HiisiError {
+    /// The HTTP status of the response to a request that failed with this
+    /// error, before the request got as far as a Hrana stream result.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HiisiError::NotFound(_)
+            | HiisiError::DatabaseNotFound(_)
+            | HiisiError::UnsupportedVersion(_) => StatusCode::NOT_FOUND,
+            HiisiError::DatabaseExists(_) | HiisiError::DatabaseInUse(_) => StatusCode::CONFLICT,
+            HiisiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            HiisiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            // The client may retry once the replica has caught up.
+            HiisiError::ReplicaBehind(..) | HiisiError::BackupBusy => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            // The server failed to serve the request, not the client to make
+            // it.
+            HiisiError::InternalError(_)
+            | HiisiError::IOError(..)
+            | HiisiError::OutOfMemory
+            | HiisiError::SqliteError(..)
+            | HiisiError::TlsError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            HiisiError::ProtocolError(_)
+            | HiisiError::JsonParseError(_)
+            | HiisiError::InvalidNamespace(_)
+            | HiisiError::ArgsInvalid(_)
+            | HiisiError::StreamExpired
+            | HiisiError::InvalidConfig(_)
+            | HiisiError::StmtRejected(_)
+            | HiisiError::SqlTooLarge(_)
+            | HiisiError::ResultTooLarge(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HiisiError;
+    use crate::http::StatusCode;
+
+    #[test]
+    fn errors_map_to_status() {
+        assert_eq!(
+            HiisiError::NotFound("/v2/foo".to_owned()).status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            HiisiError::ProtocolError("Invalid baton".to_owned()).status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            HiisiError::IOError("write", std::io::ErrorKind::Other.into()).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
 }
@@ -1,68 +1,246 @@
 //! Query executor.
 
-use crate::database::{StepResult, Stmt, Type};
+use crate::database::{is_storage_error, Connection, StepResult, Stmt, Type};
+use crate::filter::{first_keyword, parse_attach, StmtFilter};
 use crate::manager::ResourceManager;
 use crate::proto;
+use crate::session::Session;
 use crate::{HiisiError, Result};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
+use std::time::Duration;
 
 pub struct Request {
     pub database: String,
+    pub version: proto::Version,
     pub req: proto::PipelineReqBody,
 }
 
-impl Request {
-    fn baton(&self) -> String {
-        self.req.baton.to_owned().unwrap_or(generate_baton())
+/// The log target of slow statements, so that `RUST_LOG` can tell the slow
+/// statement log apart from the rest, as in `RUST_LOG=hiisi::slow_query`.
+pub const SLOW_QUERY_TARGET: &str = "hiisi::slow_query";
+
+pub fn execute_client_req(
+    manager: Rc<ResourceManager>,
+    req: Request,
+) -> Result<proto::PipelineRespBody> {
+    execute_client_req_with_id(manager, req, None)
+}
+
+/// Execute a pipeline request like `execute_client_req()`, tagging the slow
+/// statements that it logs with the correlation id of the request.
+pub fn execute_client_req_with_id(
+    manager: Rc<ResourceManager>,
+    req: Request,
+    request_id: Option<&str>,
+) -> Result<proto::PipelineRespBody> {
+    match execute_client_req_or_block(manager, req, request_id, false)? {
+        PipelineOutcome::Done(resp) => Ok(resp),
+        // Nothing blocks unless asked to.
+        PipelineOutcome::Blocked(_) => unreachable!("pipeline blocked without blocking"),
     }
 }
 
-fn generate_baton() -> String {
-    // NOTE: This is different from the baton generation in libSQL server.
-    uuid::Uuid::new_v4().to_string()
+/// The outcome of a pipeline request that may block on a locked database.
+pub enum PipelineOutcome {
+    Done(proto::PipelineRespBody),
+    Blocked(BlockedPipeline),
 }
 
-pub fn execute_client_req(
+/// A pipeline request that stopped at an execute request whose statement
+/// found its database locked by another connection.
+///
+/// The requests before it have been executed on the session, whose baton
+/// is rotated only once the whole pipeline is done.
+pub struct BlockedPipeline {
+    session: Rc<Session>,
+    req: proto::PipelineReqBody,
+    /// The results of the requests before the blocked one.
+    results: Vec<proto::StreamResult>,
+}
+
+/// Execute a pipeline request like `execute_client_req_with_id()`, but if
+/// `block` is set, stop at an execute request that fails with `SQLITE_BUSY`
+/// rather than report the error, so that the caller can execute the request
+/// again with `resume_client_req()` once the lock may have been released.
+///
+/// Only execute requests block, because a failed statement has no effect
+/// that executing it again would repeat, unlike a failed step of a batch or
+/// a sequence, whose earlier statements have run.
+pub fn execute_client_req_or_block(
     manager: Rc<ResourceManager>,
     req: Request,
-) -> Result<proto::PipelineRespBody> {
-    let db_name = &req.database;
-    let baton = &req.baton();
-    let req = &req.req;
-    let mut responses = Vec::new();
-    responses
-        .try_reserve(req.requests.len())
+    request_id: Option<&str>,
+    block: bool,
+) -> Result<PipelineOutcome> {
+    let session = resolve_session(&manager, &req.req.baton, &req.database, req.version)?;
+    execute_pipeline(manager, session, req.req, Vec::new(), request_id, block)
+}
+
+/// Execute a blocked pipeline request from the request that it stopped at,
+/// like `execute_client_req_or_block()`.
+pub fn resume_client_req(
+    manager: Rc<ResourceManager>,
+    blocked: BlockedPipeline,
+    request_id: Option<&str>,
+    block: bool,
+) -> Result<PipelineOutcome> {
+    // The transaction of the session may have expired while the request
+    // waited.
+    manager.expire_transactions();
+    if !manager.has_session(blocked.session.id) {
+        return Err(HiisiError::StreamExpired);
+    }
+    execute_pipeline(
+        manager,
+        blocked.session,
+        blocked.req,
+        blocked.results,
+        request_id,
+        block,
+    )
+}
+
+/// Execute the requests of a pipeline that come after the ones that
+/// `results` holds the results of.
+fn execute_pipeline(
+    manager: Rc<ResourceManager>,
+    session: Rc<Session>,
+    req: proto::PipelineReqBody,
+    mut results: Vec<proto::StreamResult>,
+    request_id: Option<&str>,
+    block: bool,
+) -> Result<PipelineOutcome> {
+    // The connection of a session is opened by its first statement, so a
+    // pipeline without requests, which clients send to keep their baton
+    // fresh, does not open one.
+    results
+        .try_reserve(req.requests.len() - results.len())
         .map_err(|_| HiisiError::OutOfMemory)?;
-    for req in &req.requests {
-        let resp = match req {
+    let mut closed = false;
+    for index in results.len()..req.requests.len() {
+        if closed {
+            return Err(HiisiError::StreamExpired);
+        }
+        let stream_req = &req.requests[index];
+        let resp = match stream_req {
             proto::StreamRequest::None => todo!(),
-            proto::StreamRequest::Close(_) => exec_close(manager.clone(), db_name, baton)?,
+            proto::StreamRequest::Close(_) => {
+                closed = true;
+                exec_close(manager.clone(), &session)
+            }
             proto::StreamRequest::Execute(req) => {
-                exec_execute(manager.clone(), &req, db_name, baton)?
+                exec_execute(manager.clone(), req, &session, request_id)
+            }
+            proto::StreamRequest::Batch(req) => {
+                exec_batch(manager.clone(), req, &session, request_id)
+            }
+            proto::StreamRequest::Sequence(req) => {
+                exec_sequence(manager.clone(), req, &session, request_id)
             }
-            proto::StreamRequest::Batch(_) => todo!(),
-            proto::StreamRequest::Sequence(_) => todo!(),
-            proto::StreamRequest::Describe(_) => todo!(),
-            proto::StreamRequest::StoreSql(_) => todo!(),
-            proto::StreamRequest::CloseSql(_) => todo!(),
-            proto::StreamRequest::GetAutocommit(_) => todo!(),
+            proto::StreamRequest::Describe(req) => exec_describe(manager.clone(), req, &session),
+            proto::StreamRequest::StoreSql(req) => exec_store_sql(req, &session),
+            proto::StreamRequest::CloseSql(req) => exec_close_sql(req, &session),
+            proto::StreamRequest::GetAutocommit(_) => exec_get_autocommit(&session),
         };
-        responses.push(resp);
+        let blocked = block
+            && matches!(stream_req, proto::StreamRequest::Execute(_))
+            && matches!(
+                resp,
+                Err(HiisiError::SqliteError(libsql_ffi::SQLITE_BUSY, _))
+            );
+        if blocked {
+            log::trace!(
+                "Request {} on {} is waiting for a lock (session = {})",
+                index,
+                session.db_name,
+                session.id
+            );
+            return Ok(PipelineOutcome::Blocked(BlockedPipeline {
+                session,
+                req,
+                results,
+            }));
+        }
+        let resp = match resp {
+            Ok(resp) => resp,
+            // A request that fails does not fail the pipeline: the error is
+            // reported for the request, and the requests after it still run.
+            Err(err) if is_request_error(&err) => proto::StreamResult::Error {
+                error: to_proto_error(&err),
+            },
+            Err(err) => return Err(err),
+        };
+        results.push(resp);
     }
-    return Ok(proto::PipelineRespBody {
-        baton: Some(baton.to_owned()),
+    // A closed stream has no baton the client could continue with.
+    let baton = if closed {
+        None
+    } else {
+        Some(manager.issue_baton(&session))
+    };
+    Ok(PipelineOutcome::Done(proto::PipelineRespBody {
+        baton,
         base_url: None,
-        results: responses,
-    });
+        results,
+    }))
 }
 
-fn exec_close(
-    manager: Rc<ResourceManager>,
-    db_name: &str,
-    baton: &str,
-) -> Result<proto::StreamResult> {
-    log::trace!("Closing connection: {} (baton = {})", db_name, baton);
-    manager.drop_conn(db_name, baton)?;
+/// The highest replication index that the requests of a pipeline read with,
+/// if any of them does.
+pub fn pipeline_replication_index(req: &proto::PipelineReqBody) -> Option<u64> {
+    req.requests
+        .iter()
+        .filter_map(|req| match req {
+            proto::StreamRequest::Execute(req) => req.stmt.replication_index,
+            proto::StreamRequest::Batch(req) => req.batch.replication_index,
+            proto::StreamRequest::Sequence(req) => req.replication_index,
+            proto::StreamRequest::Describe(req) => req.replication_index,
+            _ => None,
+        })
+        .max()
+}
+
+/// Whether an error fails only the request it happened in. Other errors,
+/// such as failures of the storage, fail the whole pipeline.
+fn is_request_error(err: &HiisiError) -> bool {
+    matches!(
+        err,
+        HiisiError::SqliteError(..)
+            | HiisiError::ProtocolError(_)
+            | HiisiError::ArgsInvalid(_)
+            | HiisiError::StmtRejected(_)
+            | HiisiError::SqlTooLarge(_)
+            | HiisiError::ResultTooLarge(_)
+            | HiisiError::ReplicaBehind(..)
+    )
+}
+
+/// Look up the session a baton refers to, or open a new session for a
+/// request without a baton.
+pub(crate) fn resolve_session(
+    manager: &ResourceManager,
+    baton: &Option<String>,
+    database: &str,
+    version: proto::Version,
+) -> Result<Rc<Session>> {
+    // Roll back abandoned transactions first, so that the request neither
+    // waits on their locks nor continues one of them.
+    manager.expire_transactions();
+    match baton {
+        Some(baton) => manager.get_session(baton).ok_or(HiisiError::StreamExpired),
+        None => Ok(manager.create_session(database, version)),
+    }
+}
+
+fn exec_close(manager: Rc<ResourceManager>, session: &Session) -> Result<proto::StreamResult> {
+    log::trace!(
+        "Closing stream: {} (session = {})",
+        session.db_name,
+        session.id
+    );
+    manager.drop_session(session.id);
     Ok(proto::StreamResult::Ok {
         response: proto::StreamResponse::Close(proto::CloseStreamResp {}),
     })
@@ -71,25 +249,140 @@ fn exec_close(
 fn exec_execute(
     manager: Rc<ResourceManager>,
     req: &proto::ExecuteStreamReq,
-    db_name: &str,
-    baton: &str,
+    session: &Session,
+    request_id: Option<&str>,
 ) -> Result<proto::StreamResult> {
     log::trace!(
-        "Executing SQL statement: {:?} on {} (baton = {}",
+        "Executing SQL statement: {:?} on {} (session = {})",
         req.stmt,
-        db_name,
-        baton
+        session.db_name,
+        session.id
     );
-    let conn = manager.get_conn(db_name, baton)?;
-    let sql = req.stmt.sql.as_ref().ok_or(HiisiError::InternalError(
-        "No SQL statement found".to_string(),
-    ))?;
-    let stmt = conn.prepare(sql)?;
-    let result = make_execute_result(stmt)?;
-    Ok(result)
+    let conn = manager.get_conn(session)?;
+    manager.check_replication_index(&session.db_name, &conn, req.stmt.replication_index)?;
+    let mut result = execute_timed_stmt(&manager, &conn, session, &req.stmt, request_id)?;
+    result.replication_index = Some(manager.replication_index(&session.db_name, &conn)?);
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::Execute(proto::ExecuteStreamResp { result }),
+    })
 }
 
-fn make_execute_result(stmt: Stmt) -> Result<proto::StreamResult> {
+fn exec_sequence(
+    manager: Rc<ResourceManager>,
+    req: &proto::SequenceStreamReq,
+    session: &Session,
+    request_id: Option<&str>,
+) -> Result<proto::StreamResult> {
+    log::trace!(
+        "Executing sequence: {:?} on {} (session = {})",
+        req.sql,
+        session.db_name,
+        session.id
+    );
+    let conn = manager.get_conn(session)?;
+    manager.check_replication_index(&session.db_name, &conn, req.replication_index)?;
+    let sql = resolve_sql(session, &req.sql, req.sql_id)?;
+    // The sequence stops at the first failing statement. Statements that
+    // were executed before it are not rolled back, just like with
+    // `sqlite3_exec()`.
+    let start = manager.clock().now();
+    let result = execute_sequence(&conn, &SessionFilter(session), &sql);
+    if let Some(threshold) = manager.slow_query_threshold() {
+        let elapsed = manager.clock().now().saturating_sub(start);
+        if elapsed >= threshold {
+            let outcome = match &result {
+                Ok(total_changes) => format!("changes={}", total_changes),
+                Err((index, err)) => format!("failed at statement {}: {}", index, err),
+            };
+            log_slow(
+                request_id,
+                "sequence",
+                &session.db_name,
+                elapsed,
+                0,
+                &outcome,
+                &format!("sql={:?}", sql),
+            );
+        }
+    }
+    let total_changes = match result {
+        Ok(total_changes) => total_changes,
+        Err((index, err)) => {
+            roll_back_on_storage_error(&conn, &err);
+            let mut error = to_proto_error(&err);
+            error.message = format!("Statement {} in sequence failed: {}", index, error.message);
+            return Ok(proto::StreamResult::Error { error });
+        }
+    };
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::Sequence(proto::SequenceStreamResp { total_changes }),
+    })
+}
+
+/// Execute every statement in a SQL script that passes `filter`, returning
+/// the number of rows that the script changed, or the index of the statement
+/// that failed or was rejected on error.
+///
+/// The changes are counted from the total changes of the connection before
+/// the script, so the changes of earlier requests on a pooled connection are
+/// not included.
+fn execute_sequence(
+    conn: &Connection,
+    filter: &dyn StmtFilter,
+    sql: &str,
+) -> std::result::Result<u64, (usize, HiisiError)> {
+    let total_changes = conn.total_changes();
+    let mut rest = sql;
+    let mut index = 0;
+    while !rest.is_empty() {
+        let (stmt, tail) = conn.prepare_with_tail(rest).map_err(|err| (index, err))?;
+        if let Some(stmt) = stmt {
+            let text = &rest[..rest.len() - tail.len()];
+            filter.check(text).map_err(|err| (index, err))?;
+            // Rows returned by the statements are discarded.
+            while let StepResult::Row = stmt.step().map_err(|err| (index, err))? {}
+            index += 1;
+        } else if tail.len() == rest.len() {
+            // Nothing but whitespace or comments is left.
+            break;
+        }
+        rest = tail;
+    }
+    Ok(conn.total_changes() - total_changes)
+}
+
+fn exec_describe(
+    manager: Rc<ResourceManager>,
+    req: &proto::DescribeStreamReq,
+    session: &Session,
+) -> Result<proto::StreamResult> {
+    log::trace!(
+        "Describing SQL statement: {:?} on {} (session = {})",
+        req.sql,
+        session.db_name,
+        session.id
+    );
+    let conn = manager.get_conn(session)?;
+    manager.check_replication_index(&session.db_name, &conn, req.replication_index)?;
+    let sql = resolve_sql(session, &req.sql, req.sql_id)?;
+    let result = describe(&conn, &sql)?;
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::Describe(proto::DescribeStreamResp { result }),
+    })
+}
+
+/// Describe the parameters and result columns of a SQL statement without
+/// executing it.
+fn describe(conn: &Connection, sql: &str) -> Result<proto::DescribeResult> {
+    let stmt = conn.prepare(sql)?;
+    let param_count = stmt.bind_parameter_count();
+    let mut params = Vec::with_capacity(param_count as usize);
+    for i in 1..=param_count {
+        let name = stmt.bind_parameter_name(i);
+        params.push(proto::DescribeParam {
+            name: name.map(Into::into),
+        });
+    }
     let column_count = stmt.column_count();
     let mut cols = Vec::with_capacity(column_count as usize);
     for i in 0..column_count {
@@ -99,41 +392,742 @@ fn make_execute_result(stmt: Stmt) -> Result<proto::StreamResult> {
                 "No column name found for column {}",
                 i
             )))?;
-        let decltype = stmt.column_decltype(i);
-        let col = proto::Col {
-            name: Some(name.into()),
-            decltype: decltype.map(Into::into),
+        cols.push(proto::DescribeCol {
+            name: name.into(),
+            decltype: stmt.column_decltype(i).map(Into::into),
+        });
+    }
+    Ok(proto::DescribeResult {
+        params,
+        cols,
+        is_explain: stmt.is_explain(),
+        is_readonly: stmt.is_readonly(),
+    })
+}
+
+fn exec_store_sql(
+    req: &proto::StoreSqlStreamReq,
+    session: &Session,
+) -> Result<proto::StreamResult> {
+    log::trace!(
+        "Storing SQL text {}: {:?} (session = {})",
+        req.sql_id,
+        req.sql,
+        session.id
+    );
+    session.store_sql(req.sql_id, req.sql.clone())?;
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::StoreSql(proto::StoreSqlStreamResp {}),
+    })
+}
+
+fn exec_close_sql(
+    req: &proto::CloseSqlStreamReq,
+    session: &Session,
+) -> Result<proto::StreamResult> {
+    log::trace!("Closing SQL text {} (session = {})", req.sql_id, session.id);
+    session.close_sql(req.sql_id);
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::CloseSql(proto::CloseSqlStreamResp {}),
+    })
+}
+
+fn exec_get_autocommit(session: &Session) -> Result<proto::StreamResult> {
+    // A session without a connection cannot have a transaction open.
+    let is_autocommit = match session.conn.borrow().as_ref() {
+        Some(conn) => conn.is_autocommit(),
+        None => true,
+    };
+    log::trace!(
+        "Getting autocommit: {} (session = {})",
+        is_autocommit,
+        session.id
+    );
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::GetAutocommit(proto::GetAutocommitStreamResp {
+            is_autocommit,
+        }),
+    })
+}
+
+fn exec_batch(
+    manager: Rc<ResourceManager>,
+    req: &proto::BatchStreamReq,
+    session: &Session,
+    request_id: Option<&str>,
+) -> Result<proto::StreamResult> {
+    log::trace!(
+        "Executing batch: {:?} on {} (session = {})",
+        req.batch,
+        session.db_name,
+        session.id
+    );
+    let conn = manager.get_conn(session)?;
+    manager.check_replication_index(&session.db_name, &conn, req.batch.replication_index)?;
+    let steps = &req.batch.steps;
+    let mut step_results = Vec::with_capacity(steps.len());
+    let mut step_errors = Vec::with_capacity(steps.len());
+    for step in steps {
+        let enabled = match &step.condition {
+            Some(cond) => eval_cond(cond, &step_results, &step_errors, conn.is_autocommit())?,
+            None => true,
         };
-        cols.push(col);
+        if !enabled {
+            step_results.push(None);
+            step_errors.push(None);
+            continue;
+        }
+        // A failing step does not fail the whole batch: the error is
+        // reported for the step and later steps decide whether to run
+        // based on their conditions.
+        match execute_timed_stmt(&manager, &conn, session, &step.stmt, request_id) {
+            Ok(mut result) => {
+                result.replication_index =
+                    Some(manager.replication_index(&session.db_name, &conn)?);
+                step_results.push(Some(result));
+                step_errors.push(None);
+            }
+            Err(err) => {
+                step_results.push(None);
+                step_errors.push(Some(to_proto_error(&err)));
+            }
+        }
+    }
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::Batch(proto::BatchStreamResp {
+            result: proto::BatchResult {
+                step_results,
+                step_errors,
+                replication_index: Some(manager.replication_index(&session.db_name, &conn)?),
+            },
+        }),
+    })
+}
+
+/// Evaluate a batch step condition against the outcome of earlier steps.
+///
+/// Conditions can only refer to steps that have already been evaluated, so a
+/// reference to the current step or anything after it is a protocol error.
+pub(crate) fn eval_cond<R>(
+    cond: &proto::BatchCond,
+    step_results: &[Option<R>],
+    step_errors: &[Option<proto::Error>],
+    is_autocommit: bool,
+) -> Result<bool> {
+    let check_step = |step: u32| {
+        let step = step as usize;
+        if step >= step_results.len() {
+            return Err(HiisiError::ProtocolError(format!(
+                "Batch condition refers to step {} which has not been executed",
+                step
+            )));
+        }
+        Ok(step)
+    };
+    let eval = |cond| eval_cond(cond, step_results, step_errors, is_autocommit);
+    match cond {
+        proto::BatchCond::None => Err(HiisiError::ProtocolError(
+            "Invalid batch condition".to_owned(),
+        )),
+        proto::BatchCond::Ok { step } => Ok(step_results[check_step(*step)?].is_some()),
+        proto::BatchCond::Error { step } => Ok(step_errors[check_step(*step)?].is_some()),
+        proto::BatchCond::Not { cond } => Ok(!eval(cond)?),
+        // Evaluate every sub-condition, even if the result is already known,
+        // so that invalid step references are always reported.
+        proto::BatchCond::And(list) => list
+            .conds
+            .iter()
+            .try_fold(true, |acc, cond| Ok(eval(cond)? && acc)),
+        proto::BatchCond::Or(list) => list
+            .conds
+            .iter()
+            .try_fold(false, |acc, cond| Ok(eval(cond)? || acc)),
+        proto::BatchCond::IsAutocommit {} => Ok(is_autocommit),
+    }
+}
+
+pub(crate) fn to_proto_error(err: &HiisiError) -> proto::Error {
+    let code = match err {
+        HiisiError::SqliteError(rc, _) => crate::database::error_code(*rc),
+        HiisiError::ProtocolError(_) | HiisiError::JsonParseError(_) => "PROTOCOL_ERROR",
+        HiisiError::ArgsInvalid(_) => "ARGS_INVALID",
+        HiisiError::StmtRejected(_) => "STMT_REJECTED",
+        // SQLite fails with the same code for SQL over its own limit.
+        HiisiError::SqlTooLarge(_) => "SQLITE_TOOBIG",
+        HiisiError::ResultTooLarge(_) => "RESULT_TOO_LARGE",
+        HiisiError::ReplicaBehind(..) => "REPLICA_BEHIND",
+        HiisiError::RateLimited(_) => "RATE_LIMITED",
+        _ => "INTERNAL_ERROR",
+    };
+    let message = match err {
+        // The message of SQLite is what clients show to their users, as
+        // the code is in a field of its own.
+        HiisiError::SqliteError(_, message) => message.clone(),
+        _ => err.to_string(),
+    };
+    proto::Error {
+        message,
+        code: Some(code.to_owned()),
+    }
+}
+
+/// Resolve the SQL text of a request that refers to it either inline or by a
+/// SQL id stored in the session.
+///
+/// A stored SQL text is looked up when the statement is executed, so closing
+/// a SQL id affects only the statements that come after it in the pipeline.
+/// The resolved text must fit in the `max_sql_bytes` of the session.
+fn resolve_sql<'a>(
+    session: &Session,
+    sql: &'a Option<String>,
+    sql_id: Option<i32>,
+) -> Result<Cow<'a, str>> {
+    let sql = match (sql, sql_id) {
+        (Some(sql), None) => Cow::Borrowed(sql.as_str()),
+        (None, Some(sql_id)) => session.get_sql(sql_id).map(Cow::Owned).ok_or_else(|| {
+            HiisiError::ProtocolError(format!("SQL text with id {} was not found", sql_id))
+        })?,
+        (Some(_), Some(_)) => {
+            return Err(HiisiError::ProtocolError(
+                "Received both SQL text and SQL id".to_owned(),
+            ))
+        }
+        (None, None) => {
+            return Err(HiisiError::ProtocolError(
+                "Received neither SQL text nor SQL id".to_owned(),
+            ))
+        }
+    };
+    session.check_sql_len(&sql)?;
+    Ok(sql)
+}
+
+fn execute_stmt(
+    conn: &Connection,
+    session: &Session,
+    stmt: &proto::Stmt,
+) -> Result<proto::StmtResult> {
+    let want_rows = stmt.want_rows.unwrap_or(true);
+    let result = prepare_stmt(conn, session, stmt)
+        .and_then(|stmt| make_stmt_result(conn, stmt, want_rows, session.max_result_rows));
+    if let Err(err) = &result {
+        roll_back_on_storage_error(conn, err);
+    }
+    result
+}
+
+/// Roll back the transaction of a connection after a statement failed on a
+/// full or failing storage.
+///
+/// Depending on where the write failed, SQLite has rolled back either the
+/// statement or the whole transaction. The transaction is rolled back
+/// explicitly, as SQLite recommends, so that the client does not go on with
+/// a transaction whose state depends on where the storage failed, and sees
+/// from the autocommit state of the stream that the transaction is gone.
+pub(crate) fn roll_back_on_storage_error(conn: &Connection, err: &HiisiError) {
+    let HiisiError::SqliteError(rc, _) = err else {
+        return;
+    };
+    if !is_storage_error(*rc) || conn.is_autocommit() {
+        return;
+    }
+    log::debug!("Rolling back transaction after {}", err);
+    if let Err(err) = conn.exec("ROLLBACK") {
+        log::debug!("Failed to roll back transaction: {}", err);
+    }
+}
+
+/// Execute a statement like `execute_stmt()`, and log it if it ran for at
+/// least the slow query threshold of the resource manager.
+fn execute_timed_stmt(
+    manager: &ResourceManager,
+    conn: &Connection,
+    session: &Session,
+    stmt: &proto::Stmt,
+    request_id: Option<&str>,
+) -> Result<proto::StmtResult> {
+    let Some(threshold) = manager.slow_query_threshold() else {
+        return execute_stmt(conn, session, stmt);
+    };
+    let start = manager.clock().now();
+    let result = execute_stmt(conn, session, stmt);
+    let elapsed = manager.clock().now().saturating_sub(start);
+    if elapsed >= threshold {
+        log_slow_stmt(request_id, &session.db_name, stmt, result.as_ref(), elapsed);
     }
+    result
+}
+
+/// Log a slow statement with the correlation id of its pipeline request,
+/// its SQL, the number of arguments bound to it, and the number of rows it
+/// returned.
+fn log_slow_stmt(
+    request_id: Option<&str>,
+    database: &str,
+    stmt: &proto::Stmt,
+    result: std::result::Result<&proto::StmtResult, &HiisiError>,
+    elapsed: Duration,
+) {
+    let outcome = match result {
+        Ok(result) => format!("rows={}", result.rows.len()),
+        Err(err) => format!("failed: {}", err),
+    };
+    log_slow(
+        request_id,
+        "statement",
+        database,
+        elapsed,
+        stmt.args.len() + stmt.named_args.len(),
+        &outcome,
+        &describe_sql(stmt),
+    );
+}
+
+/// Describe the SQL of a statement for the slow statement log.
+pub(crate) fn describe_sql(stmt: &proto::Stmt) -> String {
+    match (&stmt.sql, stmt.sql_id) {
+        (Some(sql), _) => format!("sql={:?}", sql),
+        (None, sql_id) => format!("sql_id={}", sql_id.unwrap_or_default()),
+    }
+}
+
+/// Log a slow statement, sequence or cursor step in the slow statement
+/// log.
+pub(crate) fn log_slow(
+    request_id: Option<&str>,
+    kind: &str,
+    database: &str,
+    elapsed: Duration,
+    args: usize,
+    outcome: &str,
+    sql: &str,
+) {
+    log::warn!(
+        target: SLOW_QUERY_TARGET,
+        "[{}] slow {} database={} duration={:?} args={} {} {}",
+        request_id.unwrap_or("-"),
+        kind,
+        database,
+        elapsed,
+        args,
+        outcome,
+        sql
+    );
+}
+
+pub(crate) fn prepare_stmt(
+    conn: &Connection,
+    session: &Session,
+    stmt: &proto::Stmt,
+) -> Result<Stmt> {
+    let sql = resolve_sql(session, &stmt.sql, stmt.sql_id)?;
+    if let Some(targets) = &session.attach_targets {
+        if first_keyword(&sql).eq_ignore_ascii_case("ATTACH") {
+            return prepare_attach(conn, targets, &sql, stmt);
+        }
+    }
+    // A rejected statement is not even prepared.
+    SessionFilter(session).check(&sql)?;
+    let prepared = conn.prepare(&sql)?;
+    let args = if stmt.named_args.is_empty() {
+        positional_args(&prepared, &stmt.args)?
+    } else if stmt.args.is_empty() {
+        named_args(&prepared, &stmt.named_args)?
+    } else {
+        return Err(HiisiError::ArgsInvalid(
+            "Statement has both positional and named arguments".to_owned(),
+        ));
+    };
+    if session.strict_args && !prepared.is_explain() {
+        check_arg_types(conn, &sql, &prepared, &args)?;
+    }
+    for (i, arg) in args.iter().enumerate() {
+        bind_value(&prepared, i as i32 + 1, arg)?;
+    }
+    Ok(prepared)
+}
+
+/// Prepare an `ATTACH` statement of a session that has attach targets, so
+/// that it attaches the database file of the target that it names rather
+/// than whatever file the client asks for.
+fn prepare_attach(
+    conn: &Connection,
+    targets: &HashMap<String, String>,
+    sql: &str,
+    stmt: &proto::Stmt,
+) -> Result<Stmt> {
+    let Some((name, schema)) = parse_attach(sql) else {
+        return Err(HiisiError::StmtRejected(
+            "ATTACH statements must have the form ATTACH DATABASE '<name>' AS <schema>".to_owned(),
+        ));
+    };
+    let Some(path) = targets.get(name) else {
+        return Err(HiisiError::StmtRejected(format!(
+            "Database {} cannot be attached",
+            name
+        )));
+    };
+    if !stmt.args.is_empty() || !stmt.named_args.is_empty() {
+        return Err(HiisiError::ArgsInvalid(
+            "ATTACH statements take no arguments".to_owned(),
+        ));
+    }
+    // The schema name is a plain identifier, so it needs no quoting.
+    let prepared = conn.prepare(&format!("ATTACH DATABASE ? AS {}", schema))?;
+    prepared.bind_text(1, path)?;
+    Ok(prepared)
+}
+
+/// The statement filter of a session, which `ATTACH` and `DETACH` bypass if
+/// the session has attach targets.
+struct SessionFilter<'a>(&'a Session);
+
+impl StmtFilter for SessionFilter<'_> {
+    fn check(&self, sql: &str) -> Result<()> {
+        let session = self.0;
+        if session.attach_targets.is_some() {
+            let keyword = first_keyword(sql);
+            if keyword.eq_ignore_ascii_case("DETACH") {
+                return Ok(());
+            }
+            // Only an `ATTACH` that is prepared on its own is rewritten to
+            // attach a target, so one in a sequence cannot be allowed.
+            if keyword.eq_ignore_ascii_case("ATTACH") {
+                return Err(HiisiError::StmtRejected(
+                    "ATTACH statements must be executed on their own".to_owned(),
+                ));
+            }
+        }
+        session.stmt_filter.check(sql)
+    }
+}
+
+/// Match positional arguments to the parameters of a prepared statement,
+/// returning the argument of every parameter in order.
+fn positional_args<'a>(stmt: &Stmt, args: &'a [proto::Value]) -> Result<Vec<&'a proto::Value>> {
+    let param_count = stmt.bind_parameter_count() as usize;
+    if args.len() != param_count {
+        return Err(HiisiError::ArgsInvalid(format!(
+            "Statement has {} parameters, but {} arguments were given",
+            param_count,
+            args.len()
+        )));
+    }
+    Ok(args.iter().collect())
+}
+
+/// Match named arguments to the parameters of a prepared statement,
+/// returning the argument of every parameter in order.
+///
+/// The name of an argument may omit the prefix character of the parameter,
+/// in which case `:name`, `@name`, and `$name` are tried in that order.
+/// Every parameter must be given an argument.
+fn named_args<'a>(stmt: &Stmt, args: &'a [proto::NamedArg]) -> Result<Vec<&'a proto::Value>> {
+    let param_count = stmt.bind_parameter_count();
+    let mut bound = vec![None; param_count as usize];
+    for arg in args {
+        let index = if arg.name.starts_with([':', '@', '$']) {
+            stmt.bind_parameter_index(&arg.name)
+        } else {
+            [':', '@', '$']
+                .iter()
+                .find_map(|prefix| stmt.bind_parameter_index(&format!("{}{}", prefix, arg.name)))
+        };
+        let index = index.ok_or_else(|| {
+            HiisiError::ArgsInvalid(format!("Statement has no parameter named {}", arg.name))
+        })?;
+        bound[index as usize - 1] = Some(&arg.value);
+    }
+    if let Some(i) = bound.iter().position(Option::is_none) {
+        let index = i as i32 + 1;
+        let name = stmt.bind_parameter_name(index).unwrap_or("?");
+        return Err(HiisiError::ArgsInvalid(format!(
+            "No argument given for parameter {} ({})",
+            index, name
+        )));
+    }
+    Ok(bound.into_iter().flatten().collect())
+}
+
+/// The kind of value that a statement uses a parameter as.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ParamUse {
+    Any,
+    Numeric,
+    Text,
+}
+
+impl ParamUse {
+    /// The use of a value that SQLite applies a type affinity to.
+    fn from_affinity(affinity: u8) -> Self {
+        match affinity {
+            b'B' => ParamUse::Text,
+            b'C' | b'D' | b'E' => ParamUse::Numeric,
+            _ => ParamUse::Any,
+        }
+    }
+
+    fn accepts(self, value: &proto::Value) -> bool {
+        match (self, value) {
+            (ParamUse::Any, _) | (_, proto::Value::None | proto::Value::Null) => true,
+            (ParamUse::Numeric, value) => matches!(
+                value,
+                proto::Value::Integer { .. } | proto::Value::Float { .. }
+            ),
+            (ParamUse::Text, value) => matches!(value, proto::Value::Text { .. }),
+        }
+    }
+}
+
+/// Check that the arguments of a statement have the types that the statement
+/// uses its parameters as.
+///
+/// SQLite does not type parameters, so the uses are read out of the bytecode
+/// of the statement: a parameter that is an operand of arithmetic must be a
+/// number, and one that is compared with, or stored in, a column must match
+/// the affinity of the column. Parameters that are used in other ways, such
+/// as the arguments of functions, are not checked.
+fn check_arg_types(
+    conn: &Connection,
+    sql: &str,
+    stmt: &Stmt,
+    args: &[&proto::Value],
+) -> Result<()> {
+    let uses = param_uses(conn, sql, args.len())?;
+    for (i, (param_use, arg)) in uses.iter().zip(args).enumerate() {
+        if param_use.accepts(arg) {
+            continue;
+        }
+        let index = i as i32 + 1;
+        let name = stmt.bind_parameter_name(index).unwrap_or("?");
+        let expected = match param_use {
+            ParamUse::Numeric => "a number",
+            _ => "text",
+        };
+        return Err(HiisiError::ArgsInvalid(format!(
+            "Argument for parameter {} ({}) is {}, but the statement uses it as {}",
+            index,
+            name,
+            value_type_name(arg),
+            expected
+        )));
+    }
+    Ok(())
+}
+
+fn value_type_name(value: &proto::Value) -> &'static str {
+    match value {
+        proto::Value::None => "missing",
+        proto::Value::Null => "null",
+        proto::Value::Integer { .. } => "an integer",
+        proto::Value::Float { .. } => "a float",
+        proto::Value::Text { .. } => "text",
+        proto::Value::Blob { .. } => "a blob",
+    }
+}
+
+/// An instruction of the bytecode of a statement, as listed by `EXPLAIN`.
+struct Insn {
+    opcode: String,
+    p1: i64,
+    p2: i64,
+    p3: i64,
+    p4: Option<String>,
+    p5: i64,
+}
+
+/// Find out how a statement uses each of its `param_count` parameters.
+///
+/// A `Variable` instruction loads a parameter into a register, and the
+/// instructions that read the register use the parameter. Only registers that
+/// no other instruction writes are followed, so that a register that SQLite
+/// reuses for another value does not get the use of that value.
+fn param_uses(conn: &Connection, sql: &str, param_count: usize) -> Result<Vec<ParamUse>> {
+    let explain = conn.prepare(&format!("EXPLAIN {}", sql))?;
+    let mut program = Vec::new();
+    while let StepResult::Row = explain.step()? {
+        let p4 = match explain.column_type(5) {
+            Type::Null => None,
+            _ => Some(explain.column_text(5).to_owned()),
+        };
+        program.push(Insn {
+            opcode: explain.column_text(1).to_owned(),
+            p1: explain.column_int(2),
+            p2: explain.column_int(3),
+            p3: explain.column_int(4),
+            p4,
+            p5: explain.column_int(6),
+        });
+    }
+    let mut params = HashMap::new();
+    let mut clobbered = HashSet::new();
+    for insn in &program {
+        if insn.opcode == "Variable" {
+            if params.insert(insn.p2, insn.p1 as usize - 1).is_some() {
+                clobbered.insert(insn.p2);
+            }
+        } else {
+            clobbered.extend(written_registers(insn));
+        }
+    }
+    params.retain(|reg, _| !clobbered.contains(reg));
+    let mut uses = vec![ParamUse::Any; param_count];
+    for insn in &program {
+        for (reg, param_use) in register_uses(insn) {
+            match params.get(&reg) {
+                Some(&param) if param < param_count && uses[param] == ParamUse::Any => {
+                    uses[param] = param_use;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(uses)
+}
+
+/// The registers that an instruction writes.
+fn written_registers(insn: &Insn) -> std::ops::Range<i64> {
+    match insn.opcode.as_str() {
+        "Integer" | "Int64" | "Real" | "String8" | "String" | "Blob" | "Copy" | "SCopy"
+        | "IntCopy" | "Rowid" | "NewRowid" => insn.p2..insn.p2 + 1,
+        "Null" => insn.p2..insn.p3.max(insn.p2) + 1,
+        "Move" => insn.p2..insn.p2 + insn.p3,
+        "Column" | "Function" | "Concat" | "Add" | "Subtract" | "Multiply" | "Divide"
+        | "Remainder" | "BitAnd" | "BitOr" | "ShiftLeft" | "ShiftRight" | "MakeRecord" => {
+            insn.p3..insn.p3 + 1
+        }
+        _ => 0..0,
+    }
+}
+
+/// The registers that an instruction reads, with how it uses their values.
+fn register_uses(insn: &Insn) -> Vec<(i64, ParamUse)> {
+    match insn.opcode.as_str() {
+        "Add" | "Subtract" | "Multiply" | "Divide" | "Remainder" | "BitAnd" | "BitOr"
+        | "ShiftLeft" | "ShiftRight" => {
+            vec![(insn.p1, ParamUse::Numeric), (insn.p2, ParamUse::Numeric)]
+        }
+        "MustBeInt" | "AddImm" => vec![(insn.p1, ParamUse::Numeric)],
+        // The low bits of P5 hold the affinity that the operands are
+        // compared with.
+        "Eq" | "Ne" | "Lt" | "Le" | "Gt" | "Ge" => {
+            let param_use = ParamUse::from_affinity((insn.p5 & 0x47) as u8);
+            vec![(insn.p1, param_use), (insn.p3, param_use)]
+        }
+        // P4 holds the affinities of the registers from P1 on.
+        "Affinity" | "MakeRecord" => match &insn.p4 {
+            Some(affinities) => (insn.p1..)
+                .zip(affinities.bytes().take(insn.p2.max(0) as usize))
+                .map(|(reg, affinity)| (reg, ParamUse::from_affinity(affinity)))
+                .collect(),
+            None => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+fn bind_value(stmt: &Stmt, index: i32, value: &proto::Value) -> Result<()> {
+    match value {
+        proto::Value::None => Err(HiisiError::ArgsInvalid(format!(
+            "Argument {} has no value",
+            index
+        ))),
+        proto::Value::Null => stmt.bind_null(index),
+        proto::Value::Integer { value } => stmt.bind_int(index, *value),
+        proto::Value::Float { value } => stmt.bind_float(index, *value),
+        proto::Value::Text { value } => stmt.bind_text(index, value),
+        proto::Value::Blob { value } => stmt.bind_blob(index, value),
+    }
+}
+
+/// Step a statement to completion and collect its result, failing once it
+/// returns more than `max_rows` rows, as the whole result is buffered in
+/// the response.
+///
+/// If the client doesn't want the rows, the statement is still stepped
+/// through every row, so that it has its full effect, but the rows are
+/// never read out of SQLite.
+fn make_stmt_result(
+    conn: &Connection,
+    stmt: Stmt,
+    want_rows: bool,
+    max_rows: Option<usize>,
+) -> Result<proto::StmtResult> {
+    let column_count = stmt.column_count();
+    let cols = make_cols(&stmt)?;
     let mut rows = Vec::new();
     loop {
         match stmt.step()? {
-            StepResult::Row => {
+            StepResult::Row if want_rows => {
+                if let Some(max_rows) = max_rows.filter(|max_rows| rows.len() >= *max_rows) {
+                    return Err(HiisiError::ResultTooLarge(max_rows));
+                }
                 let row = to_row(&stmt, column_count)?;
                 rows.push(row);
             }
+            StepResult::Row => {}
             StepResult::Done => break,
         }
     }
-    let resp = proto::ExecuteStreamResp {
-        result: proto::StmtResult {
-            cols,
-            rows,
-            affected_row_count: 0,
-            last_insert_rowid: None,
-            replication_index: None,
-            rows_read: 0,
-            rows_written: 0,
-            query_duration_ms: 0.0,
-        },
-    };
-    Ok(proto::StreamResult::Ok {
-        response: proto::StreamResponse::Execute(resp),
+    Ok(proto::StmtResult {
+        cols,
+        rows,
+        affected_row_count: affected_row_count(conn, &stmt),
+        last_insert_rowid: last_insert_rowid(conn, &stmt),
+        replication_index: None,
+        rows_read: 0,
+        rows_written: 0,
+        query_duration_ms: 0.0,
     })
 }
 
-fn to_row(stmt: &Stmt, column_count: i32) -> Result<proto::Row> {
+/// Returns the number of rows a statement that has run to completion changed.
+///
+/// `sqlite3_changes()` is not reset by statements that don't change
+/// anything, so read-only statements are reported as changing no rows.
+pub(crate) fn affected_row_count(conn: &Connection, stmt: &Stmt) -> u64 {
+    if stmt.is_readonly() {
+        0
+    } else {
+        conn.changes()
+    }
+}
+
+/// Returns the rowid of the last row that a statement that has run to
+/// completion inserted.
+///
+/// The rowid is read right after the statement has run, before the next
+/// statement on the connection overwrites it. Read-only statements insert
+/// nothing, so they have no rowid to report.
+pub(crate) fn last_insert_rowid(conn: &Connection, stmt: &Stmt) -> Option<i64> {
+    if stmt.is_readonly() {
+        None
+    } else {
+        Some(conn.last_insert_rowid())
+    }
+}
+
+pub(crate) fn make_cols(stmt: &Stmt) -> Result<Vec<proto::Col>> {
+    let column_count = stmt.column_count();
+    let mut cols = Vec::with_capacity(column_count as usize);
+    for i in 0..column_count {
+        let name = stmt
+            .column_name(i)
+            .ok_or(HiisiError::InternalError(format!(
+                "No column name found for column {}",
+                i
+            )))?;
+        let decltype = stmt.column_decltype(i);
+        let col = proto::Col {
+            name: Some(name.into()),
+            decltype: decltype.map(Into::into),
+        };
+        cols.push(col);
+    }
+    Ok(cols)
+}
+
+pub(crate) fn to_row(stmt: &Stmt, column_count: i32) -> Result<proto::Row> {
     let mut values = Vec::new();
     for i in 0..column_count {
         let value = match stmt.column_type(i) {
@@ -161,3 +1155,1343 @@ fn to_row(stmt: &Stmt, column_count: i32) -> Result<proto::Row> {
     }
     Ok(proto::Row { values })
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        describe, eval_cond, exec_execute, execute_client_req, execute_client_req_with_id,
+        execute_sequence, execute_stmt, Request, SLOW_QUERY_TARGET,
+    };
+    use crate::clock::SimClock;
+    use crate::cursor::{open_cursor, CursorRequest};
+    use crate::database::Connection;
+    use crate::filter::AllowAll;
+    use crate::manager::{ResourceManager, BATON_EXPIRY};
+    use crate::proto::{
+        Batch, BatchCond, BatchCondList, BatchStep, BatchStreamReq, CloseStreamReq, CursorReqBody,
+        Encoding, Error, ExecuteStreamReq, GetAutocommitStreamReq, PipelineReqBody,
+        SequenceStreamReq, Stmt, StmtResult, StoreSqlStreamReq, StreamRequest, StreamResponse,
+        StreamResult, Value, Version,
+    };
+    use crate::session::Session;
+    use crate::HiisiError;
+    use std::cell::RefCell;
+    use std::path::Path;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    thread_local! {
+        static SLOW_QUERY_LOG: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// A logger that keeps the slow statements logged on each thread, so
+    /// that a test sees only the statements of its own.
+    struct SlowQueryLogger;
+
+    impl log::Log for SlowQueryLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.target() == SLOW_QUERY_TARGET
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                SLOW_QUERY_LOG.with(|log| log.borrow_mut().push(record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static SLOW_QUERY_LOGGER: SlowQueryLogger = SlowQueryLogger;
+
+    fn step_ok() -> Option<StmtResult> {
+        Some(StmtResult {
+            cols: vec![],
+            rows: vec![],
+            affected_row_count: 0,
+            last_insert_rowid: None,
+            replication_index: None,
+            rows_read: 0,
+            rows_written: 0,
+            query_duration_ms: 0.0,
+        })
+    }
+
+    fn step_error() -> Option<Error> {
+        Some(Error {
+            message: "no such table: t".to_owned(),
+            code: Some("SQLITE_ERROR".to_owned()),
+        })
+    }
+
+    #[test]
+    fn eval_cond_nested() {
+        // Step 0 succeeded, step 1 failed, and step 2 was skipped.
+        let step_results = vec![step_ok(), None, None];
+        let step_errors = vec![None, step_error(), None];
+        let eval = |cond: &BatchCond| eval_cond(cond, &step_results, &step_errors, true).unwrap();
+
+        assert!(eval(&BatchCond::Ok { step: 0 }));
+        assert!(!eval(&BatchCond::Ok { step: 1 }));
+        assert!(eval(&BatchCond::Error { step: 1 }));
+        assert!(!eval(&BatchCond::Ok { step: 2 }));
+        assert!(!eval(&BatchCond::Error { step: 2 }));
+
+        let cond = BatchCond::And(BatchCondList {
+            conds: vec![
+                BatchCond::Ok { step: 0 },
+                BatchCond::Not {
+                    cond: Box::new(BatchCond::Or(BatchCondList {
+                        conds: vec![BatchCond::Ok { step: 1 }, BatchCond::Error { step: 2 }],
+                    })),
+                },
+            ],
+        });
+        assert!(eval(&cond));
+
+        let cond = BatchCond::Or(BatchCondList {
+            conds: vec![
+                BatchCond::Ok { step: 1 },
+                BatchCond::And(BatchCondList {
+                    conds: vec![BatchCond::Error { step: 1 }, BatchCond::IsAutocommit {}],
+                }),
+            ],
+        });
+        assert!(eval(&cond));
+        assert!(!eval_cond(&cond, &step_results, &step_errors, false).unwrap());
+
+        assert!(eval(&BatchCond::And(BatchCondList { conds: vec![] })));
+        assert!(!eval(&BatchCond::Or(BatchCondList { conds: vec![] })));
+    }
+
+    #[test]
+    fn eval_cond_invalid_step() {
+        let step_results = vec![step_ok()];
+        let step_errors = vec![None];
+        let eval = |cond: &BatchCond| eval_cond(cond, &step_results, &step_errors, true);
+
+        assert!(eval(&BatchCond::Ok { step: 1 }).is_err());
+        assert!(eval(&BatchCond::Error { step: 42 }).is_err());
+        // An invalid step is reported even if the result is known early.
+        let cond = BatchCond::Or(BatchCondList {
+            conds: vec![BatchCond::Ok { step: 0 }, BatchCond::Ok { step: 1 }],
+        });
+        assert!(eval(&cond).is_err());
+        assert!(eval(&BatchCond::None).is_err());
+    }
+
+    #[test]
+    fn describe_params_and_cols() {
+        let conn = Connection::open(Path::new(":memory:")).unwrap();
+        let result = describe(&conn, "SELECT ?1 AS a, :name").unwrap();
+        let params: Vec<_> = result.params.iter().map(|p| p.name.as_deref()).collect();
+        assert_eq!(params, vec![Some("?1"), Some(":name")]);
+        let cols: Vec<_> = result.cols.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(cols, vec!["a", ":name"]);
+        assert!(result.is_readonly);
+        assert!(!result.is_explain);
+
+        conn.prepare("CREATE TABLE t (x INTEGER)")
+            .unwrap()
+            .step()
+            .unwrap();
+        let result = describe(&conn, "INSERT INTO t VALUES (?)").unwrap();
+        assert_eq!(result.params.len(), 1);
+        assert!(result.params[0].name.is_none());
+        assert!(result.cols.is_empty());
+        assert!(!result.is_readonly);
+    }
+
+    #[test]
+    fn sequence_stops_at_first_error() {
+        let conn = Connection::open(Path::new(":memory:")).unwrap();
+        execute_sequence(
+            &conn,
+            &AllowAll,
+            "CREATE TABLE t (x);\n-- Seed the table\nINSERT INTO t VALUES (1); ; INSERT INTO t VALUES (2) -- no semicolon",
+        )
+        .unwrap();
+        let (index, _) = execute_sequence(
+            &conn,
+            &AllowAll,
+            "INSERT INTO t VALUES (3); INSERT INTO u VALUES (4); INSERT INTO t VALUES (5);",
+        )
+        .unwrap_err();
+        assert_eq!(index, 1);
+        let (stmt, _) = conn.prepare_with_tail("SELECT count(*) FROM t").unwrap();
+        let stmt = stmt.unwrap();
+        stmt.step().unwrap();
+        assert_eq!(stmt.column_int(0), 3);
+    }
+
+    #[test]
+    fn sequence_counts_total_changes() {
+        let conn = Connection::open(Path::new(":memory:")).unwrap();
+        let total_changes = execute_sequence(
+            &conn,
+            &AllowAll,
+            "CREATE TABLE t (x); INSERT INTO t VALUES (1);",
+        )
+        .unwrap();
+        assert_eq!(total_changes, 1);
+        let total_changes = execute_sequence(
+            &conn,
+            &AllowAll,
+            "INSERT INTO t VALUES (2); INSERT INTO t VALUES (3), (4); UPDATE t SET x = x + 1;",
+        )
+        .unwrap();
+        assert_eq!(total_changes, 7);
+    }
+
+    #[test]
+    fn get_autocommit_in_transaction() {
+        let db_path = std::env::temp_dir().join(format!("hiisi-test-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        let execute = |sql: &str| {
+            StreamRequest::Execute(ExecuteStreamReq {
+                stmt: Stmt::new(sql, false),
+            })
+        };
+        let get_autocommit = || StreamRequest::GetAutocommit(GetAutocommitStreamReq {});
+        let req = Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![
+                    get_autocommit(),
+                    execute("BEGIN"),
+                    get_autocommit(),
+                    execute("COMMIT"),
+                    get_autocommit(),
+                ],
+            },
+        };
+        let resp = execute_client_req(manager, req).unwrap();
+        let is_autocommit: Vec<_> = resp
+            .results
+            .iter()
+            .filter_map(|result| match result {
+                StreamResult::Ok {
+                    response: StreamResponse::GetAutocommit(resp),
+                } => Some(resp.is_autocommit),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(is_autocommit, vec![true, false, true]);
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn autocommit_follows_transaction_statements() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("test").unwrap();
+        let execute = |sql: &str| {
+            StreamRequest::Execute(ExecuteStreamReq {
+                stmt: Stmt::new(sql, false),
+            })
+        };
+        let get_autocommit = || StreamRequest::GetAutocommit(GetAutocommitStreamReq {});
+        let req = Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![
+                    execute("CREATE TABLE parent (id INTEGER PRIMARY KEY)"),
+                    execute(
+                        "CREATE TABLE child (parent_id REFERENCES parent (id) DEFERRABLE INITIALLY DEFERRED)",
+                    ),
+                    execute("BEGIN"),
+                    get_autocommit(),
+                    execute("INSERT INTO parent VALUES (1)"),
+                    get_autocommit(),
+                    execute("ROLLBACK"),
+                    get_autocommit(),
+                    // The deferred foreign key fails the commit, which leaves
+                    // the transaction open.
+                    execute("BEGIN"),
+                    execute("INSERT INTO child VALUES (2)"),
+                    execute("COMMIT"),
+                    get_autocommit(),
+                    execute("ROLLBACK"),
+                    get_autocommit(),
+                ],
+            },
+        };
+        let resp = execute_client_req(manager, req).unwrap();
+        assert!(matches!(resp.results[10], StreamResult::Error { .. }));
+        let is_autocommit: Vec<_> = resp
+            .results
+            .iter()
+            .filter_map(|result| match result {
+                StreamResult::Ok {
+                    response: StreamResponse::GetAutocommit(resp),
+                } => Some(resp.is_autocommit),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(is_autocommit, vec![false, false, true, false, true]);
+    }
+
+    #[test]
+    fn transaction_spans_requests() {
+        let db_path = std::env::temp_dir().join(format!("hiisi-test-txn-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        let execute = |sql: &str| {
+            StreamRequest::Execute(ExecuteStreamReq {
+                stmt: Stmt::new(sql, true),
+            })
+        };
+        let pipeline = |baton: Option<String>, requests: Vec<StreamRequest>| {
+            let req = Request {
+                database: "test".to_owned(),
+                version: Version::Hrana2,
+                req: PipelineReqBody { baton, requests },
+            };
+            execute_client_req(manager.clone(), req).unwrap()
+        };
+        let count = |result: &StreamResult| match result {
+            StreamResult::Ok {
+                response: StreamResponse::Execute(resp),
+            } => match resp.result.rows[0].values.as_slice() {
+                [Value::Integer { value }] => *value,
+                values => panic!("Unexpected values: {:?}", values),
+            },
+            result => panic!("Unexpected result: {:?}", result),
+        };
+
+        pipeline(None, vec![execute("CREATE TABLE t (x)")]);
+        let resp = pipeline(
+            None,
+            vec![execute("BEGIN"), execute("INSERT INTO t VALUES (1)")],
+        );
+        let resp = pipeline(
+            resp.baton,
+            vec![
+                StreamRequest::GetAutocommit(GetAutocommitStreamReq {}),
+                execute("SELECT count(*) FROM t"),
+            ],
+        );
+        assert!(matches!(
+            &resp.results[0],
+            StreamResult::Ok {
+                response: StreamResponse::GetAutocommit(resp),
+            } if !resp.is_autocommit
+        ));
+        assert_eq!(count(&resp.results[1]), 1);
+
+        // Closing the stream rolls back the transaction it left open.
+        pipeline(resp.baton, vec![StreamRequest::Close(CloseStreamReq {})]);
+        let resp = pipeline(None, vec![execute("SELECT count(*) FROM t")]);
+        assert_eq!(count(&resp.results[0]), 0);
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn replication_index_advances_with_writes() {
+        let db_path = std::env::temp_dir().join(format!("hiisi-test-repl-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        let session = Session::new(0, "test", Version::Hrana2);
+        let execute = |sql: &str, replication_index: Option<u64>| {
+            let mut stmt = Stmt::new(sql, true);
+            stmt.replication_index = replication_index;
+            match exec_execute(manager.clone(), &ExecuteStreamReq { stmt }, &session, None) {
+                Ok(StreamResult::Ok {
+                    response: StreamResponse::Execute(resp),
+                }) => Ok(resp.result.replication_index.unwrap()),
+                Ok(result) => panic!("Unexpected result: {:?}", result),
+                Err(err) => Err(err),
+            }
+        };
+        let created = execute("CREATE TABLE t (x)", None).unwrap();
+        let first = execute("INSERT INTO t VALUES (1)", None).unwrap();
+        let second = execute("INSERT INTO t VALUES (2)", None).unwrap();
+        assert!(created <= first && first <= second);
+        // The read sees the writes, so it does not go back in the index.
+        assert_eq!(execute("SELECT * FROM t", Some(second)).unwrap(), second);
+        assert!(matches!(
+            execute("SELECT * FROM t", Some(second + 1)),
+            Err(crate::HiisiError::ReplicaBehind(..))
+        ));
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn execute_reuses_session_connection() {
+        let db_path = std::env::temp_dir().join(format!("hiisi-test-exec-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        let session = Session::new(0, "test", Version::Hrana2);
+        let execute = |sql: &str| ExecuteStreamReq {
+            stmt: Stmt::new(sql, true),
+        };
+        // The session opens its connection with its first statement.
+        assert!(session.conn.borrow().is_none());
+        match exec_execute(manager.clone(), &execute("SELECT 1"), &session, None).unwrap() {
+            StreamResult::Ok {
+                response: StreamResponse::Execute(resp),
+            } => match resp.result.rows[0].values.as_slice() {
+                [Value::Integer { value: 1 }] => {}
+                values => panic!("Unexpected values: {:?}", values),
+            },
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        let conn = session.conn.borrow().clone().unwrap();
+
+        exec_execute(manager.clone(), &execute("BEGIN"), &session, None).unwrap();
+        let err = exec_execute(manager.clone(), &execute("SELEC 1"), &session, None).unwrap_err();
+        assert!(matches!(err, crate::HiisiError::SqliteError(..)));
+        // The transaction outlives the failed statement and the connection
+        // stays that of the session.
+        assert!(!conn.is_autocommit());
+        assert!(Rc::ptr_eq(&conn, session.conn.borrow().as_ref().unwrap()));
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn failed_request_reports_error_code() {
+        let db_path = std::env::temp_dir().join(format!("hiisi-test-error-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        let execute = |sql: &str| {
+            StreamRequest::Execute(ExecuteStreamReq {
+                stmt: Stmt::new(sql, false),
+            })
+        };
+        let req = Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![
+                    execute("CREATE TABLE t (x UNIQUE)"),
+                    execute("INSERT INTO t VALUES (1)"),
+                    execute("INSERT INTO t VALUES (1)"),
+                    execute("SELECT x FROM t"),
+                    execute("SELEC x FROM t"),
+                ],
+            },
+        };
+        let resp = execute_client_req(manager, req).unwrap();
+        assert_eq!(resp.results.len(), 5);
+        match &resp.results[2] {
+            StreamResult::Error { error } => {
+                assert_eq!(error.code.as_deref(), Some("SQLITE_CONSTRAINT_UNIQUE"));
+                // The message is the one of SQLite, which names the column.
+                assert_eq!(error.message, "UNIQUE constraint failed: t.x");
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        // The requests after the failed one still run.
+        assert!(matches!(resp.results[3], StreamResult::Ok { .. }));
+        match &resp.results[4] {
+            StreamResult::Error { error } => {
+                assert_eq!(error.code.as_deref(), Some("SQLITE_ERROR"));
+                assert_eq!(error.message, r#"near "SELEC": syntax error"#);
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn runaway_statement_is_interrupted() {
+        let db_path = std::env::temp_dir().join(format!("hiisi-test-steps-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        manager.set_step_limit("test", 10_000);
+        let execute = |sql: &str| {
+            StreamRequest::Execute(ExecuteStreamReq {
+                stmt: Stmt::new(sql, true),
+            })
+        };
+        let req = Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![
+                    execute(
+                        "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) \
+                         SELECT count(*) FROM c",
+                    ),
+                    execute("SELECT 1"),
+                ],
+            },
+        };
+        let resp = execute_client_req(manager, req).unwrap();
+        match &resp.results[0] {
+            StreamResult::Error { error } => {
+                assert_eq!(error.code.as_deref(), Some("SQLITE_INTERRUPT"))
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        // The connection runs the next statement as usual.
+        assert!(matches!(resp.results[1], StreamResult::Ok { .. }));
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn read_only_database_rejects_writes() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-test-read-only-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        let pipeline = |requests| Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests,
+            },
+        };
+        let execute = |sql: &str| {
+            StreamRequest::Execute(ExecuteStreamReq {
+                stmt: Stmt::new(sql, true),
+            })
+        };
+        execute_client_req(
+            manager.clone(),
+            pipeline(vec![execute("CREATE TABLE t (x)")]),
+        )
+        .unwrap();
+
+        manager.set_read_only("test", true);
+        let step = |condition, sql: &str| BatchStep {
+            condition,
+            stmt: Stmt::new(sql, true),
+        };
+        let batch = StreamRequest::Batch(BatchStreamReq {
+            batch: Batch {
+                steps: vec![
+                    step(None, "INSERT INTO t VALUES (1)"),
+                    step(Some(BatchCond::Ok { step: 0 }), "SELECT 1"),
+                    step(Some(BatchCond::Error { step: 0 }), "SELECT 2"),
+                ],
+                replication_index: None,
+            },
+        });
+        let resp = execute_client_req(
+            manager,
+            pipeline(vec![
+                execute("INSERT INTO t VALUES (1)"),
+                execute("SELECT x FROM t"),
+                batch,
+            ]),
+        )
+        .unwrap();
+        match &resp.results[0] {
+            StreamResult::Error { error } => {
+                assert_eq!(error.code.as_deref(), Some("SQLITE_READONLY"))
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(matches!(resp.results[1], StreamResult::Ok { .. }));
+        match &resp.results[2] {
+            StreamResult::Ok {
+                response: StreamResponse::Batch(resp),
+            } => {
+                let result = &resp.result;
+                assert_eq!(
+                    result.step_errors[0].as_ref().unwrap().code.as_deref(),
+                    Some("SQLITE_READONLY")
+                );
+                // The step that depends on the write is skipped.
+                assert!(result.step_results[1].is_none());
+                assert!(result.step_results[2].is_some());
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn batch_steps_report_their_own_rowids() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("test").unwrap();
+        let step = |sql: &str| BatchStep {
+            condition: None,
+            stmt: Stmt::new(sql, false),
+        };
+        let batch = StreamRequest::Batch(BatchStreamReq {
+            batch: Batch {
+                steps: vec![
+                    step("CREATE TABLE t (x)"),
+                    step("INSERT INTO t VALUES ('a')"),
+                    step("INSERT INTO t VALUES ('b')"),
+                    step("SELECT x FROM t"),
+                ],
+                replication_index: None,
+            },
+        });
+        let req = Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![batch],
+            },
+        };
+        let resp = execute_client_req(manager, req).unwrap();
+        let rowids: Vec<_> = match &resp.results[0] {
+            StreamResult::Ok {
+                response: StreamResponse::Batch(resp),
+            } => resp
+                .result
+                .step_results
+                .iter()
+                .map(|result| result.as_ref().unwrap().last_insert_rowid)
+                .collect(),
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        assert_eq!(rowids[1..], [Some(1), Some(2), None]);
+    }
+
+    #[test]
+    fn zero_threshold_logs_every_statement() {
+        // Another test may have installed the logger already.
+        let _ = log::set_logger(&SLOW_QUERY_LOGGER);
+        let max_level = log::max_level();
+        log::set_max_level(log::LevelFilter::Warn);
+        let manager = Rc::new(
+            ResourceManager::new_in_memory([0; 32]).with_slow_query_threshold(Duration::ZERO),
+        );
+        manager.create_database("test").unwrap();
+        let mut insert = Stmt::new("INSERT INTO t VALUES (?)", false);
+        insert.args = vec![Value::Integer { value: 1 }];
+        let batch = StreamRequest::Batch(BatchStreamReq {
+            batch: Batch {
+                steps: vec![
+                    BatchStep {
+                        condition: None,
+                        stmt: insert,
+                    },
+                    BatchStep {
+                        condition: None,
+                        stmt: Stmt::new("SELECT x FROM t", true),
+                    },
+                ],
+                replication_index: None,
+            },
+        });
+        let req = Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![
+                    StreamRequest::Execute(ExecuteStreamReq {
+                        stmt: Stmt::new("CREATE TABLE t (x)", false),
+                    }),
+                    batch,
+                    StreamRequest::Execute(ExecuteStreamReq {
+                        stmt: Stmt::new("SELECT * FROM missing", true),
+                    }),
+                    StreamRequest::Sequence(SequenceStreamReq {
+                        sql: Some("INSERT INTO t VALUES (2); INSERT INTO t VALUES (3)".to_owned()),
+                        sql_id: None,
+                        replication_index: None,
+                    }),
+                ],
+            },
+        };
+        SLOW_QUERY_LOG.with(|log| log.borrow_mut().clear());
+        execute_client_req_with_id(manager.clone(), req, Some("0123456789abcdef")).unwrap();
+        let cursor_req = CursorRequest {
+            database: "test".to_owned(),
+            version: Version::Hrana3,
+            req: CursorReqBody {
+                baton: None,
+                batch: Batch::from_iter([Stmt::new("SELECT x FROM t", true)]),
+            },
+        };
+        let (mut cursor, _) =
+            open_cursor(manager.clone(), cursor_req, Some("fedcba9876543210")).unwrap();
+        while cursor.next_entry().is_some() {}
+        let lines = SLOW_QUERY_LOG.with(|log| log.take());
+        log::set_max_level(max_level);
+        assert_eq!(lines.len(), 6, "{:?}", lines);
+        for line in &lines[..4] {
+            assert!(
+                line.starts_with("[0123456789abcdef] slow statement database=test duration="),
+                "{}",
+                line
+            );
+        }
+        assert!(lines[0].ends_with(r#" args=0 rows=0 sql="CREATE TABLE t (x)""#));
+        assert!(lines[1].ends_with(r#" args=1 rows=0 sql="INSERT INTO t VALUES (?)""#));
+        assert!(lines[2].ends_with(r#" args=0 rows=1 sql="SELECT x FROM t""#));
+        assert!(lines[3].contains(" args=0 failed: "), "{}", lines[3]);
+        assert!(
+            lines[4].starts_with("[0123456789abcdef] slow sequence database=test duration="),
+            "{}",
+            lines[4]
+        );
+        assert!(lines[4].contains(" args=0 changes=2 sql="), "{}", lines[4]);
+        assert!(
+            lines[5].starts_with("[fedcba9876543210] slow cursor step database=test duration="),
+            "{}",
+            lines[5]
+        );
+        assert!(lines[5].ends_with(r#" args=0 rows=3 sql="SELECT x FROM t""#));
+    }
+
+    #[test]
+    fn attach_is_rejected() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("test").unwrap();
+        let execute = |sql: &str| {
+            StreamRequest::Execute(ExecuteStreamReq {
+                stmt: Stmt::new(sql, true),
+            })
+        };
+        let req = Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![
+                    execute("ATTACH DATABASE ':memory:' AS other"),
+                    execute("SELECT 1"),
+                ],
+            },
+        };
+        let resp = execute_client_req(manager.clone(), req).unwrap();
+        match &resp.results[0] {
+            StreamResult::Error { error } => {
+                assert_eq!(error.code.as_deref(), Some("STMT_REJECTED"))
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(matches!(resp.results[1], StreamResult::Ok { .. }));
+    }
+
+    #[test]
+    fn attached_targets_can_be_joined() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        for db_name in ["app", "users", "orders", "secret"] {
+            manager.create_database(db_name).unwrap();
+        }
+        manager.set_attach_targets("app", &["users", "orders"]);
+        let execute = |sql: &str| {
+            StreamRequest::Execute(ExecuteStreamReq {
+                stmt: Stmt::new(sql, true),
+            })
+        };
+        let pipeline = |database: &str, requests: Vec<StreamRequest>| Request {
+            database: database.to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests,
+            },
+        };
+        let close = || StreamRequest::Close(CloseStreamReq {});
+        for (db_name, sql) in [
+            ("users", "CREATE TABLE users (id INTEGER, name TEXT)"),
+            ("users", "INSERT INTO users VALUES (1, 'alice'), (2, 'bob')"),
+            ("orders", "CREATE TABLE orders (user_id INTEGER, item TEXT)"),
+            ("orders", "INSERT INTO orders VALUES (2, 'book')"),
+        ] {
+            let resp = execute_client_req(
+                manager.clone(),
+                pipeline(db_name, vec![execute(sql), close()]),
+            )
+            .unwrap();
+            assert!(matches!(resp.results[0], StreamResult::Ok { .. }));
+        }
+        let resp = execute_client_req(
+            manager.clone(),
+            pipeline(
+                "app",
+                vec![
+                    execute("ATTACH 'users' AS u"),
+                    execute("ATTACH DATABASE 'orders' AS o"),
+                    execute(
+                        "SELECT name, item FROM u.users JOIN o.orders ON users.id = orders.user_id",
+                    ),
+                    execute("DETACH DATABASE o"),
+                    execute("SELECT count(*) FROM o.orders"),
+                    close(),
+                ],
+            ),
+        )
+        .unwrap();
+        for result in &resp.results[..2] {
+            assert!(matches!(result, StreamResult::Ok { .. }), "{:?}", result);
+        }
+        match &resp.results[2] {
+            StreamResult::Ok {
+                response: StreamResponse::Execute(resp),
+            } => {
+                assert_eq!(resp.result.rows.len(), 1);
+                match resp.result.rows[0].values.as_slice() {
+                    [Value::Text { value: name }, Value::Text { value: item }] => {
+                        assert_eq!((&**name, &**item), ("bob", "book"))
+                    }
+                    values => panic!("Unexpected values: {:?}", values),
+                }
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(matches!(resp.results[3], StreamResult::Ok { .. }));
+        assert!(matches!(resp.results[4], StreamResult::Error { .. }));
+        // The connection goes back to the pool with nothing attached.
+        let resp = execute_client_req(
+            manager.clone(),
+            pipeline(
+                "app",
+                vec![execute("SELECT count(*) FROM u.users"), close()],
+            ),
+        )
+        .unwrap();
+        match &resp.results[0] {
+            StreamResult::Error { error } => {
+                assert_eq!(error.code.as_deref(), Some("SQLITE_ERROR"));
+                assert_eq!(error.message, "no such table: u.users");
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn attaching_other_names_is_rejected() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        for db_name in ["app", "users", "secret"] {
+            manager.create_database(db_name).unwrap();
+        }
+        manager.set_attach_targets("app", &["users"]);
+        let execute = |sql: &str| {
+            StreamRequest::Execute(ExecuteStreamReq {
+                stmt: Stmt::new(sql, true),
+            })
+        };
+        let req = Request {
+            database: "app".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![
+                    execute("ATTACH DATABASE 'secret' AS s"),
+                    execute("ATTACH DATABASE ':memory:' AS m"),
+                    execute("ATTACH DATABASE 'users' || '' AS u"),
+                    StreamRequest::Sequence(SequenceStreamReq {
+                        sql: Some("ATTACH DATABASE 'users' AS u".to_owned()),
+                        sql_id: None,
+                        replication_index: None,
+                    }),
+                ],
+            },
+        };
+        let resp = execute_client_req(manager, req).unwrap();
+        for result in &resp.results {
+            match result {
+                StreamResult::Error { error } => {
+                    assert_eq!(error.code.as_deref(), Some("STMT_REJECTED"))
+                }
+                result => panic!("Unexpected result: {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_sql_is_rejected() {
+        let max_sql_bytes = 1024 * 1024;
+        let manager =
+            Rc::new(ResourceManager::new_in_memory([0; 32]).with_max_sql_bytes(max_sql_bytes));
+        manager.create_database("test").unwrap();
+        let huge_sql = format!("SELECT 1{}", " ".repeat(2 * 1024 * 1024));
+        let req = Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![
+                    StreamRequest::Execute(ExecuteStreamReq {
+                        stmt: Stmt::new(&huge_sql, true),
+                    }),
+                    StreamRequest::Sequence(SequenceStreamReq {
+                        sql: Some(huge_sql.clone()),
+                        sql_id: None,
+                        replication_index: None,
+                    }),
+                    StreamRequest::StoreSql(StoreSqlStreamReq {
+                        sql_id: 1,
+                        sql: huge_sql.clone(),
+                    }),
+                    StreamRequest::Execute(ExecuteStreamReq {
+                        stmt: Stmt::new("SELECT 1", true),
+                    }),
+                ],
+            },
+        };
+        let resp = execute_client_req(manager.clone(), req).unwrap();
+        for result in &resp.results[..3] {
+            match result {
+                StreamResult::Error { error } => {
+                    assert_eq!(error.code.as_deref(), Some("SQLITE_TOOBIG"));
+                    assert!(error.message.contains(&max_sql_bytes.to_string()));
+                }
+                result => panic!("Unexpected result: {:?}", result),
+            }
+        }
+        assert!(matches!(resp.results[3], StreamResult::Ok { .. }));
+    }
+
+    #[test]
+    fn full_storage_rolls_back_transaction() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("test").unwrap();
+        let stmt = |sql: &str| Stmt::new(sql, true);
+        let execute = |sql: &str| StreamRequest::Execute(ExecuteStreamReq { stmt: stmt(sql) });
+        let pipeline = |requests| Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests,
+            },
+        };
+        let insert_blob = "INSERT INTO t VALUES (zeroblob(1000000))";
+        let batch = Batch {
+            steps: vec![
+                BatchStep {
+                    condition: None,
+                    stmt: stmt("BEGIN"),
+                },
+                BatchStep {
+                    condition: Some(BatchCond::Ok { step: 0 }),
+                    stmt: stmt(insert_blob),
+                },
+                BatchStep {
+                    condition: Some(BatchCond::Ok { step: 1 }),
+                    stmt: stmt("COMMIT"),
+                },
+                BatchStep {
+                    condition: Some(BatchCond::And(BatchCondList {
+                        conds: vec![
+                            BatchCond::Not {
+                                cond: Box::new(BatchCond::Ok { step: 2 }),
+                            },
+                            BatchCond::Not {
+                                cond: Box::new(BatchCond::IsAutocommit {}),
+                            },
+                        ],
+                    })),
+                    stmt: stmt("ROLLBACK"),
+                },
+            ],
+            replication_index: None,
+        };
+        let resp = execute_client_req(
+            manager.clone(),
+            pipeline(vec![
+                execute("CREATE TABLE t (x BLOB)"),
+                execute("BEGIN"),
+                execute("INSERT INTO t VALUES (1)"),
+                // SQLite caps the size of the database at its current size,
+                // so the next write that grows it fails like on a full disk.
+                execute("PRAGMA max_page_count = 1"),
+                execute(insert_blob),
+                StreamRequest::GetAutocommit(GetAutocommitStreamReq {}),
+                StreamRequest::Batch(BatchStreamReq { batch }),
+                execute("SELECT count(*) FROM t"),
+                StreamRequest::Close(CloseStreamReq {}),
+            ]),
+        )
+        .unwrap();
+        for result in &resp.results[..4] {
+            assert!(matches!(result, StreamResult::Ok { .. }), "{:?}", result);
+        }
+        match &resp.results[4] {
+            StreamResult::Error { error } => assert_eq!(error.code.as_deref(), Some("SQLITE_FULL")),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        match &resp.results[5] {
+            StreamResult::Ok {
+                response: StreamResponse::GetAutocommit(resp),
+            } => assert!(resp.is_autocommit),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        // The failed insert is reported for its step, and the steps that
+        // depend on it are skipped.
+        match &resp.results[6] {
+            StreamResult::Ok {
+                response: StreamResponse::Batch(resp),
+            } => {
+                let ran: Vec<_> = resp
+                    .result
+                    .step_results
+                    .iter()
+                    .map(Option::is_some)
+                    .collect();
+                assert_eq!(ran, [true, false, false, false]);
+                let codes: Vec<_> = resp
+                    .result
+                    .step_errors
+                    .iter()
+                    .map(|error| error.as_ref().and_then(|error| error.code.as_deref()))
+                    .collect();
+                assert_eq!(codes, [None, Some("SQLITE_FULL"), None, None]);
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        // The row inserted before the failure was rolled back too.
+        match &resp.results[7] {
+            StreamResult::Ok {
+                response: StreamResponse::Execute(resp),
+            } => match resp.result.rows[0].values.as_slice() {
+                [Value::Integer { value: 0 }] => {}
+                values => panic!("Unexpected values: {:?}", values),
+            },
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        // The next session on the connection can write again.
+        let resp = execute_client_req(
+            manager,
+            pipeline(vec![
+                execute(insert_blob),
+                StreamRequest::Close(CloseStreamReq {}),
+            ]),
+        )
+        .unwrap();
+        assert!(
+            matches!(resp.results[0], StreamResult::Ok { .. }),
+            "{:?}",
+            resp.results[0]
+        );
+    }
+
+    #[test]
+    fn oversized_result_is_rejected() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]).with_max_result_rows(100));
+        manager.create_database("test").unwrap();
+        manager.set_max_result_rows("test", 10);
+        let series = |n: usize| {
+            StreamRequest::Execute(ExecuteStreamReq {
+                stmt: Stmt::new(
+                    format!(
+                        "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) \
+                         SELECT x FROM c LIMIT {}",
+                        n
+                    ),
+                    true,
+                ),
+            })
+        };
+        let req = Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![series(10), series(11)],
+            },
+        };
+        let resp = execute_client_req(manager, req).unwrap();
+        match &resp.results[0] {
+            StreamResult::Ok {
+                response: StreamResponse::Execute(resp),
+            } => assert_eq!(resp.result.rows.len(), 10),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        // The limit of the database overrides the limit of the manager.
+        match &resp.results[1] {
+            StreamResult::Error { error } => {
+                assert_eq!(error.code.as_deref(), Some("RESULT_TOO_LARGE"));
+                assert!(error.message.contains("cursor"), "{}", error.message);
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn empty_pipeline_rotates_baton() {
+        let clock = Rc::new(SimClock::new());
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]).with_clock(clock.clone()));
+        manager.create_database("test").unwrap();
+        let ping = |baton: Option<String>| {
+            execute_client_req(
+                manager.clone(),
+                Request {
+                    database: "test".to_owned(),
+                    version: Version::Hrana2,
+                    req: PipelineReqBody {
+                        baton,
+                        requests: vec![],
+                    },
+                },
+            )
+        };
+        let first = ping(None).unwrap().baton.unwrap();
+        let second = ping(Some(first.clone())).unwrap().baton.unwrap();
+        let third = ping(Some(second.clone())).unwrap().baton.unwrap();
+        assert_ne!(first, second);
+        assert_ne!(second, third);
+        let session = manager.get_session(&third).unwrap();
+        assert!(session.conn.borrow().is_none());
+        // A rotated baton is no longer valid.
+        assert!(matches!(ping(Some(second)), Err(HiisiError::StreamExpired)));
+
+        clock.advance(BATON_EXPIRY + Duration::from_secs(1));
+        assert!(matches!(ping(Some(third)), Err(HiisiError::StreamExpired)));
+    }
+
+    #[test]
+    fn batch_violating_foreign_key_fails() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("test").unwrap();
+        let batch = |sqls: &[&str]| Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![StreamRequest::Batch(BatchStreamReq {
+                    batch: Batch {
+                        steps: sqls
+                            .iter()
+                            .map(|sql| BatchStep {
+                                condition: None,
+                                stmt: Stmt::new(*sql, false),
+                            })
+                            .collect(),
+                        replication_index: None,
+                    },
+                })],
+            },
+        };
+        let step_errors = |resp: crate::proto::PipelineRespBody| match &resp.results[0] {
+            StreamResult::Ok {
+                response: StreamResponse::Batch(resp),
+            } => resp
+                .result
+                .step_errors
+                .iter()
+                .map(|error| error.as_ref().and_then(|error| error.code.clone()))
+                .collect::<Vec<_>>(),
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        let resp = execute_client_req(
+            manager.clone(),
+            batch(&[
+                "CREATE TABLE parent (id INTEGER PRIMARY KEY)",
+                "CREATE TABLE child (parent_id INTEGER REFERENCES parent (id))",
+                "INSERT INTO child VALUES (1)",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            step_errors(resp),
+            vec![None, None, Some("SQLITE_CONSTRAINT_FOREIGNKEY".to_owned())]
+        );
+
+        // The pooled connections are replaced by ones that do not enforce
+        // the constraint.
+        manager.set_foreign_keys("test", false);
+        let resp = execute_client_req(manager, batch(&["INSERT INTO child VALUES (1)"])).unwrap();
+        assert_eq!(step_errors(resp), vec![None]);
+    }
+
+    #[test]
+    fn blob_columns_are_base64() {
+        let conn = Connection::open(Path::new(":memory:")).unwrap();
+        let session = Session::new(0, "test", Version::Hrana2);
+        let sql = "CREATE TABLE t (b BLOB); INSERT INTO t VALUES (x'010203'), (x''), (NULL);";
+        execute_sequence(&conn, &AllowAll, sql).unwrap();
+        let stmt = Stmt::new("SELECT b FROM t ORDER BY rowid", true);
+        let result = execute_stmt(&conn, &session, &stmt).unwrap();
+        let values = result
+            .rows
+            .iter()
+            .map(|row| serde_json::to_string(&row.values[0]).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            [
+                r#"{"type":"blob","base64":"AQID"}"#,
+                r#"{"type":"blob","base64":""}"#,
+                r#"{"type":"null"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn integer_extremes_round_trip_as_strings() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("test").unwrap();
+        let msg = br#"{"baton":null,"requests":[{"type":"execute","stmt":{
+            "sql":"SELECT ?, ?",
+            "args":[
+                {"type":"integer","value":"9223372036854775807"},
+                {"type":"integer","value":"-9223372036854775808"}
+            ]
+        }}]}"#;
+        let req = crate::proto::parse_client_req(msg, Encoding::Json).unwrap();
+        let resp = execute_client_req(
+            manager,
+            Request {
+                database: "test".to_owned(),
+                version: Version::Hrana2,
+                req,
+            },
+        )
+        .unwrap();
+        let resp = crate::proto::format_resp(&resp, Encoding::Json).unwrap();
+        let resp = std::str::from_utf8(&resp).unwrap();
+        assert!(
+            resp.contains(
+                r#"[{"type":"integer","value":"9223372036854775807"},{"type":"integer","value":"-9223372036854775808"}]"#
+            ),
+            "{}",
+            resp
+        );
+    }
+
+    #[test]
+    fn bind_positional_args() {
+        let conn = Connection::open(Path::new(":memory:")).unwrap();
+        let session = Session::new(0, "test", Version::Hrana2);
+        let mut stmt = Stmt::new("SELECT ?1, ?2, ?3, ?4, ?5", true);
+        stmt.bind(Value::Null);
+        stmt.bind(Value::Integer { value: -42 });
+        stmt.bind(Value::Float { value: 0.5 });
+        stmt.bind(Value::Text {
+            value: "hiisi".into(),
+        });
+        stmt.bind(Value::Blob {
+            value: vec![0, 1, 2].into(),
+        });
+        let result = execute_stmt(&conn, &session, &stmt).unwrap();
+        assert_eq!(result.rows.len(), 1);
+        match result.rows[0].values.as_slice() {
+            [Value::Null, Value::Integer { value: -42 }, Value::Float { value: f }, Value::Text { value: text }, Value::Blob { value: blob }] =>
+            {
+                assert_eq!(*f, 0.5);
+                assert_eq!(&**text, "hiisi");
+                assert_eq!(&blob[..], &[0, 1, 2]);
+            }
+            values => panic!("Unexpected values: {:?}", values),
+        }
+
+        stmt.args.pop();
+        let err = execute_stmt(&conn, &session, &stmt).unwrap_err();
+        assert!(matches!(err, crate::HiisiError::ArgsInvalid(_)));
+    }
+
+    #[test]
+    fn bind_named_args() {
+        let conn = Connection::open(Path::new(":memory:")).unwrap();
+        let session = Session::new(0, "test", Version::Hrana2);
+        let mut stmt = Stmt::new("SELECT :a, @b", true);
+        stmt.bind_named(":a".to_owned(), Value::Integer { value: 1 });
+        let err = execute_stmt(&conn, &session, &stmt).unwrap_err();
+        assert!(matches!(err, crate::HiisiError::ArgsInvalid(_)));
+
+        stmt.bind_named("b".to_owned(), Value::Integer { value: 2 });
+        let result = execute_stmt(&conn, &session, &stmt).unwrap();
+        match result.rows[0].values.as_slice() {
+            [Value::Integer { value: 1 }, Value::Integer { value: 2 }] => {}
+            values => panic!("Unexpected values: {:?}", values),
+        }
+
+        stmt.bind_named("c".to_owned(), Value::Null);
+        let err = execute_stmt(&conn, &session, &stmt).unwrap_err();
+        assert!(matches!(err, crate::HiisiError::ArgsInvalid(_)));
+
+        stmt.named_args.pop();
+        stmt.bind(Value::Null);
+        let err = execute_stmt(&conn, &session, &stmt).unwrap_err();
+        assert!(matches!(err, crate::HiisiError::ArgsInvalid(_)));
+    }
+
+    #[test]
+    fn strict_args_reject_mistyped_blob() {
+        let conn = Connection::open(Path::new(":memory:")).unwrap();
+        let mut session = Session::new(0, "test", Version::Hrana2);
+        let blob = Value::Blob {
+            value: vec![1].into(),
+        };
+        let mut stmt = Stmt::new("SELECT :n + 1", true);
+        stmt.bind_named("n".to_owned(), blob.clone());
+        // SQLite's dynamic typing converts the blob by default.
+        execute_stmt(&conn, &session, &stmt).unwrap();
+
+        session.strict_args = true;
+        match execute_stmt(&conn, &session, &stmt) {
+            Err(crate::HiisiError::ArgsInvalid(msg)) => assert!(msg.contains(":n"), "{}", msg),
+            result => panic!("Unexpected result: {:?}", result.map(|_| ())),
+        }
+        let mut stmt = Stmt::new("SELECT ? + 1", true);
+        stmt.bind(blob);
+        assert!(matches!(
+            execute_stmt(&conn, &session, &stmt),
+            Err(crate::HiisiError::ArgsInvalid(_))
+        ));
+        let mut stmt = Stmt::new("SELECT ? + 1, length(?)", true);
+        stmt.bind(Value::Integer { value: 1 });
+        stmt.bind(Value::Blob {
+            value: vec![1].into(),
+        });
+        execute_stmt(&conn, &session, &stmt).unwrap();
+    }
+
+    #[test]
+    fn insert_returning_result() {
+        let conn = Connection::open(Path::new(":memory:")).unwrap();
+        let session = Session::new(0, "test", Version::Hrana2);
+        let create = Stmt::new("CREATE TABLE t (id INTEGER PRIMARY KEY, x TEXT)", false);
+        execute_stmt(&conn, &session, &create).unwrap();
+        let insert = Stmt::new(
+            "INSERT INTO t (x) VALUES ('a'), ('b') RETURNING id, x",
+            true,
+        );
+        let result = execute_stmt(&conn, &session, &insert).unwrap();
+        let cols: Vec<_> = result.cols.iter().map(|c| c.name.as_deref()).collect();
+        assert_eq!(cols, vec![Some("id"), Some("x")]);
+        assert_eq!(result.rows.len(), 2);
+        assert_eq!(result.affected_row_count, 2);
+        assert_eq!(result.last_insert_rowid, Some(2));
+
+        let select = Stmt::new("SELECT * FROM t", false);
+        let result = execute_stmt(&conn, &session, &select).unwrap();
+        assert!(result.rows.is_empty());
+        assert_eq!(result.affected_row_count, 0);
+        assert_eq!(result.last_insert_rowid, None);
+        let decltypes: Vec<_> = result.cols.iter().map(|c| c.decltype.as_deref()).collect();
+        assert_eq!(decltypes, vec![Some("INTEGER"), Some("TEXT")]);
+    }
+
+    #[test]
+    fn want_rows_false_skips_rows() {
+        let conn = Connection::open(Path::new(":memory:")).unwrap();
+        let session = Session::new(0, "test", Version::Hrana2);
+        let create = Stmt::new("CREATE TABLE t (id INTEGER PRIMARY KEY, x INTEGER)", false);
+        execute_stmt(&conn, &session, &create).unwrap();
+        let insert = Stmt::new("INSERT INTO t (x) VALUES (1), (2), (3)", false);
+        execute_stmt(&conn, &session, &insert).unwrap();
+
+        let update = "UPDATE t SET x = x + 1 RETURNING id, x";
+        let with_rows = execute_stmt(&conn, &session, &Stmt::new(update, true)).unwrap();
+        let without_rows = execute_stmt(&conn, &session, &Stmt::new(update, false)).unwrap();
+        assert_eq!(with_rows.rows.len(), 3);
+        assert!(without_rows.rows.is_empty());
+        assert_eq!(with_rows.affected_row_count, 3);
+        assert_eq!(without_rows.affected_row_count, 3);
+        let names = |r: &StmtResult| -> Vec<Option<String>> {
+            r.cols.iter().map(|c| c.name.clone()).collect()
+        };
+        assert_eq!(names(&with_rows), names(&without_rows));
+        assert_eq!(names(&without_rows).len(), 2);
+
+        // The update without rows still ran to completion.
+        let select = Stmt::new("SELECT sum(x) FROM t", true);
+        let result = execute_stmt(&conn, &session, &select).unwrap();
+        assert!(matches!(
+            result.rows[0].values[0],
+            Value::Integer { value: 12 }
+        ));
+    }
+}
@@ -0,0 +1,162 @@
+//! Filters that decide which statements clients may execute.
+
+use crate::{HiisiError, Result};
+
+/// A check that every statement of a client passes before it is executed.
+///
+/// The filter sees the SQL text of a statement before its arguments are
+/// bound, and rejects the statement by failing with
+/// `HiisiError::StmtRejected`, which the client receives as the error of the
+/// statement.
+pub trait StmtFilter {
+    fn check(&self, sql: &str) -> Result<()>;
+}
+
+/// A filter that rejects the statements that start with one of a set of
+/// keywords, such as `PRAGMA` or `VACUUM`.
+///
+/// The default filter rejects `ATTACH` and `DETACH`, so that clients cannot
+/// reach databases other than the one they are connected to.
+pub struct KeywordFilter {
+    keywords: Vec<String>,
+}
+
+impl KeywordFilter {
+    pub fn new(keywords: &[&str]) -> Self {
+        Self {
+            keywords: keywords.iter().map(|kw| kw.to_ascii_uppercase()).collect(),
+        }
+    }
+}
+
+impl Default for KeywordFilter {
+    fn default() -> Self {
+        Self::new(&["ATTACH", "DETACH"])
+    }
+}
+
+impl StmtFilter for KeywordFilter {
+    fn check(&self, sql: &str) -> Result<()> {
+        let keyword = first_keyword(sql).to_ascii_uppercase();
+        if self.keywords.contains(&keyword) {
+            return Err(HiisiError::StmtRejected(format!(
+                "{} statements are not allowed",
+                keyword
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A filter that allows every statement.
+pub struct AllowAll;
+
+impl StmtFilter for AllowAll {
+    fn check(&self, _sql: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Return the keyword that a statement starts with, after any whitespace
+/// and comments, or an empty string if it starts with something else.
+pub(crate) fn first_keyword(sql: &str) -> &str {
+    split_keyword(sql).0
+}
+
+/// Parse an `ATTACH [DATABASE] '<name>' AS <schema>` statement into the
+/// name of the database that it attaches and the schema name that it
+/// attaches the database as.
+///
+/// Returns `None` for any other form of `ATTACH`, such as one that attaches
+/// an expression or a parameter, or one that is followed by another
+/// statement.
+pub(crate) fn parse_attach(sql: &str) -> Option<(&str, &str)> {
+    let (keyword, rest) = split_keyword(sql);
+    if !keyword.eq_ignore_ascii_case("ATTACH") {
+        return None;
+    }
+    let rest = match split_keyword(rest) {
+        (keyword, after) if keyword.eq_ignore_ascii_case("DATABASE") => after,
+        _ => rest,
+    };
+    let (name, rest) = skip_comments(rest).strip_prefix('\'')?.split_once('\'')?;
+    // A quote in the name would be escaped as two quotes.
+    if rest.starts_with('\'') {
+        return None;
+    }
+    let (keyword, rest) = split_keyword(rest);
+    if !keyword.eq_ignore_ascii_case("AS") {
+        return None;
+    }
+    let rest = skip_comments(rest);
+    let end = rest
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(rest.len());
+    let (schema, rest) = rest.split_at(end);
+    if schema.is_empty() || schema.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let rest = skip_comments(rest);
+    let rest = rest.strip_prefix(';').map_or(rest, skip_comments);
+    rest.is_empty().then_some((name, schema))
+}
+
+/// Split the keyword that a SQL text starts with, after any whitespace and
+/// comments, from the rest of the text.
+fn split_keyword(sql: &str) -> (&str, &str) {
+    let rest = skip_comments(sql);
+    let end = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    rest.split_at(end)
+}
+
+/// Skip the whitespace and comments that a SQL text starts with.
+fn skip_comments(sql: &str) -> &str {
+    let mut rest = sql;
+    loop {
+        rest = rest.trim_start();
+        if let Some(comment) = rest.strip_prefix("--") {
+            rest = comment.split_once('\n').map_or("", |(_, rest)| rest);
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.split_once("*/").map_or("", |(_, rest)| rest);
+        } else {
+            return rest;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_attach, KeywordFilter, StmtFilter};
+
+    #[test]
+    fn keywords_are_found_after_comments() {
+        let filter = KeywordFilter::default();
+        assert!(filter.check("SELECT 'attach'").is_ok());
+        assert!(filter.check("attach ':memory:' AS other").is_err());
+        assert!(filter
+            .check("-- a comment\n /* another */ DETACH other")
+            .is_err());
+        assert!(KeywordFilter::new(&["pragma"])
+            .check("PRAGMA foreign_keys")
+            .is_err());
+    }
+
+    #[test]
+    fn attach_of_a_name_is_parsed() {
+        assert_eq!(
+            parse_attach("ATTACH DATABASE 'other' AS o"),
+            Some(("other", "o"))
+        );
+        assert_eq!(
+            parse_attach("/* c */ attach 'other-db'\nas other_db;\n"),
+            Some(("other-db", "other_db"))
+        );
+        assert_eq!(parse_attach("ATTACH ? AS o"), None);
+        assert_eq!(parse_attach("ATTACH 'a' || 'b' AS o"), None);
+        assert_eq!(parse_attach("ATTACH 'it''s' AS o"), None);
+        assert_eq!(parse_attach("ATTACH 'other' AS o; DROP TABLE t"), None);
+        assert_eq!(parse_attach("DETACH o"), None);
+    }
+}
@@ -1,11 +1,71 @@
 use bytes::{Bytes, BytesMut};
 
-pub use http::StatusCode;
+pub use http::{header, StatusCode};
 
-pub fn format_response(body: Bytes, status: http::StatusCode) -> Bytes {
-    let n = body.len();
+/// The content type of plain text bodies, such as error messages.
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
 
-    let response = http::Response::builder().status(status).body(body).unwrap();
+/// A builder of a response with the headers that every response carries:
+/// `Content-Type`, `Content-Length` and a `Connection` header that tells the
+/// client whether the connection stays open after the response.
+///
+/// The body is plain text and the connection is kept alive unless set
+/// otherwise.
+pub struct ResponseBuilder<'a> {
+    status: StatusCode,
+    content_type: &'a str,
+    keep_alive: bool,
+    headers: Vec<(header::HeaderName, &'a str)>,
+}
+
+impl<'a> ResponseBuilder<'a> {
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            content_type: TEXT_CONTENT_TYPE,
+            keep_alive: true,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_content_type(mut self, content_type: &'a str) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    /// Set whether the connection stays open after the response, which the
+    /// `Connection` header is `keep-alive` or `close` for.
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Add a header other than the ones that the builder sets itself.
+    pub fn with_header(mut self, name: header::HeaderName, value: &'a str) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    pub fn build(self, body: Bytes) -> Bytes {
+        let connection = if self.keep_alive {
+            "keep-alive"
+        } else {
+            "close"
+        };
+        let mut builder = http::Response::builder()
+            .status(self.status)
+            .header(header::CONTENT_TYPE, self.content_type)
+            .header(header::CONNECTION, connection);
+        for (name, value) in self.headers {
+            builder = builder.header(name, value);
+        }
+        let response = builder.body(body).unwrap();
+        format_response_bytes(response)
+    }
+}
+
+fn format_response_bytes(response: http::Response<Bytes>) -> Bytes {
+    let n = response.body().len();
 
     let mut response_bytes = BytesMut::new();
     response_bytes.extend_from_slice(
@@ -28,3 +88,117 @@ pub fn format_response(body: Bytes, status: http::StatusCode) -> Bytes {
 
     response_bytes.into()
 }
+
+/// Format the response that upgrades a connection to a WebSocket, which has
+/// no body, and so no `Content-Length` either.
+pub fn format_websocket_upgrade(accept: &str, protocol: Option<&str>) -> Bytes {
+    let mut head = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n",
+        accept
+    );
+    if let Some(protocol) = protocol {
+        head.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+    }
+    head.push_str("\r\n");
+    head.into()
+}
+
+/// Format the head of a response whose body is sent with chunked transfer
+/// encoding, one `format_chunk()` at a time.
+pub fn format_chunked_response_head(
+    status: http::StatusCode,
+    content_type: &str,
+    keep_alive: bool,
+) -> BytesMut {
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    let mut response_bytes = BytesMut::new();
+    response_bytes.extend_from_slice(
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nConnection: {}\r\nTransfer-Encoding: chunked\r\n\r\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or(""),
+            content_type,
+            connection
+        )
+        .as_bytes(),
+    );
+    response_bytes
+}
+
+pub fn format_chunk(buf: &mut BytesMut, data: &[u8]) {
+    buf.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    buf.extend_from_slice(data);
+    buf.extend_from_slice(b"\r\n");
+}
+
+/// The chunk that terminates a chunked response body.
+pub const LAST_CHUNK: &[u8] = b"0\r\n\r\n";
+
+/// A body with chunked transfer encoding whose chunk framing is malformed.
+#[derive(Debug, thiserror::Error)]
+#[error("Malformed chunk")]
+pub struct MalformedChunk;
+
+// Maximum length of a chunk size line, which bounds how much is buffered
+// while waiting for the line to end.
+const MAX_CHUNK_LINE_LEN: usize = 1024;
+
+/// Decode a body with chunked transfer encoding.
+///
+/// Returns the decoded body and the length of the encoded body, including
+/// the last chunk and the trailers, or `None` if the body has not been
+/// received completely yet.
+pub fn decode_chunked(buf: &[u8]) -> Result<Option<(BytesMut, usize)>, MalformedChunk> {
+    let mut body = BytesMut::new();
+    let mut pos = 0;
+    loop {
+        let line = match chunk_line(&buf[pos..])? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        pos += line.len() + 2;
+        // Chunk extensions are ignored.
+        let size = line.split(|b| *b == b';').next().unwrap_or_default();
+        let size = std::str::from_utf8(size)
+            .map_err(|_| MalformedChunk)?
+            .trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(MalformedChunk);
+        }
+        let size = usize::from_str_radix(size, 16).map_err(|_| MalformedChunk)?;
+        if size == 0 {
+            // The last chunk is followed by trailers, which are ignored, up to
+            // an empty line.
+            loop {
+                let line = match chunk_line(&buf[pos..])? {
+                    Some(line) => line,
+                    None => return Ok(None),
+                };
+                pos += line.len() + 2;
+                if line.is_empty() {
+                    return Ok(Some((body, pos)));
+                }
+            }
+        }
+        let chunk_end = pos.checked_add(size).ok_or(MalformedChunk)?;
+        if buf.len() < chunk_end.saturating_add(2) {
+            return Ok(None);
+        }
+        if &buf[chunk_end..chunk_end + 2] != b"\r\n" {
+            return Err(MalformedChunk);
+        }
+        body.extend_from_slice(&buf[pos..chunk_end]);
+        pos = chunk_end + 2;
+    }
+}
+
+/// Return the line at the beginning of `buf`, without the CRLF that ends
+/// it, or `None` if the line has not been received completely yet.
+fn chunk_line(buf: &[u8]) -> Result<Option<&[u8]>, MalformedChunk> {
+    match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end <= MAX_CHUNK_LINE_LEN => Ok(Some(&buf[..end])),
+        Some(_) => Err(MalformedChunk),
+        None if buf.len() > MAX_CHUNK_LINE_LEN => Err(MalformedChunk),
+        None => Ok(None),
+    }
+}
@@ -1,66 +1,338 @@
 use bytes::{Bytes, BytesMut};
 use polling::{Event, Events, Poller};
 
-use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "tls")]
+use super::tls::TlsConn;
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::os::fd::{AsRawFd, RawFd};
 use std::rc::Rc;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Maximum number of bytes received at once.
+const RECV_BUF_SIZE: usize = 4096;
+
+// How long a listener waits before it accepts again after an accept has
+// failed, which doubles with every failure in a row up to
+// `MAX_ACCEPT_BACKOFF`.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(1);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
 
 pub struct IO<C> {
     poller: Poller,
     events: Events,
     key_seq: usize,
+    // Operations that have not been registered with the poller yet, in the
+    // order they were submitted.
+    submission_queue: VecDeque<(usize, Completion<C>)>,
     submissions: HashMap<usize, Completion<C>>,
+    // The submissions that wait for a socket to become readable or
+    // writable, keyed by the fd of the socket. The poller takes one
+    // registration per fd, so a receive and a send on the same socket share
+    // it.
+    interests: HashMap<RawFd, Interest>,
+    // The fds that are registered with the poller. The poller disarms an fd
+    // once it reports an event on it, but the fd stays registered until it
+    // is deleted, so the next operation on it modifies the registration.
+    registered: HashSet<RawFd>,
+    // The fds whose interest has changed since the poller was last armed
+    // with it.
+    dirty: BTreeSet<RawFd>,
+    // Deadlines of the submitted operations that time out, keyed like the
+    // submissions.
+    timeouts: HashMap<usize, (Instant, TimeoutCallback<C>)>,
+    // Sleeps that have not finished yet, with the time they finish at.
+    sleeps: Vec<(Instant, Rc<socket2::Socket>, TimeoutCallback<C>)>,
+    // Accepts that wait out a backoff after a failed accept, with the time
+    // they are submitted again at.
+    accept_retries: Vec<(Instant, Completion<C>)>,
+    // The current backoff of the listeners whose last accept failed, keyed
+    // by the listener socket.
+    accept_backoffs: HashMap<RawFd, Duration>,
     completions: VecDeque<Completion<C>>,
+    // The buffer that sockets receive into, which is reused for every
+    // receive because the callback only borrows what was received.
+    recv_buf: BytesMut,
+    // Listeners whose connections are TLS, keyed by the listener socket.
+    #[cfg(feature = "tls")]
+    tls_listeners: HashMap<RawFd, Arc<rustls::ServerConfig>>,
+    // TLS state of the connections, keyed by the connection socket.
+    #[cfg(feature = "tls")]
+    tls_conns: HashMap<RawFd, TlsConn>,
     context: C,
 }
 
 impl<C> IO<C> {
+    /// Create an IO.
+    ///
+    /// Operations are queued when they are submitted, and registered with
+    /// the poller in one pass at the start of `run_once()`. The pass
+    /// combines the operations on a socket into one registration, so a
+    /// connection that has a receive and a send pending, as a kept-alive
+    /// connection does while it responds, costs one `epoll_ctl()` call per
+    /// `run_once()` rather than one per operation. A socket whose operation
+    /// completes stays registered, disarmed, so that its next operation
+    /// modifies the registration instead of deleting and adding it again.
+    /// Serving a request on a kept-alive connection takes two calls this
+    /// way, where adding and deleting every operation on its own took four.
     pub fn new(context: C) -> Self {
         Self {
             poller: Poller::new().unwrap(),
             events: Events::new(),
             key_seq: 0,
+            submission_queue: VecDeque::new(),
             submissions: HashMap::new(),
+            interests: HashMap::new(),
+            registered: HashSet::new(),
+            dirty: BTreeSet::new(),
+            timeouts: HashMap::new(),
+            sleeps: Vec::new(),
+            accept_retries: Vec::new(),
+            accept_backoffs: HashMap::new(),
             completions: VecDeque::new(),
+            recv_buf: BytesMut::with_capacity(RECV_BUF_SIZE),
+            #[cfg(feature = "tls")]
+            tls_listeners: HashMap::new(),
+            #[cfg(feature = "tls")]
+            tls_conns: HashMap::new(),
             context,
         }
     }
 
+    /// Terminate TLS with `config` on the connections that `server_sock`
+    /// accepts. Receives and sends on the connections then operate on
+    /// plaintext.
+    #[cfg(feature = "tls")]
+    pub fn listen_tls(&mut self, server_sock: &socket2::Socket, config: Arc<rustls::ServerConfig>) {
+        self.tls_listeners.insert(server_sock.as_raw_fd(), config);
+    }
+
     pub fn context(&self) -> &C {
         &self.context
     }
 
     pub fn run_once(&mut self) {
         log::debug!("Running IO loop");
+        let calls = self.flush_submission_queue();
+        log::debug!("Armed the poller with {} calls", calls);
         self.events.clear();
         let _ = self.poller.wait(
             &mut self.events,
             Some(std::time::Duration::from_micros(500)),
         );
         self.flush_submissions();
+        self.fire_timeouts();
+        self.fire_sleeps();
+        self.fire_accept_retries();
         self.flush_completions();
     }
 
+    /// Register the queued operations with the poller, and arm it with the
+    /// interests that have changed, returning the number of calls to the
+    /// poller that it took.
+    fn flush_submission_queue(&mut self) -> usize {
+        log::debug!("Registering {} submissions", self.submission_queue.len());
+        while let Some((key, c)) = self.submission_queue.pop_front() {
+            let (sock, readable) = match &c {
+                Completion::Accept { server_sock, .. } => (server_sock.clone(), true),
+                Completion::Recv { sock, .. } => (sock.clone(), true),
+                Completion::Send { sock, .. } => (sock.clone(), false),
+                _ => {
+                    todo!();
+                }
+            };
+            let fd = sock.as_raw_fd();
+            let interest = self.interests.entry(fd).or_insert_with(|| Interest {
+                sock,
+                read: None,
+                write: None,
+            });
+            let slot = if readable {
+                &mut interest.read
+            } else {
+                &mut interest.write
+            };
+            assert!(
+                slot.is_none(),
+                "{:?} is pending on sockfd {} already",
+                c,
+                fd
+            );
+            *slot = Some(key);
+            self.submissions.insert(key, c);
+            self.dirty.insert(fd);
+        }
+        let dirty = std::mem::take(&mut self.dirty);
+        dirty.into_iter().map(|fd| self.arm(fd)).sum()
+    }
+
+    /// Arm the poller with the interest of the socket with `fd`, adding the
+    /// fd to the poller if it is not registered yet, and return the number
+    /// of calls to the poller that it took.
+    fn arm(&mut self, fd: RawFd) -> usize {
+        // The interest may have been cancelled since it changed.
+        let Some(interest) = self.interests.get(&fd) else {
+            return 0;
+        };
+        let event = Event::new(
+            fd as usize,
+            interest.read.is_some(),
+            interest.write.is_some(),
+        );
+        let sock = &*interest.sock;
+        let result = if self.registered.insert(fd) {
+            unsafe { self.poller.add(sock, event) }
+        } else {
+            self.poller.modify(sock, event)
+        };
+        match result {
+            Ok(()) => 1,
+            // A socket that was dropped without `close()` took its
+            // registration with it, and the fd now belongs to a new socket.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                unsafe { self.poller.add(sock, event).unwrap() };
+                2
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                self.poller.modify(sock, event).unwrap();
+                2
+            }
+            Err(err) => panic!("Failed to register sockfd {}: {}", fd, err),
+        }
+    }
+
     fn flush_submissions(&mut self) {
         log::debug!("Flushing submissions");
-        for event in self.events.iter() {
+        let events: Vec<Event> = self.events.iter().collect();
+        for event in events {
             log::debug!("Event: {:?}", event.key);
-            let c = self.submissions.remove(&event.key).unwrap();
-            c.prepare();
-            match &c {
-                Completion::Accept { server_sock, .. } => {
-                    self.poller.delete(server_sock).unwrap();
-                }
-                Completion::Recv { sock, .. } => {
-                    self.poller.delete(sock).unwrap();
-                }
-                Completion::Send { sock, .. } => {
-                    self.poller.delete(sock).unwrap();
+            let fd = event.key as RawFd;
+            // The operation that an event was for may have timed out or been
+            // closed in the meantime.
+            let Some(interest) = self.interests.get_mut(&fd) else {
+                continue;
+            };
+            let mut ready = Vec::with_capacity(2);
+            if event.readable {
+                ready.extend(interest.read.take());
+            }
+            if event.writable {
+                ready.extend(interest.write.take());
+            }
+            if interest.read.is_none() && interest.write.is_none() {
+                self.interests.remove(&fd);
+            } else {
+                // The poller has disarmed the fd, so the operation that is
+                // still pending is armed again.
+                self.dirty.insert(fd);
+            }
+            for key in ready {
+                let c = self.submissions.remove(&key).unwrap();
+                c.prepare();
+                // A TLS receive waits for the handshake to finish and for a
+                // complete record to decrypt.
+                #[cfg(feature = "tls")]
+                if let Completion::Recv { sock, .. } = &c {
+                    if let Some(tls) = self.tls_conns.get_mut(&sock.as_raw_fd()) {
+                        if !tls.read(sock) {
+                            self.submission_queue.push_back((key, c));
+                            continue;
+                        }
+                    }
                 }
-                _ => {
-                    todo!();
+                self.timeouts.remove(&key);
+                self.completions.push_back(c);
+            }
+        }
+    }
+
+    /// Cancel the operations whose timeout has passed, and complete them
+    /// with their timeout callbacks.
+    fn fire_timeouts(&mut self) {
+        let now = Instant::now();
+        let mut expired: Vec<usize> = self
+            .timeouts
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        expired.sort_unstable();
+        for key in expired {
+            let (_, cb) = self.timeouts.remove(&key).unwrap();
+            // The operation may still be waiting in the submission queue, in
+            // which case it has not been registered with the poller.
+            let (c, registered) = match self.submissions.remove(&key) {
+                Some(c) => (c, true),
+                None => {
+                    let pos = self
+                        .submission_queue
+                        .iter()
+                        .position(|(k, _)| *k == key)
+                        .unwrap();
+                    (self.submission_queue.remove(pos).unwrap().1, false)
                 }
+            };
+            let sock = match c {
+                Completion::Recv { sock, .. } | Completion::Send { sock, .. } => sock,
+                c => unreachable!("{:?} does not time out", c),
+            };
+            log::debug!("Operation on sockfd {:?} timed out", sock);
+            if registered {
+                self.cancel_interest(sock.as_raw_fd(), key);
+            }
+            self.completions.push_back(Completion::Timeout { sock, cb });
+        }
+    }
+
+    /// Remove the submission with `key` from the interest of the socket with
+    /// `fd`, arming the poller with what is left of it.
+    ///
+    /// If nothing is left, the fd stays armed, and the event that it may
+    /// still report finds no operation to complete.
+    fn cancel_interest(&mut self, fd: RawFd, key: usize) {
+        let Some(interest) = self.interests.get_mut(&fd) else {
+            return;
+        };
+        for slot in [&mut interest.read, &mut interest.write] {
+            if *slot == Some(key) {
+                *slot = None;
+            }
+        }
+        if interest.read.is_none() && interest.write.is_none() {
+            self.interests.remove(&fd);
+        } else {
+            self.dirty.insert(fd);
+        }
+    }
+
+    /// Complete the sleeps that have finished, in the order they were made.
+    fn fire_sleeps(&mut self) {
+        let now = Instant::now();
+        let mut i = 0;
+        while i < self.sleeps.len() {
+            if self.sleeps[i].0 <= now {
+                let (_, sock, cb) = self.sleeps.remove(i);
+                self.completions.push_back(Completion::Timeout { sock, cb });
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Submit the accepts whose backoff has passed again.
+    fn fire_accept_retries(&mut self) {
+        let now = Instant::now();
+        let mut i = 0;
+        while i < self.accept_retries.len() {
+            if self.accept_retries[i].0 <= now {
+                let (_, c) = self.accept_retries.remove(i);
+                let key = self.get_key();
+                self.enqueue(key, c);
+            } else {
+                i += 1;
             }
-            self.completions.push_back(c);
         }
     }
 
@@ -89,52 +361,134 @@ impl<C> IO<C> {
             cb,
         };
         let key = self.get_key();
-        match &c {
-            Completion::Accept { server_sock, .. } => unsafe {
-                self.poller.add(server_sock, Event::readable(key)).unwrap();
-            },
-            _ => {
-                todo!();
-            }
-        }
         self.enqueue(key, c);
     }
 
-    pub fn close(&mut self, sock: Rc<socket2::Socket>) {
+    /// Close `sock`, cancelling its pending operations, and call `cb` once
+    /// it is closed.
+    ///
+    /// The socket is closed when the last reference to it is dropped, which
+    /// the completion holds until `cb` has run. Closing a socket that is
+    /// already closed releases nothing, but still completes.
+    pub fn close(&mut self, sock: Rc<socket2::Socket>, cb: CloseCallback<C>) {
         log::debug!("Closing sockfd {:?}", sock);
-        drop(sock);
+        let is_sock = |c: &Completion<C>| c.sock().is_some_and(|s| Rc::ptr_eq(s, &sock));
+        let keys: Vec<usize> = self
+            .submissions
+            .iter()
+            .filter(|(_, c)| is_sock(c))
+            .map(|(key, _)| *key)
+            .collect();
+        let fd = sock.as_raw_fd();
+        if self
+            .interests
+            .get(&fd)
+            .is_some_and(|interest| Rc::ptr_eq(&interest.sock, &sock))
+        {
+            self.interests.remove(&fd);
+        }
+        if self.registered.remove(&fd) {
+            // The fd may have been registered by a socket that was dropped
+            // without `close()`, in which case the poller has forgotten it.
+            let _ = self.poller.delete(&*sock);
+        }
+        for key in keys {
+            self.submissions.remove(&key);
+            self.timeouts.remove(&key);
+        }
+        self.accept_retries.retain(|(_, c)| !is_sock(c));
+        self.accept_backoffs.remove(&fd);
+        let timeouts = &mut self.timeouts;
+        self.submission_queue.retain(|(key, c)| {
+            let cancel = is_sock(c);
+            if cancel {
+                timeouts.remove(key);
+            }
+            !cancel
+        });
+        #[cfg(feature = "tls")]
+        {
+            self.tls_listeners.remove(&sock.as_raw_fd());
+            if let Some(mut tls) = self.tls_conns.remove(&sock.as_raw_fd()) {
+                tls.close(&sock);
+            }
+        }
+        self.completions.push_back(Completion::Close { sock, cb });
     }
 
     pub fn recv(&mut self, sock: Rc<socket2::Socket>, cb: RecvCallback<C>) {
         log::debug!("Receiving on sockfd {:?}", sock);
         let c = Completion::Recv { sock, cb };
         let key = self.get_key();
-        match &c {
-            Completion::Recv { sock, .. } => unsafe {
-                self.poller.add(sock, Event::readable(key)).unwrap();
-            },
-            _ => {
-                todo!();
-            }
-        }
         self.enqueue(key, c);
     }
 
+    /// Send the first `n` bytes of `buf` on `sock`, and call `cb` with `n`
+    /// once all of them have been written, or with zero if the send fails.
     pub fn send(&mut self, sock: Rc<socket2::Socket>, buf: Bytes, n: usize, cb: SendCallback<C>) {
         log::debug!("Sending on sockfd {:?}", sock);
-        let c = Completion::Send { sock, buf, n, cb };
+        let c = Completion::Send {
+            sock,
+            buf,
+            n,
+            sent: 0,
+            cb,
+        };
         let key = self.get_key();
-        match &c {
-            Completion::Send { sock, .. } => unsafe {
-                self.poller.add(sock, Event::writable(key)).unwrap();
-            },
-            _ => {
-                todo!();
-            }
-        }
         self.enqueue(key, c)
     }
 
+    /// Receive on `sock` like `recv()`, but cancel the receive and call
+    /// `on_timeout` instead if the socket does not become readable within
+    /// `timeout`.
+    pub fn recv_timeout(
+        &mut self,
+        sock: Rc<socket2::Socket>,
+        timeout: Duration,
+        cb: RecvCallback<C>,
+        on_timeout: TimeoutCallback<C>,
+    ) {
+        log::debug!("Receiving on sockfd {:?} with timeout {:?}", sock, timeout);
+        let c = Completion::Recv { sock, cb };
+        let key = self.get_key();
+        self.timeouts
+            .insert(key, (Instant::now() + timeout, on_timeout));
+        self.enqueue(key, c);
+    }
+
+    /// Call `cb` with `sock` once `duration` has passed, without doing
+    /// anything on the socket.
+    pub fn sleep(&mut self, sock: Rc<socket2::Socket>, duration: Duration, cb: TimeoutCallback<C>) {
+        log::debug!("Sleeping on sockfd {:?} for {:?}", sock, duration);
+        self.sleeps.push((Instant::now() + duration, sock, cb));
+    }
+
+    /// Send on `sock` like `send()`, but cancel the send and call
+    /// `on_timeout` instead if the socket does not become writable within
+    /// `timeout`.
+    pub fn send_timeout(
+        &mut self,
+        sock: Rc<socket2::Socket>,
+        buf: Bytes,
+        n: usize,
+        timeout: Duration,
+        cb: SendCallback<C>,
+        on_timeout: TimeoutCallback<C>,
+    ) {
+        log::debug!("Sending on sockfd {:?} with timeout {:?}", sock, timeout);
+        let c = Completion::Send {
+            sock,
+            buf,
+            n,
+            sent: 0,
+            cb,
+        };
+        let key = self.get_key();
+        self.timeouts
+            .insert(key, (Instant::now() + timeout, on_timeout));
+        self.enqueue(key, c);
+    }
+
     fn get_key(&mut self) -> usize {
         let ret = self.key_seq;
         self.key_seq += 1;
@@ -142,17 +496,30 @@ impl<C> IO<C> {
     }
 
     fn enqueue(&mut self, key: usize, c: Completion<C>) {
-        self.submissions.insert(key, c);
+        self.submission_queue.push_back((key, c));
     }
 }
 
+/// The operations on a socket that wait for the poller.
+struct Interest {
+    sock: Rc<socket2::Socket>,
+    /// The key of the accept or receive that waits for the socket to
+    /// become readable.
+    read: Option<usize>,
+    /// The key of the send that waits for the socket to become writable.
+    write: Option<usize>,
+}
+
 pub enum Completion<C> {
     Accept {
         server_sock: Rc<socket2::Socket>,
         server_addr: socket2::SockAddr,
         cb: AcceptCallback<C>,
     },
-    Close,
+    Close {
+        sock: Rc<socket2::Socket>,
+        cb: CloseCallback<C>,
+    },
     Recv {
         sock: Rc<socket2::Socket>,
         cb: RecvCallback<C>,
@@ -161,30 +528,47 @@ pub enum Completion<C> {
         sock: Rc<socket2::Socket>,
         buf: Bytes,
         n: usize,
+        /// Number of bytes of `buf` that earlier writes have sent.
+        sent: usize,
         cb: SendCallback<C>,
     },
+    Timeout {
+        sock: Rc<socket2::Socket>,
+        cb: TimeoutCallback<C>,
+    },
 }
 
 impl<C> std::fmt::Debug for Completion<C> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Completion::Accept { .. } => write!(f, "Accept"),
-            Completion::Close => write!(f, "Close"),
+            Completion::Close { .. } => write!(f, "Close"),
             Completion::Recv { .. } => write!(f, "Recv"),
             Completion::Send { .. } => write!(f, "Send"),
+            Completion::Timeout { .. } => write!(f, "Timeout"),
         }
     }
 }
 
 impl<C> Completion<C> {
+    /// The socket the operation is on.
+    fn sock(&self) -> Option<&Rc<socket2::Socket>> {
+        match self {
+            Completion::Accept { server_sock, .. } => Some(server_sock),
+            Completion::Close { sock, .. }
+            | Completion::Recv { sock, .. }
+            | Completion::Send { sock, .. }
+            | Completion::Timeout { sock, .. } => Some(sock),
+        }
+    }
+
     fn prepare(&self) {
         match self {
             Completion::Accept { .. } => {}
-            Completion::Close => {
-                todo!();
-            }
+            Completion::Close { .. } => {}
             Completion::Recv { .. } => {}
             Completion::Send { .. } => {}
+            Completion::Timeout { .. } => {}
         }
     }
 
@@ -195,24 +579,135 @@ impl<C> Completion<C> {
                 server_addr,
                 cb,
             } => {
-                let (sock, sock_addr) = server_sock.accept().unwrap();
+                let (sock, sock_addr) = match server_sock.accept() {
+                    Ok(accepted) => accepted,
+                    // The connection may have been reset before it was
+                    // accepted, or the process may be out of file
+                    // descriptors, so the listener accepts again rather than
+                    // taking the server down. A listener that is out of file
+                    // descriptors stays readable, so it backs off rather than
+                    // spinning on the same error.
+                    Err(err) => {
+                        let fd = server_sock.as_raw_fd();
+                        let backoff = io
+                            .accept_backoffs
+                            .get(&fd)
+                            .map_or(ACCEPT_BACKOFF, |backoff| {
+                                (*backoff * 2).min(MAX_ACCEPT_BACKOFF)
+                            });
+                        io.accept_backoffs.insert(fd, backoff);
+                        log::warn!(
+                            "Failed to accept on sockfd {:?}, retrying in {:?}: {}",
+                            server_sock,
+                            backoff,
+                            err
+                        );
+                        let c = Completion::Accept {
+                            server_sock,
+                            server_addr,
+                            cb,
+                        };
+                        io.accept_retries.push((Instant::now() + backoff, c));
+                        return;
+                    }
+                };
+                io.accept_backoffs.remove(&server_sock.as_raw_fd());
+                #[cfg(feature = "tls")]
+                if let Some(config) = io.tls_listeners.get(&server_sock.as_raw_fd()) {
+                    let tls = TlsConn::new(config.clone());
+                    io.tls_conns.insert(sock.as_raw_fd(), tls);
+                }
                 cb(io, server_sock, server_addr, Rc::new(sock), sock_addr);
             }
-            Completion::Close => {
-                todo!();
+            Completion::Close { sock, cb } => {
+                cb(io, sock);
             }
             Completion::Recv { sock, cb } => {
-                let mut buf = BytesMut::with_capacity(4096);
+                #[cfg(feature = "tls")]
+                if let Some(tls) = io.tls_conns.get_mut(&sock.as_raw_fd()) {
+                    let buf = tls.take_plaintext();
+                    let n = buf.len();
+                    cb(io, sock, &buf[..], n);
+                    return;
+                }
+                let mut buf = std::mem::take(&mut io.recv_buf);
+                buf.clear();
+                buf.reserve(RECV_BUF_SIZE);
                 let uninit = buf.spare_capacity_mut();
-                let n = sock.recv(uninit).unwrap();
+                let n = match sock.recv(uninit) {
+                    Ok(n) => n,
+                    // The socket was reported readable, but has nothing to
+                    // receive after all, so the receive waits again.
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+                        ) =>
+                    {
+                        io.recv_buf = buf;
+                        let key = io.get_key();
+                        io.enqueue(key, Completion::Recv { sock, cb });
+                        return;
+                    }
+                    // A failed receive, for example because the peer has
+                    // reset the connection, completes with zero bytes like
+                    // end-of-file does, so that the connection is closed.
+                    Err(err) => {
+                        log::debug!("Failed to receive on sockfd {:?}: {}", sock, err);
+                        0
+                    }
+                };
                 unsafe {
                     buf.set_len(n);
                 }
                 cb(io, sock, &buf[..], n);
+                io.recv_buf = buf;
             }
-            Completion::Send { sock, buf, n, cb } => {
-                let n = sock.send(&buf[..n]).unwrap();
-                cb(io, sock, n);
+            Completion::Send {
+                sock,
+                buf,
+                n,
+                sent,
+                cb,
+            } => {
+                #[cfg(feature = "tls")]
+                let written = match io.tls_conns.get_mut(&sock.as_raw_fd()) {
+                    Some(tls) => tls.write(&sock, &buf[sent..n]),
+                    None => sock.send(&buf[sent..n]),
+                };
+                #[cfg(not(feature = "tls"))]
+                let written = sock.send(&buf[sent..n]);
+                match written {
+                    // The socket took only part of the bytes, so the rest are
+                    // written once it is writable again, and the callback
+                    // runs when all of them have been.
+                    Ok(written) if written > 0 && sent + written < n => {
+                        let c = Completion::Send {
+                            sock,
+                            buf,
+                            n,
+                            sent: sent + written,
+                            cb,
+                        };
+                        let key = io.get_key();
+                        io.enqueue(key, c);
+                    }
+                    Ok(written) if sent + written == n => cb(io, sock, n),
+                    // A failed send, for example because the peer has closed
+                    // the connection, completes with zero bytes sent, even if
+                    // earlier writes sent some of them.
+                    Ok(_) => {
+                        log::debug!("Failed to send on sockfd {:?}: no bytes written", sock);
+                        cb(io, sock, 0);
+                    }
+                    Err(err) => {
+                        log::debug!("Failed to send on sockfd {:?}: {}", sock, err);
+                        cb(io, sock, 0);
+                    }
+                }
+            }
+            Completion::Timeout { sock, cb } => {
+                cb(io, sock);
             }
         }
     }
@@ -226,3 +721,78 @@ type AcceptCallback<C> =
 type RecvCallback<C> = fn(&mut IO<C>, Rc<socket2::Socket>, &[u8], usize);
 
 type SendCallback<C> = fn(&mut IO<C>, Rc<socket2::Socket>, usize);
+
+type TimeoutCallback<C> = fn(&mut IO<C>, Rc<socket2::Socket>);
+
+type CloseCallback<C> = fn(&mut IO<C>, Rc<socket2::Socket>);
+
+#[cfg(test)]
+mod test {
+    use super::IO;
+    use bytes::Bytes;
+    use socket2::{Domain, Socket, Type};
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpStream};
+    use std::rc::Rc;
+
+    type TestIO = IO<RefCell<Vec<&'static str>>>;
+
+    fn on_recv(io: &mut TestIO, _sock: Rc<Socket>, _buf: &[u8], _n: usize) {
+        io.context().borrow_mut().push("recv");
+    }
+
+    fn on_send(io: &mut TestIO, _sock: Rc<Socket>, _n: usize) {
+        io.context().borrow_mut().push("send");
+    }
+
+    fn on_recv_closed(io: &mut TestIO, _sock: Rc<Socket>, _buf: &[u8], n: usize) {
+        assert_eq!(n, 0);
+        io.context().borrow_mut().push("closed");
+    }
+
+    /// Connect a client to a listener, returning the client and the socket
+    /// of the server side of the connection.
+    fn connect() -> (TcpStream, Rc<Socket>) {
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        listener.bind(&addr.into()).unwrap();
+        listener.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap().as_socket().unwrap();
+        let client = TcpStream::connect(addr).unwrap();
+        let sock = Rc::new(listener.accept().unwrap().0);
+        (client, sock)
+    }
+
+    #[test]
+    fn operations_on_socket_share_poller_call() {
+        let (mut client, sock) = connect();
+        let mut io = IO::new(RefCell::new(Vec::new()));
+        for _ in 0..2 {
+            io.recv(sock.clone(), on_recv);
+            io.send(sock.clone(), Bytes::from_static(b"pong"), 4, on_send);
+            // The receive and the send are added together the first time,
+            // and the registration is modified the second time.
+            assert_eq!(io.flush_submission_queue(), 1);
+            client.write_all(b"ping").unwrap();
+            while io.context().borrow().len() < 2 {
+                io.run_once();
+            }
+            io.context().borrow_mut().clear();
+        }
+    }
+
+    #[test]
+    fn reset_completes_recv() {
+        let (client, sock) = connect();
+        // Closing with a zero linger resets the connection.
+        Socket::from(client)
+            .set_linger(Some(std::time::Duration::ZERO))
+            .unwrap();
+        let mut io = IO::new(RefCell::new(Vec::new()));
+        io.recv(sock, on_recv_closed);
+        while io.context().borrow().is_empty() {
+            io.run_once();
+        }
+    }
+}
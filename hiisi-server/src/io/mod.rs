@@ -4,8 +4,17 @@ mod generic;
 #[cfg(not(feature = "simulation"))]
 pub use generic::IO;
 
+#[cfg(all(feature = "tls", not(feature = "simulation")))]
+mod tls;
+
 #[cfg(feature = "simulation")]
 mod simulation;
 
 #[cfg(feature = "simulation")]
-pub use simulation::IO;
+mod trace;
+
+#[cfg(feature = "simulation")]
+pub use simulation::{Faults, Latency, IO};
+
+#[cfg(feature = "simulation")]
+pub use trace::{Trace, TraceEvent};
@@ -1,59 +1,552 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
-use std::cell::RefCell;
-use std::collections::{HashMap, VecDeque};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::os::fd::AsRawFd;
 use std::rc::Rc;
+use std::time::Duration;
+
+use super::trace::{Trace, TraceEvent};
+use crate::clock::{Clock, SimClock};
+
+/// Virtual time that passes on every `run_once()`.
+const TICK: Duration = Duration::from_millis(1);
+
+/// A delay drawn uniformly from `min_ms..=max_ms` milliseconds of virtual
+/// time.
+#[derive(Clone, Copy, Debug)]
+pub struct Latency {
+    pub min_ms: u64,
+    pub max_ms: u64,
+}
+
+/// Faults that the simulated IO injects.
+///
+/// The faults are drawn from an RNG seeded with `seed`, so a simulation
+/// with the same seed injects the same faults.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    pub seed: u64,
+    /// Network latency that delays the delivery of sent bytes to the
+    /// receiving socket.
+    pub latency_ms: Option<Latency>,
+    /// Probability that a send on an accepted socket resets the
+    /// connection.
+    ///
+    /// The connection is reset before the header, in the middle of the body
+    /// or after the body of the message that is sent, and only the bytes
+    /// before that point reach the peer. The peer then receives end-of-file,
+    /// and sends on either end of the connection fail.
+    pub reset_prob: f64,
+    /// Probability that a send on an accepted socket writes only part of
+    /// the message at first, like a real socket does under backpressure.
+    ///
+    /// The rest of the message is written when the send completes, before
+    /// its callback runs, so the peer may receive the message in two parts.
+    pub short_write_prob: f64,
+    /// Probability that a send on an accepted socket corrupts a few bytes of
+    /// the body of the message on its way to the peer.
+    ///
+    /// Only bodies of HTTP messages with a `Content-Length` are corrupted,
+    /// by zeroing bytes or flipping their bits, so the peer receives a
+    /// message that is framed right but whose body is garbled.
+    pub corrupt_prob: f64,
+}
 
 struct Socket {
     local_sock: Rc<socket2::Socket>,
     remote_sock: Rc<socket2::Socket>,
     xmit_queue: RefCell<VecDeque<Bytes>>,
+    /// Whether the socket was created by accepting a connection.
+    accepted: bool,
+    /// Whether the socket has reset the connection.
+    reset: Cell<bool>,
 }
 
 pub struct IO<C> {
     context: C,
     completions: RefCell<VecDeque<Completion<C>>>,
+    faults: Faults,
+    rng: ChaCha8Rng,
+    /// The virtual clock, which only advances in `run_once()`.
+    clock: Rc<SimClock>,
+    /// Completions that are delayed, including sleeps, keyed by the time they
+    /// fire at and a sequence number that orders completions firing at the
+    /// same time.
+    timers: BTreeMap<(u64, u64), Completion<C>>,
+    timer_seq: u64,
+    /// Timeouts of pending receives, keyed like the timers.
+    timeouts: BTreeMap<(u64, u64), (Rc<socket2::Socket>, TimeoutCallback<C>)>,
+    /// The key of the timeout of the pending receive on a socket.
+    recv_timeouts: HashMap<i32, (u64, u64)>,
+    /// Time of the latest delivery to a socket, which later deliveries
+    /// cannot precede so that the bytes of a stream stay in order.
+    last_delivery_ms: HashMap<i32, u64>,
+    /// Digest of the sequence of completions.
+    digest: std::collections::hash_map::DefaultHasher,
     listener_sockets: HashMap<i32, Rc<socket2::Socket>>,
-    conn_sockets: HashMap<i32, Socket>,
+    // Iterated when flushing the transmit queues, so ordered to keep the
+    // order of deliveries deterministic.
+    conn_sockets: BTreeMap<i32, Socket>,
+    /// Sockets whose peer has reset or closed the connection, and the bytes
+    /// the peer sent before that which the socket has not received yet.
+    ///
+    /// The entries outlive the peer socket, which the server closes as soon
+    /// as its send fails.
+    resets: BTreeMap<i32, Option<Bytes>>,
     accept_listeners: HashMap<socket2::SockAddr, (Rc<socket2::Socket>, AcceptCallback<C>)>,
+    /// Connections that wait for their listener to accept them, along with
+    /// the tick they were made on.
+    ///
+    /// A listener accepts the connections in the order of the ticks they
+    /// were made on, and the connections made on the same tick in an order
+    /// drawn from the RNG.
+    backlogs: HashMap<socket2::SockAddr, VecDeque<(Rc<socket2::Socket>, socket2::SockAddr, u64)>>,
     recv_listeners: HashMap<i32, (Rc<socket2::Socket>, RecvCallback<C>)>,
+    /// Ids that identify the sockets in traces, in the order the sockets
+    /// were registered in.
+    sock_ids: HashMap<i32, u64>,
+    next_sock_id: u64,
+    /// The trace being recorded, if any.
+    trace: Option<Trace>,
+    /// The trace being replayed, if any.
+    replay: Option<Replay<C>>,
+    /// Maximum number of completions that `run_once()` runs, if any.
+    max_events_per_tick: Option<usize>,
+    /// The completions that did not get a turn on the last tick, which run
+    /// ahead of the completions of their sockets that were queued since.
+    deferred: Vec<Completion<C>>,
+}
+
+/// The state of a replay, which feeds the completions on the server side
+/// of the connections of a trace to the callbacks.
+struct Replay<C> {
+    events: VecDeque<TraceEvent>,
+    /// The sockets of the accepted connections, keyed by trace id.
+    socks: HashMap<u64, Rc<socket2::Socket>>,
+    /// Sends that wait for their completion in the trace, keyed by socket.
+    pending_sends: HashMap<i32, VecDeque<(Bytes, SendCallback<C>)>>,
 }
 
 impl<C> IO<C> {
     pub fn new(context: C) -> Self {
+        Self::with_faults(context, Faults::default())
+    }
+
+    pub fn with_faults(context: C, faults: Faults) -> Self {
+        Self::with_clock(context, faults, Rc::new(SimClock::new()))
+    }
+
+    /// Create an IO that advances `clock`, which the context can share to
+    /// read the virtual time.
+    pub fn with_clock(context: C, faults: Faults, clock: Rc<SimClock>) -> Self {
         let completions = RefCell::new(VecDeque::new());
         let listener_sockets = HashMap::new();
-        let conn_sockets = HashMap::new();
+        let conn_sockets = BTreeMap::new();
         let accept_listeners = HashMap::new();
         let recv_listeners = HashMap::new();
         Self {
             context,
             completions,
+            rng: ChaCha8Rng::seed_from_u64(faults.seed),
+            faults,
+            clock,
+            timers: BTreeMap::new(),
+            timer_seq: 0,
+            timeouts: BTreeMap::new(),
+            recv_timeouts: HashMap::new(),
+            last_delivery_ms: HashMap::new(),
+            digest: Default::default(),
             listener_sockets,
             conn_sockets,
+            resets: BTreeMap::new(),
             accept_listeners,
+            backlogs: HashMap::new(),
             recv_listeners,
+            sock_ids: HashMap::new(),
+            next_sock_id: 0,
+            trace: None,
+            replay: None,
+            max_events_per_tick: None,
+            deferred: Vec::new(),
         }
     }
 
+    /// Run at most `n` completions on every `run_once()`, taking turns between
+    /// the sockets that have completions ready, and leave the rest for the
+    /// following ticks.
+    ///
+    /// Without a bound, a tick runs every completion that is ready however
+    /// many a busy connection has queued, which makes ticks a poor measure
+    /// of latency.
+    pub fn with_max_events_per_tick(mut self, n: usize) -> Self {
+        assert!(n > 0, "a tick must run at least one completion");
+        self.max_events_per_tick = Some(n);
+        self
+    }
+
+    /// Create an IO that replays the server side of the connections of
+    /// `trace`.
+    ///
+    /// The IO runs the accept, receive and send completions of the sockets
+    /// that were accepted on the same ticks as in the trace, but no
+    /// connections are made. The IO panics if the server diverges from the
+    /// trace, for example by sending different bytes.
+    pub fn replay(context: C, trace: Trace, clock: Rc<SimClock>) -> Self {
+        let mut io = Self::with_clock(context, Faults::default(), clock);
+        let events = trace.server_events().into_iter().cloned().collect();
+        io.replay = Some(Replay {
+            events,
+            socks: HashMap::new(),
+            pending_sends: HashMap::new(),
+        });
+        io
+    }
+
+    /// Check if all the events of the trace being replayed have been
+    /// replayed.
+    pub fn replay_finished(&self) -> bool {
+        self.replay
+            .as_ref()
+            .is_some_and(|replay| replay.events.is_empty())
+    }
+
+    /// Start recording a trace of the simulation run with `seed`.
+    pub fn start_trace(&mut self, seed: u64) {
+        self.trace = Some(Trace::new(seed));
+    }
+
+    /// Stop recording the trace, returning what was recorded.
+    pub fn take_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
     pub fn context(&self) -> &C {
         &self.context
     }
 
+    /// The current time of the virtual clock, in milliseconds.
+    pub fn now_ms(&self) -> u64 {
+        self.clock.now().as_millis() as u64
+    }
+
+    /// A digest of the completions so far, the times they fired at, and the
+    /// faults injected.
+    ///
+    /// Two simulations with the same seed have the same digest.
+    pub fn completion_digest(&self) -> u64 {
+        self.digest.finish()
+    }
+
     pub fn run_once(&mut self) {
+        self.clock.advance(TICK);
+        if self.replay.is_some() {
+            self.replay_events();
+            // Closes and sleeps are the only completions that the server makes
+            // by itself in a replay.
+            self.fire_timers();
+            self.flush_completions();
+            return;
+        }
+        self.accept_backlogs();
         self.flush_xmit_queues();
+        self.fire_timers();
+        self.fire_timeouts();
         self.flush_completions();
     }
 
+    /// Accept a connection from the backlog of every listener that is
+    /// accepting.
+    ///
+    /// The clients that connect on the same tick are told apart by the RNG
+    /// rather than by the order they happened to call `connect()` in, so
+    /// that which of them is served first follows from the seed. The
+    /// listeners are visited in the order of their addresses, as the order
+    /// of a hash map differs between runs.
+    fn accept_backlogs(&mut self) {
+        let mut addrs: Vec<socket2::SockAddr> = self
+            .accept_listeners
+            .keys()
+            .filter(|addr| {
+                self.backlogs
+                    .get(*addr)
+                    .is_some_and(|backlog| !backlog.is_empty())
+            })
+            .cloned()
+            .collect();
+        addrs.sort_by_key(|addr| addr.as_socket());
+        for addr in addrs {
+            let backlog = self.backlogs.get_mut(&addr).unwrap();
+            let first_tick = backlog[0].2;
+            let ties = backlog
+                .iter()
+                .take_while(|(_, _, tick)| *tick == first_tick)
+                .count();
+            let idx = if ties > 1 {
+                self.rng.gen_range(0..ties)
+            } else {
+                0
+            };
+            let (client_sock, client_addr, _) = backlog.remove(idx).unwrap();
+            let (server_sock, cb) = self.accept_listeners.remove(&addr).unwrap();
+            log::trace!(
+                "IO -> accept_backlog(sockfd={}, client_sockfd={})",
+                server_sock.as_raw_fd(),
+                client_sock.as_raw_fd()
+            );
+            self.enqueue(Completion::Accept {
+                server_sock,
+                server_addr: addr,
+                client_sock,
+                client_addr,
+                cb,
+            });
+        }
+    }
+
+    /// The number of the current tick.
+    fn tick(&self) -> u64 {
+        (self.clock.now().as_nanos() / TICK.as_nanos()) as u64
+    }
+
+    fn sock_id(&mut self, sockfd: i32) -> u64 {
+        if let Some(id) = self.sock_ids.get(&sockfd) {
+            return *id;
+        }
+        let id = self.next_sock_id;
+        self.next_sock_id += 1;
+        self.sock_ids.insert(sockfd, id);
+        id
+    }
+
+    fn record(&mut self, event: impl FnOnce(&mut Self) -> TraceEvent) {
+        if self.trace.is_none() {
+            return;
+        }
+        let event = event(self);
+        self.trace.as_mut().unwrap().events.push(event);
+    }
+
+    fn record_completion(&mut self, c: &Completion<C>) {
+        // A replayed server closes its sockets and sleeps by itself, so
+        // closes and sleeps are not traced.
+        if let Completion::Close { .. } | Completion::Sleep { .. } = c {
+            return;
+        }
+        let tick = self.tick();
+        self.record(|io| match c {
+            Completion::Connect { sock, addr, .. } => TraceEvent::Connect {
+                tick,
+                sock: io.sock_id(sock.as_raw_fd()),
+                addr: format_addr(addr),
+            },
+            Completion::Accept {
+                server_addr,
+                client_sock,
+                client_addr,
+                ..
+            } => TraceEvent::Accept {
+                tick,
+                sock: io.sock_id(client_sock.as_raw_fd()),
+                addr: format_addr(server_addr),
+                peer: format_addr(client_addr),
+            },
+            Completion::Close { .. } | Completion::Sleep { .. } => unreachable!(),
+            Completion::Recv { sock, buf, .. } => TraceEvent::Recv {
+                tick,
+                sock: io.sock_id(sock.as_raw_fd()),
+                data: buf.clone(),
+            },
+            Completion::Send { sock, buf, n, .. } => TraceEvent::Send {
+                tick,
+                sock: io.sock_id(sock.as_raw_fd()),
+                data: buf.clone(),
+                n: *n,
+            },
+            Completion::Timeout { sock, .. } => TraceEvent::Timeout {
+                tick,
+                sock: io.sock_id(sock.as_raw_fd()),
+            },
+        });
+    }
+
+    /// Run the completions of the trace that happened on the current tick.
+    fn replay_events(&mut self) {
+        let tick = self.tick();
+        loop {
+            let replay = self.replay.as_mut().unwrap();
+            match replay.events.front() {
+                Some(event) if event.tick() <= tick => {}
+                _ => break,
+            }
+            let event = replay.events.pop_front().unwrap();
+            let c = match event {
+                TraceEvent::Accept {
+                    sock, addr, peer, ..
+                } => {
+                    let server_addr = parse_addr(&addr);
+                    let (server_sock, cb) = match self.accept_listeners.remove(&server_addr) {
+                        Some(listener) => listener,
+                        None => panic!("Replay diverged at tick {}: no accept on {}", tick, addr),
+                    };
+                    let client_sock = Rc::new(
+                        socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)
+                            .unwrap(),
+                    );
+                    self.sock_ids.insert(client_sock.as_raw_fd(), sock);
+                    replay.socks.insert(sock, client_sock.clone());
+                    Completion::Accept {
+                        server_sock,
+                        server_addr,
+                        client_sock,
+                        client_addr: parse_addr(&peer),
+                        cb,
+                    }
+                }
+                TraceEvent::Recv { sock, data, .. } => {
+                    let sockfd = replay.socks[&sock].as_raw_fd();
+                    let (sock, cb) = match self.recv_listeners.remove(&sockfd) {
+                        Some(listener) => listener,
+                        None => panic!(
+                            "Replay diverged at tick {}: socket {} does not receive",
+                            tick, sock
+                        ),
+                    };
+                    self.cancel_recv_timeout(sockfd);
+                    Completion::Recv {
+                        sock,
+                        buf: data,
+                        cb,
+                    }
+                }
+                TraceEvent::Send {
+                    sock: id, data, n, ..
+                } => {
+                    let sock = replay.socks[&id].clone();
+                    let pending_send = replay
+                        .pending_sends
+                        .get_mut(&sock.as_raw_fd())
+                        .and_then(|pending_sends| pending_sends.pop_front());
+                    let (buf, cb) = match pending_send {
+                        Some(pending_send) => pending_send,
+                        None => panic!(
+                            "Replay diverged at tick {}: socket {} does not send",
+                            tick, id
+                        ),
+                    };
+                    if buf != data {
+                        panic!(
+                            "Replay diverged at tick {}: socket {} sends {:?} instead of {:?}",
+                            tick, id, buf, data
+                        );
+                    }
+                    Completion::Send {
+                        sock,
+                        buf,
+                        n,
+                        rest: None,
+                        cb,
+                    }
+                }
+                TraceEvent::Timeout { sock: id, .. } => {
+                    let sockfd = replay.socks[&id].as_raw_fd();
+                    let timeout = self
+                        .recv_timeouts
+                        .remove(&sockfd)
+                        .and_then(|key| self.timeouts.remove(&key));
+                    let (sock, cb) = match timeout {
+                        Some(timeout) => timeout,
+                        None => panic!(
+                            "Replay diverged at tick {}: socket {} has no receive timeout",
+                            tick, id
+                        ),
+                    };
+                    self.recv_listeners.remove(&sockfd);
+                    Completion::Timeout { sock, cb }
+                }
+                event => unreachable!("{:?} is not a server-side completion", event),
+            };
+            self.record_completion(&c);
+            c.complete(self);
+        }
+    }
+
+    fn fire_timers(&mut self) {
+        let now_ms = self.now_ms();
+        while let Some(entry) = self.timers.first_entry() {
+            if entry.key().0 > now_ms {
+                break;
+            }
+            let c = entry.remove();
+            self.enqueue(c);
+        }
+    }
+
+    /// Cancel the receives whose timeout has passed, and run their timeout
+    /// callbacks.
+    fn fire_timeouts(&mut self) {
+        let now_ms = self.now_ms();
+        while let Some(entry) = self.timeouts.first_entry() {
+            if entry.key().0 > now_ms {
+                break;
+            }
+            let (sock, cb) = entry.remove();
+            let sockfd = sock.as_raw_fd();
+            log::trace!("IO -> timeout(sockfd={})", sockfd);
+            self.recv_timeouts.remove(&sockfd);
+            self.recv_listeners.remove(&sockfd);
+            self.enqueue(Completion::Timeout { sock, cb });
+        }
+    }
+
+    fn cancel_recv_timeout(&mut self, sockfd: i32) {
+        if let Some(key) = self.recv_timeouts.remove(&sockfd) {
+            self.timeouts.remove(&key);
+        }
+    }
+
+    /// Deliver a completion to a socket after the network latency.
+    fn deliver(&mut self, sockfd: i32, c: Completion<C>) {
+        let latency = match self.faults.latency_ms {
+            Some(latency) => latency,
+            None => {
+                self.enqueue(c);
+                return;
+            }
+        };
+        let delay = self.rng.gen_range(latency.min_ms..=latency.max_ms);
+        delay.hash(&mut self.digest);
+        let tick = self.tick();
+        self.record(|io| TraceEvent::Latency {
+            tick,
+            sock: io.sock_id(sockfd),
+            delay_ms: delay,
+        });
+        let now_ms = self.now_ms();
+        let last_delivery_ms = self.last_delivery_ms.entry(sockfd).or_default();
+        let deadline = (now_ms + delay).max(*last_delivery_ms);
+        *last_delivery_ms = deadline;
+        let seq = self.timer_seq;
+        self.timer_seq += 1;
+        self.timers.insert((deadline, seq), c);
+    }
+
     fn flush_xmit_queues(&mut self) {
         let mut completions = Vec::new();
-        for (sockfd, socket) in self.conn_sockets.iter_mut() {
+        for (sockfd, socket) in self.conn_sockets.iter() {
             let mut xmit_queue = socket.xmit_queue.borrow_mut();
             if xmit_queue.is_empty() {
                 continue;
             }
             let remote_sockfd = socket.remote_sock.as_raw_fd();
+            if self.is_reset(remote_sockfd) {
+                // The peer has reset the connection, so the bytes are lost.
+                xmit_queue.clear();
+                continue;
+            }
             if !self.recv_listeners.contains_key(&remote_sockfd) {
                 continue;
             }
@@ -63,32 +556,100 @@ impl<C> IO<C> {
                 local_sockfd,
                 remote_sockfd
             );
+            // A receive completes with one message, and the messages after
+            // it wait in the queue for the next receive, so a peer that
+            // stops receiving holds up the sender.
             let (recv_socket, cb) = self.recv_listeners.remove(&remote_sockfd).unwrap();
-            while let Some(buf) = xmit_queue.pop_front() {
-                let c = Completion::Recv {
-                    sock: recv_socket.clone(),
-                    buf,
-                    cb,
-                };
-                completions.push(c);
-            }
+            let buf = xmit_queue.pop_front().unwrap();
+            let c = Completion::Recv {
+                sock: recv_socket,
+                buf,
+                cb,
+            };
+            completions.push((remote_sockfd, c));
         }
-        for c in completions {
-            self.enqueue(c);
+        // A socket whose peer has reset or closed the connection receives what
+        // the peer sent before that, and then end-of-file.
+        for (sockfd, buf) in self.resets.iter_mut() {
+            let (recv_socket, cb) = match self.recv_listeners.remove(sockfd) {
+                Some(listener) => listener,
+                None => continue,
+            };
+            let c = Completion::Recv {
+                sock: recv_socket,
+                buf: buf.take().unwrap_or_default(),
+                cb,
+            };
+            completions.push((*sockfd, c));
+        }
+        for (sockfd, c) in completions {
+            // The receive has completed before its timeout.
+            self.cancel_recv_timeout(sockfd);
+            self.deliver(sockfd, c);
         }
     }
 
     fn flush_completions(&mut self) {
         let mut completions: Vec<Completion<C>> = self.completions.borrow_mut().drain(..).collect();
+        // Completions are run from the end, so the deferred ones go last.
+        completions.append(&mut self.deferred);
+        if let Some(max_events) = self.max_events_per_tick {
+            completions = self.take_turns(completions, max_events);
+        }
         loop {
             let c = match completions.pop() {
                 Some(c) => c,
                 None => break,
             };
+            self.now_ms().hash(&mut self.digest);
+            c.hash_kind(&mut self.digest);
+            self.record_completion(&c);
             c.complete(self);
         }
     }
 
+    /// Pick at most `max_events` of `completions` to run, in the order
+    /// that they are run in, and queue the rest for the next tick.
+    ///
+    /// The sockets take turns in an order that is shuffled with the RNG on
+    /// every tick, so no socket is always first. The completions of a socket
+    /// run in the order they would without a bound: the ones that do not get
+    /// a turn run on the next tick, ahead of the completions of the socket
+    /// that the picked ones queue.
+    fn take_turns(
+        &mut self,
+        completions: Vec<Completion<C>>,
+        max_events: usize,
+    ) -> Vec<Completion<C>> {
+        if completions.len() <= max_events {
+            return completions;
+        }
+        // Completions are run from the end, so each socket's queue is too.
+        let mut queues: BTreeMap<i32, Vec<Completion<C>>> = BTreeMap::new();
+        for c in completions {
+            queues.entry(c.sockfd()).or_default().push(c);
+        }
+        let mut order: Vec<i32> = queues.keys().copied().collect();
+        order.shuffle(&mut self.rng);
+        let mut picked = Vec::with_capacity(max_events);
+        while picked.len() < max_events {
+            for sockfd in &order {
+                if picked.len() == max_events {
+                    break;
+                }
+                if let Some(c) = queues.get_mut(sockfd).and_then(|queue| queue.pop()) {
+                    picked.push(c);
+                }
+            }
+        }
+        // The ones that did not get a turn keep the order of their socket.
+        for queue in queues.into_values() {
+            self.deferred.extend(queue);
+        }
+        picked.reverse();
+        picked
+    }
+
     pub fn connect(
         &mut self,
         local_sock: Rc<socket2::Socket>,
@@ -108,23 +669,18 @@ impl<C> IO<C> {
         let local_addr: std::net::SocketAddr = local_addr.parse().unwrap();
         let local_addr: socket2::SockAddr = local_addr.into();
 
-        // Accept the connection by creating a new socket on the remote side.
-        let (accept_sock, accept_cb) = self.accept_listeners.remove(&remote_addr).unwrap();
+        // Create the socket on the remote side, and queue the connection for
+        // the listener to accept on the next tick that it is accepting.
         let remote_sock = Rc::new(
             socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap(),
         );
-        self.register_socket(remote_sock.clone(), local_sock.clone());
-        let c = Completion::Accept {
-            server_sock: accept_sock.clone(),
-            server_addr: remote_addr.clone(),
-            client_sock: remote_sock.clone(),
-            client_addr: local_addr.clone(),
-            cb: accept_cb,
-        };
-        self.enqueue(c);
+        self.register_socket(remote_sock.clone(), local_sock.clone(), true);
+        let tick = self.tick();
+        let backlog = self.backlogs.entry(remote_addr).or_default();
+        backlog.push_back((remote_sock.clone(), local_addr.clone(), tick));
 
         // Establish the connection by registering the local socket.
-        self.register_socket(local_sock.clone(), remote_sock.clone());
+        self.register_socket(local_sock.clone(), remote_sock.clone(), false);
         let c = Completion::Connect {
             sock: local_sock.clone(),
             addr: local_addr,
@@ -137,6 +693,7 @@ impl<C> IO<C> {
         &mut self,
         local_sock: Rc<socket2::Socket>,
         remote_sock: Rc<socket2::Socket>,
+        accepted: bool,
     ) {
         let local_sockfd = local_sock.as_raw_fd();
         let remote_sockfd = remote_sock.as_raw_fd();
@@ -145,12 +702,18 @@ impl<C> IO<C> {
             local_sockfd,
             remote_sockfd
         );
+        // File descriptors are reused, so a socket is given a new id even if
+        // its file descriptor had one.
+        self.sock_ids.insert(local_sockfd, self.next_sock_id);
+        self.next_sock_id += 1;
         self.conn_sockets.insert(
             local_sockfd,
             Socket {
                 local_sock: local_sock.clone(),
                 remote_sock: remote_sock.clone(),
                 xmit_queue: RefCell::new(VecDeque::new()),
+                accepted,
+                reset: Cell::new(false),
             },
         );
     }
@@ -164,38 +727,341 @@ impl<C> IO<C> {
         let sockfd = server_sock.as_raw_fd();
         log::trace!("IO -> accept(sockfd={})", sockfd);
         self.listener_sockets.insert(sockfd, server_sock.clone());
+        // The connections in the backlog are accepted on the next tick.
         self.accept_listeners.insert(addr, (server_sock, cb));
     }
 
-    pub fn close(&mut self, sock: Rc<socket2::Socket>) {
+    /// Close `sock`, cancelling its pending receive, and call `cb` once it
+    /// is closed.
+    ///
+    /// Closing a socket that is already closed releases nothing, but still
+    /// completes.
+    pub fn close(&mut self, sock: Rc<socket2::Socket>, cb: CloseCallback<C>) {
         let sockfd = sock.as_raw_fd();
         log::trace!("IO -> close(sockfd={})", sockfd);
-        self.conn_sockets.remove(&sockfd);
+        if let Some(socket) = self.conn_sockets.remove(&sockfd) {
+            // The file descriptor of a peer that has closed its end may have
+            // been reused already, so check that it is still the peer.
+            let remotefd = socket.remote_sock.as_raw_fd();
+            let connected = self
+                .conn_sockets
+                .get(&remotefd)
+                .is_some_and(|peer| Rc::ptr_eq(&peer.remote_sock, &socket.local_sock));
+            if connected && !socket.reset.get() {
+                // The peer receives the bytes that are still in flight, and
+                // then end-of-file.
+                let mut in_flight = BytesMut::new();
+                for buf in socket.xmit_queue.borrow_mut().drain(..) {
+                    in_flight.extend_from_slice(&buf);
+                }
+                let in_flight = Some(in_flight.freeze()).filter(|buf| !buf.is_empty());
+                self.resets.insert(remotefd, in_flight);
+            }
+        }
+        self.resets.remove(&sockfd);
+        self.recv_listeners.remove(&sockfd);
+        self.cancel_recv_timeout(sockfd);
+        if self.listener_sockets.remove(&sockfd).is_some() {
+            self.accept_listeners
+                .retain(|_, (server_sock, _)| !Rc::ptr_eq(server_sock, &sock));
+        }
+        self.enqueue(Completion::Close { sock, cb });
+    }
+
+    /// Shut down the writing side of `sock`, so that the peer receives the
+    /// bytes that are still in flight and then end-of-file, while `sock`
+    /// keeps receiving what the peer sends.
+    ///
+    /// Nothing may be sent on `sock` after it has been shut down.
+    pub fn shutdown(&mut self, sock: Rc<socket2::Socket>) {
+        let sockfd = sock.as_raw_fd();
+        log::trace!("IO -> shutdown(sockfd={})", sockfd);
+        let socket = match self.conn_sockets.get(&sockfd) {
+            Some(socket) => socket,
+            None => return,
+        };
+        if socket.reset.get() {
+            return;
+        }
+        let mut in_flight = BytesMut::new();
+        for buf in socket.xmit_queue.borrow_mut().drain(..) {
+            in_flight.extend_from_slice(&buf);
+        }
+        let remotefd = socket.remote_sock.as_raw_fd();
+        let in_flight = Some(in_flight.freeze()).filter(|buf| !buf.is_empty());
+        self.resets.insert(remotefd, in_flight);
     }
 
     pub fn recv(&mut self, sock: Rc<socket2::Socket>, cb: RecvCallback<C>) {
         let sockfd = sock.as_raw_fd();
         log::trace!("IO -> recv(sockfd={})", sockfd);
+        self.cancel_recv_timeout(sockfd);
         self.recv_listeners.insert(sockfd, (sock, cb));
     }
 
+    /// Receive on `sock` like `recv()`, but cancel the receive and call
+    /// `on_timeout` instead if nothing is received within `timeout` of
+    /// virtual time.
+    pub fn recv_timeout(
+        &mut self,
+        sock: Rc<socket2::Socket>,
+        timeout: Duration,
+        cb: RecvCallback<C>,
+        on_timeout: TimeoutCallback<C>,
+    ) {
+        let sockfd = sock.as_raw_fd();
+        self.recv(sock.clone(), cb);
+        let deadline = self.now_ms() + timeout.as_millis() as u64;
+        let key = (deadline, self.timer_seq);
+        self.timer_seq += 1;
+        self.timeouts.insert(key, (sock, on_timeout));
+        self.recv_timeouts.insert(sockfd, key);
+    }
+
+    /// Call `cb` with `sock` once `duration` of virtual time has passed,
+    /// without doing anything on the socket.
+    pub fn sleep(&mut self, sock: Rc<socket2::Socket>, duration: Duration, cb: TimeoutCallback<C>) {
+        log::trace!("IO -> sleep(sockfd={})", sock.as_raw_fd());
+        let deadline = self.now_ms() + duration.as_millis() as u64;
+        let seq = self.timer_seq;
+        self.timer_seq += 1;
+        self.timers
+            .insert((deadline, seq), Completion::Sleep { sock, cb });
+    }
+
+    /// Send on `sock` like `send()`, calling `on_timeout` instead if the
+    /// send does not complete within `timeout`.
+    ///
+    /// Simulated sends complete on the tick they are made on, so they never
+    /// time out.
+    pub fn send_timeout(
+        &mut self,
+        sock: Rc<socket2::Socket>,
+        buf: Bytes,
+        n: usize,
+        _timeout: Duration,
+        cb: SendCallback<C>,
+        _on_timeout: TimeoutCallback<C>,
+    ) {
+        self.send(sock, buf, n, cb);
+    }
+
+    /// Send the first `n` bytes of `buf` on `sock`, and call `cb` with `n`
+    /// once all of them have been written, or with zero if the send fails.
     pub fn send(&mut self, sock: Rc<socket2::Socket>, buf: Bytes, n: usize, cb: SendCallback<C>) {
         let sockfd = sock.as_raw_fd();
         log::trace!("IO -> send(sockfd={})", sockfd);
+        if let Some(replay) = &mut self.replay {
+            // The send completes when the trace says it does.
+            let pending_sends = replay.pending_sends.entry(sockfd).or_default();
+            pending_sends.push_back((buf, cb));
+            return;
+        }
         let socket = self.conn_sockets.get(&sockfd).unwrap();
         let localfd = socket.local_sock.as_raw_fd();
         assert!(localfd == sockfd);
-        socket.xmit_queue.borrow_mut().push_back(buf.clone());
-        let c = Completion::Send { sock, buf, n, cb };
+        // Sending to a peer that has closed the connection, or on a reset
+        // connection, completes with zero bytes sent, like a failed send does
+        // with real sockets.
+        let remotefd = socket.remote_sock.as_raw_fd();
+        if !self.conn_sockets.contains_key(&remotefd)
+            || socket.reset.get()
+            || self.is_reset(remotefd)
+        {
+            let c = Completion::Send {
+                sock,
+                buf,
+                n: 0,
+                rest: None,
+                cb,
+            };
+            self.enqueue(c);
+            return;
+        }
+        if socket.accepted && self.rng.gen_bool(self.faults.reset_prob) {
+            let off = reset_offset(&mut self.rng, &buf);
+            off.hash(&mut self.digest);
+            if let Some(trace) = &mut self.trace {
+                trace.events.push(TraceEvent::Reset {
+                    tick: (self.clock.now().as_nanos() / TICK.as_nanos()) as u64,
+                    sock: self.sock_ids[&sockfd],
+                    off,
+                });
+            }
+            log::trace!(
+                "IO -> reset(sockfd={}, n={}, off={})",
+                sockfd,
+                buf.len(),
+                off
+            );
+            // The peer receives the bytes that are still in flight, and the
+            // bytes of this message before the reset.
+            let mut in_flight = BytesMut::new();
+            for buf in socket.xmit_queue.borrow_mut().drain(..) {
+                in_flight.extend_from_slice(&buf);
+            }
+            in_flight.extend_from_slice(&buf[..off]);
+            socket.reset.set(true);
+            let in_flight = Some(in_flight.freeze()).filter(|buf| !buf.is_empty());
+            self.resets.insert(remotefd, in_flight);
+            let c = Completion::Send {
+                sock,
+                buf,
+                n: 0,
+                rest: None,
+                cb,
+            };
+            self.enqueue(c);
+            return;
+        }
+        let mut wire = buf.clone();
+        if socket.accepted
+            && self.faults.corrupt_prob > 0.0
+            && self.rng.gen_bool(self.faults.corrupt_prob)
+        {
+            if let Some((corrupted, offsets)) = corrupt_body(&mut self.rng, &buf) {
+                offsets.hash(&mut self.digest);
+                if let Some(trace) = &mut self.trace {
+                    trace.events.push(TraceEvent::Corrupt {
+                        tick: (self.clock.now().as_nanos() / TICK.as_nanos()) as u64,
+                        sock: self.sock_ids[&sockfd],
+                        offsets: offsets.clone(),
+                    });
+                }
+                log::trace!(
+                    "IO -> corrupt(sockfd={}, n={}, offsets={:?})",
+                    sockfd,
+                    buf.len(),
+                    offsets
+                );
+                // The sender's buffer stays intact, as the bytes are
+                // corrupted on the wire.
+                wire = corrupted;
+            }
+        }
+        let mut rest = None;
+        if socket.accepted
+            && buf.len() > 1
+            && self.faults.short_write_prob > 0.0
+            && self.rng.gen_bool(self.faults.short_write_prob)
+        {
+            let off = self.rng.gen_range(1..buf.len());
+            off.hash(&mut self.digest);
+            log::trace!(
+                "IO -> short_write(sockfd={}, n={}, off={})",
+                sockfd,
+                buf.len(),
+                off
+            );
+            socket.xmit_queue.borrow_mut().push_back(wire.slice(..off));
+            rest = Some(wire.slice(off..));
+        } else {
+            socket.xmit_queue.borrow_mut().push_back(wire);
+        }
+        let c = Completion::Send {
+            sock,
+            buf,
+            n,
+            rest,
+            cb,
+        };
         self.enqueue(c);
     }
 
+    /// Write the rest of a message that a short write left over, unless the
+    /// connection has been closed or reset since.
+    fn write_rest(&self, sockfd: i32, rest: Bytes) -> bool {
+        let socket = match self.conn_sockets.get(&sockfd) {
+            Some(socket) => socket,
+            None => return false,
+        };
+        let remotefd = socket.remote_sock.as_raw_fd();
+        if !self.conn_sockets.contains_key(&remotefd)
+            || socket.reset.get()
+            || self.is_reset(remotefd)
+        {
+            return false;
+        }
+        socket.xmit_queue.borrow_mut().push_back(rest);
+        true
+    }
+
+    /// Check if the socket has reset its connection.
+    fn is_reset(&self, sockfd: i32) -> bool {
+        self.conn_sockets
+            .get(&sockfd)
+            .is_some_and(|socket| socket.reset.get())
+    }
+
     fn enqueue(&self, c: Completion<C>) {
         let mut completions = self.completions.borrow_mut();
         completions.push_back(c);
     }
 }
 
+fn format_addr(addr: &socket2::SockAddr) -> String {
+    match addr.as_socket() {
+        Some(addr) => addr.to_string(),
+        None => format!("{:?}", addr),
+    }
+}
+
+fn parse_addr(addr: &str) -> socket2::SockAddr {
+    let addr: std::net::SocketAddr = addr.parse().unwrap();
+    addr.into()
+}
+
+/// Corrupt one to three bytes of the body of an HTTP message, returning the
+/// corrupted message and the offsets of the corrupted bytes, or `None` if the
+/// message has no body with a `Content-Length` to corrupt.
+///
+/// A corrupted byte is either zeroed or has all of its bits flipped, so that
+/// a text body never stays valid UTF-8 text without control characters.
+fn corrupt_body(rng: &mut ChaCha8Rng, buf: &[u8]) -> Option<(Bytes, Vec<usize>)> {
+    if !buf.starts_with(b"HTTP/") {
+        return None;
+    }
+    let header_len = buf.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+    let head = std::str::from_utf8(&buf[..header_len])
+        .ok()?
+        .to_ascii_lowercase();
+    if header_len == buf.len() || !head.contains("content-length:") {
+        return None;
+    }
+    let mut corrupted = BytesMut::from(buf);
+    let mut offsets: Vec<usize> = (0..rng.gen_range(1..=3))
+        .map(|_| rng.gen_range(header_len..buf.len()))
+        .collect();
+    offsets.sort_unstable();
+    offsets.dedup();
+    for &off in &offsets {
+        if rng.gen_bool(0.5) || corrupted[off] == 0 {
+            corrupted[off] ^= 0xff;
+        } else {
+            corrupted[off] = 0;
+        }
+    }
+    Some((corrupted.freeze(), offsets))
+}
+
+/// Choose the offset in a message at which the connection is reset: before
+/// the header, in the middle of the body, or after the body.
+///
+/// A message without a header, like a chunk of a streamed response, is all
+/// body.
+fn reset_offset(rng: &mut ChaCha8Rng, buf: &[u8]) -> usize {
+    let header_len = buf
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(0, |pos| pos + 4);
+    match rng.gen_range(0..3) {
+        0 => 0,
+        1 if header_len < buf.len() => rng.gen_range(header_len..buf.len()),
+        1 => header_len,
+        _ => buf.len(),
+    }
+}
+
 pub enum Completion<C> {
     Connect {
         sock: Rc<socket2::Socket>,
@@ -209,7 +1075,10 @@ pub enum Completion<C> {
         client_addr: socket2::SockAddr,
         cb: AcceptCallback<C>,
     },
-    Close,
+    Close {
+        sock: Rc<socket2::Socket>,
+        cb: CloseCallback<C>,
+    },
     Recv {
         sock: Rc<socket2::Socket>,
         buf: Bytes,
@@ -219,8 +1088,18 @@ pub enum Completion<C> {
         sock: Rc<socket2::Socket>,
         buf: Bytes,
         n: usize,
+        /// The end of `buf` that a short write has not written yet.
+        rest: Option<Bytes>,
         cb: SendCallback<C>,
     },
+    Timeout {
+        sock: Rc<socket2::Socket>,
+        cb: TimeoutCallback<C>,
+    },
+    Sleep {
+        sock: Rc<socket2::Socket>,
+        cb: TimeoutCallback<C>,
+    },
 }
 
 impl<C> std::fmt::Debug for Completion<C> {
@@ -228,21 +1107,47 @@ impl<C> std::fmt::Debug for Completion<C> {
         match self {
             Completion::Connect { .. } => write!(f, "Connect"),
             Completion::Accept { .. } => write!(f, "Accept"),
-            Completion::Close => write!(f, "Close"),
+            Completion::Close { .. } => write!(f, "Close"),
             Completion::Recv { .. } => write!(f, "Recv"),
             Completion::Send { .. } => write!(f, "Send"),
+            Completion::Timeout { .. } => write!(f, "Timeout"),
+            Completion::Sleep { .. } => write!(f, "Sleep"),
         }
     }
 }
 
 impl<C> Completion<C> {
+    /// The socket that the completion is for.
+    fn sockfd(&self) -> i32 {
+        match self {
+            Completion::Accept { server_sock, .. } => server_sock.as_raw_fd(),
+            Completion::Connect { sock, .. }
+            | Completion::Close { sock, .. }
+            | Completion::Recv { sock, .. }
+            | Completion::Send { sock, .. }
+            | Completion::Timeout { sock, .. }
+            | Completion::Sleep { sock, .. } => sock.as_raw_fd(),
+        }
+    }
+
+    /// Hash what the completion is, but not the sockets it refers to, whose
+    /// file descriptors vary between simulations.
+    fn hash_kind<H: Hasher>(&self, state: &mut H) {
+        format!("{:?}", self).hash(state);
+        if let Completion::Recv { buf, .. } | Completion::Send { buf, .. } = self {
+            buf.hash(state);
+        }
+    }
+
     fn key(&self) -> usize {
         match self {
             Completion::Connect { sock, .. } => sock.as_raw_fd() as usize,
             Completion::Accept { server_sock, .. } => server_sock.as_raw_fd() as usize,
-            Completion::Close => todo!(),
+            Completion::Close { sock, .. } => sock.as_raw_fd() as usize,
             Completion::Recv { sock, .. } => sock.as_raw_fd() as usize,
             Completion::Send { sock, .. } => sock.as_raw_fd() as usize,
+            Completion::Timeout { sock, .. } => sock.as_raw_fd() as usize,
+            Completion::Sleep { sock, .. } => sock.as_raw_fd() as usize,
         }
     }
 
@@ -250,11 +1155,11 @@ impl<C> Completion<C> {
         match self {
             Completion::Connect { .. } => {}
             Completion::Accept { .. } => {}
-            Completion::Close => {
-                todo!();
-            }
+            Completion::Close { .. } => {}
             Completion::Recv { .. } => {}
             Completion::Send { .. } => {}
+            Completion::Timeout { .. } => {}
+            Completion::Sleep { .. } => {}
         }
     }
 
@@ -272,16 +1177,28 @@ impl<C> Completion<C> {
             } => {
                 cb(io, server_sock, server_addr, client_sock, client_addr);
             }
-            Completion::Close => {
-                todo!();
+            Completion::Close { sock, cb } => {
+                cb(io, sock);
             }
             Completion::Recv { sock, buf, cb } => {
                 let n = buf.len();
                 cb(io, sock, &buf, n);
             }
-            Completion::Send { sock, buf, n, cb } => {
+            Completion::Send {
+                sock, n, rest, cb, ..
+            } => {
+                // A send fails if the connection goes away before it has
+                // written all of the message.
+                let written = match rest {
+                    Some(rest) => io.write_rest(sock.as_raw_fd(), rest),
+                    None => true,
+                };
+                let n = if written { n } else { 0 };
                 cb(io, sock, n);
             }
+            Completion::Timeout { sock, cb } | Completion::Sleep { sock, cb } => {
+                cb(io, sock);
+            }
         }
     }
 }
@@ -294,3 +1211,325 @@ type AcceptCallback<C> =
 type RecvCallback<C> = fn(&mut IO<C>, Rc<socket2::Socket>, &[u8], usize);
 
 type SendCallback<C> = fn(&mut IO<C>, Rc<socket2::Socket>, usize);
+
+type TimeoutCallback<C> = fn(&mut IO<C>, Rc<socket2::Socket>);
+
+type CloseCallback<C> = fn(&mut IO<C>, Rc<socket2::Socket>);
+
+#[cfg(test)]
+mod test {
+    use super::{corrupt_body, Faults, Latency, IO};
+    use bytes::Bytes;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+    use socket2::{Domain, SockAddr, Socket, Type};
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::os::fd::AsRawFd;
+    use std::rc::Rc;
+
+    type TestIO = IO<RefCell<Vec<u8>>>;
+
+    fn on_accept(
+        io: &mut TestIO,
+        server_sock: Rc<Socket>,
+        server_addr: SockAddr,
+        client_sock: Rc<Socket>,
+        _client_addr: SockAddr,
+    ) {
+        io.accept(server_sock, server_addr, on_accept);
+        io.recv(client_sock, on_server_recv);
+    }
+
+    fn on_server_recv(io: &mut TestIO, sock: Rc<Socket>, buf: &[u8], n: usize) {
+        let buf = Bytes::copy_from_slice(&buf[..n]);
+        io.send(sock.clone(), buf, n, |_, _, _| {});
+        io.recv(sock, on_server_recv);
+    }
+
+    fn on_connect(io: &mut TestIO, sock: Rc<Socket>, _addr: SockAddr) {
+        for msg in ["0", "1", "2"] {
+            io.send(
+                sock.clone(),
+                Bytes::from_static(msg.as_bytes()),
+                1,
+                |_, _, _| {},
+            );
+        }
+        io.recv(sock, on_client_recv);
+    }
+
+    fn on_client_recv(io: &mut TestIO, sock: Rc<Socket>, buf: &[u8], n: usize) {
+        io.context().borrow_mut().extend_from_slice(&buf[..n]);
+        io.recv(sock, on_client_recv);
+    }
+
+    /// Echo three messages and return what the client received, and the
+    /// digest of the completions.
+    fn echo(faults: Faults) -> (Vec<u8>, u64) {
+        let mut io = IO::with_faults(RefCell::new(Vec::new()), faults);
+        let addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.accept(server_sock, addr.into(), on_accept);
+        let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.connect(client_sock, addr.into(), on_connect);
+        for _ in 0..200 {
+            io.run_once();
+        }
+        let received = io.context().borrow().clone();
+        (received, io.completion_digest())
+    }
+
+    #[test]
+    fn connections_wait_in_backlog() {
+        let mut io = IO::new(RefCell::new(Vec::new()));
+        let addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.accept(
+            server_sock,
+            addr.into(),
+            |io, server_sock, server_addr, _, _| {
+                io.context().borrow_mut().push(b'a');
+                io.accept(server_sock, server_addr, |io, _, _, _, _| {
+                    io.context().borrow_mut().push(b'a')
+                });
+            },
+        );
+        // The second connection is made before the listener accepts again.
+        for _ in 0..2 {
+            let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+            io.connect(client_sock, addr.into(), |_, _, _| {});
+        }
+        for _ in 0..2 {
+            io.run_once();
+        }
+        assert_eq!(*io.context().borrow(), b"aa");
+    }
+
+    fn on_accept_in_order(
+        io: &mut TestIO,
+        server_sock: Rc<Socket>,
+        server_addr: SockAddr,
+        _client_sock: Rc<Socket>,
+        client_addr: SockAddr,
+    ) {
+        // The clients are told apart by the ports they are bound to, which
+        // follow the order they connected in.
+        let port = client_addr.as_socket().unwrap().port();
+        io.context().borrow_mut().push((port - 30000) as u8);
+        io.accept(server_sock, server_addr, on_accept_in_order);
+    }
+
+    /// Connect three clients on the same tick and return the order the
+    /// listener accepted them in.
+    fn accept_order(seed: u64) -> Vec<u8> {
+        let faults = Faults {
+            seed,
+            ..Faults::default()
+        };
+        let mut io = IO::with_faults(RefCell::new(Vec::new()), faults);
+        let addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.accept(server_sock, addr.into(), on_accept_in_order);
+        for _ in 0..3 {
+            let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+            io.connect(client_sock, addr.into(), |_, _, _| {});
+        }
+        for _ in 0..3 {
+            io.run_once();
+        }
+        let order = io.context().borrow().clone();
+        order
+    }
+
+    #[test]
+    fn simultaneous_connects_are_accepted_in_seeded_order() {
+        for seed in 0..8 {
+            let order = accept_order(seed);
+            let mut accepted = order.clone();
+            accepted.sort();
+            assert_eq!(accepted, [0, 2, 4]);
+            assert_eq!(accept_order(seed), order, "seed {}", seed);
+        }
+        // The order is drawn from the seed, not fixed by the order in which
+        // the clients connected.
+        let orders: HashSet<Vec<u8>> = (0..8).map(accept_order).collect();
+        assert!(orders.len() > 1);
+    }
+
+    #[test]
+    fn close_releases_socket() {
+        let mut io = IO::new(RefCell::new(Vec::new()));
+        let addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.accept(server_sock, addr.into(), |_, _, _, _, _| {});
+        let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        let client_fd = client_sock.as_raw_fd();
+        io.connect(client_sock.clone(), addr.into(), |_, _, _| {});
+        io.run_once();
+        assert!(io.conn_sockets.contains_key(&client_fd));
+
+        // Closing the socket cancels its pending receive.
+        io.recv_timeout(
+            client_sock.clone(),
+            std::time::Duration::from_secs(1),
+            |_, _, _, _| panic!("Closed socket received"),
+            |_, _| panic!("Closed socket timed out"),
+        );
+        io.close(client_sock.clone(), |io, _| {
+            io.context().borrow_mut().push(b'c')
+        });
+        // Closing the socket again is a no-op.
+        io.close(client_sock, |io, _| io.context().borrow_mut().push(b'c'));
+        for _ in 0..2_000 {
+            io.run_once();
+        }
+        assert_eq!(*io.context().borrow(), b"cc");
+        assert!(!io.conn_sockets.contains_key(&client_fd));
+        assert!(!io.recv_listeners.contains_key(&client_fd));
+        assert!(!io.recv_timeouts.contains_key(&client_fd));
+        assert!(io.timeouts.is_empty());
+    }
+
+    #[test]
+    fn corruption_is_deterministic_and_spares_the_head() {
+        let msg = b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\n{\"ok\":true}";
+        let head_len = msg.len() - 11;
+        let corrupt = |seed| corrupt_body(&mut ChaCha8Rng::seed_from_u64(seed), msg).unwrap();
+        let (corrupted, offsets) = corrupt(1);
+        assert_eq!(corrupt(1), (corrupted.clone(), offsets.clone()));
+        assert!(!offsets.is_empty());
+        assert_eq!(&corrupted[..head_len], &msg[..head_len]);
+        for (off, (a, b)) in msg.iter().zip(corrupted.iter()).enumerate() {
+            assert_eq!(a != b, offsets.contains(&off));
+        }
+        // A message without a body is left alone.
+        let empty = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+        assert!(corrupt_body(&mut ChaCha8Rng::seed_from_u64(1), empty).is_none());
+    }
+
+    #[test]
+    fn latency_is_deterministic() {
+        let faults = |seed| Faults {
+            seed,
+            latency_ms: Some(Latency {
+                min_ms: 1,
+                max_ms: 50,
+            }),
+            ..Default::default()
+        };
+        let (received, digest) = echo(faults(1));
+        // Latency delays the bytes of a stream, but does not reorder them.
+        assert_eq!(received, b"012");
+        assert_eq!(echo(faults(1)).1, digest);
+        assert_eq!(echo(Faults::default()).0, b"012");
+    }
+
+    // Keep the socket busy with two more sends for every send that
+    // completes, recording the socket of every completion.
+    fn on_saturating_send(io: &mut TestIO, sock: Rc<Socket>, _n: usize) {
+        io.context().borrow_mut().push(sock.as_raw_fd() as u8);
+        for _ in 0..2 {
+            io.send(
+                sock.clone(),
+                Bytes::from_static(b"x"),
+                1,
+                on_saturating_send,
+            );
+        }
+    }
+
+    #[test]
+    fn saturated_connections_take_turns() {
+        let mut io = IO::new(RefCell::new(Vec::new())).with_max_events_per_tick(4);
+        let addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut fds = Vec::new();
+        for _ in 0..2 {
+            let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+            fds.push(sock.as_raw_fd() as u8);
+            io.connect(sock.clone(), addr.into(), |_, _, _| {});
+            io.send(sock, Bytes::from_static(b"x"), 1, on_saturating_send);
+        }
+        io.run_once();
+        for _ in 0..1_000 {
+            io.context().borrow_mut().clear();
+            io.run_once();
+            let completed = io.context().borrow();
+            assert_eq!(completed.len(), 4);
+            // Neither connection gets more than its share of the tick.
+            for fd in &fds {
+                assert_eq!(completed.iter().filter(|c| *c == fd).count(), 2);
+            }
+        }
+    }
+
+    fn on_send_a(io: &mut TestIO, _sock: Rc<Socket>, _n: usize) {
+        io.context().borrow_mut().push(b'a');
+    }
+
+    fn on_send_b(io: &mut TestIO, sock: Rc<Socket>, _n: usize) {
+        io.context().borrow_mut().push(b'b');
+        io.send(sock, Bytes::from_static(b"x"), 1, on_send_c);
+    }
+
+    fn on_send_c(io: &mut TestIO, _sock: Rc<Socket>, _n: usize) {
+        io.context().borrow_mut().push(b'c');
+    }
+
+    /// Run two sends on a socket, the second of which makes a third, and
+    /// return the order their completions ran in.
+    fn send_order(max_events: Option<usize>) -> Vec<u8> {
+        let mut io = IO::new(RefCell::new(Vec::new()));
+        if let Some(max_events) = max_events {
+            io = io.with_max_events_per_tick(max_events);
+        }
+        let addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.connect(sock.clone(), addr.into(), |_, _, _| {});
+        io.send(sock.clone(), Bytes::from_static(b"x"), 1, on_send_a);
+        io.send(sock, Bytes::from_static(b"x"), 1, on_send_b);
+        for _ in 0..10 {
+            io.run_once();
+        }
+        io.context().take()
+    }
+
+    #[test]
+    fn deferred_completions_keep_their_order() {
+        let order = send_order(None);
+        assert_eq!(order.len(), 3);
+        // The completion that did not get a turn runs before the one that
+        // was queued after it.
+        assert_eq!(send_order(Some(1)), order);
+    }
+
+    #[test]
+    fn short_write_sends_whole_message() {
+        const MSG: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhiisi";
+        let faults = Faults {
+            seed: 1,
+            short_write_prob: 1.0,
+            ..Default::default()
+        };
+        let mut io = IO::with_faults(RefCell::new(Vec::new()), faults);
+        let addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.accept(server_sock, addr.into(), |io, _, _, client_sock, _| {
+            io.send(
+                client_sock,
+                Bytes::from_static(MSG),
+                MSG.len(),
+                |_, _, n| assert_eq!(n, MSG.len()),
+            );
+        });
+        let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.connect(client_sock, addr.into(), |io, sock, _| {
+            io.recv(sock, on_client_recv)
+        });
+        for _ in 0..10 {
+            io.run_once();
+        }
+        // The message arrives in two parts, in order.
+        assert_eq!(*io.context().borrow(), MSG);
+    }
+}
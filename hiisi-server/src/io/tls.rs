@@ -0,0 +1,105 @@
+//! TLS termination for the connections of the generic IO.
+//!
+//! A TLS connection reads the records that its socket receives, decrypts
+//! them into a plaintext buffer that receives complete with, and encrypts
+//! what is sent on it. The handshake happens as part of the receives, which
+//! complete only once there is plaintext or the connection has ended.
+
+use bytes::BytesMut;
+use rustls::{ServerConfig, ServerConnection};
+
+use std::io::{ErrorKind, Read, Write};
+use std::sync::Arc;
+
+pub(crate) struct TlsConn {
+    conn: ServerConnection,
+    /// Decrypted bytes that have not been received yet.
+    plaintext: BytesMut,
+    /// Whether the connection has ended, because the peer closed it or
+    /// because of a TLS error.
+    eof: bool,
+}
+
+impl TlsConn {
+    pub(crate) fn new(config: Arc<ServerConfig>) -> Self {
+        Self {
+            conn: ServerConnection::new(config).expect("TLS configuration is valid"),
+            plaintext: BytesMut::new(),
+            eof: false,
+        }
+    }
+
+    /// Read the records that `sock` has received, and send what the TLS
+    /// state machine has to send back, such as the next handshake message.
+    ///
+    /// Returns whether a receive can complete, that is, there is plaintext
+    /// to receive or the connection has ended.
+    pub(crate) fn read(&mut self, sock: &socket2::Socket) -> bool {
+        let mut sock_ref = sock;
+        match self.conn.read_tls(&mut sock_ref) {
+            Ok(0) => self.eof = true,
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => {
+                log::debug!("Failed to receive on sockfd {:?}: {}", sock, err);
+                self.eof = true;
+            }
+        }
+        if let Err(err) = self.conn.process_new_packets() {
+            // A failed handshake ends the connection, after the alert that
+            // tells the peer why.
+            log::debug!("TLS error on sockfd {:?}: {}", sock, err);
+            let _ = self.flush(sock);
+            self.eof = true;
+            return true;
+        }
+        let mut buf = [0; 4096];
+        loop {
+            match self.conn.reader().read(&mut buf) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(n) => self.plaintext.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                // The peer closed the connection without a close_notify.
+                Err(_) => {
+                    self.eof = true;
+                    break;
+                }
+            }
+        }
+        if let Err(err) = self.flush(sock) {
+            log::debug!("Failed to send on sockfd {:?}: {}", sock, err);
+            self.eof = true;
+        }
+        self.eof || !self.plaintext.is_empty()
+    }
+
+    /// Take the plaintext received so far, which is empty once the
+    /// connection has ended.
+    pub(crate) fn take_plaintext(&mut self) -> BytesMut {
+        self.plaintext.split()
+    }
+
+    /// Encrypt `buf` and send it on `sock`.
+    pub(crate) fn write(&mut self, sock: &socket2::Socket, buf: &[u8]) -> std::io::Result<usize> {
+        self.conn.writer().write_all(buf)?;
+        self.flush(sock)?;
+        Ok(buf.len())
+    }
+
+    /// Tell the peer that the connection is closing.
+    pub(crate) fn close(&mut self, sock: &socket2::Socket) {
+        self.conn.send_close_notify();
+        let _ = self.flush(sock);
+    }
+
+    fn flush(&mut self, sock: &socket2::Socket) -> std::io::Result<()> {
+        let mut sock_ref = sock;
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut sock_ref)?;
+        }
+        Ok(())
+    }
+}
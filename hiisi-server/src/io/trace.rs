@@ -0,0 +1,146 @@
+//! Traces of the simulated IO.
+//!
+//! A trace records the completions of a simulation in the order they were
+//! run, along with the faults that were injected. Sockets are identified by
+//! the order they were registered in rather than by file descriptor, which
+//! differs between runs.
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+
+use crate::{HiisiError, Result};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TraceEvent {
+    /// The socket `sock` connected to `addr`.
+    Connect { tick: u64, sock: u64, addr: String },
+    /// The listener on `addr` accepted a connection from `peer` as the
+    /// socket `sock`.
+    Accept {
+        tick: u64,
+        sock: u64,
+        addr: String,
+        peer: String,
+    },
+    /// The socket `sock` received `data`, which is empty at end-of-file.
+    Recv {
+        tick: u64,
+        sock: u64,
+        #[serde(with = "crate::proto::bytes_as_base64")]
+        data: Bytes,
+    },
+    /// The socket `sock` sent `n` bytes of `data`.
+    Send {
+        tick: u64,
+        sock: u64,
+        #[serde(with = "crate::proto::bytes_as_base64")]
+        data: Bytes,
+        n: usize,
+    },
+    /// A delivery to the socket `sock` was delayed by `delay_ms`.
+    Latency { tick: u64, sock: u64, delay_ms: u64 },
+    /// The socket `sock` reset its connection `off` bytes into a message.
+    Reset { tick: u64, sock: u64, off: usize },
+    /// The socket `sock` sent a message whose bytes at `offsets` the
+    /// network corrupted.
+    Corrupt {
+        tick: u64,
+        sock: u64,
+        offsets: Vec<usize>,
+    },
+    /// The pending receive on the socket `sock` timed out.
+    Timeout { tick: u64, sock: u64 },
+}
+
+impl TraceEvent {
+    /// The tick the event happened on.
+    pub fn tick(&self) -> u64 {
+        match self {
+            TraceEvent::Connect { tick, .. }
+            | TraceEvent::Accept { tick, .. }
+            | TraceEvent::Recv { tick, .. }
+            | TraceEvent::Send { tick, .. }
+            | TraceEvent::Latency { tick, .. }
+            | TraceEvent::Reset { tick, .. }
+            | TraceEvent::Corrupt { tick, .. }
+            | TraceEvent::Timeout { tick, .. } => *tick,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TraceHeader {
+    seed: u64,
+}
+
+/// A trace of a simulation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trace {
+    /// The seed that the simulation was run with.
+    pub seed: u64,
+    pub events: Vec<TraceEvent>,
+}
+
+impl Trace {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            events: Vec::new(),
+        }
+    }
+
+    /// Write the trace as JSON lines: the header with the seed, followed by
+    /// an event per line.
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        let header = TraceHeader { seed: self.seed };
+        let write_line = |w: &mut W, line: Vec<u8>| {
+            w.write_all(&line)
+                .and_then(|_| w.write_all(b"\n"))
+                .map_err(|e| HiisiError::IOError("write", e))
+        };
+        write_line(&mut w, serde_json::to_vec(&header)?)?;
+        for event in &self.events {
+            write_line(&mut w, serde_json::to_vec(event)?)?;
+        }
+        Ok(())
+    }
+
+    /// Read a trace that was written with `write()`.
+    pub fn read<R: BufRead>(r: R) -> Result<Self> {
+        let mut lines = r.lines();
+        let header = match lines.next() {
+            Some(line) => line.map_err(|e| HiisiError::IOError("read", e))?,
+            None => return Err(HiisiError::ProtocolError("Empty trace".to_owned())),
+        };
+        let header: TraceHeader = serde_json::from_str(&header)?;
+        let mut trace = Trace::new(header.seed);
+        for line in lines {
+            let line = line.map_err(|e| HiisiError::IOError("read", e))?;
+            trace.events.push(serde_json::from_str(&line)?);
+        }
+        Ok(trace)
+    }
+
+    /// The events on the server side of the connections, which are the
+    /// sockets that were accepted.
+    pub fn server_events(&self) -> Vec<&TraceEvent> {
+        let mut accepted = HashSet::new();
+        self.events
+            .iter()
+            .filter(|event| match event {
+                TraceEvent::Accept { sock, .. } => {
+                    accepted.insert(*sock);
+                    true
+                }
+                TraceEvent::Recv { sock, .. }
+                | TraceEvent::Send { sock, .. }
+                | TraceEvent::Timeout { sock, .. } => accepted.contains(sock),
+                _ => false,
+            })
+            .collect()
+    }
+}
@@ -1,15 +1,26 @@
 pub mod admin;
+pub mod baton;
+pub mod buffer;
+pub mod client;
+pub mod clock;
+pub mod cursor;
 pub mod database;
 pub mod error;
 pub mod executor;
+pub mod filter;
 pub mod http;
 pub mod io;
 pub mod manager;
 pub mod proto;
+pub mod ratelimit;
 pub mod server;
+pub mod session;
+pub mod stats;
+pub mod storage;
+pub mod websocket;
 
 pub type Result<T> = std::result::Result<T, error::HiisiError>;
 
 pub use error::HiisiError;
 pub use manager::ResourceManager;
-pub use server::{serve, Context, IO};
+pub use server::{serve, serve_all, Context, IO};
@@ -1,17 +1,19 @@
 use clap::Parser;
-use socket2::{Domain, SockAddr, Socket, Type};
 
 use std::net::SocketAddr;
-use std::rc::Rc;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
 
 use std::path::PathBuf;
+use std::time::Duration;
 
 use ctrlc;
-use hiisi::{Context, HiisiError, ResourceManager, Result, IO};
+use hiisi::manager::DirLayout;
+use hiisi::ratelimit::RateLimit;
+use hiisi::server::Builder;
+use hiisi::{ResourceManager, Result};
 
 #[derive(Parser)]
 #[command(name = "Hiisi")]
@@ -19,12 +21,89 @@ struct Cli {
     #[clap(long, short, default_value = "data", env = "SQLD_DB_PATH")]
     db_path: PathBuf,
 
+    /// How the databases are laid out in the data directory: `named` for
+    /// `<name>/<name>.db`, `flat` for `<name>.db`, or `nested` for
+    /// `<name>/data.db`.
+    #[clap(long, default_value = "named")]
+    dir_layout: DirLayout,
+
     #[arg(long, default_value = "127.0.0.1:8080", env = "SQLD_HTTP_LISTEN_ADDR")]
     http_listen_addr: SocketAddr,
 
     /// The address and port for the admin HTTP API.
     #[clap(long, env = "SQLD_ADMIN_LISTEN_ADDR")]
     admin_listen_addr: Option<SocketAddr>,
+
+    /// The maximum number of client connections open at once.
+    #[clap(long, default_value_t = hiisi::server::MAX_CONNECTIONS)]
+    max_connections: usize,
+
+    /// The maximum size of a request body in bytes.
+    #[clap(long, default_value_t = hiisi::server::MAX_BODY_BYTES)]
+    max_body_bytes: usize,
+
+    /// The maximum number of idle SQLite connections to keep per database.
+    #[clap(long, default_value_t = hiisi::manager::DEFAULT_POOL_SIZE)]
+    pool_size: usize,
+
+    /// The number of seconds after which the server rolls back a
+    /// transaction that the client has stopped using.
+    #[clap(long, default_value_t = hiisi::manager::TRANSACTION_TIMEOUT.as_secs())]
+    transaction_timeout: u64,
+
+    /// The number of milliseconds that the statement of an execute request
+    /// waits for a lock that another connection holds before it fails with
+    /// `SQLITE_BUSY`.
+    #[clap(long, default_value_t = 0)]
+    busy_timeout_ms: u64,
+
+    /// The maximum number of virtual machine instructions that a statement
+    /// may run before the server interrupts it.
+    #[clap(long)]
+    step_limit: Option<u32>,
+
+    /// Log the statements that run for this many milliseconds or longer.
+    #[clap(long)]
+    slow_query_ms: Option<u64>,
+
+    /// The maximum number of rows in the result of a statement, unless the
+    /// result is read with a cursor.
+    #[clap(long)]
+    max_result_rows: Option<usize>,
+
+    /// The number of requests per second that a database serves, with
+    /// the rest of the requests rejected with HTTP 429.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit: Option<u32>,
+
+    /// The number of requests that a database serves at once before the
+    /// rate limit applies. Defaults to the rate limit.
+    #[clap(long, requires = "rate_limit", value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit_burst: Option<u32>,
+
+    /// Apply the rate limit to every client address on its own.
+    #[clap(long, requires = "rate_limit")]
+    rate_limit_per_client: bool,
+
+    /// Reject statement arguments whose types do not match how the
+    /// statements use their parameters.
+    #[clap(long)]
+    strict_args: bool,
+
+    /// The maximum size of the SQL text of a statement in bytes.
+    #[clap(long, default_value_t = hiisi::session::DEFAULT_MAX_SQL_BYTES)]
+    max_sql_bytes: usize,
+
+    /// The PEM file with the certificate chain to serve SQL HTTP requests
+    /// over TLS with.
+    #[cfg(feature = "tls")]
+    #[clap(long, requires = "tls_key_file")]
+    tls_cert_file: Option<PathBuf>,
+
+    /// The PEM file with the private key of the TLS certificate.
+    #[cfg(feature = "tls")]
+    #[clap(long, requires = "tls_cert_file")]
+    tls_key_file: Option<PathBuf>,
 }
 
 fn main() {
@@ -37,27 +116,43 @@ fn main() {
 }
 
 fn server_loop(cli: Cli) -> Result<()> {
-    log::info!(
-        "Listening for SQL HTTP requests on {:?}",
-        cli.http_listen_addr
-    );
-
-    let listen_addr: SockAddr = cli.http_listen_addr.into();
-    let sock = listen(&listen_addr)?;
-
-    let admin = match cli.admin_listen_addr {
-        Some(addr) => {
-            log::info!("Listening for admin HTTP requests on {:?}", addr);
-            let listen_addr: SockAddr = addr.into();
-            let sock = listen(&listen_addr)?;
-            Some((listen_addr, sock))
+    let mut manager = ResourceManager::new(&cli.db_path, hiisi::baton::generate_key())
+        .with_dir_layout(cli.dir_layout)
+        .with_pool_size(cli.pool_size)
+        .with_transaction_timeout(Duration::from_secs(cli.transaction_timeout))
+        .with_busy_timeout(Duration::from_millis(cli.busy_timeout_ms))
+        .with_max_sql_bytes(cli.max_sql_bytes);
+    if let Some(step_limit) = cli.step_limit {
+        manager = manager.with_step_limit(step_limit);
+    }
+    if let Some(slow_query_ms) = cli.slow_query_ms {
+        manager = manager.with_slow_query_threshold(Duration::from_millis(slow_query_ms));
+    }
+    if let Some(max_result_rows) = cli.max_result_rows {
+        manager = manager.with_max_result_rows(max_result_rows);
+    }
+    if let Some(rate_limit) = cli.rate_limit {
+        let burst = cli.rate_limit_burst.unwrap_or(rate_limit);
+        manager = manager.with_rate_limit(RateLimit::new(rate_limit, burst));
+        if cli.rate_limit_per_client {
+            manager = manager.with_rate_limit_per_client();
         }
-        None => None,
-    };
-
-    let manager = Rc::new(ResourceManager::new(&cli.db_path));
-    let ctx = Context::<()>::new(manager, ());
-    let mut io = IO::new(ctx);
+    }
+    if cli.strict_args {
+        manager = manager.with_strict_args();
+    }
+    let mut builder = Builder::new(cli.http_listen_addr)
+        .with_manager(manager)
+        .with_max_connections(cli.max_connections)
+        .with_max_body_bytes(cli.max_body_bytes);
+    if let Some(addr) = cli.admin_listen_addr {
+        builder = builder.with_admin_listen_addr(addr);
+    }
+    #[cfg(feature = "tls")]
+    if let (Some(cert_file), Some(key_file)) = (cli.tls_cert_file, cli.tls_key_file) {
+        builder = builder.with_tls(cert_file, key_file);
+    }
+    let mut io = builder.build(())?;
 
     let running = Arc::new(AtomicBool::new(true));
     ctrlc::set_handler({
@@ -68,32 +163,13 @@ fn server_loop(cli: Cli) -> Result<()> {
         }
     })
     .unwrap();
-    hiisi::serve(&mut io, sock, listen_addr);
-    if let Some((addr, sock)) = admin {
-        hiisi::admin::serve_admin(&mut io, sock, addr);
-    }
     while running.load(Ordering::SeqCst) {
         io.run_once();
     }
+    hiisi::server::shutdown(&mut io);
     Ok(())
 }
 
-fn listen(addr: &SockAddr) -> Result<Rc<Socket>> {
-    let sock = Rc::new(
-        Socket::new(Domain::IPV4, Type::STREAM, None)
-            .map_err(|e| HiisiError::IOError("socket", e))?,
-    );
-    sock.bind(addr)
-        .map_err(|e| HiisiError::IOError("bind", e))?;
-    sock.set_reuse_address(true)
-        .map_err(|e| HiisiError::IOError("set_reuse_address", e))?;
-    sock.set_reuse_port(true)
-        .map_err(|e| HiisiError::IOError("set_reuse_port", e))?;
-    sock.listen(128)
-        .map_err(|e| HiisiError::IOError("listern", e))?;
-    Ok(sock)
-}
-
 fn init_logger() {
     let env = env_logger::Env::default().default_filter_or("info");
     env_logger::Builder::from_env(env).init();
@@ -1,11 +1,23 @@
 use sieve_cache::SieveCache;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
-use crate::database::{Connection, Database};
-use crate::Result;
+use crate::baton::BatonManager;
+use crate::clock::{Clock, WallClock};
+use crate::database::{Backup, Connection, Database, StepResult};
+use crate::filter::{KeywordFilter, StmtFilter};
+use crate::proto::Version;
+use crate::ratelimit::{RateLimit, RateLimiter};
+use crate::session::{Session, DEFAULT_MAX_SQL_BYTES};
+use crate::storage::{FileStorage, Storage};
+use crate::{HiisiError, Result};
 
 // Maximum per database page cache size in kibi-bytes.
 const MAX_PAGE_CACHE_SIZE: i64 = 1000;
@@ -13,79 +25,1665 @@ const MAX_PAGE_CACHE_SIZE: i64 = 1000;
 // Maximum number of resident connections to keep in the cache.
 const MAX_MEMORY_RESIDENT_DBS: usize = 10;
 
-// Maximum concurrent connections.
-const MAX_CONCURRENT_CONNS: usize = 100;
+// Maximum number of open sessions.
+const MAX_SESSIONS: usize = 100;
+
+// Default maximum number of idle connections to keep per database.
+pub const DEFAULT_POOL_SIZE: usize = 4;
+
+// Time after which a baton expires if the client does not use it.
+pub const BATON_EXPIRY: Duration = Duration::from_secs(10);
+
+// Default time after which a session that the client has left in a
+// transaction is rolled back.
+pub const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The largest page count that SQLite supports, which is the default limit of
+// the size of a database.
+const MAX_PAGE_COUNT: u32 = 0xffff_fffe;
+
+// Maximum length of a database name.
+const MAX_DB_NAME_LEN: usize = 64;
+
+// Number of in-memory resource managers created so far, which keeps the
+// in-memory databases of different managers apart.
+static IN_MEMORY_MANAGERS: AtomicU64 = AtomicU64::new(0);
+
+/// Check that a database name matches `^[A-Za-z0-9_-]{1,64}$`.
+///
+/// Database names become directory or file names in the data directory, so this
+/// rules out names like `..` that would escape it.
+fn is_valid_db_name(db_name: &str) -> bool {
+    !db_name.is_empty()
+        && db_name.len() <= MAX_DB_NAME_LEN
+        && db_name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Move the entry of a database in `map` from its old name to its new one.
+fn rename_key<V>(map: &RefCell<HashMap<String, V>>, from: &str, to: &str) {
+    let mut map = map.borrow_mut();
+    if let Some(value) = map.remove(from) {
+        map.insert(to.to_owned(), value);
+    }
+}
+
+/// Replace the old name of a database in `set` with its new one.
+fn rename_member(set: &RefCell<HashSet<String>>, from: &str, to: &str) {
+    let mut set = set.borrow_mut();
+    if set.remove(from) {
+        set.insert(to.to_owned());
+    }
+}
+
+/// How the databases are laid out in the data directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DirLayout {
+    /// Every database in a directory of its own, in a file named after the
+    /// database: `<data dir>/<name>/<name>.db`.
+    #[default]
+    Named,
+    /// Every database file directly in the data directory, with its `-wal`
+    /// and `-shm` sidecars next to it: `<data dir>/<name>.db`.
+    Flat,
+    /// Every database in a directory of its own, in a file named `data.db`:
+    /// `<data dir>/<name>/data.db`, so that a database can be backed up by
+    /// copying its directory.
+    Nested,
+}
+
+impl DirLayout {
+    /// The directory of a database, or `None` if the database file is
+    /// directly in the data directory.
+    fn db_dir(self, data_dir: &Path, db_name: &str) -> Option<PathBuf> {
+        match self {
+            DirLayout::Flat => None,
+            DirLayout::Named | DirLayout::Nested => Some(data_dir.join(db_name)),
+        }
+    }
+
+    fn db_file_path(self, data_dir: &Path, db_name: &str) -> PathBuf {
+        match self {
+            DirLayout::Named => data_dir.join(db_name).join(format!("{}.db", db_name)),
+            DirLayout::Flat => data_dir.join(format!("{}.db", db_name)),
+            DirLayout::Nested => data_dir.join(db_name).join("data.db"),
+        }
+    }
+}
+
+impl FromStr for DirLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "named" => Ok(DirLayout::Named),
+            "flat" => Ok(DirLayout::Flat),
+            "nested" => Ok(DirLayout::Nested),
+            _ => Err(format!(
+                "unknown directory layout `{}`, expected `named`, `flat` or `nested`",
+                s
+            )),
+        }
+    }
+}
+
+/// The replication index of a database, which counts the WAL frames that
+/// have been written to the database.
+#[derive(Default)]
+struct ReplicationIndex {
+    /// Number of frames in the WAL when the index was last advanced.
+    frame_count: u32,
+    index: u64,
+}
+
+/// The versions of the schema of a database.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+pub struct SchemaVersion {
+    /// The version that SQLite increments on every change to the schema.
+    pub schema_version: i64,
+    /// The version that the application sets with `PRAGMA user_version`.
+    pub user_version: i64,
+}
+
+/// The outcome of a checkpoint of the WAL of a database.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+pub struct Checkpoint {
+    /// Whether a reader or writer kept the checkpoint from completing, in
+    /// which case the WAL is left as long as it is.
+    pub busy: bool,
+    /// The number of frames in the WAL, or -1 if the database has no WAL.
+    pub log_frames: i64,
+    /// The number of frames that were copied back into the database file,
+    /// or -1 if the database has no WAL.
+    pub checkpointed_frames: i64,
+}
+
+/// A database together with the connection that keeps it open.
+type OpenDatabase = (Rc<Database>, Rc<Connection>);
+
+/// The registry of the databases of an in-memory resource manager.
+struct InMemoryDatabases {
+    /// Prefix of the names that SQLite knows the databases by.
+    prefix: String,
+    /// Number of databases created, which tells apart the names that SQLite
+    /// knows the databases by, as a renamed database keeps its name.
+    created: Cell<u64>,
+    dbs: RefCell<BTreeMap<String, OpenDatabase>>,
+}
 
 /// The resource manager is responsible for managing connections to databases,
 /// transactions, and more.
 pub struct ResourceManager {
     db_path: PathBuf,
 
+    /// How the databases are laid out in `db_path`.
+    dir_layout: DirLayout,
+
+    /// The databases of an in-memory resource manager, or `None` if the
+    /// databases are kept in the data directory.
+    ///
+    /// Every database keeps a connection open, because SQLite frees an
+    /// in-memory database when its last connection is closed.
+    in_memory: Option<InMemoryDatabases>,
+
     /// A cache of memory resident databases.
     ///
     /// We keep a tuple of database and connection in the cache because we
     /// need at least one connection to SQLite to keep the database in memory.
     memory_resident_dbs: RefCell<SieveCache<String, (Rc<Database>, Rc<Connection>)>>,
 
-    /// Open connections to databases.
+    /// Open sessions.
+    ///
+    /// This is map from session ids to sessions. Clients identify a session
+    /// with a baton that carries the session id. SQL statements executed
+    /// within a session are guaranteed to be executed with the same SQLite
+    /// connection, ensuring transaction and isolation guarantees.
+    sessions: RefCell<SieveCache<u64, Rc<Session>>>,
+
+    /// Ids of the sessions opened on each database.
+    ///
+    /// The session cache evicts sessions without telling, so some of the
+    /// ids may refer to sessions that no longer exist.
+    db_sessions: RefCell<HashMap<String, HashSet<u64>>>,
+
+    /// Idle connections of each database, which sessions take their
+    /// connection from before opening a new one.
+    ///
+    /// The databases are in WAL mode, so the readers and the writer of a
+    /// database can share the connections of its pool.
+    pools: RefCell<HashMap<String, Vec<Rc<Connection>>>>,
+
+    /// Maximum number of idle connections to keep per database.
+    pool_size: usize,
+
+    /// Replication indexes of the databases.
+    replication_indexes: RefCell<HashMap<String, ReplicationIndex>>,
+
+    /// Ids of the sessions that were in a transaction when their baton was
+    /// last issued.
+    ///
+    /// A transaction holds on to the locks of its database, so instead of
+    /// waiting for `BATON_EXPIRY`, a session whose client has left it in a
+    /// transaction for `transaction_timeout` is rolled back.
+    open_transactions: RefCell<BTreeSet<u64>>,
+
+    transaction_timeout: Duration,
+
+    /// How long a connection waits for a lock that another connection
+    /// holds before its statement fails with `SQLITE_BUSY`.
+    busy_timeout: Duration,
+
+    /// Maximum number of virtual machine instructions that a statement may
+    /// run before it is interrupted, unless its database has a limit of its
+    /// own in `step_limits`.
+    step_limit: Option<u32>,
+
+    /// How long a statement runs before it is logged as a slow statement,
+    /// if statements are logged at all.
+    slow_query_threshold: Option<Duration>,
+
+    /// Maximum number of rows in the result of a statement, unless its
+    /// database has a limit of its own in `result_row_limits`.
+    max_result_rows: Option<usize>,
+
+    /// The rate that the requests to a database are limited to, unless the
+    /// database has a limit of its own in `rate_limits`.
+    rate_limit: Option<RateLimit>,
+
+    /// Whether every client of a database has a rate limit of its own,
+    /// rather than sharing the limit of the database.
+    rate_limit_per_client: bool,
+
+    rate_limiter: RateLimiter,
+
+    /// Whether sessions check the types of the arguments of statements.
+    strict_args: bool,
+
+    /// The filter that the statements of sessions pass before they are
+    /// executed.
+    stmt_filter: Rc<dyn StmtFilter>,
+
+    /// Maximum size of the SQL texts of sessions, in bytes.
+    max_sql_bytes: usize,
+
+    /// Whether connections run `PRAGMA optimize` when they return to the
+    /// pool.
+    optimize_on_release: bool,
+
+    /// Step limits of the databases that override `step_limit`.
+    step_limits: RefCell<HashMap<String, u32>>,
+
+    /// Result row limits of the databases that override `max_result_rows`.
+    result_row_limits: RefCell<HashMap<String, usize>>,
+
+    /// Rate limits of the databases that override `rate_limit`.
+    rate_limits: RefCell<HashMap<String, RateLimit>>,
+
+    /// The databases that the sessions of a database may attach, if they
+    /// may attach any.
+    attach_targets: RefCell<HashMap<String, Vec<String>>>,
+
+    /// Databases that are read-only, such as replicas.
+    read_only: RefCell<HashSet<String>>,
+
+    /// Databases whose connections do not enforce foreign key constraints.
     ///
-    /// This is map from batons to connections. We use batons to identify a
-    /// session. SQL statements executed with the same baton are guaranteed
-    /// to be executed with the same SQLite connection, ensuring transaction
-    /// and isolation guarantees.
-    conns: RefCell<SieveCache<String, Rc<Connection>>>,
+    /// SQLite leaves enforcement off unless a connection turns it on, so
+    /// every connection is opened with `PRAGMA foreign_keys` set for its
+    /// database.
+    foreign_keys_off: RefCell<HashSet<String>>,
+
+    batons: BatonManager,
+
+    /// The storage that the data directory is on.
+    storage: Rc<dyn Storage>,
+
+    /// The clock that baton expiry is measured with.
+    clock: Rc<dyn Clock>,
 }
 
 impl ResourceManager {
-    pub fn new(db_path: &Path) -> Self {
-        let memory_resident_dbs = SieveCache::new(MAX_MEMORY_RESIDENT_DBS).unwrap();
-        let conns = SieveCache::new(MAX_CONCURRENT_CONNS).unwrap();
+    /// Create a resource manager, signing batons with `baton_key`.
+    ///
+    /// The databases are kept on the local file system, and time follows the
+    /// wall clock, unless set otherwise with `with_storage()` and
+    /// `with_clock()`.
+    pub fn new(db_path: &Path, baton_key: [u8; 32]) -> Self {
         std::fs::create_dir_all(db_path).unwrap();
+        Self::from_parts(db_path.to_owned(), None, baton_key)
+    }
+
+    /// Create a resource manager that keeps its databases in memory rather
+    /// than in a data directory, which is handy for tests.
+    ///
+    /// Every database is a shared-cache in-memory database, so the
+    /// connections of its sessions see the same data. The databases are
+    /// gone once the resource manager is dropped.
+    pub fn new_in_memory(baton_key: [u8; 32]) -> Self {
+        let id = IN_MEMORY_MANAGERS.fetch_add(1, Ordering::Relaxed);
+        let in_memory = InMemoryDatabases {
+            prefix: format!("hiisi-{}-{}", std::process::id(), id),
+            created: Cell::new(0),
+            dbs: RefCell::new(BTreeMap::new()),
+        };
+        Self::from_parts(PathBuf::new(), Some(in_memory), baton_key)
+    }
+
+    fn from_parts(
+        db_path: PathBuf,
+        in_memory: Option<InMemoryDatabases>,
+        baton_key: [u8; 32],
+    ) -> Self {
+        let memory_resident_dbs = SieveCache::new(MAX_MEMORY_RESIDENT_DBS).unwrap();
+        let sessions = SieveCache::new(MAX_SESSIONS).unwrap();
         ResourceManager {
-            db_path: db_path.to_owned(),
+            db_path,
+            dir_layout: DirLayout::default(),
+            in_memory,
             memory_resident_dbs: RefCell::new(memory_resident_dbs),
-            conns: RefCell::new(conns),
+            sessions: RefCell::new(sessions),
+            db_sessions: RefCell::new(HashMap::new()),
+            pools: RefCell::new(HashMap::new()),
+            pool_size: DEFAULT_POOL_SIZE,
+            replication_indexes: RefCell::new(HashMap::new()),
+            open_transactions: RefCell::new(BTreeSet::new()),
+            transaction_timeout: TRANSACTION_TIMEOUT,
+            busy_timeout: Duration::ZERO,
+            step_limit: None,
+            slow_query_threshold: None,
+            max_result_rows: None,
+            rate_limit: None,
+            rate_limit_per_client: false,
+            rate_limiter: RateLimiter::new(),
+            strict_args: false,
+            stmt_filter: Rc::new(KeywordFilter::default()),
+            max_sql_bytes: DEFAULT_MAX_SQL_BYTES,
+            optimize_on_release: false,
+            step_limits: RefCell::new(HashMap::new()),
+            result_row_limits: RefCell::new(HashMap::new()),
+            rate_limits: RefCell::new(HashMap::new()),
+            attach_targets: RefCell::new(HashMap::new()),
+            read_only: RefCell::new(HashSet::new()),
+            foreign_keys_off: RefCell::new(HashSet::new()),
+            batons: BatonManager::new(baton_key),
+            storage: Rc::new(FileStorage),
+            clock: Rc::new(WallClock::new()),
+        }
+    }
+
+    /// Keep the databases on `storage`.
+    pub fn with_storage(mut self, storage: Rc<dyn Storage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Lay out the databases in the data directory as `dir_layout` says,
+    /// which is `DirLayout::Named` by default.
+    ///
+    /// Changing the layout does not move the existing databases, so the
+    /// databases of another layout are no longer found.
+    pub fn with_dir_layout(mut self, dir_layout: DirLayout) -> Self {
+        self.dir_layout = dir_layout;
+        self
+    }
+
+    /// Keep at most `pool_size` idle connections per database.
+    pub fn with_pool_size(mut self, pool_size: usize) -> Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    /// Roll back the transactions of sessions that the client has not used
+    /// for `timeout`.
+    pub fn with_transaction_timeout(mut self, timeout: Duration) -> Self {
+        self.transaction_timeout = timeout;
+        self
+    }
+
+    /// Let the statements of the execute requests of HTTP pipelines wait up
+    /// to `timeout`, measured on the clock of the resource manager, for a
+    /// lock that another connection holds, instead of failing with
+    /// `SQLITE_BUSY` right away, which they do by default.
+    ///
+    /// The server does not wait in SQLite, which would keep it from serving
+    /// the session that holds the lock, but gets back to the request from
+    /// its event loop until the lock is released or `timeout` has passed.
+    pub fn with_busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = timeout;
+        self
+    }
+
+    /// How long a statement waits for a lock that another connection holds.
+    pub fn busy_timeout(&self) -> Duration {
+        self.busy_timeout
+    }
+
+    /// Interrupt statements that run more than `steps` virtual machine
+    /// instructions, which keeps a runaway query from taking over the
+    /// server. Statements have no limit by default.
+    pub fn with_step_limit(mut self, steps: u32) -> Self {
+        self.step_limit = Some(steps);
+        self
+    }
+
+    /// Log the statements that run for `threshold` or longer, as measured by
+    /// the clock of the resource manager, so the threshold is in virtual
+    /// time in a simulation.
+    pub fn with_slow_query_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_query_threshold = Some(threshold);
+        self
+    }
+
+    /// How long a statement runs before it is logged as a slow statement.
+    pub fn slow_query_threshold(&self) -> Option<Duration> {
+        self.slow_query_threshold
+    }
+
+    /// Fail statements whose results have more than `rows` rows, rather
+    /// than buffering a huge result set in memory, unless the result is read
+    /// with a cursor. Results have no limit by default.
+    pub fn with_max_result_rows(mut self, rows: usize) -> Self {
+        self.max_result_rows = Some(rows);
+        self
+    }
+
+    /// Limit the requests to every database to `limit`, rejecting the
+    /// requests that go over it with `RateLimited`. Requests have no limit
+    /// by default.
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Give every client address a rate limit of its own on a database,
+    /// rather than sharing the limit of the database among its clients.
+    pub fn with_rate_limit_per_client(mut self) -> Self {
+        self.rate_limit_per_client = true;
+        self
+    }
+
+    /// Reject arguments whose types do not match how statements use their
+    /// parameters, such as text that a statement adds to a number.
+    ///
+    /// SQLite converts such values following its dynamic typing, so the
+    /// check is off by default, but it helps catch bugs in clients.
+    pub fn with_strict_args(mut self) -> Self {
+        self.strict_args = true;
+        self
+    }
+
+    /// Run `PRAGMA optimize` on connections when they return to the pool, so
+    /// that SQLite keeps the statistics of the query planner up to date for
+    /// the queries the connections have run.
+    pub fn with_optimize_on_release(mut self) -> Self {
+        self.optimize_on_release = true;
+        self
+    }
+
+    /// Check the statements of sessions with `filter` before they are
+    /// executed, instead of the default filter, which rejects `ATTACH` and
+    /// `DETACH`.
+    pub fn with_stmt_filter(mut self, filter: Rc<dyn StmtFilter>) -> Self {
+        self.stmt_filter = filter;
+        self
+    }
+
+    /// Reject SQL texts larger than `max_sql_bytes` before SQLite parses
+    /// them, or stores them for later, instead of the limit of
+    /// `DEFAULT_MAX_SQL_BYTES`.
+    pub fn with_max_sql_bytes(mut self, max_sql_bytes: usize) -> Self {
+        self.max_sql_bytes = max_sql_bytes;
+        self
+    }
+
+    /// Set the step limit of the statements on a database, overriding the
+    /// limit set with `with_step_limit()`.
+    ///
+    /// The limit applies to the sessions that are opened on the database
+    /// from then on.
+    pub fn set_step_limit(&self, db_name: &str, steps: u32) {
+        self.step_limits
+            .borrow_mut()
+            .insert(db_name.to_owned(), steps);
+    }
+
+    /// Set the maximum number of rows in the results of the statements on a
+    /// database, overriding the limit set with `with_max_result_rows()`.
+    ///
+    /// The limit applies to the sessions that are opened on the database
+    /// from then on.
+    pub fn set_max_result_rows(&self, db_name: &str, rows: usize) {
+        self.result_row_limits
+            .borrow_mut()
+            .insert(db_name.to_owned(), rows);
+    }
+
+    /// Set the rate limit of the requests to a database, overriding the
+    /// limit set with `with_rate_limit()`.
+    pub fn set_rate_limit(&self, db_name: &str, limit: RateLimit) {
+        self.rate_limits
+            .borrow_mut()
+            .insert(db_name.to_owned(), limit);
+    }
+
+    /// Take a request to a database from `client` off its rate limit.
+    ///
+    /// A request over the limit fails with `RateLimited` and the time until
+    /// the client may make another one. The check does not touch the
+    /// database, so that a client over the limit costs no connection.
+    pub fn check_rate_limit(&self, db_name: &str, client: Option<IpAddr>) -> Result<()> {
+        let limit = match self.rate_limits.borrow().get(db_name) {
+            Some(limit) => *limit,
+            None => match self.rate_limit {
+                Some(limit) => limit,
+                None => return Ok(()),
+            },
+        };
+        let client = client.filter(|_| self.rate_limit_per_client);
+        self.rate_limiter
+            .acquire(db_name, client, limit, self.clock.now())
+            .map_err(HiisiError::RateLimited)
+    }
+
+    /// Let the sessions of a database attach the databases in `targets` by
+    /// name, with `ATTACH DATABASE '<name>' AS <schema>`.
+    ///
+    /// The sessions attach a target at the path of its database file, and
+    /// every other `ATTACH` is rejected, even if the statement filter would
+    /// allow it. `ATTACH` and `DETACH` no longer pass the filter on the
+    /// sessions, so this supersedes the default filter that rejects them.
+    /// A target that does not exist or is read-only when a session is
+    /// opened cannot be attached in the session.
+    ///
+    /// The targets apply to the sessions that are opened on the database
+    /// from then on. Whatever a session attaches is detached when it closes.
+    pub fn set_attach_targets(&self, db_name: &str, targets: &[&str]) {
+        self.attach_targets.borrow_mut().insert(
+            db_name.to_owned(),
+            targets.iter().map(|target| target.to_string()).collect(),
+        );
+    }
+
+    /// Make a database read-only, or writable again.
+    ///
+    /// The sessions that are opened on a read-only database from then on
+    /// open their connections with `SQLITE_OPEN_READONLY`, so that every
+    /// write fails with `SQLITE_READONLY`. The idle connections of the
+    /// database are closed, as they were opened in the previous mode.
+    pub fn set_read_only(&self, db_name: &str, read_only: bool) {
+        let mut dbs = self.read_only.borrow_mut();
+        let changed = if read_only {
+            dbs.insert(db_name.to_owned())
+        } else {
+            dbs.remove(db_name)
+        };
+        if changed {
+            self.pools.borrow_mut().remove(db_name);
+        }
+    }
+
+    /// Check if a database is read-only.
+    pub fn is_read_only(&self, db_name: &str) -> bool {
+        self.read_only.borrow().contains(db_name)
+    }
+
+    /// Turn the enforcement of foreign key constraints on a database on,
+    /// which it is by default, or off.
+    ///
+    /// The idle connections of the database are closed, as they were opened
+    /// with the previous setting. Connections that sessions hold keep it
+    /// until they are released.
+    pub fn set_foreign_keys(&self, db_name: &str, enabled: bool) {
+        let mut dbs = self.foreign_keys_off.borrow_mut();
+        let changed = if enabled {
+            dbs.remove(db_name)
+        } else {
+            dbs.insert(db_name.to_owned())
+        };
+        if changed {
+            self.pools.borrow_mut().remove(db_name);
+        }
+    }
+
+    /// Check if a database enforces foreign key constraints.
+    pub fn foreign_keys(&self, db_name: &str) -> bool {
+        !self.foreign_keys_off.borrow().contains(db_name)
+    }
+
+    fn step_limit(&self, db_name: &str) -> Option<u32> {
+        match self.step_limits.borrow().get(db_name) {
+            Some(steps) => Some(*steps),
+            None => self.step_limit,
         }
     }
 
+    /// Measure time with `clock`.
+    pub fn with_clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &Rc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Create the directory of a new database, or its file if the database
+    /// has no directory in the layout of the data directory.
+    ///
+    /// The database file in a directory is created when the first
+    /// connection is opened.
     pub fn create_database(&self, db_name: &str) -> Result<()> {
-        let db_dir = self.db_path.join(db_name);
-        std::fs::create_dir_all(db_dir.as_path()).unwrap();
+        if !is_valid_db_name(db_name) {
+            return Err(HiisiError::InvalidNamespace(db_name.to_owned()));
+        }
+        if let Some(in_memory) = &self.in_memory {
+            if in_memory.dbs.borrow().contains_key(db_name) {
+                return Err(HiisiError::DatabaseExists(db_name.to_owned()));
+            }
+            let id = in_memory.created.replace(in_memory.created.get() + 1);
+            let uri = format!(
+                "file:{}-{}-{}?mode=memory&cache=shared",
+                in_memory.prefix, id, db_name
+            );
+            let db = Database::new(uri.into());
+            let conn = db.connect()?;
+            in_memory
+                .dbs
+                .borrow_mut()
+                .insert(db_name.to_owned(), (Rc::new(db), Rc::new(conn)));
+            return Ok(());
+        }
+        let Some(db_dir) = self.dir_layout.db_dir(&self.db_path, db_name) else {
+            if self.database_exists(db_name) {
+                return Err(HiisiError::DatabaseExists(db_name.to_owned()));
+            }
+            return self
+                .storage
+                .open_file(&self.db_file_path(db_name))
+                .map_err(|e| HiisiError::IOError("open", e));
+        };
+        match self.storage.create_dir(db_dir.as_path()) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                Err(HiisiError::DatabaseExists(db_name.to_owned()))
+            }
+            Err(e) => Err(HiisiError::IOError("create_dir", e)),
+        }
+    }
+
+    /// Delete a database and its files.
+    ///
+    /// The database must not have open sessions, because their connections
+    /// would keep on using the deleted files. Deleting such a database fails
+    /// with `DatabaseInUse`, and the client has to close its streams first.
+    pub fn delete_database(&self, db_name: &str) -> Result<()> {
+        if !self.database_exists(db_name) {
+            return Err(HiisiError::DatabaseNotFound(db_name.to_owned()));
+        }
+        {
+            let mut sessions = self.sessions.borrow_mut();
+            let mut db_sessions = self.db_sessions.borrow_mut();
+            if let Some(ids) = db_sessions.get_mut(db_name) {
+                ids.retain(|id| sessions.contains_key(id));
+                if !ids.is_empty() {
+                    return Err(HiisiError::DatabaseInUse(db_name.to_owned()));
+                }
+            }
+            db_sessions.remove(db_name);
+        }
+        // Close the idle connections and the connection that keeps the
+        // database in memory, so that nothing refers to the files when they
+        // are removed.
+        self.pools.borrow_mut().remove(db_name);
+        self.replication_indexes.borrow_mut().remove(db_name);
+        self.step_limits.borrow_mut().remove(db_name);
+        self.result_row_limits.borrow_mut().remove(db_name);
+        self.rate_limits.borrow_mut().remove(db_name);
+        self.rate_limiter.remove(db_name);
+        self.attach_targets.borrow_mut().remove(db_name);
+        self.read_only.borrow_mut().remove(db_name);
+        self.memory_resident_dbs.borrow_mut().remove(db_name);
+        if let Some(in_memory) = &self.in_memory {
+            in_memory.dbs.borrow_mut().remove(db_name);
+            return Ok(());
+        }
+        // The directory holds the database file and its `-wal` and `-shm`
+        // sidecars.
+        if let Some(db_dir) = self.dir_layout.db_dir(&self.db_path, db_name) {
+            return self
+                .storage
+                .remove_dir_all(&db_dir)
+                .map_err(|e| HiisiError::IOError("remove_dir_all", e));
+        }
+        let db_file = self.db_file_path(db_name);
+        self.storage
+            .remove_file(&db_file)
+            .map_err(|e| HiisiError::IOError("remove_file", e))?;
+        for suffix in ["-wal", "-shm", "-journal"] {
+            let mut sidecar = db_file.clone().into_os_string();
+            sidecar.push(suffix);
+            match self.storage.remove_file(Path::new(&sidecar)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(HiisiError::IOError("remove_file", e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Rename a database and its files, such as to promote `staging` to
+    /// `prod`.
+    ///
+    /// The files are renamed first, and if one of the renames fails, the
+    /// ones that are done are undone, so that the database is left as it was
+    /// under its old name. Only then are the open sessions of the database
+    /// dropped, rolling back their transactions, so that their batons are no
+    /// longer valid, and its connections closed. The settings of the
+    /// database, such as its step limit, carry over to the new name.
+    pub fn rename_database(&self, from: &str, to: &str) -> Result<()> {
+        if !self.database_exists(from) {
+            return Err(HiisiError::DatabaseNotFound(from.to_owned()));
+        }
+        if !is_valid_db_name(to) {
+            return Err(HiisiError::InvalidNamespace(to.to_owned()));
+        }
+        if self.database_exists(to) {
+            return Err(HiisiError::DatabaseExists(to.to_owned()));
+        }
+        if self.in_memory.is_none() {
+            let mut renamed = Vec::new();
+            if let Err(err) = self.rename_database_files(from, to, &mut renamed) {
+                for (from_path, to_path) in renamed.iter().rev() {
+                    if let Err(e) = self.storage.rename(to_path, from_path) {
+                        log::warn!(
+                            "Failed to rename {} back to {}: {}",
+                            to_path.display(),
+                            from_path.display(),
+                            e
+                        );
+                    }
+                }
+                return Err(err);
+            }
+        }
+        let session_ids = self.db_sessions.borrow_mut().remove(from);
+        for session_id in session_ids.into_iter().flatten() {
+            self.drop_session(session_id);
+        }
+        self.pools.borrow_mut().remove(from);
+        self.memory_resident_dbs.borrow_mut().remove(from);
+        rename_key(&self.replication_indexes, from, to);
+        rename_key(&self.step_limits, from, to);
+        rename_key(&self.result_row_limits, from, to);
+        rename_key(&self.rate_limits, from, to);
+        self.rate_limiter.remove(from);
+        rename_key(&self.attach_targets, from, to);
+        rename_member(&self.read_only, from, to);
+        rename_member(&self.foreign_keys_off, from, to);
+        if let Some(in_memory) = &self.in_memory {
+            let mut dbs = in_memory.dbs.borrow_mut();
+            if let Some(db) = dbs.remove(from) {
+                dbs.insert(to.to_owned(), db);
+            }
+        }
         Ok(())
     }
 
-    pub fn get_conn(&self, db_name: &str, baton: &str) -> Result<Rc<Connection>> {
-        let mut conns = self.conns.borrow_mut();
-        if let Some(conn) = conns.get(baton) {
+    /// Rename the directory, the file and the sidecars of a database,
+    /// recording each rename that is done in `renamed` so that it can be
+    /// undone.
+    fn rename_database_files(
+        &self,
+        from: &str,
+        to: &str,
+        renamed: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> Result<()> {
+        let from_file = self.db_file_path(from);
+        let to_file = self.db_file_path(to);
+        let from_file = match (
+            self.dir_layout.db_dir(&self.db_path, from),
+            self.dir_layout.db_dir(&self.db_path, to),
+        ) {
+            (Some(from_dir), Some(to_dir)) => {
+                self.storage
+                    .rename(&from_dir, &to_dir)
+                    .map_err(|e| HiisiError::IOError("rename", e))?;
+                // The files are in the directory of the new name now, but
+                // may still be named after the old one.
+                let from_file = to_dir.join(from_file.strip_prefix(&from_dir).unwrap());
+                renamed.push((from_dir, to_dir));
+                from_file
+            }
+            _ => from_file,
+        };
+        if from_file == to_file {
+            return Ok(());
+        }
+        let mut paths = vec![(from_file.clone(), to_file.clone())];
+        for suffix in ["-wal", "-shm", "-journal"] {
+            let mut from_sidecar = from_file.clone().into_os_string();
+            from_sidecar.push(suffix);
+            let mut to_sidecar = to_file.clone().into_os_string();
+            to_sidecar.push(suffix);
+            paths.push((from_sidecar.into(), to_sidecar.into()));
+        }
+        for (from_path, to_path) in paths {
+            match self.storage.rename(&from_path, &to_path) {
+                Ok(()) => renamed.push((from_path, to_path)),
+                // A database in a directory has no file until its first
+                // connection is opened, and a database need not have any of
+                // the sidecars.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(HiisiError::IOError("rename", e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if a database exists in the data directory.
+    pub fn database_exists(&self, db_name: &str) -> bool {
+        if let Some(in_memory) = &self.in_memory {
+            return in_memory.dbs.borrow().contains_key(db_name);
+        }
+        if !is_valid_db_name(db_name) {
+            return false;
+        }
+        match self.dir_layout.db_dir(&self.db_path, db_name) {
+            Some(db_dir) => db_dir.is_dir(),
+            None => self.db_file_path(db_name).is_file(),
+        }
+    }
+
+    /// List the databases in the data directory, sorted by name.
+    ///
+    /// Every database is a directory that holds the database file and its
+    /// sidecars, or in the flat layout a `.db` file next to its sidecars, so
+    /// each database is listed once. Other entries, and entries whose names
+    /// are not valid database names, are skipped.
+    pub fn list_databases(&self) -> Result<Vec<String>> {
+        if let Some(in_memory) = &self.in_memory {
+            return Ok(in_memory.dbs.borrow().keys().cloned().collect());
+        }
+        let entries =
+            std::fs::read_dir(&self.db_path).map_err(|e| HiisiError::IOError("read_dir", e))?;
+        let mut db_names = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| HiisiError::IOError("read_dir", e))?;
+            let file_type = entry
+                .file_type()
+                .map_err(|e| HiisiError::IOError("file_type", e))?;
+            let is_db = match self.dir_layout {
+                DirLayout::Flat => file_type.is_file(),
+                DirLayout::Named | DirLayout::Nested => file_type.is_dir(),
+            };
+            if !is_db {
+                continue;
+            }
+            let file_name = entry.file_name();
+            let db_name = match (self.dir_layout, file_name.to_str()) {
+                (DirLayout::Flat, Some(file_name)) => match file_name.strip_suffix(".db") {
+                    Some(db_name) => Some(db_name),
+                    // A sidecar of a database file.
+                    None => continue,
+                },
+                (_, db_name) => db_name,
+            };
+            match db_name {
+                Some(db_name) if is_valid_db_name(db_name) => db_names.push(db_name.to_owned()),
+                _ => log::warn!(
+                    "Skipping unexpected entry in data directory: {:?}",
+                    entry.path()
+                ),
+            }
+        }
+        db_names.sort();
+        Ok(db_names)
+    }
+
+    pub fn create_session(&self, db_name: &str, version: Version) -> Rc<Session> {
+        let id = self.batons.next_session_id();
+        let mut session = Session::new(id, db_name, version);
+        session.strict_args = self.strict_args;
+        session.stmt_filter = self.stmt_filter.clone();
+        session.max_sql_bytes = self.max_sql_bytes;
+        session.max_result_rows = match self.result_row_limits.borrow().get(db_name) {
+            Some(rows) => Some(*rows),
+            None => self.max_result_rows,
+        };
+        session.attach_targets = self.attach_targets.borrow().get(db_name).map(|targets| {
+            targets
+                .iter()
+                .filter_map(|target| Some((target.clone(), self.attach_path(target)?)))
+                .collect()
+        });
+        let session = Rc::new(session);
+        session.baton_issued_at.set(self.clock.now());
+        self.sessions.borrow_mut().insert(id, session.clone());
+        self.db_sessions
+            .borrow_mut()
+            .entry(db_name.to_owned())
+            .or_default()
+            .insert(id);
+        session
+    }
+
+    /// The number of open sessions.
+    pub fn session_count(&self) -> usize {
+        self.sessions.borrow().len()
+    }
+
+    /// Check if a session is still open, rather than dropped or expired.
+    pub fn has_session(&self, session_id: u64) -> bool {
+        self.sessions.borrow_mut().contains_key(&session_id)
+    }
+
+    /// Look up the session of a baton.
+    ///
+    /// Returns `None` if the baton is forged, the session has expired, or the
+    /// baton has already been rotated. A baton that the client has not used
+    /// within `BATON_EXPIRY` expires its session.
+    pub fn get_session(&self, baton: &str) -> Option<Rc<Session>> {
+        let baton = self.batons.decode(baton)?;
+        let session = self.sessions.borrow_mut().get(&baton.session_id).cloned()?;
+        if session.baton_counter.get() != baton.counter {
+            return None;
+        }
+        if self.clock.now() > session.baton_issued_at.get() + BATON_EXPIRY {
+            log::trace!("Session {} has expired", session.id);
+            self.drop_session(session.id);
+            return None;
+        }
+        Some(session)
+    }
+
+    /// Issue a fresh baton for a session, invalidating the previous one.
+    pub fn issue_baton(&self, session: &Session) -> String {
+        let (baton, counter) = self.batons.issue(session.id);
+        session.baton_counter.set(counter);
+        session.baton_issued_at.set(self.clock.now());
+        let in_transaction = session
+            .conn
+            .borrow()
+            .as_ref()
+            .is_some_and(|conn| !conn.is_autocommit());
+        let mut open_transactions = self.open_transactions.borrow_mut();
+        if in_transaction {
+            open_transactions.insert(session.id);
+        } else {
+            open_transactions.remove(&session.id);
+        }
+        baton
+    }
+
+    /// Drop the sessions that the client has left in a transaction for
+    /// longer than the transaction timeout, which rolls back their
+    /// transactions and expires their batons.
+    pub fn expire_transactions(&self) {
+        let now = self.clock.now();
+        let ids: Vec<u64> = self.open_transactions.borrow().iter().copied().collect();
+        for id in ids {
+            let issued_at = self
+                .sessions
+                .borrow_mut()
+                .get(&id)
+                .map(|session| session.baton_issued_at.get());
+            match issued_at {
+                Some(issued_at) if now <= issued_at + self.transaction_timeout => {}
+                Some(_) => {
+                    log::debug!("Transaction of session {} has timed out", id);
+                    self.drop_session(id);
+                }
+                // The session cache has evicted the session.
+                None => {
+                    self.open_transactions.borrow_mut().remove(&id);
+                }
+            }
+        }
+    }
+
+    /// Drop a session and return the connection it holds to the pool of its
+    /// database.
+    ///
+    /// Any transaction that the session left open is rolled back right away,
+    /// rather than when the last reference to the connection goes away, so
+    /// that it releases its locks on the database. A cursor that is still
+    /// reading a statement of the session is interrupted, so that it stops at
+    /// its next row rather than running the statement to completion.
+    pub fn drop_session(&self, session_id: u64) {
+        self.open_transactions.borrow_mut().remove(&session_id);
+        let session = self.sessions.borrow_mut().remove(&session_id);
+        if let Some(session) = session {
+            if let Some(conn) = session.conn.take() {
+                if !conn.is_autocommit() {
+                    log::trace!("Rolling back transaction of session {}", session_id);
+                    if let Err(err) = conn.prepare("ROLLBACK").and_then(|stmt| stmt.step()) {
+                        log::debug!("Failed to roll back session {}: {}", session_id, err);
+                    }
+                }
+                if Rc::strong_count(&conn) > 1 {
+                    log::trace!("Interrupting cursor of session {}", session_id);
+                    conn.interrupt();
+                }
+                self.release_conn(&session.db_name, conn);
+            }
+            let mut db_sessions = self.db_sessions.borrow_mut();
+            if let Some(ids) = db_sessions.get_mut(&session.db_name) {
+                ids.remove(&session_id);
+            }
+        }
+    }
+
+    /// Get the connection of a session, which the session takes from the
+    /// pool of its database, or opens, with its first statement.
+    pub fn get_conn(&self, session: &Session) -> Result<Rc<Connection>> {
+        if let Some(conn) = session.conn.borrow().as_ref() {
             return Ok(conn.clone());
         }
-        let mut memory_resident_dbs = self.memory_resident_dbs.borrow_mut();
-        if let Some((db, _)) = memory_resident_dbs.get(db_name) {
-            let conn = Rc::new(db.connect()?);
-            conns.insert(baton.to_string(), conn.clone());
-            return Ok(conn);
-        }
-        let (db, placeholder_conn) = self.open_conn(db_name)?;
-        memory_resident_dbs.insert(db_name.to_string(), (db.clone(), placeholder_conn));
-        let conn = Rc::new(db.connect()?);
-        conns.insert(baton.to_string(), conn.clone());
+        let pooled = self
+            .pools
+            .borrow_mut()
+            .get_mut(&session.db_name)
+            .and_then(|pool| pool.pop());
+        let conn = match pooled {
+            Some(conn) => conn,
+            None => self.connect(&session.db_name)?,
+        };
+        conn.set_step_limit(self.step_limit(&session.db_name));
+        session.conn.replace(Some(conn.clone()));
         Ok(conn)
     }
 
+    /// Read the schema version of a database, which SQLite increments on
+    /// every change to the schema, and its user version, which migrations
+    /// keep track of with `PRAGMA user_version`.
+    ///
+    /// The versions are read on an idle connection of the database, outside
+    /// of any session, and reading them takes no write lock.
+    pub fn schema_version(&self, db_name: &str) -> Result<SchemaVersion> {
+        self.with_idle_conn(db_name, |conn| {
+            Ok(SchemaVersion {
+                schema_version: conn.pragma_int("schema_version")?,
+                user_version: conn.pragma_int("user_version")?,
+            })
+        })
+    }
+
+    /// Checkpoint the WAL of a database and truncate it, with `PRAGMA
+    /// wal_checkpoint(TRUNCATE)`.
+    ///
+    /// A session that is reading or writing the database keeps the
+    /// checkpoint from completing, which is reported as a busy checkpoint
+    /// rather than as an error, together with how many frames it copied.
+    pub fn checkpoint(&self, db_name: &str) -> Result<Checkpoint> {
+        self.with_idle_conn(db_name, |conn| {
+            // The replication index counts the frames that the checkpoint
+            // is about to truncate before they are gone.
+            self.replication_index(db_name, conn)?;
+            let stmt = conn.prepare("PRAGMA wal_checkpoint(TRUNCATE)")?;
+            let checkpoint = match stmt.step()? {
+                StepResult::Row => Checkpoint {
+                    busy: stmt.column_int(0) != 0,
+                    log_frames: stmt.column_int(1),
+                    checkpointed_frames: stmt.column_int(2),
+                },
+                StepResult::Done => {
+                    return Err(HiisiError::InternalError(
+                        "PRAGMA wal_checkpoint returned no result".to_owned(),
+                    ))
+                }
+            };
+            drop(stmt);
+            self.replication_index(db_name, conn)?;
+            Ok(checkpoint)
+        })
+    }
+
+    /// Rebuild the file of a database with `VACUUM`, which gives the space
+    /// of deleted rows back to the file system.
+    ///
+    /// `VACUUM` cannot run while a session holds a transaction open on the
+    /// database, so the vacuum fails with `DatabaseInUse` until the client
+    /// finishes the transaction.
+    pub fn vacuum(&self, db_name: &str) -> Result<()> {
+        let in_transaction = self.db_sessions.borrow().get(db_name).is_some_and(|ids| {
+            let open_transactions = self.open_transactions.borrow();
+            ids.iter().any(|id| open_transactions.contains(id))
+        });
+        if in_transaction {
+            return Err(HiisiError::DatabaseInUse(db_name.to_owned()));
+        }
+        self.with_idle_conn(db_name, |conn| conn.exec("VACUUM"))
+    }
+
+    /// Start an online backup of a database, which `Backup::step()` copies
+    /// into memory a few pages at a time while sessions go on writing.
+    ///
+    /// The backup reads the database on a connection of its own, which is
+    /// closed rather than pooled once the backup is dropped.
+    pub fn backup_database(&self, db_name: &str) -> Result<Backup> {
+        if !self.database_exists(db_name) {
+            return Err(HiisiError::DatabaseNotFound(db_name.to_owned()));
+        }
+        Backup::new(self.connect(db_name)?)
+    }
+
+    /// Check that a database has caught up with `replication_index` like
+    /// `check_replication_index()`, but on an idle connection of the
+    /// database, before any session is involved.
+    pub fn check_caught_up(&self, db_name: &str, replication_index: u64) -> Result<()> {
+        self.with_idle_conn(db_name, |conn| {
+            self.check_replication_index(db_name, conn, Some(replication_index))
+        })
+    }
+
+    /// Run `f` on a connection from the pool of a database, or on a new one
+    /// if the pool is empty, and return the connection to the pool.
+    fn with_idle_conn<R>(
+        &self,
+        db_name: &str,
+        f: impl FnOnce(&Connection) -> Result<R>,
+    ) -> Result<R> {
+        if !self.database_exists(db_name) {
+            return Err(HiisiError::DatabaseNotFound(db_name.to_owned()));
+        }
+        let pooled = self
+            .pools
+            .borrow_mut()
+            .get_mut(db_name)
+            .and_then(|pool| pool.pop());
+        let conn = match pooled {
+            Some(conn) => conn,
+            None => self.connect(db_name)?,
+        };
+        let result = f(&conn);
+        self.release_conn(db_name, conn);
+        result
+    }
+
+    /// Get the replication index of a database, as observed through `conn`.
+    ///
+    /// The index advances by the frames that were appended to the WAL since
+    /// the index was last read, so it never decreases, even though the WAL
+    /// starts over from zero frames after a checkpoint.
+    pub fn replication_index(&self, db_name: &str, conn: &Connection) -> Result<u64> {
+        let frame_count = conn.wal_frame_count()?;
+        let mut replication_indexes = self.replication_indexes.borrow_mut();
+        let replication = replication_indexes.entry(db_name.to_owned()).or_default();
+        if frame_count >= replication.frame_count {
+            replication.index += u64::from(frame_count - replication.frame_count);
+        } else {
+            replication.index += u64::from(frame_count);
+        }
+        replication.frame_count = frame_count;
+        Ok(replication.index)
+    }
+
+    /// Check that a database has caught up with the replication index that
+    /// a client read with, failing with `ReplicaBehind` if it has not.
+    ///
+    /// The server applies the writes of its own clients before it responds
+    /// with their replication index, so only a database whose writes come
+    /// from elsewhere, such as a read-only replica, can be behind.
+    pub fn check_replication_index(
+        &self,
+        db_name: &str,
+        conn: &Connection,
+        replication_index: Option<u64>,
+    ) -> Result<()> {
+        if let Some(replication_index) = replication_index {
+            let current = self.replication_index(db_name, conn)?;
+            if replication_index > current {
+                return Err(HiisiError::ReplicaBehind(replication_index, current));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return a connection to the pool of its database, once it has been
+    /// reset with `reset_connection()`.
+    ///
+    /// A connection that something else still refers to, such as the
+    /// statement of a cursor, is closed instead, as is a connection that
+    /// would overflow the pool or fails to reset. So is a connection that is
+    /// still in a transaction: sessions roll back their transactions before
+    /// they return their connections, so the state of such a connection is
+    /// unknown.
+    fn release_conn(&self, db_name: &str, conn: Rc<Connection>) {
+        if Rc::strong_count(&conn) > 1 {
+            return;
+        }
+        if !conn.is_autocommit() {
+            log::debug!("Discarding connection to {} in a transaction", db_name);
+            return;
+        }
+        let full = self
+            .pools
+            .borrow()
+            .get(db_name)
+            .is_some_and(|pool| pool.len() >= self.pool_size);
+        if full {
+            return;
+        }
+        if let Err(err) = self.reset_connection(db_name, &conn) {
+            log::debug!("Discarding connection to {}: {}", db_name, err);
+            return;
+        }
+        self.pools
+            .borrow_mut()
+            .entry(db_name.to_owned())
+            .or_default()
+            .push(conn);
+    }
+
+    /// Reset what a session may have changed on a connection, so that the
+    /// session that takes the connection from the pool next does not see it.
+    ///
+    /// Attached databases and temporary objects are removed, and the
+    /// configuration that the resource manager applies to new connections is
+    /// applied again, in case the session overrode it with a `PRAGMA`.
+    fn reset_connection(&self, db_name: &str, conn: &Connection) -> Result<()> {
+        conn.clear_session_state()?;
+        // A session that capped the size of the database with
+        // `PRAGMA max_page_count` would otherwise fail the writes of the next
+        // session with `SQLITE_FULL`.
+        conn.pragma("max_page_count", MAX_PAGE_COUNT.to_string())?;
+        if self.optimize_on_release {
+            conn.exec("PRAGMA optimize")?;
+        }
+        self.configure_conn(db_name, conn)
+    }
+
+    fn connect(&self, db_name: &str) -> Result<Rc<Connection>> {
+        let conn = self.connect_db(db_name)?;
+        self.configure_conn(db_name, &conn)?;
+        Ok(conn)
+    }
+
+    fn configure_conn(&self, db_name: &str, conn: &Connection) -> Result<()> {
+        let foreign_keys = if self.foreign_keys(db_name) {
+            "ON"
+        } else {
+            "OFF"
+        };
+        conn.pragma("foreign_keys", foreign_keys)?;
+        Ok(())
+    }
+
+    fn connect_db(&self, db_name: &str) -> Result<Rc<Connection>> {
+        if let Some(in_memory) = &self.in_memory {
+            let db = match in_memory.dbs.borrow().get(db_name) {
+                Some((db, _)) => db.clone(),
+                None => return Err(HiisiError::DatabaseNotFound(db_name.to_owned())),
+            };
+            let conn = if self.is_read_only(db_name) {
+                db.connect_read_only()?
+            } else {
+                db.connect()?
+            };
+            return Ok(Rc::new(conn));
+        }
+        // Open the database file through the storage before SQLite does, so
+        // that a failing storage fails the connection cleanly.
+        self.storage
+            .open_file(&self.db_file_path(db_name))
+            .map_err(|e| HiisiError::IOError("open", e))?;
+        let mut memory_resident_dbs = self.memory_resident_dbs.borrow_mut();
+        let db = match memory_resident_dbs.get(db_name) {
+            Some((db, _)) => db.clone(),
+            None => {
+                // The connection that keeps the database in memory is never
+                // read-only, as it sets up the database file and its WAL.
+                let (db, placeholder_conn) = self.open_conn(db_name)?;
+                memory_resident_dbs.insert(db_name.to_string(), (db.clone(), placeholder_conn));
+                db
+            }
+        };
+        let conn = if self.is_read_only(db_name) {
+            db.connect_read_only()?
+        } else {
+            db.connect()?
+        };
+        Ok(Rc::new(conn))
+    }
+
+    fn db_file_path(&self, db_name: &str) -> PathBuf {
+        self.dir_layout.db_file_path(&self.db_path, db_name)
+    }
+
+    /// The file name that a session attaches a database with, or `None` if
+    /// the database cannot be attached.
+    ///
+    /// A read-only database cannot be attached, as it would be attached with
+    /// the mode of the connection that attaches it.
+    fn attach_path(&self, db_name: &str) -> Option<String> {
+        if self.is_read_only(db_name) {
+            return None;
+        }
+        if let Some(in_memory) = &self.in_memory {
+            let dbs = in_memory.dbs.borrow();
+            let (db, _) = dbs.get(db_name)?;
+            return Some(db.path().to_string_lossy().into_owned());
+        }
+        if !self.database_exists(db_name) {
+            return None;
+        }
+        Some(self.db_file_path(db_name).to_string_lossy().into_owned())
+    }
+
     fn open_conn(&self, db_name: &str) -> Result<(Rc<Database>, Rc<Connection>)> {
-        let db_dir = self.db_path.join(db_name);
-        let db_path = db_dir.join(format!("{}.db", db_name));
-        let db = Database::new(db_path.into());
+        let db = Database::new(self.db_file_path(db_name).into());
         let conn = db.connect()?;
         conn.pragma("journal_mode", "WAL")?;
         conn.pragma("cache_size", format!("-{}", MAX_PAGE_CACHE_SIZE))?;
         conn.pragma("locking_mode", "EXCLUSIVE")?;
         Ok((Rc::new(db), Rc::new(conn)))
     }
+}
 
-    pub fn drop_conn(&self, _db_name: &str, baton: &str) -> Result<()> {
-        let mut conns = self.conns.borrow_mut();
-        conns.remove(baton);
-        Ok(())
+#[cfg(test)]
+mod test {
+    use super::{DirLayout, ResourceManager};
+    use crate::clock::SimClock;
+    use crate::database::StepResult;
+    use crate::proto::Version;
+    use crate::session::Session;
+    use crate::HiisiError;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    #[test]
+    fn create_database() {
+        let db_path = std::env::temp_dir().join(format!("hiisi-manager-{}", std::process::id()));
+        let manager = ResourceManager::new(&db_path, [0; 32]);
+        manager.create_database("test").unwrap();
+        assert!(matches!(
+            manager.create_database("test"),
+            Err(HiisiError::DatabaseExists(_))
+        ));
+        for name in ["", "..", "../test", "a/b"] {
+            assert!(matches!(
+                manager.create_database(name),
+                Err(HiisiError::InvalidNamespace(_))
+            ));
+        }
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn list_databases() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-manager-list-{}", std::process::id()));
+        let manager = ResourceManager::new(&db_path, [0; 32]);
+        manager.create_database("foo").unwrap();
+        manager.create_database("bar").unwrap();
+        for sidecar in ["foo.db", "foo.db-wal", "foo.db-shm", "foo.db-journal"] {
+            std::fs::write(db_path.join("foo").join(sidecar), b"").unwrap();
+        }
+        std::fs::write(db_path.join("stray.db"), b"").unwrap();
+        std::fs::create_dir(db_path.join("not a database")).unwrap();
+        assert_eq!(manager.list_databases().unwrap(), vec!["bar", "foo"]);
+        assert!(manager.database_exists("foo"));
+        assert!(!manager.database_exists("stray.db"));
+        assert!(!manager.database_exists("not a database"));
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn dir_layouts_create_expected_paths() {
+        for (dir_layout, db_file) in [
+            (DirLayout::Nested, "test/data.db"),
+            (DirLayout::Flat, "test.db"),
+        ] {
+            let db_path = std::env::temp_dir().join(format!(
+                "hiisi-manager-layout-{:?}-{}",
+                dir_layout,
+                std::process::id()
+            ));
+            let manager = ResourceManager::new(&db_path, [0; 32]).with_dir_layout(dir_layout);
+            manager.create_database("test").unwrap();
+            assert!(matches!(
+                manager.create_database("test"),
+                Err(HiisiError::DatabaseExists(_))
+            ));
+            let session = manager.create_session("test", Version::Hrana2);
+            manager.get_conn(&session).unwrap();
+            manager.drop_session(session.id);
+            drop(session);
+            assert!(db_path.join(db_file).is_file());
+            assert_eq!(manager.list_databases().unwrap(), vec!["test"]);
+
+            manager.delete_database("test").unwrap();
+            assert!(!manager.database_exists("test"));
+            assert_eq!(std::fs::read_dir(&db_path).unwrap().count(), 0);
+            std::fs::remove_dir_all(db_path).unwrap();
+        }
+    }
+
+    #[test]
+    fn rename_database_moves_files() {
+        for dir_layout in [DirLayout::Named, DirLayout::Flat, DirLayout::Nested] {
+            let db_path = std::env::temp_dir().join(format!(
+                "hiisi-manager-rename-{:?}-{}",
+                dir_layout,
+                std::process::id()
+            ));
+            let manager = ResourceManager::new(&db_path, [0; 32]).with_dir_layout(dir_layout);
+            manager.create_database("staging").unwrap();
+            manager.create_database("other").unwrap();
+            manager.set_step_limit("staging", 1_000_000);
+            let session = manager.create_session("staging", Version::Hrana2);
+            let conn = manager.get_conn(&session).unwrap();
+            for sql in [
+                "CREATE TABLE t (x)",
+                "INSERT INTO t VALUES (42)",
+                "BEGIN",
+                "INSERT INTO t VALUES (43)",
+            ] {
+                conn.prepare(sql).unwrap().step().unwrap();
+            }
+            drop(conn);
+            let baton = manager.issue_baton(&session);
+
+            assert!(matches!(
+                manager.rename_database("staging", "other"),
+                Err(HiisiError::DatabaseExists(_))
+            ));
+            assert!(matches!(
+                manager.rename_database("missing", "prod"),
+                Err(HiisiError::DatabaseNotFound(_))
+            ));
+            manager.rename_database("staging", "prod").unwrap();
+            // The session is gone, and its transaction with it.
+            assert!(manager.get_session(&baton).is_none());
+            assert!(!manager.database_exists("staging"));
+            assert_eq!(manager.list_databases().unwrap(), vec!["other", "prod"]);
+            assert_eq!(manager.step_limit("prod"), Some(1_000_000));
+
+            let session = manager.create_session("prod", Version::Hrana2);
+            let conn = manager.get_conn(&session).unwrap();
+            let stmt = conn.prepare("SELECT count(*), max(x) FROM t").unwrap();
+            assert!(matches!(stmt.step().unwrap(), StepResult::Row));
+            assert_eq!((stmt.column_int(0), stmt.column_int(1)), (1, 42));
+            drop(stmt);
+            drop(conn);
+            manager.drop_session(session.id);
+            std::fs::remove_dir_all(db_path).unwrap();
+        }
+    }
+
+    #[cfg(feature = "simulation")]
+    #[test]
+    fn failed_rename_leaves_database_intact() {
+        use crate::storage::{FaultyStorage, FileStorage, EIO};
+
+        // The rename of the database file fails in the named layout, after
+        // its directory has been renamed, and the rename of the `-wal`
+        // sidecar in the flat one, after the database file has been renamed.
+        for dir_layout in [DirLayout::Named, DirLayout::Flat] {
+            let db_path = std::env::temp_dir().join(format!(
+                "hiisi-manager-rename-fault-{:?}-{}",
+                dir_layout,
+                std::process::id()
+            ));
+            let storage = Rc::new(FaultyStorage::new(FileStorage, 0, 0.0));
+            let manager = ResourceManager::new(&db_path, [0; 32])
+                .with_dir_layout(dir_layout)
+                .with_storage(storage.clone());
+            manager.create_database("staging").unwrap();
+            manager.set_step_limit("staging", 1_000_000);
+            let session = manager.create_session("staging", Version::Hrana2);
+            let conn = manager.get_conn(&session).unwrap();
+            for sql in ["CREATE TABLE t (x)", "INSERT INTO t VALUES (42)"] {
+                conn.prepare(sql).unwrap().step().unwrap();
+            }
+            drop(conn);
+            let baton = manager.issue_baton(&session);
+
+            storage.fail_after(1, EIO);
+            match manager.rename_database("staging", "prod") {
+                Err(HiisiError::IOError(_, e)) => assert_eq!(e.raw_os_error(), Some(EIO)),
+                Err(e) => panic!("Unexpected error: {}", e),
+                Ok(()) => panic!("Renaming did not fail"),
+            }
+            assert!(!manager.database_exists("prod"));
+            assert_eq!(manager.list_databases().unwrap(), vec!["staging"]);
+            assert_eq!(manager.step_limit("staging"), Some(1_000_000));
+            // The session outlives the failed rename, and reads the rows
+            // from the files under the old name.
+            let session = manager.get_session(&baton).unwrap();
+            let conn = manager.get_conn(&session).unwrap();
+            let stmt = conn.prepare("SELECT x FROM t").unwrap();
+            assert!(matches!(stmt.step().unwrap(), StepResult::Row));
+            assert_eq!(stmt.column_int(0), 42);
+            drop(stmt);
+            drop(conn);
+            manager.drop_session(session.id);
+
+            let session = manager.create_session("staging", Version::Hrana2);
+            let conn = manager.get_conn(&session).unwrap();
+            let stmt = conn.prepare("SELECT count(*) FROM t").unwrap();
+            assert!(matches!(stmt.step().unwrap(), StepResult::Row));
+            assert_eq!(stmt.column_int(0), 1);
+            drop(stmt);
+            drop(conn);
+            manager.drop_session(session.id);
+            std::fs::remove_dir_all(db_path).unwrap();
+        }
+    }
+
+    #[test]
+    fn pool_reuses_connection() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-manager-pool-{}", std::process::id()));
+        let manager = ResourceManager::new(&db_path, [0; 32]).with_pool_size(1);
+        manager.create_database("test").unwrap();
+        let first = manager.create_session("test", Version::Hrana2);
+        let conn = Rc::as_ptr(&manager.get_conn(&first).unwrap());
+        manager.drop_session(first.id);
+
+        let second = manager.create_session("test", Version::Hrana2);
+        assert_eq!(Rc::as_ptr(&manager.get_conn(&second).unwrap()), conn);
+        // A connection that is taken from the pool is not handed out twice.
+        let third = manager.create_session("test", Version::Hrana2);
+        assert_ne!(Rc::as_ptr(&manager.get_conn(&third).unwrap()), conn);
+        manager.drop_session(second.id);
+        manager.drop_session(third.id);
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn pooled_connection_is_reset() {
+        let manager = ResourceManager::new_in_memory([0; 32]).with_pool_size(1);
+        manager.create_database("test").unwrap();
+        let first = manager.create_session("test", Version::Hrana2);
+        let conn = manager.get_conn(&first).unwrap();
+        conn.exec("CREATE TEMP TABLE scratch (x); CREATE TEMP VIEW v AS SELECT * FROM scratch")
+            .unwrap();
+        conn.exec("ATTACH DATABASE ':memory:' AS other; PRAGMA foreign_keys = OFF")
+            .unwrap();
+        let ptr = Rc::as_ptr(&conn);
+        drop(conn);
+        manager.drop_session(first.id);
+
+        let second = manager.create_session("test", Version::Hrana2);
+        let reused = manager.get_conn(&second).unwrap();
+        assert_eq!(Rc::as_ptr(&reused), ptr);
+        assert!(reused.prepare("SELECT * FROM scratch").is_err());
+        assert!(reused.prepare("SELECT * FROM other.sqlite_schema").is_err());
+        assert_eq!(reused.pragma_int("foreign_keys").unwrap(), 1);
+        manager.drop_session(second.id);
+    }
+
+    #[test]
+    fn abandoned_transaction_times_out() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-manager-txn-{}", std::process::id()));
+        let clock = Rc::new(SimClock::new());
+        let manager = ResourceManager::new(&db_path, [0; 32])
+            .with_clock(clock.clone())
+            .with_transaction_timeout(Duration::from_secs(1));
+        manager.create_database("test").unwrap();
+        let execute = |session: &Session, sql: &str| {
+            let conn = manager.get_conn(session).unwrap();
+            let stmt = conn.prepare(sql);
+            stmt.and_then(|stmt| stmt.step()).map(|_| ())
+        };
+        let abandoned = manager.create_session("test", Version::Hrana2);
+        execute(&abandoned, "BEGIN IMMEDIATE").unwrap();
+        let baton = manager.issue_baton(&abandoned);
+
+        let other = manager.create_session("test", Version::Hrana2);
+        assert!(matches!(
+            execute(&other, "BEGIN IMMEDIATE"),
+            Err(HiisiError::SqliteError(..))
+        ));
+        manager.expire_transactions();
+        assert!(manager.get_session(&baton).is_some());
+
+        clock.advance(Duration::from_millis(1001));
+        manager.expire_transactions();
+        assert!(manager.get_session(&baton).is_none());
+        // The rollback released the write lock.
+        execute(&other, "BEGIN IMMEDIATE").unwrap();
+        manager.drop_session(other.id);
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn delete_database() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-manager-delete-{}", std::process::id()));
+        let manager = ResourceManager::new(&db_path, [0; 32]);
+        manager.create_database("test").unwrap();
+        // Open the database so that it has files on disk.
+        let session = manager.create_session("test", Version::Hrana2);
+        manager.get_conn(&session).unwrap();
+        assert!(matches!(
+            manager.delete_database("test"),
+            Err(HiisiError::DatabaseInUse(_))
+        ));
+        manager.drop_session(session.id);
+        drop(session);
+        manager.delete_database("test").unwrap();
+        assert!(!manager.database_exists("test"));
+        assert!(matches!(
+            manager.delete_database("test"),
+            Err(HiisiError::DatabaseNotFound(_))
+        ));
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn in_memory_database() {
+        let manager = ResourceManager::new_in_memory([0; 32]);
+        manager.create_database("test").unwrap();
+        assert!(matches!(
+            manager.create_database("test"),
+            Err(HiisiError::DatabaseExists(_))
+        ));
+        assert_eq!(manager.list_databases().unwrap(), vec!["test"]);
+        // The sessions get different connections, which share the database.
+        let writer = manager.create_session("test", Version::Hrana2);
+        let conn = manager.get_conn(&writer).unwrap();
+        for sql in ["CREATE TABLE t (x)", "INSERT INTO t VALUES (42)"] {
+            conn.prepare(sql).unwrap().step().unwrap();
+        }
+        let reader = manager.create_session("test", Version::Hrana2);
+        let conn = manager.get_conn(&reader).unwrap();
+        let stmt = conn.prepare("SELECT x FROM t").unwrap();
+        assert!(matches!(stmt.step().unwrap(), StepResult::Row));
+        assert_eq!(stmt.column_int(0), 42);
+        drop(stmt);
+        drop(conn);
+        manager.drop_session(writer.id);
+        manager.drop_session(reader.id);
+
+        manager.delete_database("test").unwrap();
+        assert!(!manager.database_exists("test"));
+        assert!(manager.list_databases().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "simulation")]
+    #[test]
+    fn storage_full() {
+        use crate::storage::{FaultyStorage, FileStorage, ENOSPC};
+        use std::rc::Rc;
+
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-manager-storage-{}", std::process::id()));
+        let storage = Rc::new(FaultyStorage::new(FileStorage, 0, 0.0));
+        let manager = ResourceManager::new(&db_path, [0; 32]).with_storage(storage.clone());
+        manager.create_database("test").unwrap();
+        // The first write to the database fails, but does not break it.
+        storage.fail_next(ENOSPC);
+        let session = manager.create_session("test", Version::Hrana2);
+        match manager.get_conn(&session) {
+            Err(HiisiError::IOError(_, e)) => assert_eq!(e.raw_os_error(), Some(ENOSPC)),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Connecting did not fail"),
+        }
+        manager.get_conn(&session).unwrap();
+        std::fs::remove_dir_all(db_path).unwrap();
     }
 }
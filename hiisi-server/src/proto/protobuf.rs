@@ -0,0 +1,622 @@
+//! Protobuf encoding of Hrana messages.
+//!
+//! The field numbers follow the `hrana.proto` schema of the Hrana over HTTP
+//! specification. Only the messages that the server receives are decoded,
+//! and only the messages that the server sends are encoded.
+
+use bytes::Bytes;
+
+use super::*;
+use crate::HiisiError;
+
+/// Decode a protobuf pipeline request.
+pub fn decode_pipeline_req(msg: &[u8]) -> Result<PipelineReqBody> {
+    let mut baton = None;
+    let mut requests = Vec::new();
+    let mut r = Reader::new(msg);
+    while let Some((tag, field)) = r.next_field()? {
+        match tag {
+            1 => baton = Some(field.string()?),
+            2 => requests.push(decode_stream_req(field.bytes()?)?),
+            _ => {}
+        }
+    }
+    Ok(PipelineReqBody { baton, requests })
+}
+
+/// Encode a pipeline response as protobuf.
+pub fn encode_pipeline_resp(resp: &PipelineRespBody) -> Bytes {
+    let mut w = Writer::default();
+    if let Some(baton) = &resp.baton {
+        w.string(1, baton);
+    }
+    if let Some(base_url) = &resp.base_url {
+        w.string(2, base_url);
+    }
+    for result in &resp.results {
+        w.message(3, |w| encode_stream_result(w, result));
+    }
+    Bytes::from(w.buf)
+}
+
+fn decode_stream_req(msg: &[u8]) -> Result<StreamRequest> {
+    let mut req = StreamRequest::None;
+    let mut r = Reader::new(msg);
+    while let Some((tag, field)) = r.next_field()? {
+        req = match tag {
+            1 => StreamRequest::Close(CloseStreamReq {}),
+            2 => {
+                let mut stmt = None;
+                let mut r = Reader::new(field.bytes()?);
+                while let Some((tag, field)) = r.next_field()? {
+                    if tag == 1 {
+                        stmt = Some(decode_stmt(field.bytes()?)?);
+                    }
+                }
+                StreamRequest::Execute(ExecuteStreamReq {
+                    stmt: stmt.ok_or_else(|| missing_field("ExecuteStreamReq.stmt"))?,
+                })
+            }
+            3 => {
+                let mut batch = None;
+                let mut r = Reader::new(field.bytes()?);
+                while let Some((tag, field)) = r.next_field()? {
+                    if tag == 1 {
+                        batch = Some(decode_batch(field.bytes()?)?);
+                    }
+                }
+                StreamRequest::Batch(BatchStreamReq {
+                    batch: batch.ok_or_else(|| missing_field("BatchStreamReq.batch"))?,
+                })
+            }
+            4 => {
+                let (sql, sql_id, replication_index) = decode_sql_ref(field.bytes()?)?;
+                StreamRequest::Sequence(SequenceStreamReq {
+                    sql,
+                    sql_id,
+                    replication_index,
+                })
+            }
+            5 => {
+                let (sql, sql_id, replication_index) = decode_sql_ref(field.bytes()?)?;
+                StreamRequest::Describe(DescribeStreamReq {
+                    sql,
+                    sql_id,
+                    replication_index,
+                })
+            }
+            6 => {
+                let mut sql_id = 0;
+                let mut sql = String::new();
+                let mut r = Reader::new(field.bytes()?);
+                while let Some((tag, field)) = r.next_field()? {
+                    match tag {
+                        1 => sql_id = field.varint()? as i32,
+                        2 => sql = field.string()?,
+                        _ => {}
+                    }
+                }
+                StreamRequest::StoreSql(StoreSqlStreamReq { sql_id, sql })
+            }
+            7 => {
+                let mut sql_id = 0;
+                let mut r = Reader::new(field.bytes()?);
+                while let Some((tag, field)) = r.next_field()? {
+                    if tag == 1 {
+                        sql_id = field.varint()? as i32;
+                    }
+                }
+                StreamRequest::CloseSql(CloseSqlStreamReq { sql_id })
+            }
+            8 => StreamRequest::GetAutocommit(GetAutocommitStreamReq {}),
+            _ => req,
+        };
+    }
+    match req {
+        StreamRequest::None => Err(HiisiError::ProtocolError(
+            "Unknown stream request".to_owned(),
+        )),
+        req => Ok(req),
+    }
+}
+
+/// Decode the SQL text or id, and the replication index, of a `Sequence` or
+/// `Describe` request.
+fn decode_sql_ref(msg: &[u8]) -> Result<(Option<String>, Option<i32>, Option<u64>)> {
+    let mut sql = None;
+    let mut sql_id = None;
+    let mut replication_index = None;
+    let mut r = Reader::new(msg);
+    while let Some((tag, field)) = r.next_field()? {
+        match tag {
+            1 => sql = Some(field.string()?),
+            2 => sql_id = Some(field.varint()? as i32),
+            3 => replication_index = Some(field.varint()?),
+            _ => {}
+        }
+    }
+    Ok((sql, sql_id, replication_index))
+}
+
+fn decode_stmt(msg: &[u8]) -> Result<Stmt> {
+    let mut stmt = Stmt {
+        sql: None,
+        sql_id: None,
+        args: vec![],
+        named_args: vec![],
+        want_rows: None,
+        replication_index: None,
+    };
+    let mut r = Reader::new(msg);
+    while let Some((tag, field)) = r.next_field()? {
+        match tag {
+            1 => stmt.sql = Some(field.string()?),
+            2 => stmt.sql_id = Some(field.varint()? as i32),
+            3 => stmt.args.push(decode_value(field.bytes()?)?),
+            4 => {
+                let mut name = String::new();
+                let mut value = Value::None;
+                let mut r = Reader::new(field.bytes()?);
+                while let Some((tag, field)) = r.next_field()? {
+                    match tag {
+                        1 => name = field.string()?,
+                        2 => value = decode_value(field.bytes()?)?,
+                        _ => {}
+                    }
+                }
+                stmt.named_args.push(NamedArg { name, value });
+            }
+            5 => stmt.want_rows = Some(field.varint()? != 0),
+            6 => stmt.replication_index = Some(field.varint()?),
+            _ => {}
+        }
+    }
+    Ok(stmt)
+}
+
+fn decode_value(msg: &[u8]) -> Result<Value> {
+    let mut value = Value::None;
+    let mut r = Reader::new(msg);
+    while let Some((tag, field)) = r.next_field()? {
+        value = match tag {
+            1 => Value::Null,
+            2 => Value::Integer {
+                value: zigzag_decode(field.varint()?),
+            },
+            3 => Value::Float {
+                value: f64::from_bits(field.fixed64()?),
+            },
+            4 => Value::Text {
+                value: field.string()?.into(),
+            },
+            5 => Value::Blob {
+                value: Bytes::copy_from_slice(field.bytes()?),
+            },
+            _ => value,
+        };
+    }
+    Ok(value)
+}
+
+fn decode_batch(msg: &[u8]) -> Result<Batch> {
+    let mut steps = Vec::new();
+    let mut replication_index = None;
+    let mut r = Reader::new(msg);
+    while let Some((tag, field)) = r.next_field()? {
+        match tag {
+            1 => {
+                let mut condition = None;
+                let mut stmt = None;
+                let mut r = Reader::new(field.bytes()?);
+                while let Some((tag, field)) = r.next_field()? {
+                    match tag {
+                        1 => condition = Some(decode_batch_cond(field.bytes()?)?),
+                        2 => stmt = Some(decode_stmt(field.bytes()?)?),
+                        _ => {}
+                    }
+                }
+                steps.push(BatchStep {
+                    condition,
+                    stmt: stmt.ok_or_else(|| missing_field("BatchStep.stmt"))?,
+                });
+            }
+            2 => replication_index = Some(field.varint()?),
+            _ => {}
+        }
+    }
+    Ok(Batch {
+        steps,
+        replication_index,
+    })
+}
+
+fn decode_batch_cond(msg: &[u8]) -> Result<BatchCond> {
+    let mut cond = BatchCond::None;
+    let mut r = Reader::new(msg);
+    while let Some((tag, field)) = r.next_field()? {
+        cond = match tag {
+            1 => BatchCond::Ok {
+                step: field.varint()? as u32,
+            },
+            2 => BatchCond::Error {
+                step: field.varint()? as u32,
+            },
+            3 => BatchCond::Not {
+                cond: Box::new(decode_batch_cond(field.bytes()?)?),
+            },
+            4 => BatchCond::And(decode_batch_cond_list(field.bytes()?)?),
+            5 => BatchCond::Or(decode_batch_cond_list(field.bytes()?)?),
+            6 => BatchCond::IsAutocommit {},
+            _ => cond,
+        };
+    }
+    Ok(cond)
+}
+
+fn decode_batch_cond_list(msg: &[u8]) -> Result<BatchCondList> {
+    let mut conds = Vec::new();
+    let mut r = Reader::new(msg);
+    while let Some((tag, field)) = r.next_field()? {
+        if tag == 1 {
+            conds.push(decode_batch_cond(field.bytes()?)?);
+        }
+    }
+    Ok(BatchCondList { conds })
+}
+
+fn encode_stream_result(w: &mut Writer, result: &StreamResult) {
+    match result {
+        StreamResult::None => {}
+        StreamResult::Ok { response } => w.message(1, |w| encode_stream_resp(w, response)),
+        StreamResult::Error { error } => w.message(2, |w| encode_error(w, error)),
+    }
+}
+
+fn encode_stream_resp(w: &mut Writer, resp: &StreamResponse) {
+    match resp {
+        StreamResponse::Close(_) => w.message(1, |_| {}),
+        StreamResponse::Execute(resp) => {
+            w.message(2, |w| w.message(1, |w| encode_stmt_result(w, &resp.result)))
+        }
+        StreamResponse::Batch(resp) => w.message(3, |w| {
+            w.message(1, |w| encode_batch_result(w, &resp.result))
+        }),
+        StreamResponse::Sequence(resp) => w.message(4, |w| w.varint(1, resp.total_changes)),
+        StreamResponse::Describe(resp) => w.message(5, |w| {
+            w.message(1, |w| encode_describe_result(w, &resp.result))
+        }),
+        StreamResponse::StoreSql(_) => w.message(6, |_| {}),
+        StreamResponse::CloseSql(_) => w.message(7, |_| {}),
+        StreamResponse::GetAutocommit(resp) => {
+            w.message(8, |w| w.varint(1, resp.is_autocommit as u64))
+        }
+    }
+}
+
+fn encode_error(w: &mut Writer, error: &Error) {
+    w.string(1, &error.message);
+    if let Some(code) = &error.code {
+        w.string(2, code);
+    }
+}
+
+fn encode_stmt_result(w: &mut Writer, result: &StmtResult) {
+    for col in &result.cols {
+        w.message(1, |w| {
+            if let Some(name) = &col.name {
+                w.string(1, name);
+            }
+            if let Some(decltype) = &col.decltype {
+                w.string(2, decltype);
+            }
+        });
+    }
+    for row in &result.rows {
+        w.message(2, |w| {
+            for value in &row.values {
+                w.message(1, |w| encode_value(w, value));
+            }
+        });
+    }
+    w.varint(3, result.affected_row_count);
+    if let Some(rowid) = result.last_insert_rowid {
+        w.varint(4, zigzag_encode(rowid));
+    }
+    if let Some(replication_index) = result.replication_index {
+        w.varint(5, replication_index);
+    }
+}
+
+fn encode_batch_result(w: &mut Writer, result: &BatchResult) {
+    // Step results and errors are maps from the step index.
+    for (step, step_result) in result.step_results.iter().enumerate() {
+        if let Some(step_result) = step_result {
+            w.message(1, |w| {
+                w.varint(1, step as u64);
+                w.message(2, |w| encode_stmt_result(w, step_result));
+            });
+        }
+    }
+    for (step, step_error) in result.step_errors.iter().enumerate() {
+        if let Some(step_error) = step_error {
+            w.message(2, |w| {
+                w.varint(1, step as u64);
+                w.message(2, |w| encode_error(w, step_error));
+            });
+        }
+    }
+    if let Some(replication_index) = result.replication_index {
+        w.varint(3, replication_index);
+    }
+}
+
+fn encode_describe_result(w: &mut Writer, result: &DescribeResult) {
+    for param in &result.params {
+        w.message(1, |w| {
+            if let Some(name) = &param.name {
+                w.string(1, name);
+            }
+        });
+    }
+    for col in &result.cols {
+        w.message(2, |w| {
+            w.string(1, &col.name);
+            if let Some(decltype) = &col.decltype {
+                w.string(2, decltype);
+            }
+        });
+    }
+    w.varint(3, result.is_explain as u64);
+    w.varint(4, result.is_readonly as u64);
+}
+
+fn encode_value(w: &mut Writer, value: &Value) {
+    match value {
+        Value::None => {}
+        Value::Null => w.message(1, |_| {}),
+        Value::Integer { value } => w.varint(2, zigzag_encode(*value)),
+        Value::Float { value } => w.fixed64(3, value.to_bits()),
+        Value::Text { value } => w.string(4, value),
+        Value::Blob { value } => w.bytes(5, value),
+    }
+}
+
+fn missing_field(name: &str) -> HiisiError {
+    HiisiError::ProtocolError(format!("Missing protobuf field {}", name))
+}
+
+fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn zigzag_decode(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+#[derive(Default)]
+pub(crate) struct Writer {
+    pub(crate) buf: Vec<u8>,
+}
+
+impl Writer {
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, tag: u32, wire_type: u64) {
+        self.raw_varint(((tag as u64) << 3) | wire_type);
+    }
+
+    pub(crate) fn varint(&mut self, tag: u32, value: u64) {
+        self.key(tag, WIRE_VARINT);
+        self.raw_varint(value);
+    }
+
+    pub(crate) fn fixed64(&mut self, tag: u32, value: u64) {
+        self.key(tag, WIRE_FIXED64);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn bytes(&mut self, tag: u32, value: &[u8]) {
+        self.key(tag, WIRE_LEN);
+        self.raw_varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    pub(crate) fn string(&mut self, tag: u32, value: &str) {
+        self.bytes(tag, value.as_bytes());
+    }
+
+    /// Write an embedded message that `f` encodes.
+    pub(crate) fn message(&mut self, tag: u32, f: impl FnOnce(&mut Writer)) {
+        let mut w = Writer::default();
+        f(&mut w);
+        self.bytes(tag, &w.buf);
+    }
+}
+
+pub(crate) enum Field<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    // No Hrana message has 32-bit fields, so their value is skipped.
+    Fixed32,
+}
+
+impl<'a> Field<'a> {
+    pub(crate) fn varint(&self) -> Result<u64> {
+        match self {
+            Field::Varint(value) => Ok(*value),
+            _ => Err(invalid_wire_type()),
+        }
+    }
+
+    pub(crate) fn fixed64(&self) -> Result<u64> {
+        match self {
+            Field::Fixed64(value) => Ok(*value),
+            _ => Err(invalid_wire_type()),
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> Result<&'a [u8]> {
+        match self {
+            Field::Bytes(value) => Ok(value),
+            _ => Err(invalid_wire_type()),
+        }
+    }
+
+    pub(crate) fn string(&self) -> Result<String> {
+        let bytes = self.bytes()?;
+        std::str::from_utf8(bytes)
+            .map(Into::into)
+            .map_err(|_| HiisiError::ProtocolError("Invalid UTF-8 in protobuf string".to_owned()))
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    /// Read the next field, or `None` at the end of the message.
+    pub(crate) fn next_field(&mut self) -> Result<Option<(u32, Field<'a>)>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let key = self.raw_varint()?;
+        let tag = (key >> 3) as u32;
+        let field = match key & 0x7 {
+            WIRE_VARINT => Field::Varint(self.raw_varint()?),
+            WIRE_FIXED64 => Field::Fixed64(u64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            WIRE_LEN => {
+                let len = self.raw_varint()? as usize;
+                Field::Bytes(self.take(len)?)
+            }
+            WIRE_FIXED32 => {
+                self.take(4)?;
+                Field::Fixed32
+            }
+            _ => return Err(invalid_wire_type()),
+        };
+        Ok(Some((tag, field)))
+    }
+
+    fn raw_varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.take(1)?.first().unwrap();
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(HiisiError::ProtocolError(
+            "Protobuf varint is too long".to_owned(),
+        ))
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(HiisiError::ProtocolError(
+                "Truncated protobuf message".to_owned(),
+            ));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+}
+
+fn invalid_wire_type() -> HiisiError {
+    HiisiError::ProtocolError("Unexpected protobuf wire type".to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_pipeline_req, encode_pipeline_resp, Reader, Writer};
+    use crate::proto::{
+        ExecuteStreamResp, PipelineRespBody, StmtResult, StreamRequest, StreamResponse,
+        StreamResult, Value,
+    };
+
+    #[test]
+    fn pipeline_round_trip() {
+        // An `Execute` request of `SELECT ?` with an integer argument,
+        // followed by a `Close` request.
+        let mut req = Writer::default();
+        req.message(2, |w| {
+            w.message(2, |w| {
+                w.message(1, |w| {
+                    w.string(1, "SELECT ?");
+                    w.message(3, |w| w.varint(2, 84));
+                    w.varint(5, 1);
+                })
+            })
+        });
+        req.message(2, |w| w.message(1, |_| {}));
+        let req = decode_pipeline_req(&req.buf).unwrap();
+        assert!(req.baton.is_none());
+        assert_eq!(req.requests.len(), 2);
+        match &req.requests[0] {
+            StreamRequest::Execute(req) => {
+                assert_eq!(req.stmt.sql.as_deref(), Some("SELECT ?"));
+                assert_eq!(req.stmt.want_rows, Some(true));
+                assert!(matches!(req.stmt.args[..], [Value::Integer { value: 42 }]));
+            }
+            req => panic!("Unexpected request: {:?}", req),
+        }
+        assert!(matches!(req.requests[1], StreamRequest::Close(_)));
+
+        let resp = PipelineRespBody {
+            baton: Some("baton".to_owned()),
+            base_url: None,
+            results: vec![StreamResult::Ok {
+                response: StreamResponse::Execute(ExecuteStreamResp {
+                    result: StmtResult {
+                        cols: vec![],
+                        rows: vec![],
+                        affected_row_count: 3,
+                        last_insert_rowid: Some(-1),
+                        replication_index: None,
+                        rows_read: 0,
+                        rows_written: 0,
+                        query_duration_ms: 0.0,
+                    },
+                }),
+            }],
+        };
+        let resp = encode_pipeline_resp(&resp);
+        let mut r = Reader::new(&resp);
+        let (tag, field) = r.next_field().unwrap().unwrap();
+        assert_eq!(tag, 1);
+        assert_eq!(field.string().unwrap(), "baton");
+        // results[0].ok.execute.result
+        let (tag, field) = r.next_field().unwrap().unwrap();
+        assert_eq!(tag, 3);
+        let mut result = field.bytes().unwrap();
+        for expected_tag in [1, 2, 1] {
+            let (tag, field) = Reader::new(result).next_field().unwrap().unwrap();
+            assert_eq!(tag, expected_tag);
+            result = field.bytes().unwrap();
+        }
+        let mut r = Reader::new(result);
+        let (tag, field) = r.next_field().unwrap().unwrap();
+        assert_eq!((tag, field.varint().unwrap()), (3, 3));
+        let (tag, field) = r.next_field().unwrap().unwrap();
+        assert_eq!((tag, field.varint().unwrap()), (4, 1));
+        assert!(r.next_field().unwrap().is_none());
+    }
+}
@@ -0,0 +1,134 @@
+//! Rate limiting of requests.
+//!
+//! Every namespace, or every client of a namespace, has a token bucket that
+//! holds up to `burst` tokens and refills at `requests_per_sec`. A request
+//! takes a token, and a request that finds the bucket empty is rejected
+//! until the bucket has refilled. The time is read from the clock of the
+//! resource manager, so in the simulator the buckets refill in virtual time.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Maximum number of buckets that are kept before the full ones are
+/// dropped. A full bucket is the same as a bucket that does not exist yet.
+const MAX_BUCKETS: usize = 10_000;
+
+/// The rate that the requests to a namespace are limited to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// The rate at which the bucket refills.
+    pub requests_per_sec: u32,
+    /// The number of requests that may be made at once after the bucket
+    /// has refilled.
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Limit the requests to `requests_per_sec`, with bursts of up to
+    /// `burst` requests. Neither may be zero.
+    pub fn new(requests_per_sec: u32, burst: u32) -> Self {
+        assert!(requests_per_sec > 0, "rate limit must not be zero");
+        assert!(burst > 0, "rate limit burst must not be zero");
+        Self {
+            requests_per_sec,
+            burst,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Duration,
+    /// The time at which the bucket will have refilled completely.
+    full_at: Duration,
+}
+
+impl Bucket {
+    /// Add the tokens that `limit` refills the bucket with until `now`.
+    fn refill(&mut self, limit: RateLimit, now: Duration) {
+        let elapsed = now.saturating_sub(self.updated_at).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * limit.requests_per_sec as f64).min(limit.burst as f64);
+        self.updated_at = now;
+    }
+}
+
+/// The key of a bucket: the namespace and, if the namespace is limited per
+/// client, the address of the client.
+type BucketKey = (String, Option<IpAddr>);
+
+/// The token buckets of the namespaces.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: RefCell<HashMap<BucketKey, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take a token from the bucket of `db_name` and `client` at `now`.
+    ///
+    /// Returns how long it takes for the bucket to refill by a token, if
+    /// it is empty.
+    pub fn acquire(
+        &self,
+        db_name: &str,
+        client: Option<IpAddr>,
+        limit: RateLimit,
+        now: Duration,
+    ) -> Result<(), Duration> {
+        let mut buckets = self.buckets.borrow_mut();
+        let key = (db_name.to_owned(), client);
+        if !buckets.contains_key(&key) && buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+            full_at: now,
+        });
+        bucket.refill(limit, now);
+        let rate = limit.requests_per_sec as f64;
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate));
+        }
+        bucket.tokens -= 1.0;
+        bucket.full_at = now + Duration::from_secs_f64((limit.burst as f64 - bucket.tokens) / rate);
+        Ok(())
+    }
+
+    /// Drop the buckets of a database, such as when it is deleted.
+    pub fn remove(&self, db_name: &str) {
+        self.buckets
+            .borrow_mut()
+            .retain(|(name, _), _| name != db_name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RateLimit, RateLimiter};
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let limiter = RateLimiter::new();
+        let limit = RateLimit::new(2, 3);
+        let at = Duration::from_millis;
+        for _ in 0..3 {
+            assert!(limiter.acquire("test", None, limit, at(0)).is_ok());
+        }
+        assert_eq!(
+            limiter.acquire("test", None, limit, at(250)),
+            Err(Duration::from_millis(250))
+        );
+        // Other namespaces have buckets of their own.
+        assert!(limiter.acquire("other", None, limit, at(250)).is_ok());
+        assert!(limiter.acquire("test", None, limit, at(500)).is_ok());
+        assert!(limiter.acquire("test", None, limit, at(500)).is_err());
+    }
+}
@@ -1,11 +1,25 @@
 use anyhow::Result;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use socket2::{SockAddr, Socket};
 
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::time::Duration;
 
+use crate::buffer::BufferPool;
+use crate::clock::Clock;
+use crate::cursor::{self, Cursor, CursorRequest};
+use crate::database::Backup;
 use crate::executor::{self, Request};
 use crate::http;
+use crate::stats::ServerStats;
+use crate::websocket::{self, WsConn};
 use crate::ResourceManager;
 use crate::{proto, HiisiError};
 
@@ -13,96 +27,2033 @@ pub type IO<T> = crate::io::IO<Context<T>>;
 
 pub struct Context<T> {
     pub manager: Rc<ResourceManager>,
+    /// The clock that time-dependent logic reads the time from, which is the
+    /// clock of the resource manager.
+    pub clock: Rc<dyn Clock>,
+    /// Hrana version negotiated for the request that is being handled.
+    pub version: Cell<Option<proto::Version>>,
+    /// State of the client connections.
+    conns: ConnectionRegistry,
+    /// Buffers that the connections receive requests into.
+    recv_buffers: BufferPool,
+    /// How long a connection may wait for the next request before it is
+    /// closed.
+    pub(crate) idle_timeout: Duration,
+    /// How many client connections may be open at once.
+    max_connections: usize,
+    /// How large the body of a request may be.
+    max_body_bytes: usize,
+    /// How many requests a connection may have outstanding before the
+    /// server stops reading from it.
+    max_pipeline_depth: usize,
+    /// How long a request waits for its database to catch up with the
+    /// replication index that it reads with.
+    replication_wait_timeout: Duration,
+    /// Listeners that stopped accepting because the server is at
+    /// `max_connections`, which accept again once a connection closes.
+    paused_accepts: RefCell<Vec<(Rc<Socket>, SockAddr)>>,
+    /// The listener sockets, which are closed on shutdown.
+    pub(crate) listeners: RefCell<Vec<Rc<Socket>>>,
+    /// The time by which the connections have to drain, if the server is
+    /// shutting down.
+    drain_deadline: Cell<Option<Duration>>,
+    stats: ServerStats,
+    /// Number of pipeline requests handled, which correlation ids are
+    /// derived from.
+    request_seq: Cell<u64>,
     pub user_data: T,
 }
 
+/// How long a connection may wait for the next request, unless set with
+/// `Context::with_idle_timeout()`.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many client connections may be open at once, unless set with
+/// `Context::with_max_connections()`.
+pub const MAX_CONNECTIONS: usize = 1024;
+
+/// How large the body of a request may be, unless set with
+/// `Context::with_max_body_bytes()`.
+pub const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// How many requests a connection may have outstanding, unless set with
+/// `Context::with_max_pipeline_depth()`.
+pub const MAX_PIPELINE_DEPTH: usize = 16;
+
+/// How many receive buffers are kept for reuse, unless set with
+/// `Context::with_recv_buffers()`.
+pub const RECV_BUFFERS: usize = 64;
+
+/// How long a request waits for its database to catch up with the
+/// replication index that it reads with, unless set with
+/// `Context::with_replication_wait_timeout()`.
+pub const REPLICATION_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often a waiting request checks whether its database has caught up.
+const REPLICATION_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// How often a request that waits for a lock tries to take it again.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
 impl<T> Context<T> {
     pub fn new(manager: Rc<ResourceManager>, user_data: T) -> Self {
-        Self { manager, user_data }
+        Self {
+            clock: manager.clock().clone(),
+            manager,
+            version: Cell::new(None),
+            conns: ConnectionRegistry::default(),
+            recv_buffers: BufferPool::new(RECV_BUFFERS),
+            idle_timeout: IDLE_TIMEOUT,
+            max_connections: MAX_CONNECTIONS,
+            max_body_bytes: MAX_BODY_BYTES,
+            max_pipeline_depth: MAX_PIPELINE_DEPTH,
+            replication_wait_timeout: REPLICATION_WAIT_TIMEOUT,
+            paused_accepts: RefCell::new(Vec::new()),
+            listeners: RefCell::new(Vec::new()),
+            drain_deadline: Cell::new(None),
+            stats: ServerStats::default(),
+            request_seq: Cell::new(0),
+            user_data,
+        }
+    }
+
+    /// Close connections that have not received any bytes for `timeout`,
+    /// whether they are between requests or in the middle of one.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Fail requests that read with a replication index that their database
+    /// has not caught up with within `timeout`, rather than after
+    /// `REPLICATION_WAIT_TIMEOUT`.
+    ///
+    /// A zero timeout fails them right away.
+    pub fn with_replication_wait_timeout(mut self, timeout: Duration) -> Self {
+        self.replication_wait_timeout = timeout;
+        self
+    }
+
+    /// Stop accepting connections while `max` client connections are open.
+    /// Further connections wait in the backlog of the listener until one of
+    /// the open connections closes.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Reject requests whose body is larger than `max` bytes with HTTP 413,
+    /// and close their connections.
+    ///
+    /// A body with chunked transfer encoding counts with its chunk framing,
+    /// which is what the server buffers, so the server never buffers more
+    /// than `max` bytes of a body.
+    pub fn with_max_body_bytes(mut self, max: usize) -> Self {
+        self.max_body_bytes = max;
+        self
+    }
+
+    /// Stop reading from a connection while it has `max` requests
+    /// outstanding, that is, received but not responded to, and read again
+    /// once responses have drained.
+    ///
+    /// The server reads the requests that a client pipelines while it sends
+    /// the response to the previous one, so the limit bounds how much a
+    /// client that does not read its responses makes the server buffer.
+    pub fn with_max_pipeline_depth(mut self, max: usize) -> Self {
+        self.max_pipeline_depth = max.max(1);
+        self
+    }
+
+    /// Keep up to `max` receive buffers for reuse once their connections
+    /// are done with them.
+    pub fn with_recv_buffers(mut self, max: usize) -> Self {
+        self.recv_buffers = BufferPool::new(max);
+        self
+    }
+
+    /// The number of open client connections, not counting those of the
+    /// admin API.
+    pub fn connections(&self) -> usize {
+        self.conns.count(|conn| conn.role == Role::Data)
+    }
+
+    /// The counters of what the server has done.
+    pub fn stats(&self) -> &ServerStats {
+        &self.stats
+    }
+
+    /// Assign a correlation id to the next pipeline request.
+    fn next_request_id(&self, baton: Option<&str>) -> String {
+        let seq = self.request_seq.get();
+        self.request_seq.set(seq + 1);
+        correlation_id(baton, seq)
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.drain_deadline.get().is_some()
+    }
+
+    /// Register a connection that the admin API has accepted, so that it
+    /// drains on shutdown like the others do.
+    pub(crate) fn add_admin_conn(&self, sock: Rc<Socket>, peer: Option<IpAddr>) {
+        self.conns.insert(ConnState::new(sock, peer, Role::Admin));
+    }
+
+    /// Remove a connection of the admin API once its socket is closed,
+    /// along with the backup it was sending, if any.
+    pub(crate) fn remove_admin_conn(&self, sock: &Socket) {
+        self.conns.remove(sock);
+    }
+
+    /// Mark whether an admin API connection has a request in flight.
+    pub(crate) fn set_busy(&self, sock: &Socket, busy: bool) {
+        if let Some(mut conn) = self.conns.get_mut(sock) {
+            conn.busy = busy;
+        }
+    }
+
+    /// Keep the backup that is being sent on an admin API connection until
+    /// its next step.
+    pub(crate) fn put_backup(&self, sock: &Socket, backup: Backup) {
+        if let Some(mut conn) = self.conns.get_mut(sock) {
+            conn.backup = Some(backup);
+        }
+    }
+
+    /// Take the backup that is being sent on an admin API connection, or
+    /// `None` if the connection has been closed under it.
+    pub(crate) fn take_backup(&self, sock: &Socket) -> Option<Backup> {
+        self.conns.get_mut(sock)?.backup.take()
+    }
+}
+
+/// Per-connection state, which is kept across the requests of a
+/// keep-alive connection.
+struct ConnState {
+    sock: Rc<Socket>,
+    /// Which API the connection is served with.
+    role: Role,
+    /// Bytes received that have not been handled as a request yet, in a
+    /// buffer from the pool of the context while there are any.
+    recv_buf: BytesMut,
+    /// Whether a request has been received completely, but the response to
+    /// it has not been sent yet.
+    busy: bool,
+    /// Whether the request being handled asked to close the connection
+    /// after the response.
+    close: bool,
+    /// Number of requests that have been received completely, but not
+    /// responded to, including the one being handled.
+    outstanding: usize,
+    /// Whether a receive is posted on the connection.
+    reading: bool,
+    /// Whether the client has shut down its side of the connection, so that
+    /// nothing more is received after the bytes in the buffer.
+    eof: bool,
+    /// The request being handled, if it waits for its database to catch up
+    /// with the replication index that it reads with.
+    replication_wait: Option<ReplicationWait>,
+    /// The pipeline request being handled, if it waits for a lock that
+    /// another connection holds on its database.
+    lock_wait: Option<LockWait>,
+    /// Whether the interim `100 Continue` response has been sent to the
+    /// request that is being received.
+    continue_sent: bool,
+    /// The address of the client, if it connected over IP.
+    peer: Option<IpAddr>,
+    /// The baton that the last response on the connection handed out,
+    /// which the next request on the connection most likely continues.
+    baton: Option<String>,
+    /// The cursor that is being streamed to the connection.
+    cursor: Option<Cursor>,
+    /// The WebSocket state, once the connection has been upgraded.
+    websocket: Option<WsConn>,
+    /// The backup that the admin API is sending to the connection.
+    backup: Option<Backup>,
+}
+
+/// A request that waits for its database to catch up, which is executed
+/// again every `REPLICATION_POLL_INTERVAL` until `deadline`.
+struct ReplicationWait {
+    req: Bytes,
+    deadline: Duration,
+}
+
+/// A pipeline request that waits for a lock, which is executed again from
+/// the request that it stopped at every `LOCK_RETRY_INTERVAL` until
+/// `deadline`.
+struct LockWait {
+    exec: PipelineExec,
+    blocked: executor::BlockedPipeline,
+    deadline: Duration,
+}
+
+impl ConnState {
+    fn new(sock: Rc<Socket>, peer: Option<IpAddr>, role: Role) -> Self {
+        Self {
+            sock,
+            role,
+            recv_buf: BytesMut::new(),
+            busy: false,
+            close: false,
+            outstanding: 0,
+            reading: false,
+            eof: false,
+            replication_wait: None,
+            lock_wait: None,
+            continue_sent: false,
+            peer,
+            baton: None,
+            cursor: None,
+            websocket: None,
+            backup: None,
+        }
+    }
+
+    /// Whether the connection is waiting for a request, with nothing of it
+    /// received yet.
+    fn is_idle(&self) -> bool {
+        !self.busy && self.recv_buf.is_empty()
+    }
+}
+
+/// The state of the open client connections.
+///
+/// A connection is registered when it is accepted and removed when its
+/// socket is closed. The connections are keyed by the raw fds of their
+/// sockets, which the kernel hands out again once a socket is closed, so a
+/// lookup finds a connection only if it has the very socket looked up. A
+/// callback that fires for a connection that has closed in the meantime
+/// finds nothing, rather than the state of a later connection.
+///
+/// All of the state of a connection, down to the cursor that it streams or
+/// the streams of its WebSocket, is kept in its entry, so that a closed
+/// connection is cleaned up from the entry that is removed.
+#[derive(Default)]
+struct ConnectionRegistry {
+    conns: RefCell<HashMap<RawFd, ConnState>>,
+}
+
+impl ConnectionRegistry {
+    fn insert(&self, conn: ConnState) {
+        self.conns.borrow_mut().insert(conn.sock.as_raw_fd(), conn);
+    }
+
+    fn remove(&self, sock: &Socket) -> Option<ConnState> {
+        let mut conns = self.conns.borrow_mut();
+        match conns.get(&sock.as_raw_fd()) {
+            Some(conn) if std::ptr::eq(&*conn.sock, sock) => conns.remove(&sock.as_raw_fd()),
+            _ => None,
+        }
+    }
+
+    fn get(&self, sock: &Socket) -> Option<Ref<'_, ConnState>> {
+        Ref::filter_map(self.conns.borrow(), |conns| {
+            conns
+                .get(&sock.as_raw_fd())
+                .filter(|conn| std::ptr::eq(&*conn.sock, sock))
+        })
+        .ok()
+    }
+
+    fn get_mut(&self, sock: &Socket) -> Option<RefMut<'_, ConnState>> {
+        RefMut::filter_map(self.conns.borrow_mut(), |conns| {
+            conns
+                .get_mut(&sock.as_raw_fd())
+                .filter(|conn| std::ptr::eq(&*conn.sock, sock))
+        })
+        .ok()
+    }
+
+    fn len(&self) -> usize {
+        self.conns.borrow().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.conns.borrow().is_empty()
+    }
+
+    /// The number of connections that `filter` selects.
+    fn count(&self, filter: impl Fn(&ConnState) -> bool) -> usize {
+        self.conns
+            .borrow()
+            .values()
+            .filter(|conn| filter(conn))
+            .count()
+    }
+
+    /// The sockets of the connections that `filter` selects, in the order of
+    /// their fds, which keeps simulations deterministic.
+    fn socks(&self, filter: impl Fn(&ConnState) -> bool) -> Vec<Rc<Socket>> {
+        let mut socks: Vec<Rc<Socket>> = self
+            .conns
+            .borrow()
+            .values()
+            .filter(|conn| filter(conn))
+            .map(|conn| conn.sock.clone())
+            .collect();
+        socks.sort_by_key(|sock| sock.as_raw_fd());
+        socks
+    }
+}
+
+/// How long a shutdown waits for the requests in flight to finish before it
+/// closes their connections.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn serve<T>(io: &mut IO<T>, sock: Rc<Socket>, addr: SockAddr) {
+    io.context().listeners.borrow_mut().push(sock.clone());
+    io.accept(sock, addr, on_accept);
+}
+
+/// What the connections that a listener accepts are served with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// The SQL HTTP API, as served by `serve()`.
+    Data,
+    /// The admin HTTP API, as served by `admin::serve_admin()`.
+    Admin,
+}
+
+/// Serve every listener with the handler of its role on the same IO.
+///
+/// Every listener re-arms its own accept, so a listener that has no
+/// connections waiting does not hold up the others.
+pub fn serve_all<T>(io: &mut IO<T>, listeners: Vec<(Rc<Socket>, SockAddr, Role)>) {
+    for (sock, addr, role) in listeners {
+        match role {
+            Role::Data => serve(io, sock, addr),
+            Role::Admin => crate::admin::serve_admin(io, sock, addr),
+        }
+    }
+}
+
+/// Serve like `serve()`, but over TLS with `config`.
+///
+/// A connection whose handshake fails is closed like a connection that the
+/// client closes.
+#[cfg(all(feature = "tls", not(feature = "simulation")))]
+pub fn serve_tls<T>(
+    io: &mut IO<T>,
+    sock: Rc<Socket>,
+    addr: SockAddr,
+    config: std::sync::Arc<rustls::ServerConfig>,
+) {
+    io.listen_tls(&sock, config);
+    serve(io, sock, addr);
+}
+
+/// Builds a server that is ready to run: its resource manager, its context,
+/// its IO, and the listeners that the IO serves.
+///
+/// The databases are kept in memory unless the builder has a data directory
+/// or a resource manager of its own.
+pub struct Builder {
+    listen_addr: SocketAddr,
+    admin_listen_addr: Option<SocketAddr>,
+    data_dir: Option<PathBuf>,
+    manager: Option<ResourceManager>,
+    /// The PEM files of the certificate chain and the private key to serve
+    /// SQL HTTP requests over TLS with.
+    tls_files: Option<(PathBuf, PathBuf)>,
+    max_connections: usize,
+    max_body_bytes: usize,
+    #[cfg(feature = "simulation")]
+    faults: crate::io::Faults,
+}
+
+impl Builder {
+    /// Start building a server that listens for SQL HTTP requests on
+    /// `listen_addr`.
+    pub fn new(listen_addr: SocketAddr) -> Self {
+        Self {
+            listen_addr,
+            admin_listen_addr: None,
+            data_dir: None,
+            manager: None,
+            tls_files: None,
+            max_connections: MAX_CONNECTIONS,
+            max_body_bytes: MAX_BODY_BYTES,
+            #[cfg(feature = "simulation")]
+            faults: crate::io::Faults::default(),
+        }
+    }
+
+    /// Listen for admin HTTP requests on `addr` as well.
+    pub fn with_admin_listen_addr(mut self, addr: SocketAddr) -> Self {
+        self.admin_listen_addr = Some(addr);
+        self
+    }
+
+    /// Keep the databases in `data_dir`, with a resource manager that has
+    /// the default configuration and a random baton key.
+    pub fn with_data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(data_dir.into());
+        self
+    }
+
+    /// Serve the databases of `manager`, which is configured already.
+    pub fn with_manager(mut self, manager: ResourceManager) -> Self {
+        self.manager = Some(manager);
+        self
+    }
+
+    /// Serve SQL HTTP requests over TLS with the certificate chain in
+    /// `cert_file` and the private key in `key_file`, which are PEM files.
+    pub fn with_tls(mut self, cert_file: impl Into<PathBuf>, key_file: impl Into<PathBuf>) -> Self {
+        self.tls_files = Some((cert_file.into(), key_file.into()));
+        self
+    }
+
+    /// See `Context::with_max_connections()`.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// See `Context::with_max_body_bytes()`.
+    pub fn with_max_body_bytes(mut self, max: usize) -> Self {
+        self.max_body_bytes = max;
+        self
+    }
+
+    /// Inject the network faults of `faults` into the simulated IO.
+    #[cfg(feature = "simulation")]
+    pub fn with_faults(mut self, faults: crate::io::Faults) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Build the server, with `user_data` in its context.
+    ///
+    /// The default database is created unless it exists already, so that
+    /// requests without a `Host` have a database to go to. Fails if the
+    /// options conflict, or if a listener cannot be set up.
+    pub fn build<T>(self, user_data: T) -> crate::Result<IO<T>> {
+        if self.data_dir.is_some() && self.manager.is_some() {
+            return Err(HiisiError::InvalidConfig(
+                "a data directory and a resource manager are mutually exclusive".to_owned(),
+            ));
+        }
+        if self.tls_files.is_some() && (cfg!(feature = "simulation") || !cfg!(feature = "tls")) {
+            return Err(HiisiError::InvalidConfig(
+                "TLS requires the `tls` feature, which the simulated IO does not support"
+                    .to_owned(),
+            ));
+        }
+        let manager = match (self.manager, &self.data_dir) {
+            (Some(manager), _) => manager,
+            (None, Some(data_dir)) => ResourceManager::new(data_dir, crate::baton::generate_key()),
+            (None, None) => ResourceManager::new_in_memory(crate::baton::generate_key()),
+        };
+        let manager = Rc::new(manager);
+        match manager.create_database(DEFAULT_DATABASE) {
+            Ok(()) | Err(HiisiError::DatabaseExists(_)) => {}
+            Err(e) => return Err(e),
+        }
+        let ctx = Context::new(manager, user_data)
+            .with_max_connections(self.max_connections)
+            .with_max_body_bytes(self.max_body_bytes);
+        #[cfg(feature = "simulation")]
+        let mut io = IO::with_faults(ctx, self.faults);
+        #[cfg(not(feature = "simulation"))]
+        let mut io = IO::new(ctx);
+
+        let mut listeners = Vec::new();
+        log::info!("Listening for SQL HTTP requests on {:?}", self.listen_addr);
+        let data_sock = listen(self.listen_addr)?;
+        let data_addr: SockAddr = self.listen_addr.into();
+        if let Some(addr) = self.admin_listen_addr {
+            log::info!("Listening for admin HTTP requests on {:?}", addr);
+            listeners.push((listen(addr)?, addr.into(), Role::Admin));
+        }
+        match self.tls_files {
+            #[cfg(all(feature = "tls", not(feature = "simulation")))]
+            Some((cert_file, key_file)) => {
+                let config = load_tls_config(&cert_file, &key_file)?;
+                serve_tls(&mut io, data_sock, data_addr, config);
+            }
+            _ => listeners.push((data_sock, data_addr, Role::Data)),
+        }
+        serve_all(&mut io, listeners);
+        Ok(io)
+    }
+}
+
+/// Set up a listener socket on `addr`.
+#[cfg(not(feature = "simulation"))]
+fn listen(addr: SocketAddr) -> crate::Result<Rc<Socket>> {
+    let domain = socket2::Domain::for_address(addr);
+    let sock = Socket::new(domain, socket2::Type::STREAM, None)
+        .map_err(|e| HiisiError::IOError("socket", e))?;
+    sock.set_reuse_address(true)
+        .map_err(|e| HiisiError::IOError("set_reuse_address", e))?;
+    sock.set_reuse_port(true)
+        .map_err(|e| HiisiError::IOError("set_reuse_port", e))?;
+    sock.bind(&addr.into())
+        .map_err(|e| HiisiError::IOError("bind", e))?;
+    sock.listen(128)
+        .map_err(|e| HiisiError::IOError("listen", e))?;
+    Ok(Rc::new(sock))
+}
+
+/// Set up a listener socket on `addr`, which the simulated IO needs no more
+/// than an unbound socket for.
+#[cfg(feature = "simulation")]
+fn listen(_addr: SocketAddr) -> crate::Result<Rc<Socket>> {
+    let sock = Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)
+        .map_err(|e| HiisiError::IOError("socket", e))?;
+    Ok(Rc::new(sock))
+}
+
+/// Load the certificate chain and the private key to serve over TLS with.
+#[cfg(all(feature = "tls", not(feature = "simulation")))]
+fn load_tls_config(
+    cert_file: &std::path::Path,
+    key_file: &std::path::Path,
+) -> crate::Result<std::sync::Arc<rustls::ServerConfig>> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| HiisiError::TlsError(format!("{}: {}", cert_file.display(), e)))?;
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|e| HiisiError::TlsError(format!("{}: {}", key_file.display(), e)))?;
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| HiisiError::TlsError(e.to_string()))?;
+    Ok(std::sync::Arc::new(config))
+}
+
+/// Shut the server down gracefully.
+///
+/// Connections that wait for a request are closed right away, and the
+/// others once the request that they are receiving or responding to has
+/// been handled. New connections get HTTP 503 while the connections drain.
+/// Connections that have not drained within `DRAIN_TIMEOUT` are closed
+/// regardless. Finally, the listener sockets are closed.
+///
+/// The function runs the IO until the server has shut down.
+pub fn shutdown<T>(io: &mut IO<T>) {
+    let clock = io.context().clock.clone();
+    let deadline = clock.now() + DRAIN_TIMEOUT;
+    io.context().drain_deadline.set(Some(deadline));
+    log::info!(
+        "Shutting down, draining {} connections",
+        io.context().conns.len()
+    );
+    let idle = io.context().conns.socks(ConnState::is_idle);
+    for sock in idle {
+        close_conn(io, sock);
+    }
+    while !io.context().conns.is_empty() && clock.now() < deadline {
+        io.run_once();
+    }
+    let stragglers = io.context().conns.socks(|_| true);
+    if !stragglers.is_empty() {
+        log::warn!(
+            "Closing {} connections that did not drain in time",
+            stragglers.len()
+        );
+    }
+    for sock in stragglers {
+        close_conn(io, sock);
+    }
+    // Listeners that are paused at the connection limit must not accept
+    // again once they are closed.
+    io.context().paused_accepts.take();
+    let listeners = io.context().listeners.take();
+    for sock in listeners {
+        io.close(sock, on_listener_close);
+    }
+    // Run the closes.
+    io.run_once();
+    log::info!("Shut down");
+}
+
+fn on_listener_close<T>(_io: &mut IO<T>, _sock: Rc<Socket>) {}
+
+/// The number of requests that have been received, but not responded to.
+pub fn requests_in_flight<T>(io: &IO<T>) -> usize {
+    io.context().conns.count(|conn| conn.busy)
+}
+
+fn on_accept<T>(
+    io: &mut IO<T>,
+    server_sock: Rc<Socket>,
+    server_addr: SockAddr,
+    conn_sock: Rc<Socket>,
+    sock_addr: SockAddr,
+) {
+    log::trace!("Server accepted connection from {:?}", sock_addr);
+    conn_sock.set_nodelay(true).unwrap();
+    io.context().stats.add_connection();
+    let peer = sock_addr.as_socket().map(|addr| addr.ip());
+    let mut conn = ConnState::new(conn_sock.clone(), peer, Role::Data);
+    if io.context().is_draining() {
+        // The connection is answered and closed, but it drains like the
+        // others do.
+        conn.busy = true;
+        conn.close = true;
+        io.context().conns.insert(conn);
+        accept_next(io, server_sock, server_addr);
+        let resp = http::ResponseBuilder::new(http::StatusCode::SERVICE_UNAVAILABLE)
+            .with_keep_alive(false)
+            .build("Server is shutting down".into());
+        let n = resp.len();
+        io.send(conn_sock, resp, n, on_send);
+        return;
+    }
+    io.context().conns.insert(conn);
+    accept_next(io, server_sock, server_addr);
+    recv_request(io, conn_sock);
+}
+
+/// Accept the next connection on the listener, unless the server is at its
+/// connection limit, in which case the listener waits for a connection to
+/// close.
+fn accept_next<T>(io: &mut IO<T>, server_sock: Rc<Socket>, server_addr: SockAddr) {
+    let ctx = io.context();
+    if ctx.connections() >= ctx.max_connections {
+        log::debug!(
+            "At the limit of {} connections, pausing accept",
+            ctx.max_connections
+        );
+        ctx.paused_accepts
+            .borrow_mut()
+            .push((server_sock, server_addr));
+        return;
+    }
+    io.accept(server_sock, server_addr, on_accept);
+}
+
+/// Receive the next request on the connection, or more of the request that
+/// is being received.
+fn recv_request<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    if let Some(mut conn) = io.context().conns.get_mut(&sock) {
+        if conn.reading || conn.eof {
+            return;
+        }
+        conn.reading = true;
+    }
+    let timeout = io.context().idle_timeout;
+    io.recv_timeout(sock, timeout, on_recv, on_recv_timeout);
+}
+
+/// Receive the requests that the client pipelines while the response to its
+/// previous request is being sent, unless the connection has
+/// `max_pipeline_depth` requests outstanding already, in which case reading
+/// resumes once responses have drained.
+fn read_ahead<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    let max_pipeline_depth = io.context().max_pipeline_depth;
+    let read = match io.context().conns.get(&sock) {
+        Some(conn) if conn.close => false,
+        Some(conn) if conn.outstanding >= max_pipeline_depth => {
+            log::trace!(
+                "Connection has {} requests outstanding, pausing reads",
+                conn.outstanding
+            );
+            false
+        }
+        Some(_) => true,
+        None => false,
+    };
+    if read {
+        recv_request(io, sock);
+    }
+}
+
+fn on_recv_timeout<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    let busy = match io.context().conns.get_mut(&sock) {
+        Some(mut conn) => {
+            conn.reading = false;
+            conn.busy
+        }
+        None => false,
+    };
+    if busy {
+        // The connection is not idle, as a response is being sent. Reading
+        // starts over once it has been.
+        log::trace!("Read ahead timed out while responding");
+        return;
+    }
+    log::trace!("Closing idle connection");
+    close_conn(io, sock);
+}
+
+const HEALTH_RESPONSE: &str = r#"{"status":"ok"}"#;
+
+const VERSION_RESPONSE: &str = concat!(
+    r#"{"version":""#,
+    env!("CARGO_PKG_VERSION"),
+    r#"","protocols":["hrana2","hrana3"]}"#
+);
+
+// The response header that carries the correlation id of a pipeline
+// request.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// The content type of the Prometheus text exposition format.
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// Maximum size of the cursor entries that are sent in one chunk.
+const MAX_CURSOR_CHUNK_SIZE: usize = 16 * 1024;
+
+enum Response {
+    // A complete HTTP response.
+    Complete(Bytes),
+    // The beginning of a cursor response. The rest of the response is
+    // produced from the cursor as the previous part has been sent.
+    Cursor(Bytes, Box<Cursor>),
+    // A pipeline request that waits for a lock on its database.
+    Blocked(PipelineExec, executor::BlockedPipeline),
+}
+
+/// What the response to a pipeline request is logged and formatted with.
+struct PipelineExec {
+    request_id: String,
+    database: String,
+    version: proto::Version,
+    encoding: proto::Encoding,
+    request_count: usize,
+    keep_alive: bool,
+    /// When the request started executing.
+    start: Duration,
+}
+
+/// Execute a request, and format its response for a connection that stays
+/// open afterwards if `keep_alive` is set.
+///
+/// A request that is executed again, because it waits for its database to
+/// catch up, has been counted against the rate limit already, and passes
+/// `count_rate_limit` as `false`.
+fn execute_request<T>(
+    io: &mut IO<T>,
+    sock: &Socket,
+    buf: &[u8],
+    keep_alive: bool,
+    count_rate_limit: bool,
+) -> Result<Response> {
+    let ctx = io.context();
+    match parse_request(buf)? {
+        ClientRequest::Pipeline(req, encoding) => {
+            check_database(ctx, &req.database)?;
+            if count_rate_limit {
+                check_rate_limit(ctx, sock, &req.database)?;
+            }
+            if let Some(replication_index) = executor::pipeline_replication_index(&req.req) {
+                ctx.manager
+                    .check_caught_up(&req.database, replication_index)?;
+            }
+            ctx.version.set(Some(req.version));
+            ctx.stats.add_request();
+            let exec = PipelineExec {
+                request_id: ctx.next_request_id(req.req.baton.as_deref()),
+                database: req.database.clone(),
+                version: req.version,
+                encoding,
+                request_count: req.req.requests.len(),
+                keep_alive,
+                start: ctx.clock.now(),
+            };
+            let block = !ctx.manager.busy_timeout().is_zero();
+            let outcome = executor::execute_client_req_or_block(
+                ctx.manager.clone(),
+                req,
+                Some(&exec.request_id),
+                block,
+            );
+            finish_pipeline(ctx, sock, exec, outcome)
+        }
+        ClientRequest::Health => Ok(Response::Complete(
+            http::ResponseBuilder::new(http::StatusCode::OK)
+                .with_content_type("application/json")
+                .with_keep_alive(keep_alive)
+                .build(Bytes::from_static(HEALTH_RESPONSE.as_bytes())),
+        )),
+        ClientRequest::Version => Ok(Response::Complete(
+            http::ResponseBuilder::new(http::StatusCode::OK)
+                .with_content_type("application/json")
+                .with_keep_alive(keep_alive)
+                .build(Bytes::from_static(VERSION_RESPONSE.as_bytes())),
+        )),
+        ClientRequest::Metrics => {
+            let sessions = ctx.manager.session_count();
+            Ok(Response::Complete(
+                http::ResponseBuilder::new(http::StatusCode::OK)
+                    .with_content_type(METRICS_CONTENT_TYPE)
+                    .with_keep_alive(keep_alive)
+                    .build(ctx.stats.format_prometheus(sessions).into()),
+            ))
+        }
+        ClientRequest::WebSocket {
+            database,
+            version,
+            key,
+            protocol,
+        } => {
+            check_database(ctx, &database)?;
+            if count_rate_limit {
+                check_rate_limit(ctx, sock, &database)?;
+            }
+            // A socket that is not registered, as with `handle_bytes()`, is
+            // not upgraded, as it is never read from.
+            if let Some(mut conn) = ctx.conns.get_mut(sock) {
+                conn.websocket = Some(WsConn::new(database, version, conn.peer));
+            }
+            Ok(Response::Complete(http::format_websocket_upgrade(
+                &websocket::accept_key(&key),
+                protocol,
+            )))
+        }
+        ClientRequest::Cursor(req) => {
+            check_database(ctx, &req.database)?;
+            if count_rate_limit {
+                check_rate_limit(ctx, sock, &req.database)?;
+            }
+            if let Some(replication_index) = req.req.batch.replication_index {
+                ctx.manager
+                    .check_caught_up(&req.database, replication_index)?;
+            }
+            ctx.version.set(Some(req.version));
+            ctx.stats.add_request();
+            let request_id = ctx.next_request_id(req.req.baton.as_deref());
+            let (mut cursor, resp) =
+                cursor::open_cursor(ctx.manager.clone(), req, Some(&request_id))?;
+            let mut buf = http::format_chunked_response_head(
+                http::StatusCode::OK,
+                "application/json",
+                keep_alive,
+            );
+            let mut data = BytesMut::new();
+            proto::format_msg_into(&resp, &mut data)?;
+            data.extend_from_slice(b"\n");
+            if let Some(mut conn) = ctx.conns.get_mut(sock) {
+                conn.baton = resp.baton.clone();
+            }
+            if format_cursor_chunk(&mut buf, &mut cursor, data)? {
+                Ok(Response::Cursor(buf.into(), Box::new(cursor)))
+            } else {
+                Ok(Response::Complete(buf.into()))
+            }
+        }
+    }
+}
+
+/// Log and format the response to a pipeline request that was received on
+/// `sock`, unless the request waits for a lock.
+fn finish_pipeline<T>(
+    ctx: &Context<T>,
+    sock: &Socket,
+    exec: PipelineExec,
+    outcome: crate::Result<executor::PipelineOutcome>,
+) -> Result<Response> {
+    let resp = match outcome {
+        Ok(executor::PipelineOutcome::Blocked(blocked)) => {
+            return Ok(Response::Blocked(exec, blocked))
+        }
+        Ok(executor::PipelineOutcome::Done(resp)) => Ok(resp),
+        Err(err) => Err(err),
+    };
+    log_request(
+        &exec.request_id,
+        exec.version,
+        &exec.database,
+        exec.request_count,
+        resp.as_ref(),
+        ctx.clock.now().saturating_sub(exec.start),
+    );
+    let resp = resp?;
+    if let Some(mut conn) = ctx.conns.get_mut(sock) {
+        conn.baton = resp.baton.clone();
+    }
+    for result in &resp.results {
+        ctx.stats
+            .add_result(matches!(result, proto::StreamResult::Ok { .. }));
+    }
+    let resp = proto::format_resp(&resp, exec.encoding)?;
+    Ok(Response::Complete(
+        http::ResponseBuilder::new(http::StatusCode::OK)
+            .with_content_type(exec.encoding.content_type())
+            .with_keep_alive(exec.keep_alive)
+            .with_header(
+                http::header::HeaderName::from_static(REQUEST_ID_HEADER),
+                &exec.request_id,
+            )
+            .build(resp),
+    ))
+}
+
+/// Derive the correlation id of a pipeline request from the baton it
+/// continues and its sequence number on the server.
+///
+/// `DefaultHasher::new()` always uses the same keys, so a seeded simulation
+/// assigns the same ids on every run.
+fn correlation_id(baton: Option<&str>, seq: u64) -> String {
+    let mut hasher = DefaultHasher::new();
+    baton.hash(&mut hasher);
+    seq.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Log a pipeline request that has been executed.
+fn log_request(
+    request_id: &str,
+    version: proto::Version,
+    database: &str,
+    request_count: usize,
+    resp: std::result::Result<&proto::PipelineRespBody, &HiisiError>,
+    latency: Duration,
+) {
+    let path = match version {
+        proto::Version::Hrana2 => "/v2/pipeline",
+        proto::Version::Hrana3 => "/v3/pipeline",
+    };
+    match resp {
+        Ok(resp) => {
+            let results: Vec<_> = resp
+                .results
+                .iter()
+                .map(|result| match result {
+                    proto::StreamResult::Ok { response } => response.type_name(),
+                    proto::StreamResult::Error { .. } => "error",
+                    proto::StreamResult::None => "none",
+                })
+                .collect();
+            log::debug!(
+                "[{}] POST {} database={} requests={} results={:?} latency={:?}",
+                request_id,
+                path,
+                database,
+                request_count,
+                results,
+                latency
+            );
+        }
+        Err(err) => log::debug!(
+            "[{}] POST {} database={} requests={} failed: {} latency={:?}",
+            request_id,
+            path,
+            database,
+            request_count,
+            err,
+            latency
+        ),
+    }
+}
+
+/// Check that the database a request is routed to exists, rather than
+/// letting the first connection to it create the database file.
+fn check_database<T>(ctx: &Context<T>, db_name: &str) -> std::result::Result<(), RequestError> {
+    if !ctx.manager.database_exists(db_name) {
+        return Err(HiisiError::DatabaseNotFound(db_name.to_owned()).into());
+    }
+    Ok(())
+}
+
+/// Take a request to a database off its rate limit, before the request
+/// opens a connection to the database.
+fn check_rate_limit<T>(ctx: &Context<T>, sock: &Socket, db_name: &str) -> Result<()> {
+    let peer = ctx.conns.get(sock).and_then(|conn| conn.peer);
+    ctx.manager.check_rate_limit(db_name, peer)?;
+    Ok(())
+}
+
+/// Append a chunk of cursor entries, starting with `data`, to `buf`.
+///
+/// Returns `false` if the cursor is exhausted, in which case the chunk that
+/// terminates the response is appended as well.
+fn format_cursor_chunk(
+    buf: &mut BytesMut,
+    cursor: &mut Cursor,
+    mut data: BytesMut,
+) -> Result<bool> {
+    let mut more = true;
+    while data.len() < MAX_CURSOR_CHUNK_SIZE {
+        match cursor.next_entry() {
+            Some(entry) => {
+                proto::format_msg_into(&entry, &mut data)?;
+                data.extend_from_slice(b"\n");
+            }
+            None => {
+                more = false;
+                break;
+            }
+        }
+    }
+    if !data.is_empty() {
+        http::format_chunk(buf, &data);
+    }
+    if !more {
+        buf.extend_from_slice(http::LAST_CHUNK);
+    }
+    Ok(more)
+}
+
+fn on_recv<T>(io: &mut IO<T>, sock: Rc<Socket>, buf: &[u8], n: usize) {
+    if n == 0 {
+        // A receive that completes with no bytes is end-of-file, whereas a
+        // short read completes with some. The client may still be waiting
+        // for the responses to the requests it has sent, so those are
+        // handled before the connection is closed.
+        let half_closed = match io.context().conns.get_mut(&sock) {
+            Some(mut conn) if conn.websocket.is_none() => {
+                conn.reading = false;
+                conn.eof = true;
+                true
+            }
+            _ => false,
+        };
+        if !half_closed {
+            log::trace!("Client closed connection");
+            close_conn(io, sock);
+            return;
+        }
+        log::trace!("Client shut down its side of the connection");
+        process_request(io, sock);
+        return;
+    }
+    {
+        let mut entry = io
+            .context()
+            .conns
+            .get_mut(&sock)
+            .expect("connection is accepted");
+        let conn = &mut *entry;
+        conn.reading = false;
+        // When receiving POST request with chunked encoding,
+        // the end marker consist of those bytes - [13, 10, 48, 13, 10, 13, 10]
+        // and we don't recv them from the socket in one go.
+        if conn.recv_buf.is_empty() && n == 7 && is_complete_chunked_encoding_mark(&buf[..n]) {
+            drop(entry);
+            recv_request(io, sock);
+            return;
+        }
+        io.context().stats.add_bytes_received(n);
+        if conn.recv_buf.capacity() == 0 {
+            conn.recv_buf = io.context().recv_buffers.take();
+        }
+        conn.recv_buf.extend_from_slice(&buf[..n]);
+        let max_body_bytes = io.context().max_body_bytes;
+        conn.outstanding =
+            usize::from(conn.busy) + complete_requests(&conn.recv_buf, max_body_bytes);
+    }
+    process_request(io, sock);
+}
+
+/// Count the requests that have been received completely at the beginning
+/// of `buf`.
+fn complete_requests(mut buf: &[u8], max_body_bytes: usize) -> usize {
+    let mut count = 0;
+    while let Ok(Some(len)) = request_len(buf, max_body_bytes) {
+        count += 1;
+        buf = &buf[len..];
+    }
+    count
+}
+
+/// Handle the next request received on the connection, or wait for more of
+/// it if it has not been received completely.
+fn process_request<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    let busy = io.context().conns.get(&sock).is_some_and(|conn| conn.busy);
+    if busy {
+        // Responses go out in the order of the requests, so the request is
+        // handled once the response to the previous one has been sent.
+        read_ahead(io, sock);
+        return;
+    }
+    let websocket = io
+        .context()
+        .conns
+        .get(&sock)
+        .is_some_and(|conn| conn.websocket.is_some());
+    if websocket {
+        process_frames(io, sock);
+        return;
+    }
+    let mut send_continue = false;
+    let req = {
+        let mut entry = io
+            .context()
+            .conns
+            .get_mut(&sock)
+            .expect("connection is accepted");
+        let conn = &mut *entry;
+        let max_body_bytes = io.context().max_body_bytes;
+        // A body that is too large fails before the client is told to send
+        // it, as its `Content-Length` is checked first.
+        let req = match request_len(&conn.recv_buf, max_body_bytes).and_then(|len| match len {
+            None if !conn.continue_sent => {
+                send_continue = expects_continue(&conn.recv_buf)?;
+                Ok(None)
+            }
+            len => Ok(len),
+        }) {
+            // Bytes after the request are the beginning of the next request,
+            // so they are kept in the buffer.
+            Ok(Some(len)) => Ok(Some(conn.recv_buf.split_to(len).freeze())),
+            Ok(None) => Ok(None),
+            // The end of a malformed request is unknown, so everything that
+            // has been received is discarded with it.
+            Err(err) => {
+                conn.recv_buf.clear();
+                // The rest of an oversized body is still on its way, and
+                // would be mistaken for the next request. So is the body of
+                // a request whose expectation fails, if the client sends it
+                // anyway.
+                if let RequestError::BodyTooLarge(_) | RequestError::ExpectationFailed = err {
+                    conn.close = true;
+                }
+                Err(err)
+            }
+        };
+        if send_continue {
+            conn.continue_sent = true;
+        }
+        if let Ok(Some(req)) = &req {
+            conn.busy = true;
+            conn.close = is_connection_close(req);
+            conn.continue_sent = false;
+        }
+        req
+    };
+    let req = match req {
+        Ok(Some(req)) => req,
+        Ok(None) => {
+            let (idle, eof) = io
+                .context()
+                .conns
+                .get(&sock)
+                .map_or((false, false), |conn| (conn.is_idle(), conn.eof));
+            if eof && idle {
+                log::trace!("Closing connection at end-of-file");
+                close_conn(io, sock);
+                return;
+            }
+            if eof {
+                // The rest of the request will never arrive.
+                log::trace!("Connection ended in the middle of a request");
+                if let Some(mut conn) = io.context().conns.get_mut(&sock) {
+                    conn.recv_buf.clear();
+                    conn.close = true;
+                }
+                let resp = format_request_error(&RequestError::Incomplete, false);
+                let n = resp.len();
+                io.send(sock, resp, n, on_send);
+                return;
+            }
+            if idle && io.context().is_draining() {
+                log::trace!("Closing drained connection");
+                close_conn(io, sock);
+                return;
+            }
+            if send_continue {
+                // The client waits for the interim response before it sends
+                // the body.
+                log::trace!("Sending 100 Continue");
+                let resp = Bytes::from_static(CONTINUE_RESPONSE);
+                let n = resp.len();
+                io.send(sock, resp, n, on_continue_send);
+                return;
+            }
+            log::trace!("Waiting for the rest of the request");
+            recv_request(io, sock);
+            return;
+        }
+        Err(err) => {
+            log::trace!("Malformed request: {}", err);
+            let keep_alive = io
+                .context()
+                .conns
+                .get(&sock)
+                .is_some_and(|conn| !conn.close);
+            let resp = format_request_error(&err, keep_alive);
+            let n = resp.len();
+            io.send(sock, resp, n, on_send);
+            return;
+        }
+    };
+    let keep_alive = io
+        .context()
+        .conns
+        .get(&sock)
+        .is_some_and(|conn| !conn.close);
+    respond(io, sock, req, keep_alive, None);
+}
+
+/// Execute a request and send its response.
+///
+/// A request that reads with a replication index that its database has not
+/// caught up with waits for it without blocking the other connections, and
+/// is executed again on every `REPLICATION_POLL_INTERVAL` until the database
+/// has caught up or `deadline` has passed. The deadline is set by the first
+/// execution of the request. A pipeline request that waits for a lock waits
+/// likewise, as `wait_for_lock()` describes.
+fn respond<T>(
+    io: &mut IO<T>,
+    sock: Rc<Socket>,
+    req: Bytes,
+    keep_alive: bool,
+    deadline: Option<Duration>,
+) {
+    let resp = match execute_request(io, &sock, &req, keep_alive, deadline.is_none()) {
+        Ok(Response::Complete(resp)) => resp,
+        Ok(Response::Cursor(resp, cursor)) => {
+            if let Some(mut conn) = io.context().conns.get_mut(&sock) {
+                conn.cursor = Some(*cursor);
+            }
+            let n = resp.len();
+            io.send(sock.clone(), resp, n, on_cursor_send);
+            read_ahead(io, sock);
+            return;
+        }
+        Ok(Response::Blocked(exec, blocked)) => {
+            let deadline = io.context().clock.now() + io.context().manager.busy_timeout();
+            wait_for_lock(io, &sock, exec, blocked, deadline);
+            read_ahead(io, sock);
+            return;
+        }
+        Err(x) => {
+            let behind = matches!(
+                x.downcast_ref::<HiisiError>(),
+                Some(HiisiError::ReplicaBehind(..))
+            );
+            if behind && wait_for_replica(io, &sock, req, deadline) {
+                read_ahead(io, sock);
+                return;
+            }
+            format_error_response(&x, keep_alive)
+        }
+    };
+
+    let n = resp.len();
+    io.send(sock.clone(), resp, n, on_send);
+    read_ahead(io, sock);
+}
+
+/// Park a request until its database may have caught up, returning `false`
+/// if it has waited for long enough already.
+fn wait_for_replica<T>(
+    io: &mut IO<T>,
+    sock: &Rc<Socket>,
+    req: Bytes,
+    deadline: Option<Duration>,
+) -> bool {
+    let now = io.context().clock.now();
+    let deadline = deadline.unwrap_or(now + io.context().replication_wait_timeout);
+    if now >= deadline {
+        return false;
+    }
+    match io.context().conns.get_mut(&sock) {
+        Some(mut conn) => conn.replication_wait = Some(ReplicationWait { req, deadline }),
+        None => return false,
+    }
+    log::trace!("Waiting for the replica to catch up");
+    let interval = REPLICATION_POLL_INTERVAL.min(deadline - now);
+    io.sleep(sock.clone(), interval, on_replication_poll);
+    true
+}
+
+fn on_replication_poll<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    // The connection may have closed while the request waited.
+    let wait = match io.context().conns.get_mut(&sock) {
+        Some(mut conn) => {
+            let keep_alive = !conn.close;
+            conn.replication_wait.take().map(|wait| (wait, keep_alive))
+        }
+        None => None,
+    };
+    if let Some((wait, keep_alive)) = wait {
+        respond(io, sock, wait.req, keep_alive, Some(wait.deadline));
+    }
+}
+
+/// Park a pipeline request that waits for a lock until the lock may have
+/// been released.
+///
+/// The other connections are served while the request waits, so the
+/// session that holds the lock gets to commit or roll back its transaction.
+/// The request is executed again from the request that it stopped at on
+/// every `LOCK_RETRY_INTERVAL`, and once `deadline` has passed, the request
+/// that waits fails with `SQLITE_BUSY` like it would without a busy timeout.
+fn wait_for_lock<T>(
+    io: &mut IO<T>,
+    sock: &Rc<Socket>,
+    exec: PipelineExec,
+    blocked: executor::BlockedPipeline,
+    deadline: Duration,
+) {
+    let now = io.context().clock.now();
+    match io.context().conns.get_mut(sock) {
+        Some(mut conn) => {
+            conn.lock_wait = Some(LockWait {
+                exec,
+                blocked,
+                deadline,
+            })
+        }
+        None => return,
+    }
+    log::trace!("Waiting for a lock on the database");
+    let interval = LOCK_RETRY_INTERVAL.min(deadline.saturating_sub(now));
+    io.sleep(sock.clone(), interval, on_lock_retry);
+}
+
+fn on_lock_retry<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    // The connection may have closed while the request waited.
+    let wait = io
+        .context()
+        .conns
+        .get_mut(&sock)
+        .and_then(|mut conn| conn.lock_wait.take());
+    let Some(wait) = wait else {
+        return;
+    };
+    let ctx = io.context();
+    let block = ctx.clock.now() < wait.deadline;
+    let keep_alive = wait.exec.keep_alive;
+    let outcome = executor::resume_client_req(
+        ctx.manager.clone(),
+        wait.blocked,
+        Some(&wait.exec.request_id),
+        block,
+    );
+    let resp = match finish_pipeline(ctx, &sock, wait.exec, outcome) {
+        Ok(Response::Complete(resp)) => resp,
+        Ok(Response::Blocked(exec, blocked)) => {
+            wait_for_lock(io, &sock, exec, blocked, wait.deadline);
+            return;
+        }
+        Ok(Response::Cursor(..)) => unreachable!("pipeline response is a cursor"),
+        Err(x) => format_error_response(&x, keep_alive),
+    };
+    let n = resp.len();
+    io.send(sock.clone(), resp, n, on_send);
+    read_ahead(io, sock);
+}
+
+/// Handle the requests in `buf` as if they had been received on `sock`, and
+/// return the bytes of their responses, without sending or receiving
+/// anything on the socket.
+///
+/// Every complete request in `buf` is handled along the path that requests
+/// received from the network take, and cursor responses are streamed to the
+/// end. A request that is incomplete at the end of `buf` gets no response.
+/// This is a hook for tests, which cannot drive WebSocket frames through it.
+pub fn handle_bytes<T>(io: &mut IO<T>, sock: &Socket, mut buf: &[u8]) -> Bytes {
+    let max_body_bytes = io.context().max_body_bytes;
+    let mut out = BytesMut::new();
+    while !buf.is_empty() {
+        let len = match request_len(buf, max_body_bytes) {
+            Ok(Some(len)) => len,
+            Ok(None) => break,
+            Err(err) => {
+                out.extend_from_slice(&format_request_error(&err, false));
+                break;
+            }
+        };
+        let (req, rest) = buf.split_at(len);
+        buf = rest;
+        let keep_alive = !is_connection_close(req);
+        match execute_request(io, sock, req, keep_alive, true) {
+            Ok(Response::Complete(resp)) => out.extend_from_slice(&resp),
+            Ok(Response::Blocked(exec, blocked)) => {
+                // Nothing else runs while the bytes are handled, so the lock
+                // would not be released by waiting for it.
+                let ctx = io.context();
+                let outcome = executor::resume_client_req(
+                    ctx.manager.clone(),
+                    blocked,
+                    Some(&exec.request_id),
+                    false,
+                );
+                match finish_pipeline(ctx, sock, exec, outcome) {
+                    Ok(Response::Complete(resp)) => out.extend_from_slice(&resp),
+                    Ok(_) => unreachable!("pipeline blocked without blocking"),
+                    Err(err) => out.extend_from_slice(&format_error_response(&err, keep_alive)),
+                }
+            }
+            Ok(Response::Cursor(resp, mut cursor)) => {
+                out.extend_from_slice(&resp);
+                let ctx = io.context();
+                loop {
+                    match format_cursor_chunk(&mut out, &mut cursor, BytesMut::new()) {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        Err(err) => {
+                            log::error!("Failed to format cursor entry: {}", err);
+                            cursor.abort(&ctx.manager);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(err) => out.extend_from_slice(&format_error_response(&err, keep_alive)),
+        }
+    }
+    out.freeze()
+}
+
+/// Handle the frames received on a WebSocket connection, or wait for more
+/// of them if none has been received completely.
+fn process_frames<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    let ctx = io.context();
+    let mut out = BytesMut::new();
+    let idle = {
+        let mut entry = ctx.conns.get_mut(&sock).expect("connection is accepted");
+        let conn = &mut *entry;
+        let ws = conn.websocket.as_mut().expect("connection is upgraded");
+        loop {
+            match websocket::parse_frame(&conn.recv_buf, ctx.max_body_bytes) {
+                Ok(Some((frame, len))) => {
+                    let _ = conn.recv_buf.split_to(len);
+                    if !ws.handle_frame(&ctx.manager, &ctx.stats, frame, &mut out) {
+                        conn.close = true;
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(code) => {
+                    log::trace!("Malformed WebSocket frame");
+                    conn.recv_buf.clear();
+                    websocket::format_close_frame(&mut out, code, None);
+                    conn.close = true;
+                    break;
+                }
+            }
+        }
+        if !out.is_empty() {
+            conn.busy = true;
+        }
+        conn.is_idle()
+    };
+    if !out.is_empty() {
+        let n = out.len();
+        io.send(sock, out.freeze(), n, on_send);
+        return;
+    }
+    if idle && io.context().is_draining() {
+        log::trace!("Closing drained connection");
+        close_conn(io, sock);
+        return;
+    }
+    recv_request(io, sock);
+}
+
+fn format_error_response(err: &anyhow::Error, keep_alive: bool) -> Bytes {
+    match err.downcast_ref::<RequestError>() {
+        Some(err) => format_request_error(err, keep_alive),
+        None => {
+            let hiisi_err = err.downcast_ref::<HiisiError>();
+            let status = hiisi_err.map_or(http::StatusCode::BAD_REQUEST, HiisiError::status);
+            let retry_after;
+            let mut builder = http::ResponseBuilder::new(status).with_keep_alive(keep_alive);
+            if let Some(HiisiError::RateLimited(wait)) = hiisi_err {
+                retry_after = retry_after_secs(*wait).to_string();
+                builder = builder.with_header(http::header::RETRY_AFTER, &retry_after);
+            }
+            builder.build(format!("{}", err).into())
+        }
+    }
+}
+
+/// The `Retry-After` of a rate limited request, in whole seconds, which
+/// the client must not retry before `wait` has passed.
+fn retry_after_secs(wait: Duration) -> u64 {
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    secs.max(1)
+}
+
+/// Format the response to a request that failed to parse or validate.
+///
+/// The body is a Hrana error object, so that clients can tell the failure
+/// apart from other errors by its code.
+fn format_request_error(err: &RequestError, keep_alive: bool) -> Bytes {
+    let error = err.to_proto_error();
+    let body = match proto::format_msg(&error) {
+        Ok(body) => body,
+        Err(_) => error.message.into(),
+    };
+    let mut builder =
+        http::ResponseBuilder::new(err.status()).with_content_type("application/json");
+    match err {
+        RequestError::MethodNotAllowed { allow, .. } => {
+            builder = builder.with_header(http::header::ALLOW, allow);
+        }
+        // The rest of an oversized body would be mistaken for the next
+        // request, and so would a body that the client sends regardless of
+        // a failed expectation, so the connection is closed.
+        RequestError::BodyTooLarge(_) | RequestError::ExpectationFailed => {
+            return builder.with_keep_alive(false).build(body)
+        }
+        _ => {}
+    }
+    builder.with_keep_alive(keep_alive).build(body)
+}
+
+fn close_conn<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    io.close(sock, on_close);
+}
+
+/// Free the state of a connection once its socket is closed.
+fn on_close<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    let conn = io.context().conns.remove(&sock);
+    if let Some(mut conn) = conn {
+        log::trace!("Connection closed (baton = {:?})", conn.baton);
+        if let Some(cursor) = conn.cursor.take() {
+            cursor.abort(&io.context().manager);
+        }
+        if let Some(ws) = conn.websocket.take() {
+            ws.close(&io.context().manager);
+        }
+        if conn.recv_buf.capacity() > 0 {
+            io.context().recv_buffers.put(conn.recv_buf);
+        }
+    }
+    let paused = io.context().paused_accepts.borrow_mut().pop();
+    if let Some((server_sock, server_addr)) = paused {
+        accept_next(io, server_sock, server_addr);
+    }
+}
+
+fn on_cursor_send<T>(io: &mut IO<T>, sock: Rc<Socket>, n: usize) {
+    let manager = io.context().manager.clone();
+    let cursor = io
+        .context()
+        .conns
+        .get_mut(&sock)
+        .and_then(|mut conn| conn.cursor.take());
+    let mut cursor = cursor.expect("cursor is streamed to the socket");
+    if n == 0 {
+        log::trace!("Client closed connection while reading a cursor");
+        cursor.abort(&manager);
+        close_conn(io, sock);
+        return;
+    }
+    io.context().stats.add_bytes_sent(n);
+    let mut buf = BytesMut::new();
+    match format_cursor_chunk(&mut buf, &mut cursor, BytesMut::new()) {
+        Ok(true) => {
+            if let Some(mut conn) = io.context().conns.get_mut(&sock) {
+                conn.cursor = Some(cursor);
+            }
+            let n = buf.len();
+            io.send(sock, buf.into(), n, on_cursor_send);
+        }
+        Ok(false) => {
+            let n = buf.len();
+            io.send(sock, buf.into(), n, on_send);
+        }
+        Err(err) => {
+            // The response is already partially sent, so the only way to
+            // tell the client about the failure is to close the connection.
+            log::error!("Failed to format cursor entry: {}", err);
+            cursor.abort(&manager);
+            close_conn(io, sock);
+        }
+    }
+}
+
+/// Return the length of the request at the beginning of `buf`, or `None` if
+/// the request head, or the body that follows it, have not been received
+/// completely yet. The body is either `Content-Length` bytes long or, with
+/// `Transfer-Encoding: chunked`, ends with the last chunk.
+///
+/// A body larger than `max_body_bytes` fails as soon as its `Content-Length`
+/// is known, or a chunked body as soon as more than `max_body_bytes` of it
+/// have been received.
+fn request_len(
+    buf: &[u8],
+    max_body_bytes: usize,
+) -> std::result::Result<Option<usize>, RequestError> {
+    parse_head(buf, |req, status| {
+        let body_off = match status {
+            httparse::Status::Complete(body_off) => body_off,
+            httparse::Status::Partial => return Ok(None),
+        };
+        body_len(buf, req, body_off, max_body_bytes)
+    })
+}
+
+fn body_len(
+    buf: &[u8],
+    req: &httparse::Request,
+    body_off: usize,
+    max_body_bytes: usize,
+) -> std::result::Result<Option<usize>, RequestError> {
+    if is_chunked(req)? {
+        let body_len = http::decode_chunked(&buf[body_off..])?.map(|(_, len)| len);
+        let received = body_len.unwrap_or(buf.len() - body_off);
+        if received > max_body_bytes {
+            return Err(RequestError::BodyTooLarge(max_body_bytes));
+        }
+        return Ok(body_len.map(|body_len| body_off + body_len));
+    }
+    let mut content_length = None;
+    for header in req.headers.iter() {
+        if header.name.eq_ignore_ascii_case("Content-Length") {
+            let value = header_str(header, "Content-Length")?;
+            let value = value
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| RequestError::InvalidHeader("Content-Length"))?;
+            content_length = Some(value);
+            break;
+        }
     }
+    if content_length.is_some_and(|content_length| content_length > max_body_bytes) {
+        return Err(RequestError::BodyTooLarge(max_body_bytes));
+    }
+    // Without a `Content-Length`, the body is empty, and the bytes that
+    // follow the head belong to the next request.
+    let len = match content_length {
+        Some(content_length) => body_off + content_length,
+        None => body_off,
+    };
+    if buf.len() < len {
+        return Ok(None);
+    }
+    Ok(Some(len))
 }
 
-pub fn serve<T>(io: &mut IO<T>, sock: Rc<Socket>, addr: SockAddr) {
-    io.accept(sock, addr, on_accept);
+/// Whether the request body has chunked transfer encoding, which takes
+/// precedence over `Content-Length`.
+fn is_chunked(req: &httparse::Request) -> std::result::Result<bool, RequestError> {
+    for header in req.headers.iter() {
+        if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
+            let value = header_str(header, "Transfer-Encoding")?;
+            // Chunked is the only encoding that is supported, as the end of
+            // the body is unknown with the others.
+            return match value.trim() {
+                value if value.eq_ignore_ascii_case("chunked") => Ok(true),
+                _ => Err(RequestError::InvalidHeader("Transfer-Encoding")),
+            };
+        }
+    }
+    Ok(false)
 }
 
-fn on_accept<T>(
-    io: &mut IO<T>,
-    server_sock: Rc<Socket>,
-    server_addr: SockAddr,
-    conn_sock: Rc<Socket>,
-    sock_addr: SockAddr,
-) {
-    log::trace!("Server accepted connection from {:?}", sock_addr);
-    conn_sock.set_nodelay(true).unwrap();
-    io.accept(server_sock, server_addr, on_accept);
-    io.recv(conn_sock, on_recv);
+/// The interim response that tells a client which expects it to send the
+/// body of its request.
+const CONTINUE_RESPONSE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// Check if the request at the beginning of `buf` asks with
+/// `Expect: 100-continue` to be told to send its body, once its head is
+/// complete.
+///
+/// Any other expectation fails, as the server does not know how to meet it.
+fn expects_continue(buf: &[u8]) -> std::result::Result<bool, RequestError> {
+    parse_head(buf, |req, status| {
+        if status.is_partial() {
+            return Ok(false);
+        }
+        let mut expects = false;
+        for header in req.headers.iter() {
+            if header.name.eq_ignore_ascii_case("Expect") {
+                let value = header_str(header, "Expect")?;
+                if !value.trim().eq_ignore_ascii_case("100-continue") {
+                    return Err(RequestError::ExpectationFailed);
+                }
+                expects = true;
+            }
+        }
+        Ok(expects)
+    })
 }
 
-fn execute_request<T>(io: &mut IO<T>, buf: &[u8]) -> Result<Bytes> {
-    let ctx = io.context();
-    let req = parse_request(&buf)?;
-    let resp = executor::execute_client_req(ctx.manager.clone(), req)?;
-    Ok(proto::format_msg(&resp)?)
+/// Whether the request has a `Connection: close` header.
+fn is_connection_close(req: &[u8]) -> bool {
+    parse_head(req, |parsed, _| {
+        Ok(parsed.headers.iter().any(|header| {
+            header.name.eq_ignore_ascii_case("Connection")
+                && std::str::from_utf8(header.value).is_ok_and(|value| {
+                    value
+                        .split(',')
+                        .any(|token| token.trim().eq_ignore_ascii_case("close"))
+                })
+        }))
+    })
+    .unwrap_or(false)
 }
 
-fn on_recv<T>(io: &mut IO<T>, sock: Rc<Socket>, buf: &[u8], n: usize) {
-    if n == 0 {
-        log::trace!("Client closed connection");
-        io.close(sock);
-        return;
+/// Number of headers that fit in the array that requests are first parsed
+/// with.
+const HEADERS: usize = 64;
+
+/// Maximum number of headers in a request. Requests with more headers are
+/// rejected with `431 Request Header Fields Too Large`.
+pub const MAX_HEADERS: usize = 96;
+
+/// Parse the head of the request at the beginning of `buf` and pass it to
+/// `f`, along with the offset of the body if the head is complete.
+///
+/// The head is parsed into an array on the stack first, and parsed again
+/// into larger arrays if it has more headers than fit, up to `MAX_HEADERS`.
+fn parse_head<'b, R>(
+    buf: &'b [u8],
+    f: impl FnOnce(
+        &mut httparse::Request<'_, 'b>,
+        httparse::Status<usize>,
+    ) -> std::result::Result<R, RequestError>,
+) -> std::result::Result<R, RequestError> {
+    let mut stack_headers = [httparse::EMPTY_HEADER; HEADERS];
+    let mut heap_headers;
+    let mut len = HEADERS;
+    loop {
+        let headers: &mut [httparse::Header<'b>] = if len == HEADERS {
+            &mut stack_headers
+        } else {
+            heap_headers = vec![httparse::EMPTY_HEADER; len];
+            &mut heap_headers
+        };
+        let mut req = httparse::Request::new(headers);
+        match req.parse(buf) {
+            Ok(status) => return f(&mut req, status),
+            Err(httparse::Error::TooManyHeaders) if len < MAX_HEADERS => {
+                len = (len * 2).min(MAX_HEADERS);
+            }
+            Err(httparse::Error::TooManyHeaders) => return Err(RequestError::TooManyHeaders),
+            Err(err) => return Err(err.into()),
+        }
     }
-    // When receiving POST request with chunked encoding,
-    // the end marker consist of those bytes - [13, 10, 48, 13, 10, 13, 10]
-    // and we don't recv them from the socket in one go.
-    if n == 7 && is_complete_chunked_encoding_mark(buf) {
-        return;
+}
+
+fn is_complete_chunked_encoding_mark(buf: &[u8]) -> bool {
+    buf == b"\r\n0\r\n\r\n"
+}
+
+/// A request that the server could not parse or validate.
+#[derive(Debug, thiserror::Error)]
+pub enum RequestError {
+    #[error("Malformed HTTP request: {0}")]
+    Http(#[from] httparse::Error),
+    #[error("Incomplete HTTP request")]
+    Incomplete,
+    #[error("Too many headers, the maximum is {}", MAX_HEADERS)]
+    TooManyHeaders,
+    #[error("Request body is larger than {0} bytes")]
+    BodyTooLarge(usize),
+    #[error("Invalid {0} header")]
+    InvalidHeader(&'static str),
+    #[error(transparent)]
+    MalformedChunk(#[from] http::MalformedChunk),
+    #[error("Expectation failed, only 100-continue is supported")]
+    ExpectationFailed,
+    #[error("Method not allowed: {method}")]
+    MethodNotAllowed { method: String, allow: &'static str },
+    #[error(transparent)]
+    Protocol(#[from] HiisiError),
+}
+
+impl RequestError {
+    pub fn status(&self) -> http::StatusCode {
+        match self {
+            RequestError::MethodNotAllowed { .. } => http::StatusCode::METHOD_NOT_ALLOWED,
+            RequestError::TooManyHeaders => http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            RequestError::BodyTooLarge(_) => http::StatusCode::PAYLOAD_TOO_LARGE,
+            RequestError::ExpectationFailed => http::StatusCode::EXPECTATION_FAILED,
+            RequestError::Protocol(err) => err.status(),
+            _ => http::StatusCode::BAD_REQUEST,
+        }
     }
-    let resp = match execute_request(io, &buf[..n]) {
-        Ok(resp) => http::format_response(resp, http::StatusCode::OK),
-        Err(x) => http::format_response(
-            format!("{}", x).into(),
-            http::StatusCode::BAD_REQUEST,
-        ),
-    };
 
-    let n = resp.len();
-    io.send(sock, resp.into(), n, on_send);
+    pub fn to_proto_error(&self) -> proto::Error {
+        match self {
+            RequestError::Protocol(err) => executor::to_proto_error(err),
+            err => proto::Error {
+                message: err.to_string(),
+                code: Some("HTTP_PARSE_ERROR".to_owned()),
+            },
+        }
+    }
 }
 
-fn is_complete_chunked_encoding_mark(buf: &[u8]) -> bool {
-    buf == b"\r\n0\r\n\r\n" 
+fn header_str<'a>(
+    header: &httparse::Header<'a>,
+    name: &'static str,
+) -> std::result::Result<&'a str, RequestError> {
+    std::str::from_utf8(header.value).map_err(|_| RequestError::InvalidHeader(name))
 }
 
 enum Route {
-    // The `/v2/pipeline` route.
-    Pipeline,
-}
-
-fn parse_request(buf: &[u8]) -> Result<Request> {
-    let mut headers = [httparse::EMPTY_HEADER; 64];
-    let mut req = httparse::Request::new(&mut headers);
-    let body_off = req.parse(buf)?.unwrap();
-    let database = parse_database(&mut req)?;
-    match parse_route(req.path.unwrap()) {
-        Some(Route::Pipeline) => {
-            let req = proto::parse_client_req(&buf[body_off..])?;
-            Ok(Request {
+    // The `/v2/pipeline` and `/v3/pipeline` routes.
+    Pipeline(proto::Version),
+    // The `/v3/cursor` route.
+    Cursor,
+    // The `/health` route.
+    Health,
+    // The `/version` route.
+    Version,
+    // The `/metrics` route.
+    Metrics,
+    // The `/v2` and `/v3` routes, which upgrade to a WebSocket.
+    WebSocket(proto::Version),
+}
+
+impl Route {
+    /// The method that the route accepts.
+    fn method(&self) -> &'static str {
+        match self {
+            Route::Pipeline(_) | Route::Cursor => "POST",
+            Route::Health | Route::Version | Route::Metrics | Route::WebSocket(_) => "GET",
+        }
+    }
+}
+
+enum ClientRequest {
+    // A pipeline request, and the encoding of its body that the response
+    // mirrors.
+    Pipeline(Request, proto::Encoding),
+    Cursor(CursorRequest),
+    Health,
+    Version,
+    Metrics,
+    // A WebSocket upgrade, with the `Sec-WebSocket-Key` of the handshake and
+    // the subprotocol that the server picked from the ones the client
+    // offered.
+    WebSocket {
+        database: String,
+        version: proto::Version,
+        key: String,
+        protocol: Option<&'static str>,
+    },
+}
+
+fn parse_request(buf: &[u8]) -> std::result::Result<ClientRequest, RequestError> {
+    parse_head(buf, |req, status| match status {
+        httparse::Status::Complete(body_off) => parse_request_head(buf, req, body_off),
+        httparse::Status::Partial => Err(RequestError::Incomplete),
+    })
+}
+
+fn parse_request_head(
+    buf: &[u8],
+    req: &mut httparse::Request,
+    body_off: usize,
+) -> std::result::Result<ClientRequest, RequestError> {
+    let route = parse_route(req.path.ok_or(RequestError::Incomplete)?)?;
+    let method = req.method.ok_or(RequestError::Incomplete)?;
+    if method != route.method() {
+        return Err(RequestError::MethodNotAllowed {
+            method: method.to_owned(),
+            allow: route.method(),
+        });
+    }
+    let decoded;
+    let body = if is_chunked(req)? {
+        decoded = http::decode_chunked(&buf[body_off..])?.ok_or(RequestError::Incomplete)?;
+        &decoded.0[..]
+    } else {
+        &buf[body_off..]
+    };
+    match route {
+        Route::Pipeline(version) => {
+            let database = parse_database(req)?;
+            let encoding = parse_encoding(req)?;
+            let req = proto::parse_client_req(body, encoding)?;
+            Ok(ClientRequest::Pipeline(
+                Request {
+                    database: database.to_owned(),
+                    version,
+                    req,
+                },
+                encoding,
+            ))
+        }
+        Route::Cursor => {
+            let database = parse_database(req)?;
+            let req = proto::parse_cursor_req(body)?;
+            Ok(ClientRequest::Cursor(CursorRequest {
                 database: database.to_owned(),
+                version: proto::Version::Hrana3,
                 req,
+            }))
+        }
+        // Probes bypass the database and the stream machinery entirely.
+        Route::Health => Ok(ClientRequest::Health),
+        Route::Version => Ok(ClientRequest::Version),
+        Route::Metrics => Ok(ClientRequest::Metrics),
+        Route::WebSocket(version) => {
+            let database = parse_database(req)?;
+            let key = parse_websocket_key(req)?;
+            let protocol = parse_websocket_protocol(req, version)?;
+            Ok(ClientRequest::WebSocket {
+                database,
+                version,
+                key,
+                protocol,
             })
         }
-        None => Err(HiisiError::ProtocolError("Invalid path".to_owned()).into()),
     }
 }
 
-const DEFAULT_DATABASE: &'static str = "default";
+/// Check that a request asks to upgrade to a WebSocket, and return the
+/// `Sec-WebSocket-Key` of the handshake.
+fn parse_websocket_key(req: &httparse::Request) -> std::result::Result<String, RequestError> {
+    let has_token = |name: &str, token: &str| {
+        req.headers.iter().any(|header| {
+            header.name.eq_ignore_ascii_case(name)
+                && std::str::from_utf8(header.value).is_ok_and(|value| {
+                    value
+                        .split(',')
+                        .any(|value| value.trim().eq_ignore_ascii_case(token))
+                })
+        })
+    };
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err(HiisiError::ProtocolError("Expected a WebSocket upgrade".to_owned()).into());
+    }
+    if !has_token("Sec-WebSocket-Version", "13") {
+        return Err(HiisiError::ProtocolError("Unsupported WebSocket version".to_owned()).into());
+    }
+    let key = req
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Sec-WebSocket-Key"))
+        .ok_or(RequestError::InvalidHeader("Sec-WebSocket-Key"))?;
+    Ok(header_str(key, "Sec-WebSocket-Key")?.to_owned())
+}
+
+/// Pick the subprotocol of the Hrana version of the route, if the client
+/// offers subprotocols. A client that offers others, but not that one,
+/// speaks a protocol the server does not.
+fn parse_websocket_protocol(
+    req: &httparse::Request,
+    version: proto::Version,
+) -> std::result::Result<Option<&'static str>, RequestError> {
+    let protocol = match version {
+        proto::Version::Hrana2 => "hrana2",
+        proto::Version::Hrana3 => "hrana3",
+    };
+    let mut offered = false;
+    for header in req.headers.iter() {
+        if header.name.eq_ignore_ascii_case("Sec-WebSocket-Protocol") {
+            offered = true;
+            let value = header_str(header, "Sec-WebSocket-Protocol")?;
+            if value.split(',').any(|value| value.trim() == protocol) {
+                return Ok(Some(protocol));
+            }
+        }
+    }
+    if offered {
+        return Err(
+            HiisiError::ProtocolError(format!("Expected the {} subprotocol", protocol)).into(),
+        );
+    }
+    Ok(None)
+}
+
+/// The database of requests without a `Host` header.
+pub const DEFAULT_DATABASE: &str = "default";
 
-fn parse_database(req: &mut httparse::Request) -> Result<String> {
+fn parse_database(req: &mut httparse::Request) -> std::result::Result<String, RequestError> {
     let mut host: Option<&str> = None;
     for header in req.headers.iter() {
-        if header.name == "Host" {
-            host = Some(std::str::from_utf8(header.value)?);
+        if header.name.eq_ignore_ascii_case("Host") {
+            host = Some(header_str(header, "Host")?);
             break;
         }
     }
@@ -119,13 +2070,1125 @@ fn parse_database(req: &mut httparse::Request) -> Result<String> {
     }
 }
 
-fn parse_route(path: &str) -> Option<Route> {
+fn parse_encoding(req: &httparse::Request) -> std::result::Result<proto::Encoding, RequestError> {
+    let mut content_type: Option<&str> = None;
+    for header in req.headers.iter() {
+        if header.name.eq_ignore_ascii_case("Content-Type") {
+            content_type = Some(header_str(header, "Content-Type")?);
+            break;
+        }
+    }
+    proto::Encoding::from_content_type(content_type).ok_or_else(|| {
+        HiisiError::UnsupportedMediaType(content_type.unwrap_or_default().to_owned()).into()
+    })
+}
+
+fn parse_route(path: &str) -> std::result::Result<Route, HiisiError> {
+    let invalid_path = || HiisiError::NotFound(path.to_owned());
     match path {
-        "/v2/pipeline" => Some(Route::Pipeline),
-        _ => None,
+        "/health" => return Ok(Route::Health),
+        "/version" => return Ok(Route::Version),
+        "/metrics" => return Ok(Route::Metrics),
+        "/v2" => return Ok(Route::WebSocket(proto::Version::Hrana2)),
+        "/v3" => return Ok(Route::WebSocket(proto::Version::Hrana3)),
+        _ => {}
+    }
+    let (version, endpoint) = path
+        .strip_prefix('/')
+        .and_then(|path| path.split_once('/'))
+        .ok_or_else(invalid_path)?;
+    let version = match version {
+        "v2" => proto::Version::Hrana2,
+        "v3" => proto::Version::Hrana3,
+        _ if version.starts_with('v') => {
+            return Err(HiisiError::UnsupportedVersion(version.to_owned()))
+        }
+        _ => return Err(invalid_path()),
+    };
+    match (endpoint, version) {
+        ("pipeline", _) => Ok(Route::Pipeline(version)),
+        // Cursors were introduced in Hrana 3.
+        ("cursor", proto::Version::Hrana3) => Ok(Route::Cursor),
+        _ => Err(invalid_path()),
+    }
+}
+
+/// Receive the body of a request once the client has been told to send it.
+fn on_continue_send<T>(io: &mut IO<T>, sock: Rc<Socket>, n: usize) {
+    if n == 0 {
+        log::trace!("Client closed connection before receiving 100 Continue");
+        close_conn(io, sock);
+        return;
+    }
+    io.context().stats.add_bytes_sent(n);
+    recv_request(io, sock);
+}
+
+fn on_send<T>(io: &mut IO<T>, sock: Rc<Socket>, n: usize) {
+    if n == 0 {
+        log::trace!("Client closed connection before receiving response");
+        close_conn(io, sock);
+        return;
+    }
+    io.context().stats.add_bytes_sent(n);
+    let close = match io.context().conns.get_mut(&sock) {
+        Some(mut conn) => {
+            conn.busy = false;
+            conn.outstanding = conn.outstanding.saturating_sub(1);
+            // The request has been handled, so the buffer can be reused
+            // unless the next request is already in it.
+            if conn.recv_buf.is_empty() && conn.recv_buf.capacity() > 0 {
+                let buf = std::mem::take(&mut conn.recv_buf);
+                io.context().recv_buffers.put(buf);
+            }
+            conn.close
+        }
+        None => false,
+    };
+    if close {
+        log::trace!("Closing connection as the client requested");
+        close_conn(io, sock);
+        return;
     }
+    process_request(io, sock)
 }
 
-fn on_send<T>(io: &mut IO<T>, sock: Rc<Socket>, _n: usize) {
-    io.recv(sock, on_recv)
+#[cfg(all(test, feature = "simulation"))]
+mod test {
+    use super::{
+        expects_continue, parse_request, request_len, serve, serve_all, Builder, ClientRequest,
+        Context, RequestError, Role, IO, MAX_BODY_BYTES,
+    };
+    use crate::clock::SimClock;
+    use crate::executor;
+    use crate::io::{Faults, Latency};
+    use crate::manager::BATON_EXPIRY;
+    use crate::proto::{
+        CloseStreamReq, ExecuteStreamReq, PipelineReqBody, Stmt, StreamRequest, StreamResponse,
+        StreamResult, Version,
+    };
+    use crate::{HiisiError, ResourceManager};
+    use bytes::Bytes;
+    use socket2::{Domain, Socket, Type};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::os::fd::AsRawFd;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    // HTTP status codes and bodies of the responses received, keyed by
+    // client socket.
+    type TestIO = IO<RefCell<HashMap<i32, (u16, String)>>>;
+
+    fn on_client_connect(_io: &mut TestIO, _sock: Rc<Socket>, _addr: socket2::SockAddr) {}
+
+    fn on_client_send(io: &mut TestIO, sock: Rc<Socket>, _n: usize) {
+        io.recv(sock, on_client_recv);
+    }
+
+    fn on_client_recv(io: &mut TestIO, sock: Rc<Socket>, buf: &[u8], _n: usize) {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut resp = httparse::Response::new(&mut headers);
+        let body_off = resp.parse(buf).unwrap().unwrap();
+        let body = String::from_utf8_lossy(&buf[body_off..]).into_owned();
+        io.context()
+            .user_data
+            .borrow_mut()
+            .insert(sock.as_raw_fd(), (resp.code.unwrap(), body));
+    }
+
+    fn connect_client(io: &mut TestIO, server_addr: std::net::SocketAddr, path: &str) -> i32 {
+        connect_client_to(io, server_addr, "test.localhost", path, "SELECT 1")
+    }
+
+    fn connect_client_to(
+        io: &mut TestIO,
+        server_addr: std::net::SocketAddr,
+        host: &str,
+        path: &str,
+        sql: &str,
+    ) -> i32 {
+        let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        send_request(io, sock.clone(), server_addr, host, path, sql);
+        sock.as_raw_fd()
+    }
+
+    fn send_request(
+        io: &mut TestIO,
+        sock: Rc<Socket>,
+        server_addr: std::net::SocketAddr,
+        host: &str,
+        path: &str,
+        sql: &str,
+    ) {
+        io.connect(sock.clone(), server_addr.into(), on_client_connect);
+        let body = format!(
+            r#"{{"baton":null,"requests":[{{"type":"execute","stmt":{{"sql":"{}"}}}}]}}"#,
+            sql
+        );
+        let req = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            host,
+            body.len(),
+            body
+        );
+        let n = req.len();
+        io.send(sock, Bytes::from(req), n, on_client_send);
+    }
+
+    #[test]
+    fn request_len_waits_for_head_and_body() {
+        let req = b"POST /v2/pipeline HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody";
+        // The blank line that ends the head is split.
+        assert_eq!(request_len(&req[..47], MAX_BODY_BYTES).unwrap(), None);
+        assert_eq!(request_len(&req[..50], MAX_BODY_BYTES).unwrap(), None);
+        assert_eq!(request_len(req, MAX_BODY_BYTES).unwrap(), Some(req.len()));
+        // Without a `Content-Length`, the request ends with its head.
+        let req = b"GET /health HTTP/1.1\r\n\r\nGET /nope HTTP/1.1\r\n\r\n";
+        assert_eq!(request_len(req, MAX_BODY_BYTES).unwrap(), Some(24));
+    }
+
+    #[test]
+    fn parse_request_rejects_invalid_headers() {
+        let req = b"POST /v2/pipeline HTTP/1.1\r\nHost: \xff.localhost\r\n\r\n";
+        let err = parse_request(req).err().unwrap();
+        assert!(matches!(err, RequestError::InvalidHeader("Host")));
+        assert_eq!(err.status().as_u16(), 400);
+
+        let req = b"POST /v2/pipeline HTTP/1.1\r\nContent-Length: -1\r\n\r\n";
+        let err = request_len(req, MAX_BODY_BYTES).err().unwrap();
+        assert!(matches!(err, RequestError::InvalidHeader("Content-Length")));
+    }
+
+    #[test]
+    fn parse_probe_requests() {
+        // Probes don't need a database, so a bare `Host` is fine.
+        let req = b"GET /health HTTP/1.1\r\nHost: localhost:8080\r\n\r\n";
+        assert!(matches!(parse_request(req), Ok(ClientRequest::Health)));
+        let req = b"GET /version HTTP/1.1\r\n\r\n";
+        assert!(matches!(parse_request(req), Ok(ClientRequest::Version)));
+        let req = b"POST /health HTTP/1.1\r\n\r\n";
+        assert!(matches!(
+            parse_request(req),
+            Err(RequestError::MethodNotAllowed { allow: "GET", .. })
+        ));
+    }
+
+    #[test]
+    fn parse_chunked_request() {
+        let body = r#"{"baton":null,"requests":[{"type":"execute","stmt":{"sql":"SELECT 1"}}]}"#;
+        let (first, second) = body.split_at(10);
+        let req = format!(
+            "POST /v2/pipeline HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n{:x};ext=1\r\n{}\r\n0\r\n\r\n",
+            first.len(),
+            first,
+            second.len(),
+            second
+        );
+        let req = req.as_bytes();
+        // The request is complete only once the last chunk is received.
+        assert_eq!(
+            request_len(&req[..req.len() - 2], MAX_BODY_BYTES).unwrap(),
+            None
+        );
+        assert_eq!(request_len(req, MAX_BODY_BYTES).unwrap(), Some(req.len()));
+        assert!(matches!(
+            parse_request(req),
+            Ok(ClientRequest::Pipeline(..))
+        ));
+
+        let req = b"POST /v2/pipeline HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        let err = request_len(req, MAX_BODY_BYTES).err().unwrap();
+        assert!(matches!(err, RequestError::MalformedChunk(_)));
+        assert_eq!(err.status().as_u16(), 400);
+    }
+
+    fn request_with_headers(headers: usize) -> String {
+        let mut req = "POST /v2/pipeline HTTP/1.1\r\nHost: test.localhost\r\n".to_owned();
+        for i in 0..headers {
+            req.push_str(&format!("X-Forwarded-{}: proxy\r\n", i));
+        }
+        let body = r#"{"baton":null,"requests":[]}"#;
+        req.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+        req
+    }
+
+    #[test]
+    fn parse_request_with_many_headers() {
+        // The head is parsed again with room for more headers.
+        let req = request_with_headers(80);
+        assert_eq!(
+            request_len(req.as_bytes(), MAX_BODY_BYTES).unwrap(),
+            Some(req.len())
+        );
+        assert!(matches!(
+            parse_request(req.as_bytes()),
+            Ok(ClientRequest::Pipeline(..))
+        ));
+
+        let req = request_with_headers(100);
+        let err = request_len(req.as_bytes(), MAX_BODY_BYTES).err().unwrap();
+        assert!(matches!(err, RequestError::TooManyHeaders));
+        assert_eq!(err.status().as_u16(), 431);
+    }
+
+    #[test]
+    fn request_len_rejects_oversized_body() {
+        // The body is rejected before any of it has been received.
+        let req = b"POST /v2/pipeline HTTP/1.1\r\nContent-Length: 11\r\n\r\n";
+        let err = request_len(req, 10).err().unwrap();
+        assert!(matches!(err, RequestError::BodyTooLarge(10)));
+        assert_eq!(err.status().as_u16(), 413);
+
+        let req = b"POST /v2/pipeline HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nbody\r\n";
+        assert_eq!(request_len(req, 10).unwrap(), None);
+        let req =
+            b"POST /v2/pipeline HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nbody\r\n4\r\n";
+        let err = request_len(req, 10).err().unwrap();
+        assert!(matches!(err, RequestError::BodyTooLarge(10)));
+    }
+
+    #[test]
+    fn expects_continue_once_head_is_complete() {
+        let req =
+            b"POST /v2/pipeline HTTP/1.1\r\nExpect: 100-Continue\r\nContent-Length: 4\r\n\r\n";
+        assert!(!expects_continue(&req[..req.len() - 2]).unwrap());
+        assert!(expects_continue(req).unwrap());
+        let req = b"POST /v2/pipeline HTTP/1.1\r\nContent-Length: 4\r\n\r\n";
+        assert!(!expects_continue(req).unwrap());
+
+        let req = b"POST /v2/pipeline HTTP/1.1\r\nExpect: 200-ok\r\nContent-Length: 4\r\n\r\n";
+        let err = expects_continue(req).err().unwrap();
+        assert!(matches!(err, RequestError::ExpectationFailed));
+        assert_eq!(err.status().as_u16(), 417);
+    }
+
+    #[test]
+    fn parse_request_rejects_truncated_body() {
+        let body = r#"{"baton":null,"requests":[{"type":"exec"#;
+        let req = format!(
+            "POST /v2/pipeline HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let err = parse_request(req.as_bytes()).err().unwrap();
+        assert!(matches!(
+            err,
+            RequestError::Protocol(HiisiError::JsonParseError(_))
+        ));
+        assert_eq!(err.status().as_u16(), 400);
+        assert_eq!(err.to_proto_error().code.as_deref(), Some("PROTOCOL_ERROR"));
+    }
+
+    #[test]
+    fn serve_v2_and_v3_clients() {
+        let db_path = std::env::temp_dir().join(format!("hiisi-server-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        let mut io = TestIO::new(Context::new(manager, RefCell::new(HashMap::new())));
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+
+        let v2_client = connect_client(&mut io, server_addr, "/v2/pipeline");
+        // The simulated IO accepts one connection per `accept()`, which the
+        // server re-arms when it accepts the previous connection.
+        for _ in 0..10 {
+            io.run_once();
+        }
+        let v3_client = connect_client(&mut io, server_addr, "/v3/pipeline");
+        for _ in 0..10 {
+            io.run_once();
+        }
+        let v4_client = connect_client(&mut io, server_addr, "/v4/pipeline");
+        for _ in 0..10 {
+            io.run_once();
+        }
+
+        let resps = io.context().user_data.borrow();
+        assert_eq!(resps[&v2_client].0, 200);
+        assert_eq!(resps[&v3_client].0, 200);
+        assert_eq!(resps[&v4_client].0, 404);
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn too_many_headers_are_rejected() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-server-headers-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        let mut io = TestIO::new(Context::new(manager, RefCell::new(HashMap::new())));
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+
+        let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.connect(sock.clone(), server_addr.into(), on_client_connect);
+        let req = request_with_headers(100);
+        let n = req.len();
+        let headers_client = sock.as_raw_fd();
+        io.send(sock, Bytes::from(req), n, on_client_send);
+        for _ in 0..10 {
+            io.run_once();
+        }
+        // The server keeps serving other clients.
+        let client = connect_client(&mut io, server_addr, "/v2/pipeline");
+        for _ in 0..10 {
+            io.run_once();
+        }
+
+        let resps = io.context().user_data.borrow();
+        assert_eq!(resps[&headers_client].0, 431);
+        assert_eq!(resps[&client].0, 200);
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn stats_count_requests() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-server-stats-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        let mut io = TestIO::new(Context::new(manager, RefCell::new(HashMap::new())));
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+
+        for _ in 0..2 {
+            connect_client(&mut io, server_addr, "/v2/pipeline");
+            for _ in 0..10 {
+                io.run_once();
+            }
+        }
+        let stats = io.context().stats();
+        assert_eq!(stats.connections(), 2);
+        assert_eq!(stats.requests_served(), 2);
+        assert_eq!(stats.ok_results(), 2);
+        assert_eq!(stats.error_results(), 0);
+        assert!(stats.bytes_received() > 0);
+        assert!(stats.bytes_sent() > 0);
+
+        let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.connect(sock.clone(), server_addr.into(), on_client_connect);
+        let req = "GET /metrics HTTP/1.1\r\n\r\n";
+        let metrics_client = sock.as_raw_fd();
+        io.send(sock, Bytes::from(req), req.len(), on_client_send);
+        for _ in 0..10 {
+            io.run_once();
+        }
+        let resps = io.context().user_data.borrow();
+        let (status, body) = &resps[&metrics_client];
+        assert_eq!(*status, 200);
+        // Probes are not counted as requests served.
+        assert!(body.contains("\nhiisi_requests_total 2\n"));
+        assert!(body.contains("\nhiisi_results_total{result=\"ok\"} 2\n"));
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn accept_waits_for_connection_limit() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-server-limit-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        let ctx = Context::new(manager, RefCell::new(HashMap::new())).with_max_connections(2);
+        let mut io = TestIO::new(ctx);
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+
+        let clients: Vec<(Rc<Socket>, i32)> = (0..3)
+            .map(|_| {
+                let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+                send_request(
+                    &mut io,
+                    sock.clone(),
+                    server_addr,
+                    "test.localhost",
+                    "/v2/pipeline",
+                    "SELECT 1",
+                );
+                let fd = sock.as_raw_fd();
+                (sock, fd)
+            })
+            .collect();
+        for _ in 0..10 {
+            io.run_once();
+        }
+        // The third connection waits until one of the others closes.
+        assert_eq!(io.context().connections(), 2);
+        assert_eq!(io.context().user_data.borrow()[&clients[0].1].0, 200);
+        assert_eq!(io.context().user_data.borrow()[&clients[1].1].0, 200);
+        assert!(!io.context().user_data.borrow().contains_key(&clients[2].1));
+
+        io.close(clients[0].0.clone(), |_, _| {});
+        for _ in 0..10 {
+            io.run_once();
+        }
+        assert_eq!(io.context().connections(), 2);
+        assert_eq!(io.context().user_data.borrow()[&clients[2].1].0, 200);
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn registry_tracks_open_connections() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("test").unwrap();
+        let mut io = TestIO::new(Context::new(manager, RefCell::new(HashMap::new())));
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+        for _ in 0..3 {
+            let clients: Vec<Rc<Socket>> = (0..3)
+                .map(|_| {
+                    let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+                    send_request(
+                        &mut io,
+                        sock.clone(),
+                        server_addr,
+                        "test.localhost",
+                        "/v2/pipeline",
+                        "SELECT 1",
+                    );
+                    sock
+                })
+                .collect();
+            for _ in 0..10 {
+                io.run_once();
+            }
+            assert_eq!(io.context().connections(), clients.len());
+            // Every connection keeps the baton that its response handed out.
+            assert_eq!(
+                io.context().conns.socks(|conn| conn.baton.is_some()).len(),
+                clients.len()
+            );
+
+            // Closing a client frees its connection on the server, and the
+            // next connection may get the same socket number.
+            io.context().user_data.borrow_mut().clear();
+            for sock in clients {
+                io.close(sock, |_, _| {});
+                for _ in 0..10 {
+                    io.run_once();
+                }
+            }
+            assert_eq!(io.context().connections(), 0);
+        }
+    }
+
+    /// Serve `clients` requests one after another and return the number of
+    /// receive buffers allocated for them.
+    fn recv_buffer_allocations(max_buffers: usize, clients: usize) -> usize {
+        let db_path = std::env::temp_dir().join(format!(
+            "hiisi-server-buffers-{}-{}",
+            std::process::id(),
+            max_buffers
+        ));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("test").unwrap();
+        let ctx =
+            Context::new(manager, RefCell::new(HashMap::new())).with_recv_buffers(max_buffers);
+        let mut io = TestIO::new(ctx);
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+        for _ in 0..clients {
+            let client = connect_client(&mut io, server_addr, "/v2/pipeline");
+            while !io.context().user_data.borrow().contains_key(&client) {
+                io.run_once();
+            }
+            assert_eq!(io.context().user_data.borrow()[&client].0, 200);
+        }
+        std::fs::remove_dir_all(db_path).unwrap();
+        io.context().recv_buffers.allocations()
+    }
+
+    #[test]
+    fn recv_buffers_are_reused() {
+        // Without a pool, every request is received into a new buffer.
+        assert_eq!(recv_buffer_allocations(0, 10), 10);
+        assert_eq!(recv_buffer_allocations(super::RECV_BUFFERS, 10), 1);
+    }
+
+    #[test]
+    fn route_by_host() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-server-host-{}", std::process::id()));
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+        manager.create_database("foo").unwrap();
+        manager.create_database("bar").unwrap();
+        let mut io = TestIO::new(Context::new(manager, RefCell::new(HashMap::new())));
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+
+        let mut send = |host: &str, sql: &str| {
+            let client = connect_client_to(&mut io, server_addr, host, "/v2/pipeline", sql);
+            for _ in 0..10 {
+                io.run_once();
+            }
+            io.context().user_data.borrow()[&client].clone()
+        };
+        let (code, _) = send("foo.localhost", "CREATE TABLE t(x)");
+        assert_eq!(code, 200);
+        let (code, body) = send("foo.localhost", "SELECT * FROM t");
+        assert_eq!(code, 200);
+        assert!(body.contains(r#""type":"ok""#), "{}", body);
+        // The table exists only in the database of `foo`.
+        let (code, body) = send("bar.localhost", "SELECT * FROM t");
+        assert_eq!(code, 200);
+        assert!(body.contains(r#""type":"error""#), "{}", body);
+        let (code, body) = send("baz.localhost", "SELECT 1");
+        assert_eq!(code, 404);
+        assert!(body.contains("Database not found: baz"), "{}", body);
+
+        // Header names are case-insensitive, and clients such as hyper send
+        // them in lowercase.
+        let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.connect(sock.clone(), server_addr.into(), on_client_connect);
+        let body =
+            r#"{"baton":null,"requests":[{"type":"execute","stmt":{"sql":"SELECT * FROM t"}}]}"#;
+        let req = format!(
+            "POST /v2/pipeline HTTP/1.1\r\nhost: foo.localhost\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let client = sock.as_raw_fd();
+        let n = req.len();
+        io.send(sock, Bytes::from(req), n, on_client_send);
+        for _ in 0..10 {
+            io.run_once();
+        }
+        let (code, body) = io.context().user_data.borrow()[&client].clone();
+        assert_eq!(code, 200);
+        assert!(body.contains(r#""type":"ok""#), "{}", body);
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn listeners_route_by_role() {
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("test").unwrap();
+        let mut io = TestIO::new(Context::new(manager.clone(), RefCell::new(HashMap::new())));
+
+        let data_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let admin_addr: std::net::SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let data_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        let admin_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve_all(
+            &mut io,
+            vec![
+                (data_sock, data_addr.into(), Role::Data),
+                (admin_sock, admin_addr.into(), Role::Admin),
+            ],
+        );
+
+        let mut send = |addr, path: &str| {
+            let client = connect_client(&mut io, addr, path);
+            for _ in 0..10 {
+                io.run_once();
+            }
+            io.context().user_data.borrow()[&client].0
+        };
+        // Each listener serves only the API of its role.
+        assert_eq!(send(data_addr, "/v2/pipeline"), 200);
+        assert_eq!(send(admin_addr, "/v2/pipeline"), 404);
+        assert_eq!(send(data_addr, "/v1/namespaces/foo/create"), 404);
+        assert!(!manager.database_exists("foo"));
+        assert_eq!(send(admin_addr, "/v1/namespaces/foo/create"), 201);
+        assert!(manager.database_exists("foo"));
+        // Both listeners keep accepting after every connection.
+        assert_eq!(send(data_addr, "/v2/pipeline"), 200);
+        assert_eq!(send(admin_addr, "/v1/namespaces/bar/create"), 201);
+    }
+
+    #[test]
+    fn builder_serves_requests() {
+        let data_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let admin_addr: std::net::SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let mut io = Builder::new(data_addr)
+            .with_admin_listen_addr(admin_addr)
+            .build(RefCell::new(HashMap::new()))
+            .unwrap();
+        let client = connect_client_to(&mut io, data_addr, "localhost", "/v2/pipeline", "SELECT 1");
+        for _ in 0..10 {
+            io.run_once();
+        }
+        let (code, body) = &io.context().user_data.borrow()[&client];
+        assert_eq!(*code, 200, "{}", body);
+
+        // TLS is not available to the simulated IO.
+        let err = Builder::new(data_addr)
+            .with_tls("cert.pem", "key.pem")
+            .build(())
+            .err()
+            .unwrap();
+        assert!(matches!(err, HiisiError::InvalidConfig(_)));
+    }
+
+    fn on_client_send_ignore(_io: &mut TestIO, _sock: Rc<Socket>, _n: usize) {}
+
+    #[test]
+    fn pipelined_requests_get_backpressure() {
+        const DEPTH: usize = 4;
+        const REQUESTS: usize = 50;
+        let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+        manager.create_database("test").unwrap();
+        let ctx =
+            Context::new(manager, RefCell::new(HashMap::new())).with_max_pipeline_depth(DEPTH);
+        let mut io = TestIO::new(ctx);
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+        // The client streams a large cursor, which keeps the server busy for
+        // many ticks, and pipelines requests behind it without ever reading
+        // a response.
+        let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.connect(sock.clone(), server_addr.into(), on_client_connect);
+        let body = r#"{"baton":null,"batch":{"steps":[{"stmt":{"sql":"WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c LIMIT 20000) SELECT x FROM c"}}]}}"#;
+        let cursor_req = format!(
+            "POST /v3/cursor HTTP/1.1\r\nHost: test.localhost\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let n = cursor_req.len();
+        io.send(sock.clone(), cursor_req.into(), n, on_client_send_ignore);
+        let body = r#"{"baton":null,"requests":[{"type":"execute","stmt":{"sql":"SELECT 1"}}]}"#;
+        let req = format!(
+            "POST /v2/pipeline HTTP/1.1\r\nHost: test.localhost\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        for _ in 0..REQUESTS {
+            let n = req.len();
+            io.send(
+                sock.clone(),
+                Bytes::from(req.clone()),
+                n,
+                on_client_send_ignore,
+            );
+        }
+        let mut peak = 0;
+        for _ in 0..1000 {
+            io.run_once();
+            let conns = io.context().conns.conns.borrow();
+            let Some(conn) = conns.values().next() else {
+                continue;
+            };
+            // Requests that the server has not read wait on the client side,
+            // rather than in the buffer of the server.
+            let buffered = super::complete_requests(&conn.recv_buf, MAX_BODY_BYTES);
+            assert!(
+                conn.outstanding <= DEPTH,
+                "{} outstanding",
+                conn.outstanding
+            );
+            assert!(buffered < DEPTH, "{} buffered", buffered);
+            peak = peak.max(conn.outstanding);
+        }
+        assert_eq!(peak, DEPTH);
+        // Every request was served in the end.
+        assert_eq!(io.context().stats().requests_served(), REQUESTS as u64 + 1);
+    }
+
+    fn on_client_send_request_id(io: &mut TestIO, sock: Rc<Socket>, _n: usize) {
+        io.recv(sock, on_client_recv_request_id);
+    }
+
+    // Records the correlation id of the response in place of its body.
+    fn on_client_recv_request_id(io: &mut TestIO, sock: Rc<Socket>, buf: &[u8], _n: usize) {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut resp = httparse::Response::new(&mut headers);
+        resp.parse(buf).unwrap().unwrap();
+        let request_id = resp
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(super::REQUEST_ID_HEADER))
+            .map(|header| String::from_utf8_lossy(header.value).into_owned())
+            .unwrap();
+        io.context()
+            .user_data
+            .borrow_mut()
+            .insert(sock.as_raw_fd(), (resp.code.unwrap(), request_id));
+    }
+
+    /// Send pipeline requests from a few clients and return the correlation
+    /// ids of their responses, in the order the clients connected.
+    fn correlation_ids(seed: u64) -> Vec<String> {
+        let db_path = std::env::temp_dir().join(format!(
+            "hiisi-server-request-id-{}-{}",
+            std::process::id(),
+            seed
+        ));
+        let clock = Rc::new(SimClock::new());
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]).with_clock(clock.clone()));
+        manager.create_database("test").unwrap();
+        let faults = Faults {
+            seed,
+            latency_ms: Some(Latency {
+                min_ms: 1,
+                max_ms: 100,
+            }),
+            ..Default::default()
+        };
+        let ctx = Context::new(manager, RefCell::new(HashMap::new()));
+        let mut io = TestIO::with_clock(ctx, faults, clock);
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+        let body = r#"{"baton":null,"requests":[{"type":"execute","stmt":{"sql":"SELECT 1"}}]}"#;
+        let req = format!(
+            "POST /v2/pipeline HTTP/1.1\r\nHost: test.localhost\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let clients: Vec<_> = (0..3)
+            .map(|_| {
+                let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+                io.connect(sock.clone(), server_addr.into(), on_client_connect);
+                let n = req.len();
+                io.send(
+                    sock.clone(),
+                    Bytes::from(req.clone()),
+                    n,
+                    on_client_send_request_id,
+                );
+                sock.as_raw_fd()
+            })
+            .collect();
+        while io.context().user_data.borrow().len() < clients.len() {
+            io.run_once();
+        }
+        std::fs::remove_dir_all(db_path).unwrap();
+        let responses = io.context().user_data.borrow();
+        clients
+            .iter()
+            .map(|client| {
+                let (code, request_id) = &responses[client];
+                assert_eq!(*code, 200);
+                request_id.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn correlation_ids_are_reproducible() {
+        let ids = correlation_ids(7);
+        assert_eq!(ids.len(), 3);
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(id.len(), 16);
+            assert!(!ids[..i].contains(id), "duplicate id {}", id);
+        }
+        assert_eq!(correlation_ids(7), ids);
+    }
+
+    fn on_client_send_and_close(io: &mut TestIO, sock: Rc<Socket>, _n: usize) {
+        io.close(sock, on_client_close);
+    }
+
+    fn on_client_close(_io: &mut TestIO, _sock: Rc<Socket>) {}
+
+    #[test]
+    fn disconnect_releases_cursor_session() {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-server-disconnect-{}", std::process::id()));
+        let clock = Rc::new(SimClock::new());
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]).with_clock(clock.clone()));
+        manager.create_database("test").unwrap();
+        let ctx = Context::new(manager.clone(), RefCell::new(HashMap::new()));
+        let mut io = TestIO::with_clock(ctx, Faults::default(), clock);
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+        // The client starts a query that never ends, and disconnects as soon
+        // as it has sent it.
+        let body = r#"{"baton":null,"batch":{"steps":[{"stmt":{"sql":"WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT x FROM c"}}]}}"#;
+        let req = format!(
+            "POST /v3/cursor HTTP/1.1\r\nHost: test.localhost\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.connect(sock.clone(), server_addr.into(), on_client_connect);
+        let n = req.len();
+        io.send(sock, Bytes::from(req), n, on_client_send_and_close);
+        let mut ticks = 0;
+        while manager.session_count() == 0 {
+            assert!(ticks < 100, "Server did not open the cursor");
+            io.run_once();
+            ticks += 1;
+        }
+        // The send of the first chunk fails, and the server drops the
+        // session of the cursor as soon as it completes.
+        io.run_once();
+        assert_eq!(manager.session_count(), 0);
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    fn on_client_send_and_shutdown(io: &mut TestIO, sock: Rc<Socket>, _n: usize) {
+        io.shutdown(sock.clone());
+        io.recv(sock, on_client_recv);
+    }
+
+    #[test]
+    fn half_closed_connections_are_cleaned_up() {
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut io = Builder::new(server_addr)
+            .build(RefCell::new(HashMap::new()))
+            .unwrap();
+        let body = r#"{"baton":null,"requests":[{"type":"execute","stmt":{"sql":"SELECT 1"}}]}"#;
+        let req = format!(
+            "POST /v2/pipeline HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        // One client shuts down its side right after a complete request, and
+        // the other in the middle of the body.
+        let complete = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        let truncated = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        for (sock, len) in [(&complete, req.len()), (&truncated, req.len() - 10)] {
+            io.connect(sock.clone(), server_addr.into(), on_client_connect);
+            let buf = Bytes::copy_from_slice(&req.as_bytes()[..len]);
+            io.send(sock.clone(), buf, len, on_client_send_and_shutdown);
+            for _ in 0..10 {
+                io.run_once();
+            }
+        }
+
+        let resps = io.context().user_data.borrow();
+        let (code, body) = &resps[&complete.as_raw_fd()];
+        assert_eq!(*code, 200, "{}", body);
+        let (code, body) = &resps[&truncated.as_raw_fd()];
+        assert_eq!(*code, 400, "{}", body);
+        assert!(body.contains("HTTP_PARSE_ERROR"), "{}", body);
+        assert!(io.context().conns.is_empty());
+    }
+
+    /// Open a stream and return the virtual time its baton expires at.
+    fn baton_expiry_time(seed: u64) -> u64 {
+        let db_path = std::env::temp_dir().join(format!(
+            "hiisi-server-expiry-{}-{}",
+            std::process::id(),
+            seed
+        ));
+        let clock = Rc::new(SimClock::new());
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]).with_clock(clock.clone()));
+        manager.create_database("test").unwrap();
+        let faults = Faults {
+            seed,
+            latency_ms: Some(Latency {
+                min_ms: 1,
+                max_ms: 100,
+            }),
+            ..Default::default()
+        };
+        let ctx = Context::new(manager.clone(), RefCell::new(HashMap::new()));
+        let mut io = TestIO::with_clock(ctx, faults, clock);
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+        let client = connect_client(&mut io, server_addr, "/v2/pipeline");
+        let body = loop {
+            io.run_once();
+            if let Some((_, body)) = io.context().user_data.borrow().get(&client) {
+                break body.clone();
+            }
+        };
+        let baton = crate::proto::parse_resp(body.as_bytes())
+            .unwrap()
+            .baton
+            .unwrap();
+        while manager.get_session(&baton).is_some() {
+            io.run_once();
+        }
+        std::fs::remove_dir_all(db_path).unwrap();
+        io.now_ms()
+    }
+
+    /// Write to the test database of `manager` outside of the server, like
+    /// the replication of a replica would, returning the replication index
+    /// of the write.
+    fn apply_write(manager: &Rc<ResourceManager>, sql: &str) -> u64 {
+        let req = executor::Request {
+            database: "test".to_owned(),
+            version: Version::Hrana2,
+            req: PipelineReqBody {
+                baton: None,
+                requests: vec![
+                    StreamRequest::Execute(ExecuteStreamReq {
+                        stmt: Stmt::new(sql, false),
+                    }),
+                    StreamRequest::Close(CloseStreamReq {}),
+                ],
+            },
+        };
+        let resp = executor::execute_client_req(manager.clone(), req).unwrap();
+        match &resp.results[0] {
+            StreamResult::Ok {
+                response: StreamResponse::Execute(resp),
+            } => resp.result.replication_index.unwrap(),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    /// Read with a replication index one write ahead of the database, and
+    /// apply the write at `apply_ms` of virtual time, if at all. Returns the
+    /// response and the time it was received at.
+    fn read_ahead_of_replica(
+        name: &str,
+        timeout: Duration,
+        apply_ms: Option<u64>,
+    ) -> (u16, String, u64) {
+        let db_path = std::env::temp_dir().join(format!(
+            "hiisi-server-replica-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let clock = Rc::new(SimClock::new());
+        let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]).with_clock(clock.clone()));
+        manager.create_database("test").unwrap();
+        let index = apply_write(&manager, "CREATE TABLE t (x)");
+        let ctx = Context::new(manager.clone(), RefCell::new(HashMap::new()))
+            .with_replication_wait_timeout(timeout);
+        let mut io = TestIO::with_clock(ctx, Faults::default(), clock);
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+        let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.connect(sock.clone(), server_addr.into(), on_client_connect);
+        let body = format!(
+            r#"{{"baton":null,"requests":[{{"type":"execute","stmt":{{"sql":"SELECT * FROM t","replication_index":"{}"}}}}]}}"#,
+            index + 1
+        );
+        let req = format!(
+            "POST /v2/pipeline HTTP/1.1\r\nHost: test.localhost\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let client = sock.as_raw_fd();
+        let n = req.len();
+        io.send(sock, Bytes::from(req), n, on_client_send);
+        let resp = loop {
+            io.run_once();
+            if Some(io.now_ms()) == apply_ms {
+                apply_write(&manager, "INSERT INTO t VALUES (1)");
+            }
+            if let Some((status, body)) = io.context().user_data.borrow().get(&client) {
+                break (*status, body.clone(), io.now_ms());
+            }
+        };
+        std::fs::remove_dir_all(db_path).unwrap();
+        resp
+    }
+
+    #[test]
+    fn reads_wait_for_replica_to_catch_up() {
+        let (status, body, received_ms) =
+            read_ahead_of_replica("caught-up", Duration::from_secs(1), Some(100));
+        assert_eq!(status, 200, "{}", body);
+        assert!((100..200).contains(&received_ms));
+    }
+
+    #[test]
+    fn reads_time_out_on_replica_behind() {
+        let (status, body, received_ms) =
+            read_ahead_of_replica("behind", Duration::from_millis(100), None);
+        assert_eq!(status, 503);
+        assert!(body.contains("Replica is behind"), "{}", body);
+        assert!((100..200).contains(&received_ms));
+    }
+
+    /// Send an insert while a session of the server holds the write lock of
+    /// the database, which the session releases at `release_ms` if set, and
+    /// return the status and the body of the response, and the time at which
+    /// it was received.
+    fn write_while_locked(
+        name: &str,
+        busy_timeout: Duration,
+        release_ms: Option<u64>,
+    ) -> (u16, String, u64) {
+        let db_path =
+            std::env::temp_dir().join(format!("hiisi-server-lock-{}-{}", name, std::process::id()));
+        let clock = Rc::new(SimClock::new());
+        let manager = Rc::new(
+            ResourceManager::new(&db_path, [0; 32])
+                .with_clock(clock.clone())
+                .with_busy_timeout(busy_timeout),
+        );
+        manager.create_database("test").unwrap();
+        apply_write(&manager, "CREATE TABLE t (x)");
+        let holder = manager.create_session("test", Version::Hrana2);
+        let conn = manager.get_conn(&holder).unwrap();
+        conn.prepare("BEGIN IMMEDIATE").unwrap().step().unwrap();
+        let ctx = Context::new(manager.clone(), RefCell::new(HashMap::new()));
+        let mut io = TestIO::with_clock(ctx, Faults::default(), clock);
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        serve(&mut io, server_sock, server_addr.into());
+        let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+        io.connect(sock.clone(), server_addr.into(), on_client_connect);
+        let body = r#"{"baton":null,"requests":[{"type":"execute","stmt":{"sql":"INSERT INTO t VALUES (1)"}},{"type":"close"}]}"#;
+        let req = format!(
+            "POST /v2/pipeline HTTP/1.1\r\nHost: test.localhost\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let client = sock.as_raw_fd();
+        let n = req.len();
+        io.send(sock, Bytes::from(req), n, on_client_send);
+        let resp = loop {
+            io.run_once();
+            if Some(io.now_ms()) == release_ms {
+                conn.prepare("COMMIT").unwrap().step().unwrap();
+            }
+            if let Some((status, body)) = io.context().user_data.borrow().get(&client) {
+                break (*status, body.clone(), io.now_ms());
+            }
+        };
+        drop(conn);
+        manager.drop_session(holder.id);
+        std::fs::remove_dir_all(db_path).unwrap();
+        resp
+    }
+
+    #[test]
+    fn write_waits_for_lock_to_be_released() {
+        let (status, body, received_ms) =
+            write_while_locked("released", Duration::from_secs(1), Some(100));
+        assert_eq!(status, 200, "{}", body);
+        assert!(body.contains(r#""affected_row_count":1"#), "{}", body);
+        assert!((100..200).contains(&received_ms));
+    }
+
+    #[test]
+    fn write_times_out_on_held_lock() {
+        let (status, body, received_ms) =
+            write_while_locked("held", Duration::from_millis(100), None);
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""code":"SQLITE_BUSY""#), "{}", body);
+        assert!((100..200).contains(&received_ms));
+    }
+
+    #[test]
+    fn baton_expires_at_same_tick() {
+        // The latency of the request decides when the baton is issued.
+        let expiry_time = baton_expiry_time(1);
+        assert!(expiry_time > BATON_EXPIRY.as_millis() as u64);
+        assert_eq!(baton_expiry_time(1), expiry_time);
+    }
 }
@@ -0,0 +1,113 @@
+//! Client sessions.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::database::Connection;
+use crate::filter::{KeywordFilter, StmtFilter};
+use crate::proto::Version;
+use crate::{HiisiError, Result};
+
+// Maximum number of SQL texts a session can store.
+const MAX_SQL_COUNT: usize = 150;
+
+/// Maximum size of the SQL text of a statement or sequence, in bytes, unless
+/// set otherwise with `ResourceManager::with_max_sql_bytes()`.
+pub const DEFAULT_MAX_SQL_BYTES: usize = 1024 * 1024;
+
+/// A session is the server-side state of a Hrana stream.
+///
+/// Clients identify a session with the baton they received in the previous
+/// response, which is valid only until the next response rotates it. All SQL
+/// statements executed within a session run on the same
+/// SQLite connection, which is opened lazily on first use.
+pub struct Session {
+    pub id: u64,
+    pub db_name: String,
+    /// Hrana version the stream was opened with.
+    pub version: Version,
+    /// Counter of the most recently issued baton for the session.
+    pub baton_counter: Cell<u64>,
+    /// Time the most recent baton was issued at, or the session was opened
+    /// at if no baton has been issued yet.
+    pub baton_issued_at: Cell<Duration>,
+    pub conn: RefCell<Option<Rc<Connection>>>,
+    /// Whether the arguments of statements are checked against how the
+    /// statements use their parameters before they are bound.
+    pub strict_args: bool,
+    /// The filter that statements pass before they are executed.
+    pub stmt_filter: Rc<dyn StmtFilter>,
+    /// Maximum size of SQL text that the session prepares or stores, in
+    /// bytes.
+    pub max_sql_bytes: usize,
+    /// Maximum number of rows in the result of a statement, unless the
+    /// result is read with a cursor.
+    pub max_result_rows: Option<usize>,
+    /// The databases that the session may attach, as the file names that
+    /// SQLite opens them with, keyed by database name.
+    ///
+    /// If set, `ATTACH` and `DETACH` bypass the statement filter, and
+    /// `ATTACH` attaches only these databases. Otherwise, they are up to the
+    /// filter like any other statement.
+    pub attach_targets: Option<HashMap<String, String>>,
+    /// SQL texts stored with `StoreSql`, keyed by SQL id.
+    sqls: RefCell<HashMap<i32, String>>,
+}
+
+impl Session {
+    pub fn new(id: u64, db_name: &str, version: Version) -> Self {
+        Self {
+            id,
+            db_name: db_name.to_owned(),
+            version,
+            baton_counter: Cell::new(0),
+            baton_issued_at: Cell::new(Duration::ZERO),
+            conn: RefCell::new(None),
+            strict_args: false,
+            stmt_filter: Rc::new(KeywordFilter::default()),
+            max_sql_bytes: DEFAULT_MAX_SQL_BYTES,
+            max_result_rows: None,
+            attach_targets: None,
+            sqls: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn store_sql(&self, sql_id: i32, sql: String) -> Result<()> {
+        self.check_sql_len(&sql)?;
+        let mut sqls = self.sqls.borrow_mut();
+        if sqls.contains_key(&sql_id) {
+            return Err(HiisiError::ProtocolError(format!(
+                "SQL text with id {} already exists",
+                sql_id
+            )));
+        }
+        if sqls.len() >= MAX_SQL_COUNT {
+            return Err(HiisiError::ProtocolError(format!(
+                "Too many stored SQL texts (the limit is {})",
+                MAX_SQL_COUNT
+            )));
+        }
+        sqls.insert(sql_id, sql);
+        Ok(())
+    }
+
+    /// Remove a stored SQL text. Closing an unknown SQL id is not an error.
+    pub fn close_sql(&self, sql_id: i32) {
+        self.sqls.borrow_mut().remove(&sql_id);
+    }
+
+    pub fn get_sql(&self, sql_id: i32) -> Option<String> {
+        self.sqls.borrow().get(&sql_id).cloned()
+    }
+
+    /// Check that `sql` is no larger than `max_sql_bytes`, which keeps SQLite
+    /// from parsing huge texts.
+    pub fn check_sql_len(&self, sql: &str) -> Result<()> {
+        if sql.len() > self.max_sql_bytes {
+            return Err(HiisiError::SqlTooLarge(self.max_sql_bytes));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! Counters of what the server has done, for observability.
+
+use std::cell::Cell;
+use std::fmt::Write;
+
+/// Counters that the IO and request handlers of the server update as they
+/// go.
+///
+/// The server runs on a single thread, so the counters are plain cells,
+/// which cost an addition to update.
+#[derive(Default)]
+pub struct ServerStats {
+    connections: Cell<u64>,
+    requests_served: Cell<u64>,
+    bytes_received: Cell<u64>,
+    bytes_sent: Cell<u64>,
+    ok_results: Cell<u64>,
+    error_results: Cell<u64>,
+}
+
+impl ServerStats {
+    /// The number of client connections accepted.
+    pub fn connections(&self) -> u64 {
+        self.connections.get()
+    }
+
+    /// The number of pipeline and cursor requests served.
+    pub fn requests_served(&self) -> u64 {
+        self.requests_served.get()
+    }
+
+    /// The number of bytes received from clients.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.get()
+    }
+
+    /// The number of bytes sent to clients.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.get()
+    }
+
+    /// The number of requests in pipelines that succeeded.
+    pub fn ok_results(&self) -> u64 {
+        self.ok_results.get()
+    }
+
+    /// The number of requests in pipelines that failed.
+    pub fn error_results(&self) -> u64 {
+        self.error_results.get()
+    }
+
+    pub(crate) fn add_connection(&self) {
+        add(&self.connections, 1);
+    }
+
+    pub(crate) fn add_request(&self) {
+        add(&self.requests_served, 1);
+    }
+
+    pub(crate) fn add_bytes_received(&self, n: usize) {
+        add(&self.bytes_received, n as u64);
+    }
+
+    pub(crate) fn add_bytes_sent(&self, n: usize) {
+        add(&self.bytes_sent, n as u64);
+    }
+
+    pub(crate) fn add_result(&self, ok: bool) {
+        if ok {
+            add(&self.ok_results, 1);
+        } else {
+            add(&self.error_results, 1);
+        }
+    }
+
+    /// Format the counters, and the number of open `sessions`, in the
+    /// Prometheus text exposition format.
+    pub fn format_prometheus(&self, sessions: usize) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, u64)]| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            for (labels, value) in values {
+                writeln!(out, "{}{} {}", name, labels, value).unwrap();
+            }
+        };
+        metric(
+            "hiisi_connections_total",
+            "counter",
+            "Client connections accepted.",
+            &[("", self.connections())],
+        );
+        metric(
+            "hiisi_sessions",
+            "gauge",
+            "Open sessions.",
+            &[("", sessions as u64)],
+        );
+        metric(
+            "hiisi_requests_total",
+            "counter",
+            "Pipeline and cursor requests served.",
+            &[("", self.requests_served())],
+        );
+        metric(
+            "hiisi_received_bytes_total",
+            "counter",
+            "Bytes received from clients.",
+            &[("", self.bytes_received())],
+        );
+        metric(
+            "hiisi_sent_bytes_total",
+            "counter",
+            "Bytes sent to clients.",
+            &[("", self.bytes_sent())],
+        );
+        metric(
+            "hiisi_results_total",
+            "counter",
+            "Results of the requests in pipelines.",
+            &[
+                ("{result=\"ok\"}", self.ok_results()),
+                ("{result=\"error\"}", self.error_results()),
+            ],
+        );
+        out
+    }
+}
+
+fn add(counter: &Cell<u64>, n: u64) {
+    counter.set(counter.get().wrapping_add(n));
+}
@@ -0,0 +1,156 @@
+//! Storage that databases are kept on.
+
+use std::io;
+use std::path::Path;
+
+/// File system operations that the resource manager performs on the data
+/// directory.
+///
+/// SQLite reads and writes the database files itself, but the resource
+/// manager opens a database file through the storage before SQLite does, so
+/// that a failing storage is reported as an I/O error rather than as an
+/// SQLite error deep inside a statement.
+pub trait Storage {
+    /// Create a directory, failing if it already exists.
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove a directory and everything in it.
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Open a file for writing, creating it if it does not exist.
+    fn open_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove a file.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Rename a file or directory, replacing `to` if it is a file.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+}
+
+/// Storage on the local file system.
+pub struct FileStorage;
+
+impl Storage for FileStorage {
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_dir_all(path)
+    }
+
+    fn open_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+}
+
+/// The `EIO` error number.
+pub const EIO: i32 = 5;
+
+/// The `ENOSPC` error number.
+pub const ENOSPC: i32 = 28;
+
+/// Storage that fails operations with `EIO` or `ENOSPC`.
+///
+/// The faults are drawn from an RNG seeded with `seed`, so the same seed
+/// injects the same sequence of faults.
+#[cfg(feature = "simulation")]
+pub struct FaultyStorage<S> {
+    inner: S,
+    rng: std::cell::RefCell<rand_chacha::ChaCha8Rng>,
+    fault_prob: f64,
+    /// A fault to inject regardless of the probability, and how many
+    /// operations to let through before it.
+    next_fault: std::cell::Cell<Option<(usize, i32)>>,
+}
+
+#[cfg(feature = "simulation")]
+impl<S: Storage> FaultyStorage<S> {
+    /// Wrap `inner`, failing each operation with probability `fault_prob`.
+    pub fn new(inner: S, seed: u64, fault_prob: f64) -> Self {
+        use rand::SeedableRng;
+
+        Self {
+            inner,
+            rng: std::cell::RefCell::new(rand_chacha::ChaCha8Rng::seed_from_u64(seed)),
+            fault_prob,
+            next_fault: std::cell::Cell::new(None),
+        }
+    }
+
+    /// Fail the next operation with the error number `errno`.
+    pub fn fail_next(&self, errno: i32) {
+        self.fail_after(0, errno);
+    }
+
+    /// Fail the operation after the next `skip` ones with the error number
+    /// `errno`.
+    pub fn fail_after(&self, skip: usize, errno: i32) {
+        self.next_fault.set(Some((skip, errno)));
+    }
+
+    fn fault(&self, op: &str, path: &Path) -> io::Result<()> {
+        use rand::Rng;
+
+        let errno = match self.next_fault.take() {
+            Some((0, errno)) => errno,
+            next_fault => {
+                if let Some((skip, errno)) = next_fault {
+                    self.next_fault.set(Some((skip - 1, errno)));
+                }
+                let mut rng = self.rng.borrow_mut();
+                if !rng.gen_bool(self.fault_prob) {
+                    return Ok(());
+                }
+                if rng.gen_bool(0.5) {
+                    EIO
+                } else {
+                    ENOSPC
+                }
+            }
+        };
+        let err = io::Error::from_raw_os_error(errno);
+        log::trace!("Storage -> {}({:?}) fails with {}", op, path, err);
+        Err(err)
+    }
+}
+
+#[cfg(feature = "simulation")]
+impl<S: Storage> Storage for FaultyStorage<S> {
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.fault("create_dir", path)?;
+        self.inner.create_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.fault("remove_dir_all", path)?;
+        self.inner.remove_dir_all(path)
+    }
+
+    fn open_file(&self, path: &Path) -> io::Result<()> {
+        self.fault("open_file", path)?;
+        self.inner.open_file(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.fault("remove_file", path)?;
+        self.inner.remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.fault("rename", from)?;
+        self.inner.rename(from, to)
+    }
+}
@@ -0,0 +1,557 @@
+//! Hrana over WebSocket.
+//!
+//! A client upgrades a `GET /v2` or `GET /v3` request to a WebSocket, says
+//! `hello`, and then sends `request` messages that the server answers with
+//! `response_ok` or `response_error` messages carrying the same request id.
+//! A connection multiplexes any number of streams, each of which the server
+//! runs as a pipeline stream: the requests of a stream are executed like
+//! pipeline requests that continue the stream with its latest baton. Every
+//! request takes a token off the rate limit of the database, just like a
+//! pipeline request does.
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bytes::{BufMut, BytesMut};
+use sha1::{Digest, Sha1};
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::rc::Rc;
+
+use crate::executor::{self, Request};
+use crate::manager::ResourceManager;
+use crate::proto;
+use crate::stats::ServerStats;
+use crate::HiisiError;
+
+/// The GUID that RFC 6455 appends to the key of the handshake.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xA;
+
+/// Status codes of close frames.
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Compute the `Sec-WebSocket-Accept` of the handshake from the
+/// `Sec-WebSocket-Key` of the client.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// A WebSocket frame, with its payload unmasked.
+#[derive(Debug)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub masked: bool,
+    pub payload: Vec<u8>,
+}
+
+/// Parse the frame at the beginning of `buf`, returning the frame and its
+/// length, or `None` if the frame has not been received completely.
+///
+/// A frame with a payload larger than `max_payload` fails with the status
+/// code to close the connection with.
+pub fn parse_frame(
+    buf: &[u8],
+    max_payload: usize,
+) -> std::result::Result<Option<(Frame, usize)>, u16> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    if buf[0] & 0x70 != 0 {
+        // No extensions are negotiated, so the reserved bits must be clear.
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    let opcode = buf[0] & 0x0f;
+    let masked = buf[1] & 0x80 != 0;
+    let (payload_len, mut off) = match buf[1] & 0x7f {
+        126 => {
+            let Some(len) = buf.get(2..4) else {
+                return Ok(None);
+            };
+            (u16::from_be_bytes([len[0], len[1]]) as u64, 4)
+        }
+        127 => {
+            let Some(len) = buf.get(2..10) else {
+                return Ok(None);
+            };
+            (u64::from_be_bytes(len.try_into().unwrap()), 10)
+        }
+        len => (len as u64, 2),
+    };
+    if payload_len > max_payload as u64 {
+        return Err(CLOSE_MESSAGE_TOO_BIG);
+    }
+    let payload_len = payload_len as usize;
+    let mask = if masked {
+        let Some(mask) = buf.get(off..off + 4) else {
+            return Ok(None);
+        };
+        off += 4;
+        Some([mask[0], mask[1], mask[2], mask[3]])
+    } else {
+        None
+    };
+    let Some(payload) = buf.get(off..off + payload_len) else {
+        return Ok(None);
+    };
+    let mut payload = payload.to_vec();
+    if let Some(mask) = mask {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    let frame = Frame {
+        fin,
+        opcode,
+        masked,
+        payload,
+    };
+    Ok(Some((frame, off + payload_len)))
+}
+
+/// Append a final frame to `buf`, masked with `mask` if it is given.
+///
+/// Frames from the server are never masked, but frames from clients always
+/// are.
+pub fn format_frame(buf: &mut BytesMut, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) {
+    buf.put_u8(0x80 | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len if len < 126 => buf.put_u8(mask_bit | len as u8),
+        len if len <= u16::MAX as usize => {
+            buf.put_u8(mask_bit | 126);
+            buf.put_u16(len as u16);
+        }
+        len => {
+            buf.put_u8(mask_bit | 127);
+            buf.put_u64(len as u64);
+        }
+    }
+    match mask {
+        Some(mask) => {
+            buf.put_slice(&mask);
+            buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => buf.put_slice(payload),
+    }
+}
+
+/// Append a close frame with `code` to `buf`.
+pub fn format_close_frame(buf: &mut BytesMut, code: u16, mask: Option<[u8; 4]>) {
+    format_frame(buf, OPCODE_CLOSE, &code.to_be_bytes(), mask);
+}
+
+/// The state of a connection that has been upgraded to a WebSocket.
+pub(crate) struct WsConn {
+    database: String,
+    version: proto::Version,
+    /// The address of the client, if it connected over IP.
+    client: Option<IpAddr>,
+    /// Whether the client has said `hello`.
+    hello: bool,
+    /// The open streams by their id, with the baton that the next request
+    /// on the stream continues the stream with. A stream that has not run
+    /// any requests yet has no baton.
+    streams: HashMap<i32, Option<String>>,
+    /// The SQL texts that the client has stored, which the streams of the
+    /// connection share.
+    sqls: HashMap<i32, String>,
+}
+
+impl WsConn {
+    pub(crate) fn new(database: String, version: proto::Version, client: Option<IpAddr>) -> Self {
+        Self {
+            database,
+            version,
+            client,
+            hello: false,
+            streams: HashMap::new(),
+            sqls: HashMap::new(),
+        }
+    }
+
+    /// Handle a frame from the client, appending the frames to respond with
+    /// to `out`.
+    ///
+    /// Returns `false` if the connection is to be closed once `out` has
+    /// been sent, which ends with a close frame.
+    pub(crate) fn handle_frame(
+        &mut self,
+        manager: &Rc<ResourceManager>,
+        stats: &ServerStats,
+        frame: Frame,
+        out: &mut BytesMut,
+    ) -> bool {
+        if !frame.masked {
+            format_close_frame(out, CLOSE_PROTOCOL_ERROR, None);
+            return false;
+        }
+        match frame.opcode {
+            _ if !frame.fin => {
+                // Hrana messages are small enough that clients send them
+                // whole, so fragmented messages are not supported.
+                format_close_frame(out, CLOSE_UNSUPPORTED_DATA, None);
+                false
+            }
+            OPCODE_TEXT => match self.handle_msg(manager, stats, &frame.payload) {
+                Some(msg) => {
+                    // Server messages are plain data, which always
+                    // serialize.
+                    let msg = proto::format_msg(&msg).unwrap();
+                    format_frame(out, OPCODE_TEXT, &msg, None);
+                    true
+                }
+                None => {
+                    format_close_frame(out, CLOSE_PROTOCOL_ERROR, None);
+                    false
+                }
+            },
+            OPCODE_PING => {
+                format_frame(out, OPCODE_PONG, &frame.payload, None);
+                true
+            }
+            OPCODE_PONG => true,
+            OPCODE_CLOSE => {
+                format_close_frame(out, CLOSE_NORMAL, None);
+                false
+            }
+            // Binary frames would carry Protobuf messages, which the server
+            // only speaks over HTTP.
+            OPCODE_BINARY | OPCODE_CONTINUATION => {
+                format_close_frame(out, CLOSE_UNSUPPORTED_DATA, None);
+                false
+            }
+            _ => {
+                format_close_frame(out, CLOSE_PROTOCOL_ERROR, None);
+                false
+            }
+        }
+    }
+
+    /// Handle a message from the client, returning the message to respond
+    /// with, or `None` if the client has broken the protocol.
+    fn handle_msg(
+        &mut self,
+        manager: &Rc<ResourceManager>,
+        stats: &ServerStats,
+        msg: &[u8],
+    ) -> Option<proto::WsServerMsg> {
+        let msg: proto::WsClientMsg = match serde_json::from_slice(msg) {
+            Ok(msg) => msg,
+            Err(err) => {
+                log::trace!("Malformed WebSocket message: {}", err);
+                return None;
+            }
+        };
+        match msg {
+            proto::WsClientMsg::Hello { .. } => {
+                self.hello = true;
+                Some(proto::WsServerMsg::HelloOk {})
+            }
+            proto::WsClientMsg::Request { .. } if !self.hello => {
+                log::trace!("WebSocket request before hello");
+                None
+            }
+            proto::WsClientMsg::Request {
+                request_id,
+                request,
+            } => {
+                stats.add_request();
+                // Closing a stream takes no token, so that a client over its
+                // limit can still release its sessions rather than hold them
+                // until their batons expire. `hello` is not a request and
+                // takes none either.
+                let limited = !matches!(request, proto::WsRequest::CloseStream { .. });
+                let resp = if limited {
+                    manager
+                        .check_rate_limit(&self.database, self.client)
+                        .map_err(|err| executor::to_proto_error(&err))
+                } else {
+                    Ok(())
+                };
+                let resp = resp.and_then(|()| self.handle_request(manager, request_id, request));
+                stats.add_result(resp.is_ok());
+                Some(match resp {
+                    Ok(response) => proto::WsServerMsg::ResponseOk {
+                        request_id,
+                        response,
+                    },
+                    Err(error) => proto::WsServerMsg::ResponseError { request_id, error },
+                })
+            }
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        manager: &Rc<ResourceManager>,
+        request_id: i32,
+        request: proto::WsRequest,
+    ) -> std::result::Result<proto::WsResponse, proto::Error> {
+        match request {
+            proto::WsRequest::OpenStream { stream_id } => {
+                if self.streams.contains_key(&stream_id) {
+                    return Err(protocol_error(format!(
+                        "Stream {} is already open",
+                        stream_id
+                    )));
+                }
+                self.streams.insert(stream_id, None);
+                Ok(proto::WsResponse::OpenStream {})
+            }
+            proto::WsRequest::CloseStream { stream_id } => {
+                let baton = self
+                    .streams
+                    .remove(&stream_id)
+                    .ok_or_else(|| protocol_error(format!("Stream {} is not open", stream_id)))?;
+                if let Some(baton) = baton {
+                    close_stream(manager, &self.database, self.version, baton);
+                }
+                Ok(proto::WsResponse::CloseStream {})
+            }
+            proto::WsRequest::Execute {
+                stream_id,
+                mut stmt,
+            } => {
+                self.resolve_stmt(&mut stmt)?;
+                let req = proto::StreamRequest::Execute(proto::ExecuteStreamReq { stmt });
+                self.execute(manager, request_id, stream_id, req)
+            }
+            proto::WsRequest::Batch {
+                stream_id,
+                mut batch,
+            } => {
+                for step in &mut batch.steps {
+                    self.resolve_stmt(&mut step.stmt)?;
+                }
+                let req = proto::StreamRequest::Batch(proto::BatchStreamReq { batch });
+                self.execute(manager, request_id, stream_id, req)
+            }
+            proto::WsRequest::Sequence {
+                stream_id,
+                sql,
+                sql_id,
+            } => {
+                let req = proto::StreamRequest::Sequence(proto::SequenceStreamReq {
+                    sql: Some(self.resolve_sql(sql, sql_id)?),
+                    sql_id: None,
+                    replication_index: None,
+                });
+                self.execute(manager, request_id, stream_id, req)
+            }
+            proto::WsRequest::Describe {
+                stream_id,
+                sql,
+                sql_id,
+            } => {
+                let req = proto::StreamRequest::Describe(proto::DescribeStreamReq {
+                    sql: Some(self.resolve_sql(sql, sql_id)?),
+                    sql_id: None,
+                    replication_index: None,
+                });
+                self.execute(manager, request_id, stream_id, req)
+            }
+            proto::WsRequest::StoreSql { sql_id, sql } => {
+                self.sqls.insert(sql_id, sql);
+                Ok(proto::WsResponse::StoreSql(proto::StoreSqlStreamResp {}))
+            }
+            proto::WsRequest::CloseSql { sql_id } => {
+                self.sqls.remove(&sql_id);
+                Ok(proto::WsResponse::CloseSql(proto::CloseSqlStreamResp {}))
+            }
+            proto::WsRequest::GetAutocommit { stream_id } => {
+                let req = proto::StreamRequest::GetAutocommit(proto::GetAutocommitStreamReq {});
+                self.execute(manager, request_id, stream_id, req)
+            }
+        }
+    }
+
+    /// Execute a request on a stream as a pipeline request that continues
+    /// the stream, logging its slow statements with the id that the client
+    /// gave the request.
+    fn execute(
+        &mut self,
+        manager: &Rc<ResourceManager>,
+        request_id: i32,
+        stream_id: i32,
+        request: proto::StreamRequest,
+    ) -> std::result::Result<proto::WsResponse, proto::Error> {
+        let baton = self
+            .streams
+            .get(&stream_id)
+            .ok_or_else(|| protocol_error(format!("Stream {} is not open", stream_id)))?;
+        let req = Request {
+            database: self.database.clone(),
+            version: self.version,
+            req: proto::PipelineReqBody {
+                baton: baton.clone(),
+                requests: vec![request],
+            },
+        };
+        let request_id = format!("ws-{}", request_id);
+        let mut resp =
+            executor::execute_client_req_with_id(manager.clone(), req, Some(&request_id))
+                .map_err(|err| executor::to_proto_error(&err))?;
+        self.streams.insert(stream_id, resp.baton);
+        match resp.results.pop() {
+            Some(proto::StreamResult::Ok { response }) => Ok(response.into()),
+            Some(proto::StreamResult::Error { error }) => Err(error),
+            _ => Err(protocol_error("Missing stream result".to_owned())),
+        }
+    }
+
+    /// Replace the SQL id of a statement with the SQL text it refers to.
+    fn resolve_stmt(&self, stmt: &mut proto::Stmt) -> std::result::Result<(), proto::Error> {
+        if stmt.sql_id.is_some() {
+            stmt.sql = Some(self.resolve_sql(stmt.sql.take(), stmt.sql_id.take())?);
+        }
+        Ok(())
+    }
+
+    fn resolve_sql(
+        &self,
+        sql: Option<String>,
+        sql_id: Option<i32>,
+    ) -> std::result::Result<String, proto::Error> {
+        match (sql, sql_id) {
+            (Some(sql), None) => Ok(sql),
+            (None, Some(sql_id)) => self
+                .sqls
+                .get(&sql_id)
+                .cloned()
+                .ok_or_else(|| protocol_error(format!("SQL text {} not found", sql_id))),
+            (Some(_), Some(_)) => Err(protocol_error(
+                "Received both SQL text and SQL id".to_owned(),
+            )),
+            (None, None) => Err(protocol_error(
+                "Received neither SQL text nor SQL id".to_owned(),
+            )),
+        }
+    }
+
+    /// Close the streams that are still open once the connection is gone,
+    /// which rolls back their transactions.
+    pub(crate) fn close(self, manager: &Rc<ResourceManager>) {
+        let mut batons: Vec<_> = self.streams.into_iter().collect();
+        // Close in a fixed order, which keeps simulations deterministic.
+        batons.sort_by_key(|(stream_id, _)| *stream_id);
+        for (_, baton) in batons {
+            if let Some(baton) = baton {
+                close_stream(manager, &self.database, self.version, baton);
+            }
+        }
+    }
+}
+
+/// Close a stream like a pipeline `close` request does.
+fn close_stream(
+    manager: &Rc<ResourceManager>,
+    database: &str,
+    version: proto::Version,
+    baton: String,
+) {
+    let req = Request {
+        database: database.to_owned(),
+        version,
+        req: proto::PipelineReqBody {
+            baton: Some(baton),
+            requests: vec![proto::StreamRequest::Close(proto::CloseStreamReq {})],
+        },
+    };
+    if let Err(err) = executor::execute_client_req(manager.clone(), req) {
+        // The stream may have expired already.
+        log::trace!("Failed to close WebSocket stream: {}", err);
+    }
+}
+
+fn protocol_error(message: String) -> proto::Error {
+    executor::to_proto_error(&HiisiError::ProtocolError(message))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{accept_key, format_frame, parse_frame, WsConn, OPCODE_TEXT};
+    use crate::clock::SimClock;
+    use crate::manager::ResourceManager;
+    use crate::proto;
+    use crate::ratelimit::RateLimit;
+    use crate::stats::ServerStats;
+    use bytes::BytesMut;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGJzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn masked_frame_round_trips() {
+        let payload = vec![b'x'; 300];
+        let mut buf = BytesMut::new();
+        format_frame(&mut buf, OPCODE_TEXT, &payload, Some([1, 2, 3, 4]));
+        assert!(parse_frame(&buf[..buf.len() - 1], 1024).unwrap().is_none());
+        let (frame, len) = parse_frame(&buf, 1024).unwrap().unwrap();
+        assert_eq!(len, buf.len());
+        assert!(frame.fin && frame.masked);
+        assert_eq!(frame.opcode, OPCODE_TEXT);
+        assert_eq!(frame.payload, payload);
+        assert!(parse_frame(&buf, 100).is_err());
+    }
+
+    #[test]
+    fn requests_take_tokens_off_rate_limit() {
+        let clock = Rc::new(SimClock::new());
+        let manager = Rc::new(
+            ResourceManager::new_in_memory([0; 32])
+                .with_clock(clock.clone())
+                .with_rate_limit(RateLimit::new(1, 2)),
+        );
+        manager.create_database("test").unwrap();
+        let stats = ServerStats::default();
+        let mut ws = WsConn::new("test".to_owned(), proto::Version::Hrana2, None);
+        let mut send = |msg: &str| {
+            let mut buf = BytesMut::new();
+            format_frame(&mut buf, OPCODE_TEXT, msg.as_bytes(), Some([1, 2, 3, 4]));
+            let (frame, _) = parse_frame(&buf, 1024).unwrap().unwrap();
+            let mut out = BytesMut::new();
+            assert!(ws.handle_frame(&manager, &stats, frame, &mut out));
+            let (frame, _) = parse_frame(&out, 1024).unwrap().unwrap();
+            String::from_utf8(frame.payload).unwrap()
+        };
+        send(r#"{"type":"hello","jwt":null}"#);
+        let open =
+            r#"{"type":"request","request_id":1,"request":{"type":"open_stream","stream_id":1}}"#;
+        let execute = r#"{"type":"request","request_id":2,"request":{"type":"execute","stream_id":1,"stmt":{"sql":"SELECT 1"}}}"#;
+        assert!(send(open).contains(r#""type":"response_ok""#));
+        assert!(send(execute).contains(r#""type":"response_ok""#));
+        // The burst is used up, so the next request waits for the bucket.
+        let resp = send(execute);
+        assert!(resp.contains(r#""type":"response_error""#), "{}", resp);
+        assert!(resp.contains(r#""code":"RATE_LIMITED""#), "{}", resp);
+        clock.advance(Duration::from_secs(1));
+        assert!(send(execute).contains(r#""type":"response_ok""#));
+        // Closing the stream takes no token, even with the bucket empty.
+        let close =
+            r#"{"type":"request","request_id":3,"request":{"type":"close_stream","stream_id":1}}"#;
+        let resp = send(close);
+        assert!(resp.contains(r#""type":"response_ok""#), "{}", resp);
+        assert_eq!(manager.session_count(), 0);
+    }
+}
@@ -0,0 +1,86 @@
+#![cfg(not(feature = "simulation"))]
+
+use hiisi::{Context, ResourceManager, IO};
+use socket2::{Domain, SockAddr, Socket, Type};
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::rc::Rc;
+
+fn request(close: bool) -> String {
+    let body = r#"{"baton":null,"requests":[{"type":"execute","stmt":{"sql":"SELECT 1"}}]}"#;
+    let connection = if close { "close" } else { "keep-alive" };
+    format!(
+        "POST /v2/pipeline HTTP/1.1\r\nConnection: {}\r\nContent-Length: {}\r\n\r\n{}",
+        connection,
+        body.len(),
+        body
+    )
+}
+
+fn listen() -> (Rc<Socket>, SocketAddr) {
+    let sock = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    sock.bind(&addr.into()).unwrap();
+    sock.listen(128).unwrap();
+    let addr = sock.local_addr().unwrap().as_socket().unwrap();
+    (Rc::new(sock), addr)
+}
+
+/// Read one response from `stream`, which must have a `Content-Length`.
+fn read_response(stream: &mut TcpStream) -> String {
+    let mut resp = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "Connection closed before the response was complete");
+        resp.extend_from_slice(&buf[..n]);
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut parsed = httparse::Response::new(&mut headers);
+        if let httparse::Status::Complete(body_off) = parsed.parse(&resp).unwrap() {
+            let len: usize = parsed
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("Content-Length"))
+                .map(|header| std::str::from_utf8(header.value).unwrap().parse().unwrap())
+                .unwrap();
+            if resp.len() >= body_off + len {
+                return String::from_utf8(resp).unwrap();
+            }
+        }
+    }
+}
+
+#[test]
+fn serve_requests_on_kept_alive_connection() {
+    let db_path = std::env::temp_dir().join(format!("hiisi-keep-alive-{}", std::process::id()));
+    let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+    manager
+        .create_database(hiisi::server::DEFAULT_DATABASE)
+        .unwrap();
+    let mut io = IO::new(Context::new(manager, ()));
+    let (sock, addr) = listen();
+    let sock_addr: SockAddr = addr.into();
+    hiisi::serve(&mut io, sock, sock_addr);
+
+    // The server reads ahead on the connection while it sends the first
+    // response, so the receive and the send are pending on the same socket
+    // at once.
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request(false).as_bytes()).unwrap();
+        let first = read_response(&mut stream);
+        stream.write_all(request(true).as_bytes()).unwrap();
+        let second = read_response(&mut stream);
+        (first, second)
+    });
+    while !client.is_finished() {
+        io.run_once();
+    }
+    let (first, second) = client.join().unwrap();
+    for resp in [first, second] {
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        assert!(resp.contains(r#""type":"ok""#), "{}", resp);
+    }
+    std::fs::remove_dir_all(db_path).unwrap();
+}
@@ -0,0 +1,171 @@
+use hiisi::clock::SimClock;
+use hiisi::ratelimit::RateLimit;
+use hiisi::{Context, ResourceManager, IO};
+use socket2::{Domain, Socket, Type};
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Set up a server with an in-memory default database, and a socket that
+/// stands in for a client connection.
+fn setup() -> (IO<()>, Socket) {
+    let manager = Rc::new(ResourceManager::new_in_memory([0; 32]));
+    manager
+        .create_database(hiisi::server::DEFAULT_DATABASE)
+        .unwrap();
+    let io = IO::new(Context::new(manager, ()));
+    let sock = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    (io, sock)
+}
+
+/// Handle `req` and return the response, which must be text.
+fn handle(req: &[u8]) -> String {
+    let (mut io, sock) = setup();
+    let resp = hiisi::server::handle_bytes(&mut io, &sock, req);
+    String::from_utf8(resp.to_vec()).unwrap()
+}
+
+#[test]
+fn select_one() {
+    let body = r#"{"baton":null,"requests":[{"type":"execute","stmt":{"sql":"SELECT 1"}}]}"#;
+    let req = format!(
+        "POST /v2/pipeline HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let resp = handle(req.as_bytes());
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+    assert!(resp.contains(r#""type":"ok""#), "{}", resp);
+    assert!(resp.contains(r#""value":"1""#), "{}", resp);
+}
+
+#[test]
+fn garbage_is_bad_request() {
+    let resp = handle(b"\x00\x01garbage\r\n\r\n");
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", resp);
+    assert!(resp.contains("HTTP_PARSE_ERROR"), "{}", resp);
+}
+
+#[test]
+fn unknown_path_is_not_found() {
+    let resp = handle(b"GET /nope HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+    assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", resp);
+}
+
+#[test]
+fn pipelined_requests_get_responses_in_order() {
+    let (mut io, sock) = setup();
+    let req = b"GET /health HTTP/1.1\r\n\r\nGET /nope HTTP/1.1\r\n\r\n";
+    let resp = hiisi::server::handle_bytes(&mut io, &sock, req);
+    let resp = String::from_utf8(resp.to_vec()).unwrap();
+    // Both requests have no body, so the second one starts right after the
+    // head of the first.
+    assert_eq!(resp.matches("HTTP/1.1 ").count(), 2, "{}", resp);
+    let not_found = resp.find("HTTP/1.1 404 ").expect(&resp);
+    assert!(
+        resp[..not_found].starts_with("HTTP/1.1 200 OK\r\n"),
+        "{}",
+        resp
+    );
+}
+
+/// Parse the head of `resp`, returning its status code, its headers with
+/// lowercase names, and its body.
+fn parse_response(resp: &[u8]) -> (u16, HashMap<String, String>, &[u8]) {
+    let mut headers = [httparse::EMPTY_HEADER; 16];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let body_off = match parsed.parse(resp).unwrap() {
+        httparse::Status::Complete(body_off) => body_off,
+        httparse::Status::Partial => panic!("Incomplete response head"),
+    };
+    let headers = parsed
+        .headers
+        .iter()
+        .map(|header| {
+            let value = std::str::from_utf8(header.value).unwrap();
+            (header.name.to_ascii_lowercase(), value.to_owned())
+        })
+        .collect();
+    (parsed.code.unwrap(), headers, &resp[body_off..])
+}
+
+#[test]
+fn responses_have_required_headers() {
+    let (mut io, sock) = setup();
+    let body = r#"{"baton":null,"requests":[]}"#;
+    let req = format!(
+        "POST /v2/pipeline HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let resp = hiisi::server::handle_bytes(&mut io, &sock, req.as_bytes());
+    let (code, headers, body) = parse_response(&resp);
+    assert_eq!(code, 200);
+    assert_eq!(headers["content-type"], "application/json");
+    assert_eq!(headers["content-length"], body.len().to_string());
+    assert_eq!(headers["connection"], "close");
+
+    // An empty message is a pipeline request without a baton or requests.
+    let req = b"POST /v2/pipeline HTTP/1.1\r\nContent-Type: application/x-protobuf\r\nContent-Length: 0\r\n\r\n";
+    let resp = hiisi::server::handle_bytes(&mut io, &sock, req);
+    let (code, headers, body) = parse_response(&resp);
+    assert_eq!(code, 200);
+    assert_eq!(headers["content-type"], "application/x-protobuf");
+    assert_eq!(headers["content-length"], body.len().to_string());
+    assert_eq!(headers["connection"], "keep-alive");
+
+    let resp = hiisi::server::handle_bytes(&mut io, &sock, b"DELETE /health HTTP/1.1\r\n\r\n");
+    let (code, headers, _) = parse_response(&resp);
+    assert_eq!(code, 405);
+    assert_eq!(headers["allow"], "GET");
+    assert_eq!(headers["connection"], "keep-alive");
+}
+
+#[test]
+fn requests_over_rate_limit_are_rejected() {
+    let clock = Rc::new(SimClock::new());
+    let manager = Rc::new(
+        ResourceManager::new_in_memory([0; 32])
+            .with_clock(clock.clone())
+            .with_rate_limit(RateLimit::new(2, 3)),
+    );
+    manager
+        .create_database(hiisi::server::DEFAULT_DATABASE)
+        .unwrap();
+    let mut io = IO::new(Context::new(manager.clone(), ()));
+    let sock = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    let body = r#"{"baton":null,"requests":[{"type":"execute","stmt":{"sql":"SELECT 1"}}]}"#;
+    let req = format!(
+        "POST /v2/pipeline HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let send = |io: &mut IO<()>| {
+        let resp = hiisi::server::handle_bytes(io, &sock, req.as_bytes());
+        let (code, headers, _) = parse_response(&resp);
+        (code, headers.get("retry-after").cloned())
+    };
+
+    // The burst goes through, and the rest waits for the bucket to refill.
+    let codes: Vec<_> = (0..5).map(|_| send(&mut io)).collect();
+    assert_eq!(codes[..3], [(200, None), (200, None), (200, None)]);
+    assert_eq!(
+        codes[3..],
+        [(429, Some("1".to_owned())), (429, Some("1".to_owned()))]
+    );
+
+    // The bucket refills by a request every 500 ms.
+    let mut ok = 0;
+    for _ in 0..8 {
+        clock.advance(Duration::from_millis(250));
+        match send(&mut io) {
+            (200, None) => ok += 1,
+            (429, Some(_)) => {}
+            resp => panic!("Unexpected response: {:?}", resp),
+        }
+    }
+    assert_eq!(ok, 4);
+    // The rejected requests did not open streams on the database.
+    assert_eq!(manager.session_count(), 3 + ok);
+}
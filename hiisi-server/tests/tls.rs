@@ -0,0 +1,89 @@
+#![cfg(all(feature = "tls", not(feature = "simulation")))]
+
+use hiisi::{Context, ResourceManager, IO};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use socket2::{Domain, SockAddr, Socket, Type};
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::rc::Rc;
+use std::sync::Arc;
+
+fn request() -> String {
+    let body = r#"{"baton":null,"requests":[{"type":"execute","stmt":{"sql":"SELECT 1"}}]}"#;
+    format!(
+        "POST /v2/pipeline HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+fn self_signed_cert() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der());
+    (cert.der().clone(), key.into())
+}
+
+fn listen() -> (Rc<Socket>, SocketAddr) {
+    let sock = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    sock.bind(&addr.into()).unwrap();
+    sock.listen(128).unwrap();
+    let addr = sock.local_addr().unwrap().as_socket().unwrap();
+    (Rc::new(sock), addr)
+}
+
+/// Send the request over TLS, trusting `cert`, and return the response.
+fn tls_request(addr: SocketAddr, cert: CertificateDer<'static>) -> String {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let conn = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
+    let mut stream = rustls::StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+    stream.write_all(request().as_bytes()).unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+#[test]
+fn serve_over_tls() {
+    let db_path = std::env::temp_dir().join(format!("hiisi-tls-{}", std::process::id()));
+    let manager = Rc::new(ResourceManager::new(&db_path, [0; 32]));
+    manager
+        .create_database(hiisi::server::DEFAULT_DATABASE)
+        .unwrap();
+    let mut io = IO::new(Context::new(manager, ()));
+
+    let (cert, key) = self_signed_cert();
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], key)
+        .unwrap();
+    let (sock, addr) = listen();
+    let sock_addr: SockAddr = addr.into();
+    hiisi::server::serve_tls(&mut io, sock, sock_addr, Arc::new(config));
+
+    let client = std::thread::spawn(move || {
+        // A client that does not speak TLS fails the handshake, which closes
+        // its connection only.
+        let mut plaintext = TcpStream::connect(addr).unwrap();
+        plaintext.write_all(request().as_bytes()).unwrap();
+        let mut resp = Vec::new();
+        let _ = plaintext.read_to_end(&mut resp);
+        assert!(!resp.starts_with(b"HTTP/1.1"));
+
+        tls_request(addr, cert)
+    });
+    while !client.is_finished() {
+        io.run_once();
+    }
+    let resp = client.join().unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+    assert!(resp.contains(r#""type":"ok""#), "{}", resp);
+    std::fs::remove_dir_all(db_path).unwrap();
+}
@@ -0,0 +1,177 @@
+//! The knobs of the faults that a simulation injects.
+
+/// Probability that a client request is fuzzed, unless set with `FUZZ_PROB`.
+pub const DEFAULT_FUZZ_PROB: f64 = 0.1;
+
+/// The smallest probability that `FaultConfig::reductions()` halves a fault
+/// probability to.
+const MIN_REDUCED_PROB: f64 = 0.01;
+
+/// How likely the faults of a simulation are.
+///
+/// The faults themselves are drawn from the seed of the simulation, so the
+/// same configuration and seed inject the same faults.
+#[derive(Clone, Debug)]
+pub struct FaultConfig {
+    /// Probability that a client sends garbage instead of its request.
+    pub fuzz_prob: f64,
+    /// Probability that the server resets a connection when it sends a
+    /// message.
+    pub reset_prob: f64,
+    /// Range of network latency, if any, that delays the delivery of sent
+    /// bytes.
+    pub latency_ms: Option<hiisi::io::Latency>,
+    /// Probability that a storage operation of the resource manager fails.
+    pub storage_fault_prob: f64,
+    /// Probability that the network corrupts the body of a response.
+    pub corrupt_prob: f64,
+    /// Probability that the server sends a response in two writes, so that
+    /// it arrives in more than one receive.
+    pub short_write_prob: f64,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        Self {
+            fuzz_prob: DEFAULT_FUZZ_PROB,
+            reset_prob: 0.0,
+            latency_ms: None,
+            storage_fault_prob: 0.0,
+            corrupt_prob: 0.0,
+            short_write_prob: 0.0,
+        }
+    }
+}
+
+impl FaultConfig {
+    /// Read the configuration from `FUZZ_PROB`, `RESET_PROB`,
+    /// `LATENCY_MIN_MS`, `LATENCY_MAX_MS`, `STORAGE_FAULT_PROB`,
+    /// `CORRUPT_PROB` and `SHORT_WRITE_PROB`, with the defaults of
+    /// `FaultConfig::default()` for the ones that are not set.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            fuzz_prob: prob_from_env("FUZZ_PROB", defaults.fuzz_prob),
+            reset_prob: prob_from_env("RESET_PROB", defaults.reset_prob),
+            latency_ms: latency_from_env(),
+            storage_fault_prob: prob_from_env("STORAGE_FAULT_PROB", defaults.storage_fault_prob),
+            corrupt_prob: prob_from_env("CORRUPT_PROB", defaults.corrupt_prob),
+            short_write_prob: prob_from_env("SHORT_WRITE_PROB", defaults.short_write_prob),
+        }
+    }
+
+    /// The network faults that the IO injects, drawn from `seed`.
+    pub fn io_faults(&self, seed: u64) -> hiisi::io::Faults {
+        hiisi::io::Faults {
+            seed,
+            latency_ms: self.latency_ms,
+            reset_prob: self.reset_prob,
+            short_write_prob: self.short_write_prob,
+            corrupt_prob: self.corrupt_prob,
+        }
+    }
+
+    /// The configurations that inject fewer faults than this one, each by
+    /// turning off one of the faults or, failing that, by halving how likely
+    /// it is.
+    ///
+    /// Turning faults off comes first, so that a search that takes the
+    /// first reduction that still fails ends up with as few faults as
+    /// possible before it tries to make them rarer.
+    pub fn reductions(&self) -> Vec<FaultConfig> {
+        let mut off = Vec::new();
+        let mut rarer = Vec::new();
+        let probs: [fn(&mut FaultConfig) -> &mut f64; 5] = [
+            |config| &mut config.fuzz_prob,
+            |config| &mut config.reset_prob,
+            |config| &mut config.storage_fault_prob,
+            |config| &mut config.corrupt_prob,
+            |config| &mut config.short_write_prob,
+        ];
+        for prob in probs {
+            let mut config = self.clone();
+            let p = prob(&mut config);
+            if *p == 0.0 {
+                continue;
+            }
+            let half = *p / 2.0;
+            *p = 0.0;
+            off.push(config.clone());
+            // Halving a probability that is already small makes little
+            // difference to a simulation of a few thousand ticks.
+            if half >= MIN_REDUCED_PROB {
+                *prob(&mut config) = half;
+                rarer.push(config);
+            }
+        }
+        if let Some(latency) = self.latency_ms {
+            off.push(FaultConfig {
+                latency_ms: None,
+                ..self.clone()
+            });
+            if latency.max_ms > latency.min_ms {
+                let max_ms = latency.min_ms + (latency.max_ms - latency.min_ms) / 2;
+                rarer.push(FaultConfig {
+                    latency_ms: Some(hiisi::io::Latency { max_ms, ..latency }),
+                    ..self.clone()
+                });
+            }
+        }
+        off.extend(rarer);
+        off
+    }
+
+    /// Format the configuration as a Rust expression, to paste into a
+    /// regression test.
+    pub fn to_rust(&self) -> String {
+        let latency_ms = match self.latency_ms {
+            Some(latency) => format!(
+                "Some(hiisi::io::Latency {{ min_ms: {}, max_ms: {} }})",
+                latency.min_ms, latency.max_ms
+            ),
+            None => "None".to_owned(),
+        };
+        format!(
+            "FaultConfig {{\n    fuzz_prob: {:?},\n    reset_prob: {:?},\n    latency_ms: {},\n    storage_fault_prob: {:?},\n    corrupt_prob: {:?},\n    short_write_prob: {:?},\n}}",
+            self.fuzz_prob,
+            self.reset_prob,
+            latency_ms,
+            self.storage_fault_prob,
+            self.corrupt_prob,
+            self.short_write_prob
+        )
+    }
+}
+
+/// Read a fault probability from the environment variable `name`.
+fn prob_from_env(name: &str, default: f64) -> f64 {
+    let prob = match std::env::var(name) {
+        Ok(prob) => prob.parse::<f64>().unwrap(),
+        Err(_) => default,
+    };
+    // Some requests must get through for the clients to make progress.
+    assert!(
+        (0.0..1.0).contains(&prob),
+        "{} must be at least 0 and less than 1",
+        name
+    );
+    prob
+}
+
+/// Read the range of network latency to inject from `LATENCY_MIN_MS` and
+/// `LATENCY_MAX_MS`. Latency is injected only if `LATENCY_MAX_MS` is set.
+fn latency_from_env() -> Option<hiisi::io::Latency> {
+    let max_ms = std::env::var("LATENCY_MAX_MS")
+        .ok()?
+        .parse::<u64>()
+        .unwrap();
+    let min_ms = match std::env::var("LATENCY_MIN_MS") {
+        Ok(min_ms) => min_ms.parse::<u64>().unwrap(),
+        Err(_) => 0,
+    };
+    assert!(
+        min_ms <= max_ms,
+        "LATENCY_MIN_MS must not exceed LATENCY_MAX_MS"
+    );
+    Some(hiisi::io::Latency { min_ms, max_ms })
+}
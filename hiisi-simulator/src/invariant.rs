@@ -0,0 +1,136 @@
+//! Invariants that the simulator checks on every tick.
+//!
+//! An invariant is a property of the responses that the clients observe,
+//! which must hold no matter how the requests of the clients interleave or
+//! which faults are injected. To check a property of your own, implement
+//! `Invariant` and register it on the simulation before running it:
+//!
+//! ```ignore
+//! struct NoErrors;
+//!
+//! impl Invariant for NoErrors {
+//!     fn name(&self) -> &str {
+//!         "no errors"
+//!     }
+//!
+//!     fn check(&mut self, observations: &[Observation]) -> Result<(), String> {
+//!         for observation in observations {
+//!             if let Some(error) = observation.resp.results.iter().find_map(|result| match result {
+//!                 StreamResult::Error { error } => Some(error),
+//!                 _ => None,
+//!             }) {
+//!                 return Err(format!("client {} got {:?}", observation.client_id, error));
+//!             }
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let mut sim = start_simulation(seed, data_dir);
+//! sim.register_invariant(Box::new(NoErrors));
+//! ```
+
+use hiisi::proto::{
+    PipelineReqBody, PipelineRespBody, StreamRequest, StreamResponse, StreamResult,
+};
+
+use std::collections::HashSet;
+
+/// A pipeline request that a client sent and the response it got.
+#[derive(Debug)]
+pub struct Observation {
+    pub client_id: usize,
+    pub req: PipelineReqBody,
+    pub resp: PipelineRespBody,
+}
+
+/// A property that the simulation must not violate.
+pub trait Invariant {
+    /// The name the invariant is reported with when it is violated.
+    fn name(&self) -> &str;
+
+    /// Check the invariant against the observations of a tick, returning a
+    /// description of the violation if there is one.
+    ///
+    /// Invariants that span multiple ticks keep the state they need between
+    /// the checks.
+    fn check(&mut self, observations: &[Observation]) -> Result<(), String>;
+}
+
+/// The invariants that every simulation checks.
+pub fn builtin_invariants() -> Vec<Box<dyn Invariant>> {
+    vec![Box::new(BatonRotation), Box::new(NoDoubleClose::default())]
+}
+
+/// Every response baton differs from the request baton, so that a baton can
+/// be used only once.
+pub struct BatonRotation;
+
+impl Invariant for BatonRotation {
+    fn name(&self) -> &str {
+        "baton rotation"
+    }
+
+    fn check(&mut self, observations: &[Observation]) -> Result<(), String> {
+        for observation in observations {
+            if let (Some(req_baton), Some(resp_baton)) =
+                (&observation.req.baton, &observation.resp.baton)
+            {
+                if req_baton == resp_baton {
+                    return Err(format!(
+                        "client {} got back the baton {} it sent",
+                        observation.client_id, req_baton
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A stream is closed at most once: closing the stream of a baton that has
+/// already closed it must fail.
+#[derive(Default)]
+pub struct NoDoubleClose {
+    // The batons that closed their stream.
+    closed: HashSet<String>,
+}
+
+impl Invariant for NoDoubleClose {
+    fn name(&self) -> &str {
+        "no double close"
+    }
+
+    fn check(&mut self, observations: &[Observation]) -> Result<(), String> {
+        for observation in observations {
+            // A stream that is opened and closed by the same request has no
+            // baton to close it with again.
+            let Some(baton) = &observation.req.baton else {
+                continue;
+            };
+            let closed = observation
+                .req
+                .requests
+                .iter()
+                .zip(&observation.resp.results)
+                .any(|(req, result)| {
+                    matches!(
+                        (req, result),
+                        (
+                            StreamRequest::Close(_),
+                            StreamResult::Ok {
+                                response: StreamResponse::Close(_)
+                            }
+                        )
+                    )
+                });
+            if closed && !self.closed.insert(baton.clone()) {
+                return Err(format!(
+                    "client {} closed the stream of baton {} twice",
+                    observation.client_id, baton
+                ));
+            }
+        }
+        Ok(())
+    }
+}
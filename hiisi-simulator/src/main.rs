@@ -1,24 +1,225 @@
-use std::{cell::RefCell, rc::Rc};
+mod faults;
+mod invariant;
+mod minimize;
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
 
 use bytes::{Bytes, BytesMut};
+use hiisi::server::Role;
 use rand::prelude::*;
 use rand_chacha::ChaCha8Rng;
 use socket2::{Domain, Socket, Type};
 use std::os::fd::AsRawFd;
 
 use std::path::Path;
+use std::time::Duration;
+
+use faults::FaultConfig;
+use invariant::{Invariant, Observation};
 
 const TEST_DATABASE_NAME: &str = "test";
 const TEST_DATABASE_HOST: &str = "test.localhost";
 
+// The database of the client that bursts over its rate limit, which the
+// client creates and limits through the admin interface.
+const LIMITED_DATABASE_NAME: &str = "limited";
+const LIMITED_DATABASE_HOST: &str = "limited.localhost";
+const LIMITED_RATE_LIMIT: &str = r#"{"requests_per_sec":1,"burst":2}"#;
+
 pub struct UserData {
     rng: RefCell<ChaCha8Rng>,
+    // How likely the faults that the clients inject are.
+    faults: FaultConfig,
+    // The pipeline endpoint of the Hrana version the clients speak.
+    pipeline_path: &'static str,
+    clients: Vec<Client>,
+    // The client that each client socket belongs to, by file descriptor.
+    client_socks: RefCell<HashMap<i32, usize>>,
+    // Clients that are ready to send a request, along with the next request
+    // of their flow if they are in the middle of one.
+    ready_clients: RefCell<Vec<(Rc<Socket>, Option<ClientReq>)>>,
+    // Number of times the admin client has retried creating the database.
+    admin_retries: Cell<usize>,
+    // Number of times the server has closed the connection of the stalled
+    // client.
+    stalled_disconnects: Cell<usize>,
+    // The baton of the transaction that the abandoning client has left open,
+    // and the virtual time in milliseconds at which the client connects
+    // next, unless it is connected.
+    abandoned_txn: RefCell<(Option<String>, Option<u64>)>,
+    // How long the server lets a client leave a transaction open.
+    transaction_timeout: Duration,
+    // Number of abandoned transactions that the server has rolled back.
+    abandoned_rollbacks: Cell<usize>,
+    // The responses the clients have observed during the current tick.
+    observations: RefCell<Vec<Observation>>,
+    // The state of the WebSocket client.
+    ws_client: RefCell<WsClient>,
+    // The state of the client that expects `100 Continue`.
+    continue_client: RefCell<ContinueClient>,
+    // The state of the client that shuts down its side of the connection
+    // after its request.
+    half_closing_client: RefCell<HalfClosingClient>,
+    // The state of the client whose writes fail on a full database.
+    full_client: RefCell<FullClient>,
+    // The state of the client that bursts over its rate limit.
+    throttled_client: RefCell<ThrottledClient>,
+    // Number of responses whose body the network has corrupted on their way
+    // to a client.
+    corrupted_responses: Cell<usize>,
+    // The responses that have arrived in part, by the file descriptor of
+    // the client socket, with the callback that handles the whole response.
+    partial_resps: RefCell<HashMap<i32, (Vec<u8>, RecvCallback)>>,
+    // Number of responses that arrived in more than one receive.
+    reassembled_responses: Cell<usize>,
+}
+
+/// The state of the client that speaks Hrana over a WebSocket.
+#[derive(Default)]
+pub struct WsClient {
+    // Bytes received that have not been handled yet.
+    recv_buf: Vec<u8>,
+    // Whether the server has accepted the upgrade.
+    upgraded: bool,
+    // Whether the server has answered the hello and the ping.
+    hello_ok: bool,
+    pong: bool,
+    // The ids of the requests that the server has answered.
+    answered: Vec<i32>,
+    // The virtual time in milliseconds at which the client connects next,
+    // unless it is connected.
+    wake_at: Option<u64>,
+    // Number of times the server has answered all of the requests.
+    exchanges: usize,
+}
+
+/// The state of the client that sends the body of its request only once the
+/// server has answered `Expect: 100-continue`.
+#[derive(Default)]
+pub struct ContinueClient {
+    // Bytes received that have not been handled yet.
+    recv_buf: Vec<u8>,
+    // Whether the server has sent the interim response.
+    continued: bool,
+    // The virtual time in milliseconds at which the client connects next,
+    // unless it is connected.
+    wake_at: Option<u64>,
+    // Number of final responses that followed the interim response.
+    exchanges: usize,
+}
+
+/// The state of the client that shuts down its side of the connection as
+/// soon as it has sent its request.
+#[derive(Default)]
+pub struct HalfClosingClient {
+    // The virtual time in milliseconds at which the client connects next,
+    // unless it is connected.
+    wake_at: Option<u64>,
+    // Number of responses that the client received on a half-closed
+    // connection, which the server closed after them.
+    exchanges: usize,
+}
+
+/// The state of the client that fills its database, and goes on with its
+/// stream after the write that does not fit has failed.
+#[derive(Default)]
+pub struct FullClient {
+    // The baton of the stream on which the write failed, if it has.
+    baton: Option<String>,
+    // The virtual time in milliseconds at which the client connects next,
+    // unless it is connected.
+    wake_at: Option<u64>,
+    // Number of streams that the client read from after a failed write.
+    survivals: usize,
+}
+
+/// The state of the client that sends requests to its rate-limited
+/// database back to back, and waits for as long as the server tells it to
+/// once it has gone over the limit.
+#[derive(Default)]
+pub struct ThrottledClient {
+    // Whether the database of the client has been created and limited.
+    limited: bool,
+    // Whether the client has waited for the `Retry-After` of its last
+    // rejected request, and has not lost a request since, which would have
+    // taken the token that the bucket refilled with.
+    waited: bool,
+    // The virtual time in milliseconds at which the client connects next,
+    // unless it is connected.
+    wake_at: Option<u64>,
+    // Number of requests that the server rejected over the rate limit.
+    throttled: usize,
+    // Number of requests that went through once the client had waited.
+    recovered: usize,
+}
+
+/// A simulated client, which sends requests on a connection of its own.
+#[derive(Default)]
+pub struct Client {
+    // The request the client is waiting a response for.
+    pending_req: RefCell<Option<ClientReq>>,
+    // The chunked cursor response body received so far.
+    cursor_resp: RefCell<Vec<u8>>,
+    // The second fragment of a request that is sent in two.
+    pending_fragment: RefCell<Option<Bytes>>,
+    // Number of times the client has retried its current request flow after
+    // the server reset the connection.
+    retries: Cell<usize>,
+    // Number of HTTP 200 responses the client has received.
+    ok_responses: Cell<usize>,
+    // Number of responses the client has received.
+    responses: Cell<usize>,
 }
 
+// Number of clients, unless set with `CLIENTS`.
+const DEFAULT_CLIENTS: usize = 3;
+
+// How long the server lets a connection idle, on top of the network latency.
+// Shorter than the default of the server, so that the stalled client is
+// disconnected often.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How long the server lets a client leave a transaction open, on top of the
+// network latency.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How long a write waits for the write lock of the database. The lock holder
+// is a session of another client, which the server keeps serving while the
+// write waits, so the write fails with `SQLITE_BUSY` only if the other client
+// holds the lock for longer.
+const BUSY_TIMEOUT: Duration = Duration::from_millis(20);
+
+// How long the abandoning client waits before it abandons its next
+// transaction, so that the other clients get to write most of the time.
+const ABANDON_INTERVAL: Duration = Duration::from_secs(5);
+
+// How long the WebSocket client waits between its connections.
+const WS_INTERVAL: Duration = Duration::from_secs(2);
+
+// How long the client that expects `100 Continue` waits between its
+// connections.
+const CONTINUE_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long the client that half-closes its connections waits between them.
+const HALF_CLOSE_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long the client whose writes fail on a full database waits between its
+// streams.
+const FULL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Number of retries after which a request flow is considered to have failed.
+const MAX_RETRIES: usize = 100;
+
 type Context = hiisi::server::Context<UserData>;
 
 type IO = hiisi::server::IO<UserData>;
 
+type RecvCallback = fn(&mut IO, Rc<Socket>, &[u8], usize);
+
 pub fn main() {
     init_logger();
 
@@ -27,130 +228,2530 @@ pub fn main() {
         Err(_) => rand::thread_rng().next_u64(),
     };
 
+    if std::env::args().any(|arg| arg == "--check-determinism") {
+        check_determinism(seed, ticks_from_env());
+        return;
+    }
+    if let Some(path) = arg_value("--record") {
+        record(seed, ticks_from_env(), Path::new(&path));
+        return;
+    }
+    if let Some(path) = arg_value("--replay") {
+        replay(Path::new(&path));
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--minimize") {
+        minimize(seed, ticks_from_env());
+        return;
+    }
+
     log::info!("Starting simulation with seed {}", seed);
 
-    let rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut sim = start_simulation(seed, Path::new("data"));
+
+    // Main simulation loop.
+    loop {
+        sim.step();
+    }
+}
+
+// Number of ticks that `--check-determinism` and `--record` run the
+// simulation for, unless set with `TICKS`.
+const DEFAULT_TICKS: u64 = 10_000;
+
+fn ticks_from_env() -> u64 {
+    match std::env::var("TICKS") {
+        Ok(ticks) => ticks.parse::<u64>().unwrap(),
+        Err(_) => DEFAULT_TICKS,
+    }
+}
+
+/// The value of the command line option `name`.
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != name);
+    args.next()?;
+    Some(
+        args.next()
+            .unwrap_or_else(|| panic!("{} requires a value", name)),
+    )
+}
+
+/// Run the simulation for `ticks` and write its trace to `path`.
+///
+/// The trace is written even if the simulation fails, so that the failure
+/// can be analyzed.
+fn record(seed: u64, ticks: u64, path: &Path) {
+    log::info!("Recording simulation with seed {} to {:?}", seed, path);
+    let data_dir = temp_data_dir(seed, "record");
+    let mut sim = start_simulation(seed, &data_dir);
+    sim.io.start_trace(seed);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        for _ in 0..ticks {
+            sim.step();
+        }
+    }));
+    let trace = sim.io.take_trace().unwrap();
+    let file = std::fs::File::create(path).unwrap();
+    trace.write(std::io::BufWriter::new(file)).unwrap();
+    log::info!("Recorded {} events", trace.events.len());
+    std::fs::remove_dir_all(&data_dir).unwrap();
+    if let Err(err) = result {
+        std::panic::resume_unwind(err);
+    }
+}
+
+/// Replay the server side of the trace at `path`.
+///
+/// The server has to be set up like it was when the trace was recorded, so
+/// the fault knobs in the environment must be the same.
+fn replay(path: &Path) {
+    let file = std::fs::File::open(path).unwrap();
+    let trace = hiisi::io::Trace::read(std::io::BufReader::new(file)).unwrap();
+    log::info!(
+        "Replaying simulation with seed {} from {:?}",
+        trace.seed,
+        path
+    );
+    let data_dir = temp_data_dir(trace.seed, "replay");
+    let mut io = start_replay(trace, &data_dir);
+    while !io.replay_finished() {
+        io.run_once();
+    }
+    log::info!("Replayed simulation in {} ticks", io.now_ms());
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+/// Shrink the fault configuration of a simulation that fails with `seed`
+/// within `ticks`, and print a regression test for the smallest
+/// configuration that still fails.
+fn minimize(seed: u64, ticks: u64) {
+    log::info!(
+        "Minimizing simulation with seed {} for {} ticks",
+        seed,
+        ticks
+    );
+    match minimize::minimize_failure(seed, ticks, FaultConfig::from_env(), Vec::new) {
+        Some(failure) => {
+            log::info!("Smallest failing faults: {:?}", failure.fault_config);
+            println!("{}", failure.regression_test());
+        }
+        None => log::info!("Simulation with seed {} does not fail", seed),
+    }
+}
+
+/// An empty data directory for a run of the simulation.
+fn temp_data_dir(seed: u64, run: &str) -> std::path::PathBuf {
+    let data_dir =
+        std::env::temp_dir().join(format!("hiisi-sim-{}-{}-{}", std::process::id(), seed, run));
+    if data_dir.exists() {
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+    data_dir
+}
+
+/// Run the simulation twice with the same seed, and panic if the two runs
+/// differ in the bytes sent and received, their timing, or the faults
+/// injected.
+fn check_determinism(seed: u64, ticks: u64) {
+    log::info!(
+        "Checking determinism of simulation with seed {} for {} ticks",
+        seed,
+        ticks
+    );
+    let first = run_simulation(seed, ticks, 0);
+    let second = run_simulation(seed, ticks, 1);
+    if let Some(tick) = first.iter().zip(&second).position(|(a, b)| a != b) {
+        panic!(
+            "Simulation with seed {} is not deterministic: runs diverged at tick {}",
+            seed,
+            tick + 1
+        );
+    }
+    log::info!("Simulation with seed {} is deterministic", seed);
+}
+
+/// Run the simulation for `ticks`, returning the digest of the completions
+/// after every tick.
+///
+/// Every run starts from an empty data directory of its own, so that the
+/// previous run does not leak into it.
+fn run_simulation(seed: u64, ticks: u64, run: usize) -> Vec<u64> {
+    let data_dir = temp_data_dir(seed, &run.to_string());
+    let mut sim = start_simulation(seed, &data_dir);
+    let digests = (0..ticks)
+        .map(|_| {
+            sim.step();
+            sim.io.completion_digest()
+        })
+        .collect();
+    std::fs::remove_dir_all(&data_dir).unwrap();
+    digests
+}
+
+/// A simulation of the server and its clients, which checks invariants on
+/// every tick.
+pub struct Simulation {
+    seed: u64,
+    // Number of ticks the simulation has run for.
+    tick: u64,
+    io: IO,
+    invariants: Vec<Box<dyn Invariant>>,
+}
+
+impl Simulation {
+    fn new(seed: u64, io: IO) -> Self {
+        Self {
+            seed,
+            tick: 0,
+            io,
+            invariants: invariant::builtin_invariants(),
+        }
+    }
+
+    /// Check `invariant` on every tick, in addition to the built-in
+    /// invariants.
+    pub fn register_invariant(&mut self, invariant: Box<dyn Invariant>) {
+        self.invariants.push(invariant);
+    }
+
+    /// Run a tick of the simulation: let one of the clients that are ready
+    /// send its request, run the IO once, and check the invariants against
+    /// the responses that the clients observed.
+    ///
+    /// The client is picked with the seeded RNG, so the same seed
+    /// interleaves the requests of the clients in the same way.
+    pub fn step(&mut self) {
+        let io = &mut self.io;
+        let next = {
+            let user_data = &io.context().user_data;
+            let mut ready_clients = user_data.ready_clients.borrow_mut();
+            if ready_clients.is_empty() {
+                None
+            } else {
+                let idx = user_data.rng.borrow_mut().gen_range(0..ready_clients.len());
+                Some(ready_clients.swap_remove(idx))
+            }
+        };
+        match next {
+            Some((sock, Some(client_req))) => send_client_req(io, sock, client_req),
+            Some((sock, None)) => perform_client_req(io, sock),
+            None => {}
+        }
+        let now_ms = io.now_ms();
+        let wake_abandoning_client = {
+            let mut abandoned_txn = io.context().user_data.abandoned_txn.borrow_mut();
+            match abandoned_txn.1 {
+                Some(wake_at) if wake_at <= now_ms => abandoned_txn.1.take().is_some(),
+                _ => false,
+            }
+        };
+        if wake_abandoning_client {
+            spawn_abandoning_client(io);
+        }
+        let wake_ws_client = {
+            let mut ws_client = io.context().user_data.ws_client.borrow_mut();
+            match ws_client.wake_at {
+                Some(wake_at) if wake_at <= now_ms => ws_client.wake_at.take().is_some(),
+                _ => false,
+            }
+        };
+        if wake_ws_client {
+            spawn_ws_client(io);
+        }
+        let wake_continue_client = {
+            let mut continue_client = io.context().user_data.continue_client.borrow_mut();
+            match continue_client.wake_at {
+                Some(wake_at) if wake_at <= now_ms => continue_client.wake_at.take().is_some(),
+                _ => false,
+            }
+        };
+        if wake_continue_client {
+            spawn_continue_client(io);
+        }
+        let wake_half_closing_client = {
+            let mut half_closing_client = io.context().user_data.half_closing_client.borrow_mut();
+            match half_closing_client.wake_at {
+                Some(wake_at) if wake_at <= now_ms => half_closing_client.wake_at.take().is_some(),
+                _ => false,
+            }
+        };
+        if wake_half_closing_client {
+            spawn_half_closing_client(io);
+        }
+        let wake_full_client = {
+            let mut full_client = io.context().user_data.full_client.borrow_mut();
+            match full_client.wake_at {
+                Some(wake_at) if wake_at <= now_ms => full_client.wake_at.take().is_some(),
+                _ => false,
+            }
+        };
+        if wake_full_client {
+            spawn_full_client(io);
+        }
+        let wake_throttled_client = {
+            let mut throttled_client = io.context().user_data.throttled_client.borrow_mut();
+            match throttled_client.wake_at {
+                Some(wake_at) if wake_at <= now_ms => throttled_client.wake_at.take().is_some(),
+                _ => false,
+            }
+        };
+        if wake_throttled_client {
+            spawn_throttled_client(io);
+        }
+        io.run_once();
+        self.tick += 1;
+
+        let observations = io.context().user_data.observations.take();
+        for invariant in &mut self.invariants {
+            if let Err(violation) = invariant.check(&observations) {
+                panic!(
+                    "Invariant \"{}\" violated at tick {} of simulation with seed {}: {}",
+                    invariant.name(),
+                    self.tick,
+                    self.seed,
+                    violation
+                );
+            }
+        }
+    }
+}
+
+/// Set up the server and the clients of a simulation, which start running
+/// when the simulation is stepped.
+fn start_simulation(seed: u64, data_dir: &Path) -> Simulation {
+    start_simulation_with_faults(seed, data_dir, FaultConfig::from_env())
+}
+
+/// Set up a simulation like `start_simulation()`, injecting the faults of
+/// `fault_config` instead of the ones configured in the environment.
+fn start_simulation_with_faults(
+    seed: u64,
+    data_dir: &Path,
+    fault_config: FaultConfig,
+) -> Simulation {
+    let (ctx, faults, clock) = new_context(seed, data_dir, fault_config);
+    let mut io = hiisi::server::IO::with_clock(ctx, faults, clock);
+    if let Some(n) = max_events_per_tick_from_env() {
+        log::info!("Running at most {} completions per tick", n);
+        io = io.with_max_events_per_tick(n);
+    }
+    serve(&mut io);
+
+    // Create the test database through the admin interface. The clients
+    // connect to the server once the database exists.
+    let admin_addr: std::net::SocketAddr = ADMIN_ADDR.parse().unwrap();
+    let admin_client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    io.connect(
+        admin_client_sock,
+        admin_addr.into(),
+        on_admin_client_connect,
+    );
+    Simulation::new(seed, io)
+}
+
+/// Set up the server of a simulation to replay `trace` on.
+fn start_replay(trace: hiisi::io::Trace, data_dir: &Path) -> IO {
+    let (ctx, _, clock) = new_context(trace.seed, data_dir, FaultConfig::from_env());
+    let mut io = hiisi::server::IO::replay(ctx, trace, clock);
+    serve(&mut io);
+    io
+}
+
+/// Bind the server sockets to the server addresses.
+fn serve(io: &mut IO) {
+    let server_addr: std::net::SocketAddr = SERVER_ADDR.parse().unwrap();
+    let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    let admin_addr: std::net::SocketAddr = ADMIN_ADDR.parse().unwrap();
+    let admin_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    hiisi::server::serve_all(
+        io,
+        vec![
+            (server_sock, server_addr.into(), Role::Data),
+            (admin_sock, admin_addr.into(), Role::Admin),
+        ],
+    );
+}
+
+/// Derive the server and client configuration of a simulation from the
+/// seed.
+fn new_context(
+    seed: u64,
+    data_dir: &Path,
+    fault_config: FaultConfig,
+) -> (Context, hiisi::io::Faults, Rc<hiisi::clock::SimClock>) {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    // Derive the baton key from the seed so that batons are the same when a
+    // simulation is replayed.
+    let mut baton_key = [0; 32];
+    rng.fill_bytes(&mut baton_key);
+    let pipeline_path = if rng.gen_bool(0.5) {
+        "/v2/pipeline"
+    } else {
+        "/v3/pipeline"
+    };
+    log::info!("Client sends pipeline requests to {}", pipeline_path);
+    log::info!("Injecting faults {:?}", fault_config);
+    let faults = fault_config.io_faults(rng.next_u64());
+    let storage = Rc::new(hiisi::storage::FaultyStorage::new(
+        hiisi::storage::FileStorage,
+        rng.next_u64(),
+        fault_config.storage_fault_prob,
+    ));
+    // A request that is delayed by the network must not time out, and
+    // neither must a transaction whose next request is.
+    let max_latency_ms = faults.latency_ms.map_or(0, |latency| latency.max_ms);
+    let transaction_timeout = TRANSACTION_TIMEOUT + Duration::from_millis(2 * max_latency_ms);
+    let nr_clients = clients_from_env();
+    log::info!("Simulating {} clients", nr_clients);
     let user_data = UserData {
         rng: RefCell::new(rng),
+        faults: fault_config,
+        pipeline_path,
+        clients: (0..nr_clients).map(|_| Client::default()).collect(),
+        client_socks: RefCell::new(HashMap::new()),
+        ready_clients: RefCell::new(Vec::new()),
+        admin_retries: Cell::new(0),
+        stalled_disconnects: Cell::new(0),
+        abandoned_txn: RefCell::new((None, None)),
+        transaction_timeout,
+        abandoned_rollbacks: Cell::new(0),
+        observations: RefCell::new(Vec::new()),
+        ws_client: RefCell::new(WsClient::default()),
+        continue_client: RefCell::new(ContinueClient::default()),
+        half_closing_client: RefCell::new(HalfClosingClient::default()),
+        full_client: RefCell::new(FullClient::default()),
+        throttled_client: RefCell::new(ThrottledClient::default()),
+        corrupted_responses: Cell::new(0),
+        partial_resps: RefCell::new(HashMap::new()),
+        reassembled_responses: Cell::new(0),
     };
-    let manager = Rc::new(hiisi::manager::ResourceManager::new(Path::new("data")));
-    // TODO: Use the admin interface to create the database as part of simulation.
-    manager.create_database(TEST_DATABASE_NAME).unwrap();
-    let ctx = Context::new(manager, user_data);
-    let mut io = hiisi::server::IO::new(ctx);
+    // The server reads the time from the virtual clock that the IO advances.
+    let clock = Rc::new(hiisi::clock::SimClock::new());
+    let manager = Rc::new(
+        hiisi::manager::ResourceManager::new(data_dir, baton_key)
+            .with_storage(storage)
+            .with_clock(clock.clone())
+            .with_transaction_timeout(transaction_timeout)
+            .with_busy_timeout(BUSY_TIMEOUT),
+    );
+    let idle_timeout = IDLE_TIMEOUT + Duration::from_millis(max_latency_ms);
+    let ctx = Context::new(manager, user_data).with_idle_timeout(idle_timeout);
+    (ctx, faults, clock)
+}
 
-    let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
-    let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
-    let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+const SERVER_ADDR: &str = "127.0.0.1:8080";
+const ADMIN_ADDR: &str = "127.0.0.1:8081";
 
-    // Bind the server socket to the server address.
-    hiisi::server::serve(&mut io, server_sock, server_addr.clone().into());
+fn on_admin_client_connect(io: &mut IO, sock: Rc<socket2::Socket>, _addr: socket2::SockAddr) {
+    send_admin_create(io, sock);
+}
 
-    // Connect the client socket to the server address.
-    io.connect(client_sock, server_addr.clone().into(), on_client_connect);
+fn send_admin_create(io: &mut IO, sock: Rc<socket2::Socket>) {
+    let req = format!(
+        "POST /v1/namespaces/{}/create HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        TEST_DATABASE_NAME
+    );
+    let n = req.len();
+    io.send(sock, req.into(), n, on_admin_client_send);
+}
 
-    // Main simulation loop.
-    loop {
-        io.run_once();
+fn on_admin_client_send(io: &mut IO, sock: Rc<socket2::Socket>, _n: usize) {
+    recv_resp(io, sock, on_admin_client_recv);
+}
+
+fn on_admin_client_recv(io: &mut IO, sock: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    if n == 0 {
+        // The server reset the connection, so create the database again. It
+        // may have been created before the reset, which is fine.
+        log::trace!("Admin connection was reset, retrying");
+        io.close(sock, on_admin_client_reset_close);
+        return;
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut resp = httparse::Response::new(&mut headers);
+    resp.parse(buf).unwrap().unwrap();
+    // The data directory outlives the simulation, so the database may exist
+    // from a previous run.
+    match resp.code.unwrap() {
+        201 => log::info!("Created database {}", TEST_DATABASE_NAME),
+        409 => log::info!("Database {} already exists", TEST_DATABASE_NAME),
+        code if code >= 500 => {
+            // The storage failed, so try again.
+            log::info!("Failed to create database: HTTP {}, retrying", code);
+            count_retry(&io.context().user_data.admin_retries);
+            send_admin_create(io, sock);
+            return;
+        }
+        code => panic!("Failed to create database: HTTP {}", code),
     }
+    io.close(sock, on_admin_client_close);
 }
 
-fn on_client_connect(io: &mut IO, sock: Rc<socket2::Socket>, client_addr: socket2::SockAddr) {
-    let sockfd = sock.as_raw_fd();
-    log::trace!("Client is connected to {}", sockfd);
-    perform_client_req(io, sock);
+fn on_admin_client_reset_close(io: &mut IO, _sock: Rc<socket2::Socket>) {
+    let admin_addr: std::net::SocketAddr = ADMIN_ADDR.parse().unwrap();
+    let admin_client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    io.connect(
+        admin_client_sock,
+        admin_addr.into(),
+        on_admin_client_connect,
+    );
 }
 
-fn perform_client_req(io: &mut IO, sock: Rc<Socket>) {
-    let req = hiisi::proto::StreamRequest::Execute(hiisi::proto::ExecuteStreamReq {
-        stmt: hiisi::proto::Stmt {
-            sql: Some("SELECT 1".to_owned()),
+fn on_admin_client_close(io: &mut IO, _sock: Rc<socket2::Socket>) {
+    for client_id in 0..io.context().user_data.clients.len() {
+        spawn_client(io, client_id);
+    }
+    spawn_stalled_client(io);
+    let now_ms = io.now_ms();
+    io.context().user_data.abandoned_txn.borrow_mut().1 = Some(now_ms);
+    io.context().user_data.ws_client.borrow_mut().wake_at = Some(now_ms);
+    io.context().user_data.continue_client.borrow_mut().wake_at = Some(now_ms);
+    io.context()
+        .user_data
+        .half_closing_client
+        .borrow_mut()
+        .wake_at = Some(now_ms);
+    io.context().user_data.full_client.borrow_mut().wake_at = Some(now_ms);
+    io.context().user_data.throttled_client.borrow_mut().wake_at = Some(now_ms);
+}
+
+/// Connect a client that begins a transaction and disappears, which the
+/// server has to roll back once the transaction has timed out. The client
+/// comes back with the baton of the transaction after the timeout, and
+/// expects the baton to have expired.
+fn spawn_abandoning_client(io: &mut IO) {
+    let server_addr: std::net::SocketAddr = SERVER_ADDR.parse().unwrap();
+    let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    io.connect(
+        client_sock,
+        server_addr.into(),
+        on_abandoning_client_connect,
+    );
+}
+
+fn on_abandoning_client_connect(io: &mut IO, sock: Rc<socket2::Socket>, _addr: socket2::SockAddr) {
+    let baton = io.context().user_data.abandoned_txn.borrow().0.clone();
+    let req = match baton {
+        None => hiisi::proto::StreamRequest::Sequence(hiisi::proto::SequenceStreamReq {
+            sql: Some(
+                "CREATE TABLE IF NOT EXISTS abandoned(x); BEGIN IMMEDIATE; INSERT INTO abandoned VALUES (1);"
+                    .to_owned(),
+            ),
             sql_id: None,
-            args: vec![],
-            named_args: vec![],
-            want_rows: Some(true),
             replication_index: None,
-        },
-    });
+        }),
+        Some(_) => hiisi::proto::StreamRequest::Execute(hiisi::proto::ExecuteStreamReq {
+            stmt: hiisi::proto::Stmt::new("SELECT count(*) FROM abandoned", true),
+        }),
+    };
     let req = hiisi::proto::PipelineReqBody {
-        baton: None,
+        baton,
         requests: vec![req],
     };
-    let buf = hiisi::proto::format_msg(&req).unwrap();
-    let mut http_req = BytesMut::new();
-    let http_header = format!(
-        "POST /v2/pipeline HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n",
-        TEST_DATABASE_HOST,
-        buf.len()
-    );
-    http_req.extend_from_slice(http_header.as_bytes());
-    http_req.extend_from_slice(&buf);
+    let path = io.context().user_data.pipeline_path;
+    let http_req = format_http_req(path, hiisi::proto::format_msg(&req).unwrap());
     let n = http_req.len();
-    send_client_msg(io, sock, http_req.into(), n);
+    io.send(sock, http_req, n, on_abandoning_client_send);
 }
 
-fn send_client_msg(io: &mut IO, sock: Rc<socket2::Socket>, buf: Bytes, n: usize) {
-    match gen_perform_client_req_fault(io.context()) {
-        PerformClientReqFault::Normal => {
-            io.send(sock, buf, n, on_client_send_normal);
-        }
-        PerformClientReqFault::Fuzz => {
-            let bad_request = Bytes::from_static(b"FUZZ FUZZ FUZZ"); // Fuzzed request.
-            io.send(sock, bad_request, n, on_client_send_fuzz);
+fn on_abandoning_client_send(io: &mut IO, sock: Rc<socket2::Socket>, _n: usize) {
+    recv_resp(io, sock, on_abandoning_client_recv);
+}
+
+fn on_abandoning_client_recv(io: &mut IO, sock: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    if is_shutting_down(&buf[..n]) {
+        io.close(sock, on_client_shutdown_close);
+        return;
+    }
+    if n > 0 && is_corrupted(&buf[..n]) {
+        // Try again on a new connection.
+        count_corrupted(&io.context().user_data);
+        let now_ms = io.now_ms();
+        io.context().user_data.abandoned_txn.borrow_mut().1 = Some(now_ms);
+        io.close(sock, on_abandoning_client_close);
+        return;
+    }
+    let now_ms = io.now_ms();
+    let user_data = &io.context().user_data;
+    let mut abandoned_txn = user_data.abandoned_txn.borrow_mut();
+    // The client disconnects after every response, and tries again on the
+    // next tick unless told otherwise.
+    abandoned_txn.1 = Some(now_ms);
+    if n == 0 {
+        log::trace!("Connection of the abandoning client was reset, retrying");
+        drop(abandoned_txn);
+        io.close(sock, on_abandoning_client_close);
+        return;
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut resp = httparse::Response::new(&mut headers);
+    let body_off = resp.parse(buf).unwrap().unwrap();
+    let body = &buf[body_off..];
+    let code = resp.code.unwrap();
+    if abandoned_txn.0.is_some() {
+        // The server has rolled back the transaction, so its baton has
+        // expired.
+        let body = std::str::from_utf8(body).unwrap();
+        assert_eq!(code, 400, "Abandoned transaction is still open: {}", body);
+        assert!(body.contains("expired"), "Unexpected error: {}", body);
+        log::trace!("Server rolled back the abandoned transaction");
+        user_data
+            .abandoned_rollbacks
+            .set(user_data.abandoned_rollbacks.get() + 1);
+        abandoned_txn.0 = None;
+        abandoned_txn.1 = Some(now_ms + ABANDON_INTERVAL.as_millis() as u64);
+    } else if code == 200 {
+        let resp = hiisi::proto::parse_resp(body).unwrap();
+        match &resp.results[0] {
+            hiisi::proto::StreamResult::Ok { .. } => {
+                // Come back only once the transaction has timed out.
+                let timeout_ms = user_data.transaction_timeout.as_millis() as u64;
+                abandoned_txn.0 = resp.baton;
+                abandoned_txn.1 = Some(now_ms + timeout_ms + 1);
+            }
+            // Another client holds the write lock, so try again.
+            hiisi::proto::StreamResult::Error { error } => {
+                log::trace!("Failed to abandon a transaction: {}", error.message);
+            }
+            hiisi::proto::StreamResult::None => panic!("Unexpected stream result"),
         }
+    } else {
+        // The storage failed, so try again.
+        assert!(code >= 500, "Unexpected response: HTTP {}", code);
     }
+    drop(abandoned_txn);
+    io.close(sock, on_abandoning_client_close);
 }
 
-enum PerformClientReqFault {
-    // Client sends a normal message to the server.
-    Normal,
-    // Client sends a fuzzed message to the server.
-    Fuzz,
+fn on_abandoning_client_close(_io: &mut IO, _sock: Rc<socket2::Socket>) {}
+
+/// Connect a client that stalls in the middle of its request, which the
+/// server has to disconnect once the connection has been idle for too long.
+/// The client connects again every time it is disconnected.
+fn spawn_stalled_client(io: &mut IO) {
+    let server_addr: std::net::SocketAddr = SERVER_ADDR.parse().unwrap();
+    let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    io.connect(client_sock, server_addr.into(), on_stalled_client_connect);
 }
 
-fn gen_perform_client_req_fault(ctx: &hiisi::server::Context<UserData>) -> PerformClientReqFault {
-    let user_data = &ctx.user_data;
-    let mut rng = user_data.rng.borrow_mut();
-    if rng.gen_bool(0.9) {
-        PerformClientReqFault::Normal
-    } else {
-        PerformClientReqFault::Fuzz
+fn on_stalled_client_connect(io: &mut IO, sock: Rc<socket2::Socket>, _addr: socket2::SockAddr) {
+    // Send the header of a request, but never its body.
+    let req = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: 2\r\n\r\n",
+        io.context().user_data.pipeline_path,
+        TEST_DATABASE_HOST
+    );
+    let n = req.len();
+    io.send(sock, req.into(), n, on_stalled_client_send);
+}
+
+fn on_stalled_client_send(io: &mut IO, sock: Rc<socket2::Socket>, _n: usize) {
+    recv_resp(io, sock, on_stalled_client_recv);
+}
+
+fn on_stalled_client_recv(io: &mut IO, sock: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    if is_shutting_down(&buf[..n]) {
+        io.close(sock, on_client_shutdown_close);
+        return;
     }
+    assert_eq!(
+        n,
+        0,
+        "Server responded to an incomplete request: {:?}",
+        String::from_utf8_lossy(&buf[..n])
+    );
+    log::trace!("Server closed the connection of the stalled client");
+    let user_data = &io.context().user_data;
+    user_data
+        .stalled_disconnects
+        .set(user_data.stalled_disconnects.get() + 1);
+    io.close(sock, on_stalled_client_close);
 }
 
-fn on_client_send_normal(io: &mut IO, server_sock: Rc<socket2::Socket>, n: usize) {
-    io.recv(server_sock, on_client_recv_normal);
+fn on_stalled_client_close(io: &mut IO, _sock: Rc<socket2::Socket>) {
+    spawn_stalled_client(io);
 }
 
-fn on_client_recv_normal(io: &mut IO, socket: Rc<socket2::Socket>, buf: &[u8], n: usize) {
-    let mut headers = [httparse::EMPTY_HEADER; 64];
-    let mut resp = httparse::Response::new(&mut headers);
-    let body_off = resp.parse(buf).unwrap().unwrap();
-    if resp.code.unwrap() != 200 {
-        let body = std::str::from_utf8(&buf[body_off..]).unwrap();
-        println!("Error: {:?} -> {}", resp, body);
-        assert_eq!(resp.code.unwrap(), 200);
+// The body of the request of the client that expects `100 Continue`.
+const CONTINUE_BODY: &str =
+    r#"{"baton":null,"requests":[{"type":"execute","stmt":{"sql":"SELECT 1"}},{"type":"close"}]}"#;
+
+/// Connect a client that sends the head of its request with
+/// `Expect: 100-continue`, and its body only once the server has sent the
+/// interim response, which has to come before the final one. The client
+/// connects again after `CONTINUE_INTERVAL`.
+fn spawn_continue_client(io: &mut IO) {
+    let server_addr: std::net::SocketAddr = SERVER_ADDR.parse().unwrap();
+    let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    io.connect(client_sock, server_addr.into(), on_continue_client_connect);
+}
+
+fn on_continue_client_connect(io: &mut IO, sock: Rc<socket2::Socket>, _addr: socket2::SockAddr) {
+    {
+        let mut continue_client = io.context().user_data.continue_client.borrow_mut();
+        *continue_client = ContinueClient {
+            exchanges: continue_client.exchanges,
+            ..ContinueClient::default()
+        };
     }
-    perform_client_req(io, socket);
+    let req = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nExpect: 100-continue\r\nContent-Length: {}\r\n\r\n",
+        io.context().user_data.pipeline_path,
+        TEST_DATABASE_HOST,
+        CONTINUE_BODY.len()
+    );
+    let n = req.len();
+    io.send(sock, req.into(), n, on_continue_client_send);
 }
 
-fn on_client_send_fuzz(io: &mut IO, server_sock: Rc<socket2::Socket>, n: usize) {
-    io.recv(server_sock, on_client_recv_fuzz);
+fn on_continue_client_send(io: &mut IO, sock: Rc<socket2::Socket>, _n: usize) {
+    io.recv(sock, on_continue_client_recv);
 }
 
-fn on_client_recv_fuzz(io: &mut IO, socket: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+fn on_continue_client_recv(io: &mut IO, sock: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    if is_shutting_down(&buf[..n]) {
+        io.close(sock, on_client_shutdown_close);
+        return;
+    }
+    let now_ms = io.now_ms();
+    if n == 0 {
+        log::trace!("Connection of the continue client was reset, retrying");
+        io.context().user_data.continue_client.borrow_mut().wake_at = Some(now_ms);
+        io.close(sock, on_continue_client_close);
+        return;
+    }
+    let (code, continued) = {
+        let mut continue_client = io.context().user_data.continue_client.borrow_mut();
+        continue_client.recv_buf.extend_from_slice(&buf[..n]);
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut resp = httparse::Response::new(&mut headers);
+        let head_len = match resp.parse(&continue_client.recv_buf).unwrap() {
+            httparse::Status::Complete(head_len) => head_len,
+            // Wait for the rest of the head, or for the end-of-file that
+            // follows a reset.
+            httparse::Status::Partial => {
+                drop(continue_client);
+                io.recv(sock, on_continue_client_recv);
+                return;
+            }
+        };
+        let code = resp.code.unwrap();
+        continue_client.recv_buf.drain(..head_len);
+        (code, continue_client.continued)
+    };
+    if !continued {
+        assert_eq!(
+            code, 100,
+            "Server responded with HTTP {} before 100 Continue",
+            code
+        );
+        io.context()
+            .user_data
+            .continue_client
+            .borrow_mut()
+            .continued = true;
+        let n = CONTINUE_BODY.len();
+        io.send(
+            sock,
+            Bytes::from_static(CONTINUE_BODY.as_bytes()),
+            n,
+            on_continue_client_send,
+        );
+        return;
+    }
+    // The storage may fail the request, but the server must not send
+    // another interim response.
+    assert!(
+        code == 200 || code >= 500,
+        "Unexpected response after 100 Continue: HTTP {}",
+        code
+    );
+    {
+        let mut continue_client = io.context().user_data.continue_client.borrow_mut();
+        continue_client.exchanges += 1;
+        continue_client.wake_at = Some(now_ms + CONTINUE_INTERVAL.as_millis() as u64);
+    }
+    io.close(sock, on_continue_client_close);
+}
+
+fn on_continue_client_close(_io: &mut IO, _sock: Rc<socket2::Socket>) {}
+
+// The body of the request of the client that half-closes its connection.
+const HALF_CLOSE_BODY: &str =
+    r#"{"baton":null,"requests":[{"type":"execute","stmt":{"sql":"SELECT 1"}},{"type":"close"}]}"#;
+
+/// Connect a client that sends a complete request and shuts down its side
+/// of the connection right away, as a client that has nothing more to send
+/// may. The server has to respond to the request before it closes the
+/// connection. The client connects again after `HALF_CLOSE_INTERVAL`.
+fn spawn_half_closing_client(io: &mut IO) {
+    let server_addr: std::net::SocketAddr = SERVER_ADDR.parse().unwrap();
+    let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    io.connect(
+        client_sock,
+        server_addr.into(),
+        on_half_closing_client_connect,
+    );
+}
+
+fn on_half_closing_client_connect(
+    io: &mut IO,
+    sock: Rc<socket2::Socket>,
+    _addr: socket2::SockAddr,
+) {
+    let req = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+        io.context().user_data.pipeline_path,
+        TEST_DATABASE_HOST,
+        HALF_CLOSE_BODY.len(),
+        HALF_CLOSE_BODY
+    );
+    let n = req.len();
+    io.send(sock, req.into(), n, on_half_closing_client_send);
+}
+
+fn on_half_closing_client_send(io: &mut IO, sock: Rc<socket2::Socket>, _n: usize) {
+    io.shutdown(sock.clone());
+    recv_resp(io, sock, on_half_closing_client_recv);
+}
+
+fn on_half_closing_client_recv(io: &mut IO, sock: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    if is_shutting_down(&buf[..n]) {
+        io.close(sock, on_client_shutdown_close);
+        return;
+    }
+    let now_ms = io.now_ms();
+    if n == 0 {
+        log::trace!("Connection of the half-closing client was reset, retrying");
+        io.context()
+            .user_data
+            .half_closing_client
+            .borrow_mut()
+            .wake_at = Some(now_ms);
+        io.close(sock, on_half_closing_client_close);
+        return;
+    }
+    let (code, _) = hiisi::client::parse_response(&buf[..n])
+        .unwrap()
+        .expect("response is complete");
+    // The storage may fail the request, but the server must answer it
+    // although the client sends nothing more.
+    assert!(
+        code == 200 || code >= 500,
+        "Unexpected response on a half-closed connection: HTTP {}",
+        code
+    );
+    io.recv(sock, on_half_closing_client_eof);
+}
+
+fn on_half_closing_client_eof(io: &mut IO, sock: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    assert_eq!(
+        n,
+        0,
+        "Server sent more than one response on a half-closed connection: {:?}",
+        String::from_utf8_lossy(&buf[..n])
+    );
+    let now_ms = io.now_ms();
+    {
+        let mut half_closing_client = io.context().user_data.half_closing_client.borrow_mut();
+        half_closing_client.exchanges += 1;
+        half_closing_client.wake_at = Some(now_ms + HALF_CLOSE_INTERVAL.as_millis() as u64);
+    }
+    io.close(sock, on_half_closing_client_close);
+}
+
+fn on_half_closing_client_close(_io: &mut IO, _sock: Rc<socket2::Socket>) {}
+
+/// Connect a client that caps the size of the database on its connection
+/// with `PRAGMA max_page_count`, so that its next insert fails with
+/// `SQLITE_FULL` as it would on a full disk. The client then reads on the
+/// same stream, which has to survive the failed write, and closes it.
+fn spawn_full_client(io: &mut IO) {
+    let server_addr: std::net::SocketAddr = SERVER_ADDR.parse().unwrap();
+    let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    io.connect(client_sock, server_addr.into(), on_full_client_connect);
+}
+
+fn on_full_client_connect(io: &mut IO, sock: Rc<socket2::Socket>, _addr: socket2::SockAddr) {
+    let execute = |sql: &str| {
+        hiisi::proto::StreamRequest::Execute(hiisi::proto::ExecuteStreamReq {
+            stmt: hiisi::proto::Stmt::new(sql, true),
+        })
+    };
+    let baton = io.context().user_data.full_client.borrow().baton.clone();
+    let requests = match baton {
+        None => vec![
+            execute("CREATE TABLE IF NOT EXISTS full_disk(x BLOB)"),
+            execute("PRAGMA max_page_count = 1"),
+            execute("INSERT INTO full_disk VALUES (zeroblob(1000000))"),
+        ],
+        Some(_) => vec![
+            execute("SELECT count(*) FROM full_disk"),
+            hiisi::proto::StreamRequest::Close(hiisi::proto::CloseStreamReq {}),
+        ],
+    };
+    let req = hiisi::proto::PipelineReqBody { baton, requests };
+    let path = io.context().user_data.pipeline_path;
+    let http_req = format_http_req(path, hiisi::proto::format_msg(&req).unwrap());
+    let n = http_req.len();
+    io.send(sock, http_req, n, on_full_client_send);
+}
+
+fn on_full_client_send(io: &mut IO, sock: Rc<socket2::Socket>, _n: usize) {
+    recv_resp(io, sock, on_full_client_recv);
+}
+
+fn on_full_client_recv(io: &mut IO, sock: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    if is_shutting_down(&buf[..n]) {
+        io.close(sock, on_client_shutdown_close);
+        return;
+    }
+    let now_ms = io.now_ms();
+    let user_data = &io.context().user_data;
+    let mut full_client = user_data.full_client.borrow_mut();
+    // The client disconnects after every response, and tries again on the
+    // next tick unless told otherwise.
+    full_client.wake_at = Some(now_ms);
+    if n == 0 || is_corrupted(&buf[..n]) {
+        // The server may have closed the stream before the reset, so start
+        // over with a new one.
+        log::trace!("Response to the full client was lost, retrying");
+        full_client.baton = None;
+        drop(full_client);
+        io.close(sock, on_full_client_close);
+        return;
+    }
     let mut headers = [httparse::EMPTY_HEADER; 64];
     let mut resp = httparse::Response::new(&mut headers);
     let body_off = resp.parse(buf).unwrap().unwrap();
-    if resp.code.unwrap() != 400 {
-        let body = std::str::from_utf8(&buf[body_off..]).unwrap();
-        println!("Error: {:?} -> {}", resp, body);
-        assert_eq!(resp.code.unwrap(), 400);
+    let code = resp.code.unwrap();
+    if code != 200 {
+        // The storage failed, so try again.
+        assert!(code >= 500, "Unexpected response: HTTP {}", code);
+        drop(full_client);
+        io.close(sock, on_full_client_close);
+        return;
+    }
+    let resp = hiisi::proto::parse_resp(&buf[body_off..n]).unwrap();
+    let error_code = |result: &hiisi::proto::StreamResult| match result {
+        hiisi::proto::StreamResult::Error { error } => error.code.clone(),
+        _ => None,
+    };
+    if full_client.baton.is_some() {
+        assert!(
+            matches!(resp.results[0], hiisi::proto::StreamResult::Ok { .. }),
+            "Read after a failed write failed: {:?}",
+            resp.results[0]
+        );
+        log::trace!("Stream of the full client survived a failed write");
+        full_client.baton = None;
+        full_client.survivals += 1;
+        full_client.wake_at = Some(now_ms + FULL_INTERVAL.as_millis() as u64);
+    } else if let Some(code) = error_code(&resp.results[0]) {
+        // Another client holds the write lock, so try again.
+        log::trace!("Failed to create the table of the full client: {}", code);
+    } else {
+        match error_code(&resp.results[2]).as_deref() {
+            Some("SQLITE_FULL") => full_client.baton = resp.baton,
+            Some(code) if code.starts_with("SQLITE_BUSY") => {
+                log::trace!("Insert of the full client failed with {}", code);
+            }
+            _ => panic!(
+                "Insert into a full database did not fail: {:?}",
+                resp.results[2]
+            ),
+        }
     }
-    perform_client_req(io, socket);
+    drop(full_client);
+    io.close(sock, on_full_client_close);
 }
 
-fn init_logger() {
-    let env = env_logger::Env::default().default_filter_or("info");
-    env_logger::Builder::from_env(env).init();
+fn on_full_client_close(_io: &mut IO, _sock: Rc<socket2::Socket>) {}
+
+/// Connect a client that bursts over the rate limit of its database. The
+/// client first creates the database and sets its rate limit through the
+/// admin interface, and then sends requests on new connections without
+/// pause. A request over the limit gets HTTP 429 with a `Retry-After`, and
+/// the first request after the client has waited for that long has to go
+/// through.
+fn spawn_throttled_client(io: &mut IO) {
+    let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    if io.context().user_data.throttled_client.borrow().limited {
+        let server_addr: std::net::SocketAddr = SERVER_ADDR.parse().unwrap();
+        io.connect(client_sock, server_addr.into(), on_throttled_client_connect);
+    } else {
+        let admin_addr: std::net::SocketAddr = ADMIN_ADDR.parse().unwrap();
+        io.connect(client_sock, admin_addr.into(), on_throttled_admin_connect);
+    }
+}
+
+fn on_throttled_admin_connect(io: &mut IO, sock: Rc<socket2::Socket>, _addr: socket2::SockAddr) {
+    let req = format!(
+        "POST /v1/namespaces/{}/create HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        LIMITED_DATABASE_NAME
+    );
+    let n = req.len();
+    io.send(sock, req.into(), n, on_throttled_admin_send);
+}
+
+fn on_throttled_admin_send(io: &mut IO, sock: Rc<socket2::Socket>, _n: usize) {
+    recv_resp(io, sock, on_throttled_admin_recv);
+}
+
+fn on_throttled_admin_recv(io: &mut IO, sock: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    let code = if n == 0 {
+        None
+    } else {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut resp = httparse::Response::new(&mut headers);
+        resp.parse(&buf[..n]).unwrap().unwrap();
+        resp.code
+    };
+    match code {
+        // The database exists, possibly from a previous run, so limit it.
+        Some(201) | Some(409) => {
+            let req = format!(
+                "POST /v1/namespaces/{}/rate_limit HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                LIMITED_DATABASE_NAME,
+                LIMITED_RATE_LIMIT.len(),
+                LIMITED_RATE_LIMIT
+            );
+            let n = req.len();
+            io.send(sock, req.into(), n, on_throttled_admin_send);
+            return;
+        }
+        Some(200) => {
+            log::info!("Limited database {}", LIMITED_DATABASE_NAME);
+            io.context().user_data.throttled_client.borrow_mut().limited = true;
+        }
+        // The server reset the connection or the storage failed, so start
+        // over.
+        None => log::trace!("Admin connection of the throttled client was reset, retrying"),
+        Some(code) if code >= 500 => {
+            log::info!("Failed to create limited database: HTTP {}, retrying", code);
+        }
+        Some(code) => panic!("Failed to limit database: HTTP {}", code),
+    }
+    let now_ms = io.now_ms();
+    io.context().user_data.throttled_client.borrow_mut().wake_at = Some(now_ms);
+    io.close(sock, on_throttled_client_close);
+}
+
+fn on_throttled_client_connect(io: &mut IO, sock: Rc<socket2::Socket>, _addr: socket2::SockAddr) {
+    let req = hiisi::proto::PipelineReqBody {
+        baton: None,
+        requests: vec![
+            hiisi::proto::StreamRequest::Execute(hiisi::proto::ExecuteStreamReq {
+                stmt: hiisi::proto::Stmt::new("SELECT 1", true),
+            }),
+            hiisi::proto::StreamRequest::Close(hiisi::proto::CloseStreamReq {}),
+        ],
+    };
+    let path = io.context().user_data.pipeline_path;
+    let http_req = hiisi::client::format_request(
+        LIMITED_DATABASE_HOST,
+        path,
+        &hiisi::proto::format_msg(&req).unwrap(),
+    );
+    let n = http_req.len();
+    io.send(sock, http_req, n, on_throttled_client_send);
+}
+
+fn on_throttled_client_send(io: &mut IO, sock: Rc<socket2::Socket>, _n: usize) {
+    recv_resp(io, sock, on_throttled_client_recv);
+}
+
+fn on_throttled_client_recv(io: &mut IO, sock: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    if is_shutting_down(&buf[..n]) {
+        io.close(sock, on_client_shutdown_close);
+        return;
+    }
+    let now_ms = io.now_ms();
+    let mut throttled_client = io.context().user_data.throttled_client.borrow_mut();
+    // The client sends its next request right away unless told otherwise.
+    throttled_client.wake_at = Some(now_ms);
+    if n == 0 || is_corrupted(&buf[..n]) {
+        log::trace!("Response to the throttled client was lost, retrying");
+        throttled_client.waited = false;
+        drop(throttled_client);
+        io.close(sock, on_throttled_client_close);
+        return;
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut resp = httparse::Response::new(&mut headers);
+    resp.parse(&buf[..n]).unwrap().unwrap();
+    match resp.code.unwrap() {
+        200 => {
+            if throttled_client.waited {
+                log::trace!("Throttled client went on after waiting");
+                throttled_client.waited = false;
+                throttled_client.recovered += 1;
+            }
+        }
+        429 => {
+            assert!(
+                !throttled_client.waited,
+                "Request was rejected after waiting for its Retry-After"
+            );
+            let retry_after: u64 = resp
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("Retry-After"))
+                .map(|header| std::str::from_utf8(header.value).unwrap().parse().unwrap())
+                .expect("Rate-limited response has no Retry-After");
+            log::trace!("Throttled client waits for {} s", retry_after);
+            throttled_client.waited = true;
+            throttled_client.throttled += 1;
+            throttled_client.wake_at = Some(now_ms + retry_after * 1000);
+        }
+        // The storage failed after the request had taken its token, so
+        // try again.
+        code if code >= 500 => throttled_client.waited = false,
+        code => panic!("Unexpected response: HTTP {}", code),
+    }
+    drop(throttled_client);
+    io.close(sock, on_throttled_client_close);
+}
+
+fn on_throttled_client_close(_io: &mut IO, _sock: Rc<socket2::Socket>) {}
+
+// The key of the WebSocket handshake, which the server has to hash into the
+// accept key of its response.
+const WS_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+// The mask of the frames that the WebSocket client sends.
+const WS_MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+/// Connect a client that upgrades its connection to a WebSocket, says
+/// hello, and sends requests on two streams at once, which it matches with
+/// the responses by their id. The client pings the server along with the
+/// requests, closes the WebSocket once everything has been answered, and
+/// connects again after `WS_INTERVAL`.
+fn spawn_ws_client(io: &mut IO) {
+    let server_addr: std::net::SocketAddr = SERVER_ADDR.parse().unwrap();
+    let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    io.connect(client_sock, server_addr.into(), on_ws_client_connect);
+}
+
+fn on_ws_client_connect(io: &mut IO, sock: Rc<socket2::Socket>, _addr: socket2::SockAddr) {
+    {
+        let mut ws_client = io.context().user_data.ws_client.borrow_mut();
+        *ws_client = WsClient {
+            exchanges: ws_client.exchanges,
+            ..WsClient::default()
+        };
+    }
+    let pipeline_path = io.context().user_data.pipeline_path;
+    let (path, protocol) = if pipeline_path.starts_with("/v2/") {
+        ("/v2", "hrana2")
+    } else {
+        ("/v3", "hrana3")
+    };
+    let req = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: {}\r\n\r\n",
+        path, TEST_DATABASE_HOST, WS_KEY, protocol
+    );
+    let n = req.len();
+    io.send(sock, req.into(), n, on_ws_client_send);
+}
+
+fn on_ws_client_send(io: &mut IO, sock: Rc<socket2::Socket>, _n: usize) {
+    io.recv(sock, on_ws_client_recv);
+}
+
+fn on_ws_client_recv(io: &mut IO, sock: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    if n == 0 {
+        log::trace!("Connection of the WebSocket client was reset, retrying");
+        let now_ms = io.now_ms();
+        io.context().user_data.ws_client.borrow_mut().wake_at = Some(now_ms);
+        io.close(sock, on_ws_client_close);
+        return;
+    }
+    let upgraded = {
+        let mut ws_client = io.context().user_data.ws_client.borrow_mut();
+        ws_client.recv_buf.extend_from_slice(&buf[..n]);
+        ws_client.upgraded
+    };
+    if upgraded {
+        recv_ws_frames(io, sock);
+    } else {
+        recv_ws_upgrade(io, sock);
+    }
+}
+
+/// Check the response to the upgrade, and send the requests once the
+/// server has accepted it.
+fn recv_ws_upgrade(io: &mut IO, sock: Rc<socket2::Socket>) {
+    let recv_buf = std::mem::take(&mut io.context().user_data.ws_client.borrow_mut().recv_buf);
+    if is_shutting_down(&recv_buf) {
+        io.close(sock, on_client_shutdown_close);
+        return;
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut resp = httparse::Response::new(&mut headers);
+    let head_len = match resp.parse(&recv_buf).unwrap() {
+        httparse::Status::Complete(head_len) => head_len,
+        httparse::Status::Partial => {
+            // The rest of the head, or the end-of-file of a reset, is on
+            // its way.
+            io.context().user_data.ws_client.borrow_mut().recv_buf = recv_buf;
+            io.recv(sock, on_ws_client_recv);
+            return;
+        }
+    };
+    assert_eq!(resp.code, Some(101), "WebSocket upgrade failed");
+    let accept = resp
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Sec-WebSocket-Accept"))
+        .map(|header| header.value);
+    let expected_accept = hiisi::websocket::accept_key(WS_KEY);
+    assert_eq!(accept, Some(expected_accept.as_bytes()));
+    assert_eq!(head_len, recv_buf.len(), "Server sent frames before hello");
+    io.context().user_data.ws_client.borrow_mut().upgraded = true;
+
+    let mut frames = BytesMut::new();
+    let mut push_msg = |msg: &hiisi::proto::WsClientMsg| {
+        let msg = serde_json::to_vec(msg).unwrap();
+        hiisi::websocket::format_frame(
+            &mut frames,
+            hiisi::websocket::OPCODE_TEXT,
+            &msg,
+            Some(WS_MASK),
+        );
+    };
+    push_msg(&hiisi::proto::WsClientMsg::Hello { jwt: None });
+    for (request_id, stream_id) in [(1, 1), (2, 2)] {
+        push_msg(&hiisi::proto::WsClientMsg::Request {
+            request_id,
+            request: hiisi::proto::WsRequest::OpenStream { stream_id },
+        });
+    }
+    // Each stream selects the id of its request, so that a response that
+    // is matched up with the wrong request does not go unnoticed.
+    for (request_id, stream_id) in [(3, 1), (4, 2)] {
+        push_msg(&hiisi::proto::WsClientMsg::Request {
+            request_id,
+            request: hiisi::proto::WsRequest::Execute {
+                stream_id,
+                stmt: hiisi::proto::Stmt::new(format!("SELECT {}", request_id), true),
+            },
+        });
+    }
+    hiisi::websocket::format_frame(
+        &mut frames,
+        hiisi::websocket::OPCODE_PING,
+        b"ping",
+        Some(WS_MASK),
+    );
+    let n = frames.len();
+    io.send(sock, frames.freeze(), n, on_ws_client_send);
+}
+
+/// Handle the frames that the server has sent, closing the WebSocket once
+/// everything has been answered.
+fn recv_ws_frames(io: &mut IO, sock: Rc<socket2::Socket>) {
+    let mut ws_client = io.context().user_data.ws_client.borrow_mut();
+    loop {
+        let parsed = hiisi::websocket::parse_frame(&ws_client.recv_buf, usize::MAX).unwrap();
+        let Some((frame, len)) = parsed else {
+            break;
+        };
+        ws_client.recv_buf.drain(..len);
+        assert!(!frame.masked, "Server sent a masked frame");
+        match frame.opcode {
+            hiisi::websocket::OPCODE_TEXT => {
+                let msg: hiisi::proto::WsServerMsg =
+                    serde_json::from_slice(&frame.payload).unwrap();
+                check_ws_msg(&mut ws_client, msg);
+            }
+            hiisi::websocket::OPCODE_PONG => {
+                assert_eq!(frame.payload, b"ping");
+                ws_client.pong = true;
+            }
+            hiisi::websocket::OPCODE_CLOSE => {
+                assert_eq!(
+                    frame.payload,
+                    hiisi::websocket::CLOSE_NORMAL.to_be_bytes(),
+                    "Server closed the WebSocket with an error"
+                );
+                let now_ms = io.now_ms();
+                ws_client.wake_at = Some(now_ms + WS_INTERVAL.as_millis() as u64);
+                drop(ws_client);
+                io.close(sock, on_ws_client_close);
+                return;
+            }
+            opcode => panic!("Unexpected WebSocket frame: {:#x}", opcode),
+        }
+    }
+    let done = ws_client.hello_ok && ws_client.pong && ws_client.answered.len() == 4;
+    if done {
+        ws_client.exchanges += 1;
+        ws_client.answered.clear();
+        drop(ws_client);
+        let mut frame = BytesMut::new();
+        hiisi::websocket::format_close_frame(
+            &mut frame,
+            hiisi::websocket::CLOSE_NORMAL,
+            Some(WS_MASK),
+        );
+        let n = frame.len();
+        io.send(sock, frame.freeze(), n, on_ws_client_send);
+        return;
+    }
+    drop(ws_client);
+    io.recv(sock, on_ws_client_recv);
+}
+
+/// Check a message from the server against the request it answers.
+fn check_ws_msg(ws_client: &mut WsClient, msg: hiisi::proto::WsServerMsg) {
+    let request_id = match msg {
+        hiisi::proto::WsServerMsg::HelloOk {} => {
+            ws_client.hello_ok = true;
+            return;
+        }
+        hiisi::proto::WsServerMsg::ResponseOk {
+            request_id,
+            response,
+        } => {
+            match (request_id, response) {
+                (1 | 2, hiisi::proto::WsResponse::OpenStream {}) => {}
+                (3 | 4, hiisi::proto::WsResponse::Execute(execute)) => {
+                    match execute.result.rows[0].values.as_slice() {
+                        [hiisi::proto::Value::Integer { value }] => {
+                            assert_eq!(*value, request_id as i64, "Response to the wrong request")
+                        }
+                        values => panic!("Unexpected row: {:?}", values),
+                    }
+                }
+                (request_id, response) => {
+                    panic!("Unexpected response to {}: {:?}", request_id, response)
+                }
+            }
+            request_id
+        }
+        // A storage failure fails the request, but not the connection.
+        hiisi::proto::WsServerMsg::ResponseError { request_id, error } => {
+            assert!(
+                error.message.starts_with("I/O error"),
+                "Unexpected error: {}",
+                error.message
+            );
+            request_id
+        }
+        msg => panic!("Unexpected WebSocket message: {:?}", msg),
+    };
+    assert!(
+        !ws_client.answered.contains(&request_id),
+        "Request {} was answered twice",
+        request_id
+    );
+    ws_client.answered.push(request_id);
+}
+
+fn on_ws_client_close(_io: &mut IO, _sock: Rc<socket2::Socket>) {}
+
+/// Connect the client `client_id` to the server. The client starts sending
+/// requests once it is connected.
+fn spawn_client(io: &mut IO, client_id: usize) {
+    let server_addr: std::net::SocketAddr = SERVER_ADDR.parse().unwrap();
+    let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    io.context()
+        .user_data
+        .client_socks
+        .borrow_mut()
+        .insert(client_sock.as_raw_fd(), client_id);
+    io.connect(client_sock, server_addr.into(), on_client_connect);
+}
+
+/// The client that `sock` belongs to.
+fn client<'a>(io: &'a IO, sock: &Socket) -> &'a Client {
+    let user_data = &io.context().user_data;
+    let client_id = user_data.client_socks.borrow()[&sock.as_raw_fd()];
+    &user_data.clients[client_id]
+}
+
+/// Wait for the scheduler to let the client send its next request, which
+/// is `next_req` in the middle of a flow, or a new request otherwise.
+fn schedule_client_req(io: &mut IO, sock: Rc<Socket>, next_req: Option<ClientReq>) {
+    io.context()
+        .user_data
+        .ready_clients
+        .borrow_mut()
+        .push((sock, next_req));
+}
+
+fn on_client_connect(io: &mut IO, sock: Rc<socket2::Socket>, client_addr: socket2::SockAddr) {
+    let sockfd = sock.as_raw_fd();
+    log::trace!("Client is connected to {}", sockfd);
+    // A client that reconnects after a reset retries its request.
+    let retry_req = client(io, &sock).pending_req.take();
+    match retry_req {
+        Some(client_req) => send_client_req(io, sock, client_req),
+        None => schedule_client_req(io, sock, None),
+    }
+}
+
+/// Handle a response that the server has cut short by resetting the
+/// connection, or that the network has corrupted, returning `true` if the
+/// client is reconnecting.
+fn handle_reset(io: &mut IO, sock: Rc<socket2::Socket>, buf: &[u8], n: usize) -> bool {
+    if n == 0 {
+        reconnect(io, sock);
+        return true;
+    }
+    if is_corrupted(&buf[..n]) {
+        // The client cannot tell what the server did with the request, so
+        // it retries it on a new connection as if the connection was reset.
+        count_corrupted(&io.context().user_data);
+        reconnect(io, sock);
+        return true;
+    }
+    if is_shutting_down(&buf[..n]) {
+        // The client stops, as the server does not come back.
+        log::trace!("Server is shutting down, closing client connection");
+        io.close(sock, on_client_shutdown_close);
+        return true;
+    }
+    false
+}
+
+/// Check if the response is the one the server sends to the connections
+/// that it accepts while it shuts down.
+fn is_shutting_down(buf: &[u8]) -> bool {
+    buf.starts_with(b"HTTP/1.1 503 ")
+}
+
+fn on_client_shutdown_close(_io: &mut IO, _sock: Rc<socket2::Socket>) {}
+
+/// Reconnect to the server to retry the pending request.
+fn reconnect(io: &mut IO, sock: Rc<socket2::Socket>) {
+    let client = client(io, &sock);
+    count_retry(&client.retries);
+    let retry_req = client.pending_req.take().map(ClientReq::retry);
+    log::trace!("Connection was reset, retrying {:?}", retry_req);
+    client.pending_req.replace(retry_req);
+    io.close(sock, on_client_reset_close);
+}
+
+/// Connect the client again once its reset connection is closed.
+fn on_client_reset_close(io: &mut IO, sock: Rc<socket2::Socket>) {
+    let client_id = io
+        .context()
+        .user_data
+        .client_socks
+        .borrow_mut()
+        .remove(&sock.as_raw_fd())
+        .unwrap();
+    spawn_client(io, client_id);
+}
+
+/// Count a retry of the current request flow, failing the simulation if
+/// the flow does not eventually succeed.
+fn count_retry(retries: &Cell<usize>) {
+    let retries_now = retries.get() + 1;
+    assert!(
+        retries_now <= MAX_RETRIES,
+        "Request did not succeed after {} retries",
+        MAX_RETRIES
+    );
+    retries.set(retries_now);
+}
+
+fn count_response(client: &Client) {
+    client.responses.set(client.responses.get() + 1);
+}
+
+/// Receive a response, which may arrive in parts, and hand it to `cb` once
+/// it is whole, or once the server closes the connection.
+///
+/// A response that the server cuts short by resetting the connection is
+/// followed by end-of-file, which `cb` gets with `n` of zero.
+fn recv_resp(io: &mut IO, sock: Rc<Socket>, cb: RecvCallback) {
+    io.context()
+        .user_data
+        .partial_resps
+        .borrow_mut()
+        .insert(sock.as_raw_fd(), (Vec::new(), cb));
+    io.recv(sock, on_recv_resp_part);
+}
+
+fn on_recv_resp_part(io: &mut IO, sock: Rc<Socket>, buf: &[u8], n: usize) {
+    let user_data = &io.context().user_data;
+    let sockfd = sock.as_raw_fd();
+    let (mut resp, cb) = user_data
+        .partial_resps
+        .borrow_mut()
+        .remove(&sockfd)
+        .unwrap();
+    let is_first_part = resp.is_empty();
+    resp.extend_from_slice(&buf[..n]);
+    if n > 0 && is_truncated(&resp) {
+        user_data
+            .partial_resps
+            .borrow_mut()
+            .insert(sockfd, (resp, cb));
+        io.recv(sock, on_recv_resp_part);
+        return;
+    }
+    if n > 0 && !is_first_part {
+        let reassembled = &user_data.reassembled_responses;
+        reassembled.set(reassembled.get() + 1);
+    }
+    let n = if n == 0 { 0 } else { resp.len() };
+    cb(io, sock, &resp, n);
+}
+
+/// Check if a response is shorter than its header says it is, which means
+/// that the rest of it is still on its way, or that the server reset the
+/// connection while sending it.
+///
+/// Chunked responses have no length, and are checked while they are being
+/// decoded.
+fn is_truncated(buf: &[u8]) -> bool {
+    matches!(hiisi::client::parse_response(buf), Ok(None))
+}
+
+/// Check if the body of a response fails to decode as its `Content-Type`
+/// says it should, which means that the network has corrupted it.
+///
+/// A JSON body has to parse, and any other body has to be text without
+/// control characters.
+fn is_corrupted(buf: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut resp = httparse::Response::new(&mut headers);
+    let Ok(httparse::Status::Complete(body_off)) = resp.parse(buf) else {
+        return false;
+    };
+    let body = &buf[body_off..];
+    if body.is_empty() {
+        return false;
+    }
+    let is_json = resp.headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("Content-Type")
+            && header.value.starts_with(b"application/json")
+    });
+    if is_json {
+        return serde_json::from_slice::<serde_json::Value>(body).is_err();
+    }
+    match std::str::from_utf8(body) {
+        Ok(text) => text
+            .chars()
+            .any(|c| c.is_control() && !c.is_ascii_whitespace()),
+        Err(_) => true,
+    }
+}
+
+fn count_corrupted(user_data: &UserData) {
+    log::trace!("Response was corrupted, retrying");
+    user_data
+        .corrupted_responses
+        .set(user_data.corrupted_responses.get() + 1);
+}
+
+#[derive(Debug)]
+enum ClientReq {
+    // Client executes a single statement.
+    Execute,
+    // Client executes a single statement, sending the request body with
+    // chunked transfer encoding in two chunks.
+    ChunkedExecute,
+    // Client executes a two-step batch where the second step depends on the first.
+    Batch,
+    // Client opens a stream, which it closes with the next request.
+    OpenStream,
+    // Client closes the stream identified by the baton.
+    CloseStream(String),
+    // Client attempts to continue a stream it has already closed.
+    ReuseClosedStream(String),
+    // Client stores a SQL text on a new stream.
+    StoreSql,
+    // Client executes the SQL text it stored on the stream.
+    ExecuteStoredSql(String),
+    // Client runs a multi-statement SQL script.
+    Sequence,
+    // Client begins a transaction that writes to the database, which it
+    // reads back with the next request.
+    BeginTransaction,
+    // Client reads the row that its transaction on the stream identified by
+    // the baton has written but not committed yet.
+    ReadUncommitted(String),
+    // Client commits the transaction on the stream identified by the baton.
+    Commit(String),
+    // Client reads the result of a statement through a cursor.
+    Cursor,
+    // Client sends pipeline requests back-to-back on the connection, and is
+    // waiting for the given number of responses.
+    Pipelined(usize),
+    // Client probes the server health.
+    Health,
+}
+
+impl ClientReq {
+    /// The request to retry after the connection was reset while this
+    /// request was pending.
+    ///
+    /// Requests on a stream may have been executed before the reset, which
+    /// rotates or invalidates their baton, so the client starts their flow
+    /// over on a fresh stream.
+    fn retry(self) -> ClientReq {
+        match self {
+            ClientReq::CloseStream(_) => ClientReq::OpenStream,
+            ClientReq::ExecuteStoredSql(_) => ClientReq::StoreSql,
+            ClientReq::ReadUncommitted(_) | ClientReq::Commit(_) => ClientReq::BeginTransaction,
+            ClientReq::Pipelined(_) => ClientReq::Pipelined(PIPELINED_REQS),
+            client_req => client_req,
+        }
+    }
+}
+
+// Number of requests the client sends back-to-back.
+const PIPELINED_REQS: usize = 3;
+
+// SQL id the client stores its SQL text with.
+const TEST_SQL_ID: i32 = 1;
+
+fn gen_client_req(ctx: &Context) -> ClientReq {
+    let mut rng = ctx.user_data.rng.borrow_mut();
+    match rng.gen_range(0..10) {
+        0 => ClientReq::Execute,
+        1 => ClientReq::Batch,
+        2 => ClientReq::OpenStream,
+        3 => ClientReq::StoreSql,
+        4 => ClientReq::Sequence,
+        5 => ClientReq::Cursor,
+        6 => ClientReq::Pipelined(PIPELINED_REQS),
+        7 => ClientReq::BeginTransaction,
+        8 => ClientReq::ChunkedExecute,
+        _ => ClientReq::Health,
+    }
+}
+
+fn perform_client_req(io: &mut IO, sock: Rc<Socket>) {
+    // The previous request flow has succeeded.
+    client(io, &sock).retries.set(0);
+    let client_req = gen_client_req(io.context());
+    send_client_req(io, sock, client_req);
+}
+
+fn send_client_req(io: &mut IO, sock: Rc<Socket>, client_req: ClientReq) {
+    let http_req = match client_req {
+        ClientReq::Cursor => {
+            let req = hiisi::proto::CursorReqBody {
+                baton: None,
+                batch: hiisi::proto::Batch::single(hiisi::proto::Stmt::new("SELECT 1", true)),
+            };
+            format_http_req("/v3/cursor", hiisi::proto::format_msg(&req).unwrap())
+        }
+        ClientReq::Health => Bytes::from(format!(
+            "GET /health HTTP/1.1\r\nHost: {}\r\n\r\n",
+            TEST_DATABASE_HOST
+        )),
+        ClientReq::Pipelined(n) => {
+            let req = make_pipeline_req(&ClientReq::Execute);
+            let path = io.context().user_data.pipeline_path;
+            let http_req = format_http_req(path, hiisi::proto::format_msg(&req).unwrap());
+            let mut http_reqs = BytesMut::new();
+            for _ in 0..n {
+                http_reqs.extend_from_slice(&http_req);
+            }
+            http_reqs.into()
+        }
+        ClientReq::ChunkedExecute => {
+            let req = make_pipeline_req(&client_req);
+            let path = io.context().user_data.pipeline_path;
+            format_chunked_http_req(path, hiisi::proto::format_msg(&req).unwrap())
+        }
+        _ => {
+            let req = make_pipeline_req(&client_req);
+            let path = io.context().user_data.pipeline_path;
+            format_http_req(path, hiisi::proto::format_msg(&req).unwrap())
+        }
+    };
+    client(io, &sock).pending_req.replace(Some(client_req));
+    let n = http_req.len();
+    send_client_msg(io, sock, http_req, n);
+}
+
+fn make_pipeline_req(client_req: &ClientReq) -> hiisi::proto::PipelineReqBody {
+    let select_one = || {
+        hiisi::proto::StreamRequest::Execute(hiisi::proto::ExecuteStreamReq {
+            stmt: hiisi::proto::Stmt::new("SELECT 1", true),
+        })
+    };
+    // Writes run as sequences, which report a failing statement in the
+    // stream result rather than failing the whole pipeline.
+    let sequence = |sql: &str| {
+        hiisi::proto::StreamRequest::Sequence(hiisi::proto::SequenceStreamReq {
+            sql: Some(sql.to_owned()),
+            sql_id: None,
+            replication_index: None,
+        })
+    };
+    let (baton, req) = match client_req {
+        ClientReq::Execute | ClientReq::ChunkedExecute | ClientReq::OpenStream => {
+            (None, select_one())
+        }
+        ClientReq::Batch => (
+            None,
+            hiisi::proto::StreamRequest::Batch(hiisi::proto::BatchStreamReq {
+                batch: hiisi::proto::Batch::from_iter([
+                    hiisi::proto::Stmt::new("SELECT 1", true),
+                    hiisi::proto::Stmt::new("SELECT 2", true),
+                ]),
+            }),
+        ),
+        ClientReq::CloseStream(baton) => (
+            Some(baton.clone()),
+            hiisi::proto::StreamRequest::Close(hiisi::proto::CloseStreamReq {}),
+        ),
+        ClientReq::ReuseClosedStream(baton) => (Some(baton.clone()), select_one()),
+        ClientReq::StoreSql => (
+            None,
+            hiisi::proto::StreamRequest::StoreSql(hiisi::proto::StoreSqlStreamReq {
+                sql_id: TEST_SQL_ID,
+                sql: "SELECT 1".to_owned(),
+            }),
+        ),
+        ClientReq::ExecuteStoredSql(baton) => {
+            let mut stmt = hiisi::proto::Stmt::new("", true);
+            stmt.sql = None;
+            stmt.sql_id = Some(TEST_SQL_ID);
+            (
+                Some(baton.clone()),
+                hiisi::proto::StreamRequest::Execute(hiisi::proto::ExecuteStreamReq { stmt }),
+            )
+        }
+        ClientReq::Cursor => unreachable!("cursors are not opened with pipeline requests"),
+        ClientReq::Pipelined(_) => unreachable!("pipelined requests are sent as `Execute`"),
+        ClientReq::Health => unreachable!("health probes are not pipeline requests"),
+        ClientReq::Sequence => (
+            None,
+            hiisi::proto::StreamRequest::Sequence(hiisi::proto::SequenceStreamReq {
+                // The database outlives the sequence, so drop the table
+                // first to make the script repeatable.
+                sql: Some(
+                    "DROP TABLE IF EXISTS t; CREATE TABLE t(x); INSERT INTO t VALUES (1);"
+                        .to_owned(),
+                ),
+                sql_id: None,
+                replication_index: None,
+            }),
+        ),
+        ClientReq::BeginTransaction => (
+            None,
+            sequence(
+                "CREATE TABLE IF NOT EXISTS counter(x); BEGIN IMMEDIATE; INSERT INTO counter VALUES (1);",
+            ),
+        ),
+        // The stream holds on to its connection, so the row that the
+        // connection inserted last is the uncommitted one.
+        ClientReq::ReadUncommitted(baton) => (
+            Some(baton.clone()),
+            hiisi::proto::StreamRequest::Execute(hiisi::proto::ExecuteStreamReq {
+                stmt: hiisi::proto::Stmt::new(
+                    "SELECT count(*) FROM counter WHERE rowid = last_insert_rowid()",
+                    true,
+                ),
+            }),
+        ),
+        ClientReq::Commit(baton) => (Some(baton.clone()), sequence("COMMIT")),
+    };
+    hiisi::proto::PipelineReqBody {
+        baton,
+        requests: vec![req],
+    }
+}
+
+fn format_http_req(path: &str, buf: Bytes) -> Bytes {
+    hiisi::client::format_request(TEST_DATABASE_HOST, path, &buf)
+}
+
+/// Format a request whose body is sent with chunked transfer encoding, split
+/// in two chunks.
+fn format_chunked_http_req(path: &str, buf: Bytes) -> Bytes {
+    let (first, second) = buf.split_at(buf.len() / 2);
+    hiisi::client::format_chunked_request(TEST_DATABASE_HOST, path, &[first, second])
+}
+
+fn send_client_msg(io: &mut IO, sock: Rc<socket2::Socket>, buf: Bytes, n: usize) {
+    let fault = {
+        let user_data = &io.context().user_data;
+        gen_perform_client_req_fault(&mut user_data.rng.borrow_mut(), &user_data.faults)
+    };
+    match fault {
+        PerformClientReqFault::Normal => {
+            io.send(sock, buf, n, on_client_send_normal);
+        }
+        PerformClientReqFault::Split(off) => {
+            // The server has to wait for the second fragment before it can
+            // parse the request.
+            let off = 1 + off % (n - 1);
+            client(io, &sock)
+                .pending_fragment
+                .replace(Some(buf.slice(off..n)));
+            io.send(sock, buf.slice(..off), off, on_client_send_fragment);
+        }
+        PerformClientReqFault::WrongMethod => {
+            let path = io.context().user_data.pipeline_path;
+            let bad_request = format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n",
+                path, TEST_DATABASE_HOST
+            );
+            let n = bad_request.len();
+            io.send(sock, bad_request.into(), n, on_client_send_wrong_method);
+        }
+        PerformClientReqFault::OversizedBody => {
+            // The server rejects the body before the client sends any of it.
+            let path = io.context().user_data.pipeline_path;
+            let oversized_request = format!(
+                "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n",
+                path,
+                TEST_DATABASE_HOST,
+                hiisi::server::MAX_BODY_BYTES + 1
+            );
+            let n = oversized_request.len();
+            io.send(
+                sock,
+                oversized_request.into(),
+                n,
+                on_client_send_oversized_body,
+            );
+        }
+        PerformClientReqFault::Fuzz => {
+            let bad_request = Bytes::from_static(b"FUZZ FUZZ FUZZ"); // Fuzzed request.
+            io.send(sock, bad_request, n, on_client_send_fuzz);
+        }
+    }
+}
+
+enum PerformClientReqFault {
+    // Client sends a normal message to the server.
+    Normal,
+    // Client sends a normal message to the server in two fragments, split
+    // at the given offset.
+    Split(usize),
+    // Client sends a request with a method other than `POST`.
+    WrongMethod,
+    // Client announces a body larger than the server accepts.
+    OversizedBody,
+    // Client sends a fuzzed message to the server.
+    Fuzz,
+}
+
+fn gen_perform_client_req_fault(
+    rng: &mut ChaCha8Rng,
+    faults: &FaultConfig,
+) -> PerformClientReqFault {
+    if rng.gen_bool(faults.fuzz_prob) {
+        return PerformClientReqFault::Fuzz;
+    }
+    match rng.gen_range(0..10) {
+        0..=6 => PerformClientReqFault::Normal,
+        7 => PerformClientReqFault::Split(rng.gen()),
+        8 => PerformClientReqFault::WrongMethod,
+        _ => PerformClientReqFault::OversizedBody,
+    }
+}
+
+fn on_client_send_fragment(io: &mut IO, server_sock: Rc<socket2::Socket>, _n: usize) {
+    let fragment = client(io, &server_sock).pending_fragment.take().unwrap();
+    let n = fragment.len();
+    io.send(server_sock, fragment, n, on_client_send_normal);
+}
+
+fn on_client_send_normal(io: &mut IO, server_sock: Rc<socket2::Socket>, n: usize) {
+    recv_resp(io, server_sock, on_client_recv_normal);
+}
+
+fn on_client_recv_normal(io: &mut IO, socket: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    if handle_reset(io, socket.clone(), buf, n) {
+        return;
+    }
+    count_response(client(io, &socket));
+    let (code, body) = hiisi::client::parse_response(&buf[..n]).unwrap().unwrap();
+    let client_req = client(io, &socket).pending_req.take().unwrap();
+    if code >= 500 {
+        // A storage failure fails the request, but must not bring the server
+        // down.
+        let body = std::str::from_utf8(body).unwrap();
+        assert!(body.starts_with("I/O error"), "Unexpected error: {}", body);
+        log::trace!("Server failed {:?}: {}", client_req, body);
+        match client_req {
+            // The rest of the responses are still on their way.
+            ClientReq::Pipelined(n) if n > 1 => {
+                client(io, &socket)
+                    .pending_req
+                    .replace(Some(ClientReq::Pipelined(n - 1)));
+                recv_resp(io, socket, on_client_recv_normal);
+            }
+            client_req => {
+                count_retry(&client(io, &socket).retries);
+                send_client_req(io, socket, client_req.retry());
+            }
+        }
+        return;
+    }
+    let expected_code = match client_req {
+        ClientReq::ReuseClosedStream(_) => 400,
+        _ => 200,
+    };
+    if code != expected_code {
+        let body = String::from_utf8_lossy(body);
+        println!("Error: {} -> {}", code, body);
+        assert_eq!(code, expected_code);
+    }
+    if expected_code == 200 {
+        let client = client(io, &socket);
+        client.ok_responses.set(client.ok_responses.get() + 1);
+    }
+    if let ClientReq::Cursor = client_req {
+        client(io, &socket)
+            .cursor_resp
+            .borrow_mut()
+            .extend_from_slice(body);
+        recv_cursor(io, socket);
+        return;
+    }
+    if let ClientReq::Pipelined(n) = client_req {
+        // Each response is received on its own.
+        observe_resp(io, &socket, &ClientReq::Execute, body);
+        check_client_resp(ClientReq::Execute, body);
+        if n > 1 {
+            client(io, &socket)
+                .pending_req
+                .replace(Some(ClientReq::Pipelined(n - 1)));
+            recv_resp(io, socket, on_client_recv_normal);
+        } else {
+            schedule_client_req(io, socket, None);
+        }
+        return;
+    }
+    if !matches!(
+        client_req,
+        ClientReq::ReuseClosedStream(_) | ClientReq::Health
+    ) {
+        observe_resp(io, &socket, &client_req, body);
+    }
+    let next_req = check_client_resp(client_req, body);
+    schedule_client_req(io, socket, next_req);
+}
+
+/// Record the response to a pipeline request for the invariants to check.
+fn observe_resp(io: &IO, sock: &Socket, client_req: &ClientReq, body: &[u8]) {
+    let user_data = &io.context().user_data;
+    let client_id = user_data.client_socks.borrow()[&sock.as_raw_fd()];
+    let observation = Observation {
+        client_id,
+        req: make_pipeline_req(client_req),
+        resp: hiisi::proto::parse_resp(body).unwrap(),
+    };
+    user_data.observations.borrow_mut().push(observation);
+}
+
+/// Checks that the server ran `SELECT 1` on SQLite.
+fn check_select_one(execute: &hiisi::proto::ExecuteStreamResp) {
+    let result = &execute.result;
+    assert_eq!(result.cols.len(), 1);
+    assert_eq!(result.rows.len(), 1);
+    match result.rows[0].values.as_slice() {
+        [hiisi::proto::Value::Integer { value: 1 }] => {}
+        values => panic!("Unexpected row: {:?}", values),
+    }
+    assert_eq!(result.affected_row_count, 0);
+}
+
+/// Checks the server response to a client request, returning the follow-up
+/// request if the client is in the middle of a multi-request flow.
+fn check_client_resp(client_req: ClientReq, body: &[u8]) -> Option<ClientReq> {
+    if let ClientReq::ReuseClosedStream(_) = client_req {
+        let body = std::str::from_utf8(body).unwrap();
+        assert!(body.contains("expired"), "Unexpected error: {}", body);
+        return None;
+    }
+    if let ClientReq::Health = client_req {
+        assert_eq!(body, br#"{"status":"ok"}"#);
+        return None;
+    }
+    let resp = hiisi::proto::parse_resp(body).unwrap();
+    assert_eq!(resp.results.len(), 1);
+    let response = match &resp.results[0] {
+        hiisi::proto::StreamResult::Ok { response } => response,
+        // Another client holds the write lock of the database, so the write
+        // fails. The client gives up on the flow and closes the stream,
+        // which rolls back whatever the flow has written.
+        hiisi::proto::StreamResult::Error { error }
+            if error
+                .code
+                .as_deref()
+                .is_some_and(|code| code.starts_with("SQLITE_BUSY"))
+                && matches!(
+                    client_req,
+                    ClientReq::Sequence | ClientReq::BeginTransaction | ClientReq::Commit(_)
+                ) =>
+        {
+            log::trace!("{:?} failed: {}", client_req, error.message);
+            return resp.baton.map(ClientReq::CloseStream);
+        }
+        result => panic!("Unexpected stream result: {:?}", result),
+    };
+    match (client_req, response) {
+        (
+            ClientReq::Execute | ClientReq::ChunkedExecute,
+            hiisi::proto::StreamResponse::Execute(execute),
+        ) => {
+            check_select_one(execute);
+            None
+        }
+        (ClientReq::Batch, hiisi::proto::StreamResponse::Batch(resp)) => {
+            let result = &resp.result;
+            assert_eq!(result.step_results.len(), 2);
+            assert_eq!(result.step_errors.len(), 2);
+            assert!(result.step_results.iter().all(|result| result.is_some()));
+            assert!(result.step_errors.iter().all(|error| error.is_none()));
+            None
+        }
+        (ClientReq::OpenStream, hiisi::proto::StreamResponse::Execute(execute)) => {
+            // The statement is the first of the stream, so it runs on the
+            // connection that the session opens for it.
+            check_select_one(execute);
+            let baton = resp.baton.clone().unwrap();
+            Some(ClientReq::CloseStream(baton))
+        }
+        (ClientReq::CloseStream(baton), hiisi::proto::StreamResponse::Close(_)) => {
+            assert!(resp.baton.is_none());
+            Some(ClientReq::ReuseClosedStream(baton))
+        }
+        (ClientReq::StoreSql, hiisi::proto::StreamResponse::StoreSql(_)) => {
+            let baton = resp.baton.clone().unwrap();
+            Some(ClientReq::ExecuteStoredSql(baton))
+        }
+        (ClientReq::ExecuteStoredSql(_), hiisi::proto::StreamResponse::Execute(execute)) => {
+            // Storing the SQL does not need a connection, so the session
+            // opens one only for this statement.
+            check_select_one(execute);
+            let baton = resp.baton.clone().unwrap();
+            Some(ClientReq::CloseStream(baton))
+        }
+        (ClientReq::Sequence, hiisi::proto::StreamResponse::Sequence(_)) => None,
+        (ClientReq::BeginTransaction, hiisi::proto::StreamResponse::Sequence(_)) => {
+            let baton = resp.baton.clone().unwrap();
+            Some(ClientReq::ReadUncommitted(baton))
+        }
+        (ClientReq::ReadUncommitted(_), hiisi::proto::StreamResponse::Execute(execute)) => {
+            match execute.result.rows[0].values.as_slice() {
+                [hiisi::proto::Value::Integer { value: 1 }] => {}
+                values => panic!("Uncommitted row is not visible: {:?}", values),
+            }
+            let baton = resp.baton.clone().unwrap();
+            Some(ClientReq::Commit(baton))
+        }
+        (ClientReq::Commit(_), hiisi::proto::StreamResponse::Sequence(_)) => {
+            let baton = resp.baton.clone().unwrap();
+            Some(ClientReq::CloseStream(baton))
+        }
+        (_, response) => panic!("Unexpected stream response: {:?}", response),
+    }
+}
+
+fn on_client_recv_cursor(io: &mut IO, socket: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    let client = client(io, &socket);
+    if n == 0 {
+        // The server reset the connection before the last chunk.
+        client.cursor_resp.borrow_mut().clear();
+        client.pending_req.replace(Some(ClientReq::Cursor));
+        reconnect(io, socket);
+        return;
+    }
+    client.cursor_resp.borrow_mut().extend_from_slice(&buf[..n]);
+    recv_cursor(io, socket);
+}
+
+/// Checks the cursor response once it has been received completely, or
+/// waits for more of it.
+fn recv_cursor(io: &mut IO, socket: Rc<socket2::Socket>) {
+    let body = {
+        let cursor_resp = client(io, &socket).cursor_resp.borrow();
+        decode_chunked(&cursor_resp)
+    };
+    match body {
+        Some(body) => {
+            client(io, &socket).cursor_resp.borrow_mut().clear();
+            check_cursor_resp(&body);
+            schedule_client_req(io, socket, None);
+        }
+        None => io.recv(socket, on_client_recv_cursor),
+    }
+}
+
+/// Decodes a chunked response body, returning `None` if the last chunk has
+/// not been received yet.
+fn decode_chunked(mut buf: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = buf.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&buf[..line_end]).unwrap();
+        let size = usize::from_str_radix(size, 16).unwrap();
+        let chunk_end = line_end + 2 + size;
+        if buf.len() < chunk_end + 2 {
+            return None;
+        }
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(&buf[line_end + 2..chunk_end]);
+        buf = &buf[chunk_end + 2..];
+    }
+}
+
+fn check_cursor_resp(body: &[u8]) {
+    let mut lines = body.split(|b| *b == b'\n');
+    let resp: hiisi::proto::CursorRespBody = serde_json::from_slice(lines.next().unwrap()).unwrap();
+    assert!(resp.baton.is_some());
+    let mut rows = 0;
+    for line in lines {
+        let entry: hiisi::proto::CursorEntry = serde_json::from_slice(line).unwrap();
+        match entry {
+            hiisi::proto::CursorEntry::StepBegin(begin) => {
+                assert_eq!(begin.step, 0);
+                assert_eq!(begin.cols.len(), 1);
+            }
+            hiisi::proto::CursorEntry::Row { .. } => rows += 1,
+            hiisi::proto::CursorEntry::StepEnd(_) => break,
+            entry => panic!("Unexpected cursor entry: {:?}", entry),
+        }
+    }
+    assert_eq!(rows, 1);
+}
+
+fn on_client_send_fuzz(io: &mut IO, server_sock: Rc<socket2::Socket>, n: usize) {
+    recv_resp(io, server_sock, on_client_recv_fuzz);
+}
+
+fn on_client_recv_fuzz(io: &mut IO, socket: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    if handle_reset(io, socket.clone(), buf, n) {
+        return;
+    }
+    count_response(client(io, &socket));
+    client(io, &socket).pending_req.take();
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut resp = httparse::Response::new(&mut headers);
+    let body_off = resp.parse(buf).unwrap().unwrap();
+    if resp.code.unwrap() != 400 {
+        let body = std::str::from_utf8(&buf[body_off..]).unwrap();
+        println!("Error: {:?} -> {}", resp, body);
+        assert_eq!(resp.code.unwrap(), 400);
+    }
+    schedule_client_req(io, socket, None);
+}
+
+fn on_client_send_wrong_method(io: &mut IO, server_sock: Rc<socket2::Socket>, _n: usize) {
+    recv_resp(io, server_sock, on_client_recv_wrong_method);
+}
+
+fn on_client_recv_wrong_method(io: &mut IO, socket: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    if handle_reset(io, socket.clone(), buf, n) {
+        return;
+    }
+    count_response(client(io, &socket));
+    client(io, &socket).pending_req.take();
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut resp = httparse::Response::new(&mut headers);
+    resp.parse(buf).unwrap().unwrap();
+    assert_eq!(resp.code.unwrap(), 405);
+    let allow = resp
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Allow"))
+        .map(|header| header.value);
+    assert_eq!(allow, Some(&b"POST"[..]));
+    schedule_client_req(io, socket, None);
+}
+
+fn on_client_send_oversized_body(io: &mut IO, server_sock: Rc<socket2::Socket>, _n: usize) {
+    recv_resp(io, server_sock, on_client_recv_oversized_body);
+}
+
+fn on_client_recv_oversized_body(io: &mut IO, socket: Rc<socket2::Socket>, buf: &[u8], n: usize) {
+    if handle_reset(io, socket.clone(), buf, n) {
+        return;
+    }
+    count_response(client(io, &socket));
+    client(io, &socket).pending_req.take();
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut resp = httparse::Response::new(&mut headers);
+    resp.parse(buf).unwrap().unwrap();
+    assert_eq!(resp.code.unwrap(), 413);
+    // The server closes the connection, as it does not read the body, so
+    // the client starts over on a new one.
+    io.close(socket, on_client_reset_close);
+}
+
+/// Read the number of clients to simulate from `CLIENTS`.
+fn clients_from_env() -> usize {
+    let clients = match std::env::var("CLIENTS") {
+        Ok(clients) => clients.parse::<usize>().unwrap(),
+        Err(_) => DEFAULT_CLIENTS,
+    };
+    assert!(clients > 0, "CLIENTS must be at least 1");
+    clients
+}
+
+/// The bound on the completions that a tick runs, from
+/// `MAX_EVENTS_PER_TICK`. Ticks are unbounded unless it is set.
+///
+/// A replay runs the completions on the ticks of the trace, so the bound
+/// does not apply to it.
+fn max_events_per_tick_from_env() -> Option<usize> {
+    let n = std::env::var("MAX_EVENTS_PER_TICK")
+        .ok()?
+        .parse::<usize>()
+        .unwrap();
+    assert!(n > 0, "MAX_EVENTS_PER_TICK must be at least 1");
+    Some(n)
+}
+
+fn init_logger() {
+    let env = env_logger::Env::default().default_filter_or("info");
+    env_logger::Builder::from_env(env).init();
+}
+
+#[cfg(test)]
+mod test {
+    use super::faults::FaultConfig;
+    use super::invariant::{Invariant, Observation};
+    use super::minimize::minimize_failure;
+    use super::{
+        check_determinism, gen_perform_client_req_fault, start_replay, start_simulation,
+        start_simulation_with_faults, temp_data_dir, PerformClientReqFault, Simulation,
+    };
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn fuzz_prob_one_fuzzes_every_request() {
+        let faults = FaultConfig {
+            fuzz_prob: 1.0,
+            ..FaultConfig::default()
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0..1_000 {
+            assert!(matches!(
+                gen_perform_client_req_fault(&mut rng, &faults),
+                PerformClientReqFault::Fuzz
+            ));
+        }
+    }
+
+    #[test]
+    fn all_clients_get_responses() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-clients");
+        let mut sim = start_simulation(seed, &data_dir);
+        for _ in 0..2_000 {
+            sim.step();
+        }
+        let clients = &sim.io.context().user_data.clients;
+        assert_eq!(clients.len(), 3);
+        for (client_id, client) in clients.iter().enumerate() {
+            assert!(
+                client.ok_responses.get() > 0,
+                "Client {} got no responses",
+                client_id
+            );
+        }
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn short_written_responses_are_reassembled() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-short-write");
+        let faults = FaultConfig {
+            short_write_prob: 0.5,
+            ..FaultConfig::default()
+        };
+        let mut sim = start_simulation_with_faults(seed, &data_dir, faults);
+        for _ in 0..2_000 {
+            sim.step();
+        }
+        let user_data = &sim.io.context().user_data;
+        assert!(user_data.reassembled_responses.get() > 0);
+        // A response that arrives in parts must decode once it is whole.
+        assert_eq!(user_data.corrupted_responses.get(), 0);
+        for (client_id, client) in user_data.clients.iter().enumerate() {
+            assert!(
+                client.ok_responses.get() > 0,
+                "Client {} got no responses",
+                client_id
+            );
+        }
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_responses_are_retried() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-corrupt");
+        let faults = FaultConfig {
+            corrupt_prob: 0.3,
+            ..FaultConfig::default()
+        };
+        let mut sim = start_simulation_with_faults(seed, &data_dir, faults);
+        for _ in 0..3_000 {
+            sim.step();
+        }
+        let user_data = &sim.io.context().user_data;
+        assert!(user_data.corrupted_responses.get() > 0);
+        for (client_id, client) in user_data.clients.iter().enumerate() {
+            assert!(
+                client.ok_responses.get() > 0,
+                "Client {} got no responses",
+                client_id
+            );
+        }
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    // An invariant that the first response any client observes violates.
+    struct NoResponses;
+
+    impl Invariant for NoResponses {
+        fn name(&self) -> &str {
+            "no responses"
+        }
+
+        fn check(&mut self, observations: &[Observation]) -> Result<(), String> {
+            match observations.first() {
+                Some(observation) => {
+                    Err(format!("client {} got a response", observation.client_id))
+                }
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn shutdown_drains_in_flight_request() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-shutdown");
+        let mut sim = start_simulation(seed, &data_dir);
+        while hiisi::server::requests_in_flight(&sim.io) == 0 {
+            assert!(sim.tick < 10_000, "No request was in flight");
+            sim.step();
+        }
+        let responses = |sim: &Simulation| -> usize {
+            let clients = &sim.io.context().user_data.clients;
+            clients.iter().map(|client| client.responses.get()).sum()
+        };
+        let responses_before = responses(&sim);
+        hiisi::server::shutdown(&mut sim.io);
+        assert!(responses(&sim) > responses_before);
+        assert_eq!(hiisi::server::requests_in_flight(&sim.io), 0);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn abandoned_transaction_is_rolled_back() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-abandoned");
+        let mut sim = start_simulation(seed, &data_dir);
+        while sim.io.context().user_data.abandoned_rollbacks.get() == 0 {
+            assert!(
+                sim.tick < 10_000,
+                "Abandoned transaction was not rolled back"
+            );
+            sim.step();
+        }
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn websocket_client_gets_responses() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-websocket");
+        let mut sim = start_simulation(seed, &data_dir);
+        for _ in 0..3_000 {
+            sim.step();
+        }
+        assert!(sim.io.context().user_data.ws_client.borrow().exchanges > 0);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn continue_client_gets_interim_response() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-continue");
+        let mut sim = start_simulation(seed, &data_dir);
+        for _ in 0..3_000 {
+            sim.step();
+        }
+        assert!(
+            sim.io
+                .context()
+                .user_data
+                .continue_client
+                .borrow()
+                .exchanges
+                > 0
+        );
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn half_closed_connection_gets_response() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-half-close");
+        let mut sim = start_simulation(seed, &data_dir);
+        for _ in 0..3_000 {
+            sim.step();
+        }
+        assert!(
+            sim.io
+                .context()
+                .user_data
+                .half_closing_client
+                .borrow()
+                .exchanges
+                > 0
+        );
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn stream_survives_full_database() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-full");
+        let mut sim = start_simulation(seed, &data_dir);
+        for _ in 0..3_000 {
+            sim.step();
+        }
+        assert!(sim.io.context().user_data.full_client.borrow().survivals > 0);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn throttled_client_waits_for_rate_limit() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-throttled");
+        let mut sim = start_simulation(seed, &data_dir);
+        for _ in 0..3_000 {
+            sim.step();
+        }
+        let throttled_client = sim.io.context().user_data.throttled_client.borrow();
+        assert!(throttled_client.throttled > 0);
+        assert!(throttled_client.recovered > 0);
+        drop(throttled_client);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn stalled_client_is_disconnected() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-stalled");
+        let mut sim = start_simulation(seed, &data_dir);
+        for _ in 0..3_000 {
+            sim.step();
+        }
+        assert!(sim.io.context().user_data.stalled_disconnects.get() > 0);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn invariant_violation_is_detected() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-invariant");
+        let mut sim = start_simulation(seed, &data_dir);
+        sim.register_invariant(Box::new(NoResponses));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            for _ in 0..2_000 {
+                sim.step();
+            }
+        }));
+        std::fs::remove_dir_all(&data_dir).unwrap();
+        let err = result.expect_err("Invariant violation was not detected");
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(
+            msg.starts_with("Invariant \"no responses\" violated at tick "),
+            "Unexpected panic: {}",
+            msg
+        );
+        assert!(
+            msg.contains("of simulation with seed 0: client "),
+            "Unexpected panic: {}",
+            msg
+        );
+    }
+
+    #[test]
+    fn failure_is_minimized() {
+        let faults = FaultConfig {
+            fuzz_prob: 0.5,
+            reset_prob: 0.1,
+            latency_ms: Some(hiisi::io::Latency {
+                min_ms: 1,
+                max_ms: 10,
+            }),
+            storage_fault_prob: 0.1,
+            corrupt_prob: 0.1,
+            short_write_prob: 0.1,
+        };
+        let invariants = || -> Vec<Box<dyn Invariant>> { vec![Box::new(NoResponses)] };
+        let failure = minimize_failure(0, 2_000, faults, invariants).unwrap();
+        // The invariant fails without any faults.
+        let faults = &failure.fault_config;
+        assert_eq!(faults.fuzz_prob, 0.0);
+        assert_eq!(faults.reset_prob, 0.0);
+        assert!(faults.latency_ms.is_none());
+        assert_eq!(faults.storage_fault_prob, 0.0);
+        assert_eq!(faults.corrupt_prob, 0.0);
+        assert_eq!(faults.short_write_prob, 0.0);
+        let test = failure.regression_test();
+        assert!(
+            test.contains(&format!("for _ in 0..{} {{", failure.tick)),
+            "{}",
+            test
+        );
+        assert!(test.contains("    let fault_config = FaultConfig {\n        fuzz_prob: 0.0,"));
+    }
+
+    #[test]
+    fn simulation_is_deterministic() {
+        for seed in [0, 1, 2] {
+            check_determinism(seed, 2_000);
+        }
+    }
+
+    #[test]
+    fn replay_trace() {
+        let seed = 0;
+        let data_dir = temp_data_dir(seed, "test-record");
+        let mut sim = start_simulation(seed, &data_dir);
+        sim.io.start_trace(seed);
+        for _ in 0..2_000 {
+            sim.step();
+        }
+        let trace = sim.io.take_trace().unwrap();
+        let databases = sim.io.context().manager.list_databases().unwrap();
+        std::fs::remove_dir_all(&data_dir).unwrap();
+
+        let mut buf = Vec::new();
+        trace.write(&mut buf).unwrap();
+        let read_trace = hiisi::io::Trace::read(buf.as_slice()).unwrap();
+        assert_eq!(read_trace, trace);
+
+        let data_dir = temp_data_dir(seed, "test-replay");
+        let mut replay = start_replay(read_trace, &data_dir);
+        replay.start_trace(seed);
+        while !replay.replay_finished() {
+            replay.run_once();
+        }
+        let replayed_trace = replay.take_trace().unwrap();
+        assert_eq!(replayed_trace.server_events(), trace.server_events());
+        assert_eq!(
+            replay.context().manager.list_databases().unwrap(),
+            databases
+        );
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
 }
@@ -0,0 +1,108 @@
+//! Shrinking of the fault configuration of a failing simulation.
+//!
+//! A seed reproduces a simulation only as long as the configuration stays
+//! the same, but a failure found with many faults injected is easier to
+//! debug with fewer of them. As the simulation is deterministic, a
+//! configuration either fails for a seed or it does not, so the minimizer
+//! can search for a smaller one by running the simulation again.
+
+use crate::faults::FaultConfig;
+use crate::invariant::Invariant;
+use crate::{start_simulation_with_faults, temp_data_dir};
+
+/// The invariants that a run of the minimizer checks in addition to the
+/// built-in ones. Every run starts with invariants of its own, as they keep
+/// state between ticks.
+pub type Invariants = fn() -> Vec<Box<dyn Invariant>>;
+
+/// A failing configuration that the minimizer could not reduce further.
+#[derive(Debug)]
+pub struct Failure {
+    pub seed: u64,
+    pub fault_config: FaultConfig,
+    /// The tick at which the simulation fails.
+    pub tick: u64,
+}
+
+impl Failure {
+    /// Format the failure as a regression test to paste into the tests of
+    /// the simulator, which fails until the bug is fixed.
+    ///
+    /// The test checks only the built-in invariants, so a failure of another
+    /// invariant needs it registered on the simulation of the test too.
+    pub fn regression_test(&self) -> String {
+        let fault_config = self.fault_config.to_rust().replace('\n', "\n    ");
+        format!(
+            r#"#[test]
+fn regression_seed_{seed}() {{
+    let seed = {seed};
+    let data_dir = temp_data_dir(seed, "regression");
+    let fault_config = {fault_config};
+    let mut sim = start_simulation_with_faults(seed, &data_dir, fault_config);
+    for _ in 0..{tick} {{
+        sim.step();
+    }}
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}}"#,
+            seed = self.seed,
+            fault_config = fault_config,
+            tick = self.tick,
+        )
+    }
+}
+
+/// Run the simulation with `seed` and `fault_config` for up to `ticks`, and
+/// return the tick at which it fails, if it does.
+pub fn run_until_failure(
+    seed: u64,
+    ticks: u64,
+    fault_config: FaultConfig,
+    invariants: Invariants,
+) -> Option<u64> {
+    let data_dir = temp_data_dir(seed, "minimize");
+    let mut sim = start_simulation_with_faults(seed, &data_dir, fault_config);
+    for invariant in invariants() {
+        sim.register_invariant(invariant);
+    }
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        while sim.tick < ticks {
+            sim.step();
+        }
+    }));
+    let tick = sim.tick;
+    drop(sim);
+    std::fs::remove_dir_all(&data_dir).unwrap();
+    result.err().map(|_| tick)
+}
+
+/// Find the smallest configuration that still fails the simulation with
+/// `seed` within `ticks`, starting from `fault_config`, or return `None` if
+/// `fault_config` does not fail it in the first place.
+///
+/// The minimizer takes the first reduction of the configuration that still
+/// fails, turning off one fault at a time before it makes the remaining
+/// ones rarer, until none of the reductions fails.
+pub fn minimize_failure(
+    seed: u64,
+    ticks: u64,
+    fault_config: FaultConfig,
+    invariants: Invariants,
+) -> Option<Failure> {
+    let tick = run_until_failure(seed, ticks, fault_config.clone(), invariants)?;
+    let mut failure = Failure {
+        seed,
+        fault_config,
+        tick,
+    };
+    'search: loop {
+        for reduced in failure.fault_config.reductions() {
+            log::info!("Trying faults {:?}", reduced);
+            if let Some(tick) = run_until_failure(seed, ticks, reduced.clone(), invariants) {
+                failure.fault_config = reduced;
+                failure.tick = tick;
+                continue 'search;
+            }
+        }
+        return Some(failure);
+    }
+}
@@ -0,0 +1,47 @@
+#!/usr/bin/env bash
+#
+# Capture the golden pipeline response of `hiisi-server/src/proto.rs` from
+# sqld, the libSQL server.
+#
+# The script starts the `sqld` on the `PATH` on an empty database, or uses
+# the one at `SQLD_URL` if it is set, which must serve an empty database.
+# It creates the table that the pipeline reads, sends the pipeline of
+# `hiisi-server/src/proto/pipeline_req.json`, and writes the response to
+# `hiisi-server/src/proto/pipeline_resp.json` with the baton, the durations,
+# and the replication indexes normalized, since they differ from run to
+# run. The version of sqld is recorded in the comment of the test.
+
+set -euo pipefail
+
+ROOT=$(cd "$(dirname "$0")/.." && pwd)
+PROTO_DIR=$ROOT/hiisi-server/src/proto
+
+if [ -z "${SQLD_URL:-}" ]; then
+    DB_DIR=$(mktemp -d)
+    trap 'kill $SQLD_PID 2> /dev/null; rm -rf "$DB_DIR"' EXIT
+    sqld --db-path "$DB_DIR/data.sqld" --http-listen-addr 127.0.0.1:18080 > /dev/null 2>&1 &
+    SQLD_PID=$!
+    SQLD_URL=http://127.0.0.1:18080
+    for _ in $(seq 50); do
+        curl -sf "$SQLD_URL/health" > /dev/null && break
+        sleep 0.1
+    done
+    SQLD_VERSION=$(sqld --version)
+else
+    SQLD_VERSION=$(curl -sf "$SQLD_URL/version" || echo "unknown, served at $SQLD_URL")
+fi
+
+curl -sf "$SQLD_URL/v2/pipeline" -d @- > /dev/null <<'SETUP'
+{"baton":null,"requests":[{"type":"batch","batch":{"steps":[{"stmt":{"sql":"CREATE TABLE t (id INTEGER, v)"}},{"stmt":{"sql":"INSERT INTO t VALUES (1, 'hiisi'), (2.5, NULL), (3, x'000102ff')"}}]}},{"type":"close"}]}
+SETUP
+
+curl -sf "$SQLD_URL/v2/pipeline" -d @"$PROTO_DIR/pipeline_req.json" |
+    sed -E \
+        -e 's/"baton":"[^"]*"/"baton":"baton"/' \
+        -e 's/"query_duration_ms":[0-9.eE+-]+/"query_duration_ms":0.0/g' \
+        -e 's/"replication_index":"[0-9]+"/"replication_index":null/g' \
+        > "$PROTO_DIR/pipeline_resp.json"
+echo >> "$PROTO_DIR/pipeline_resp.json"
+
+sed -i -E "s|// sqld version: .*|// sqld version: ${SQLD_VERSION//|/\\|}|" "$ROOT/hiisi-server/src/proto.rs"
+echo "Captured the response of $SQLD_VERSION"